@@ -0,0 +1,53 @@
+//! Check that a Cortex-M kernel starts with a sane vector table.
+//!
+//! The first word of the vector table is the initial stack pointer, which must
+//! be inside RAM, and the second is the reset handler, which must be a Thumb
+//! address inside flash. Erased or half written flash fails one of these.
+
+pub struct CortexMKernelValidator {
+    flash_start: u32,
+    flash_end: u32,
+    ram_start: u32,
+    ram_end: u32,
+}
+
+impl CortexMKernelValidator {
+    /// The kernel must be in `flash_start..flash_end` and its stack in
+    /// `ram_start..ram_end`.
+    pub fn new(
+        flash_start: u32,
+        flash_end: u32,
+        ram_start: u32,
+        ram_end: u32,
+    ) -> CortexMKernelValidator {
+        CortexMKernelValidator {
+            flash_start,
+            flash_end,
+            ram_start,
+            ram_end,
+        }
+    }
+}
+
+impl bootloader::interfaces::KernelValidator for CortexMKernelValidator {
+    fn kernel_valid(&self, address: u32) -> bool {
+        // The vector table must be word aligned and inside flash.
+        if address % 4 != 0
+            || address < self.flash_start
+            || address.saturating_add(8) > self.flash_end
+        {
+            return false;
+        }
+
+        let vector_table = address as *const u32;
+        let initial_sp = unsafe { core::ptr::read_volatile(vector_table) };
+        let reset_handler = unsafe { core::ptr::read_volatile(vector_table.offset(1)) };
+
+        // The stack grows down, so the initial SP may be the end of RAM.
+        let sp_valid = initial_sp > self.ram_start && initial_sp <= self.ram_end;
+        let reset_handler_valid = reset_handler & 1 == 1
+            && reset_handler & !1 >= self.flash_start
+            && reset_handler & !1 < self.flash_end;
+        sp_valid && reset_handler_valid
+    }
+}
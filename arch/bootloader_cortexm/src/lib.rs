@@ -1,3 +1,4 @@
 #![no_std]
 
 pub mod jumper;
+pub mod kernel_validator;
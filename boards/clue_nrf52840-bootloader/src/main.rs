@@ -186,6 +186,24 @@ pub unsafe fn main() {
         )
     );
 
+    // Only jump to a kernel that looks like it will run, otherwise stay in
+    // the bootloader.
+    let kernel_validator_vector_table = static_init!(
+        bootloader_cortexm::kernel_validator::CortexMKernelValidator,
+        bootloader_cortexm::kernel_validator::CortexMKernelValidator::new(
+            0x00000000, 0x00100000, 0x20000000, 0x20040000
+        )
+    );
+    let kernel_validator_crc = static_init!(
+        bootloader::kernel_validator_crc::KernelValidatorCrc,
+        bootloader::kernel_validator_crc::KernelValidatorCrc::new()
+    );
+    let kernel_validators = static_init!(
+        [&'static dyn bootloader::interfaces::KernelValidator; 2],
+        [kernel_validator_vector_table, kernel_validator_crc]
+    );
+    bootloader_enterer.set_kernel_validators(kernel_validators);
+
     // First decide if we want to actually run the bootloader or not.
     bootloader_enterer.check();
 
@@ -326,6 +344,19 @@ pub unsafe fn main() {
     hil::uart::Receive::set_receive_client(cdc, recv_auto_cdc);
     hil::uart::Receive::set_receive_client(recv_auto_cdc, bootloader);
     hil::flash::HasClient::set_client(flash_adapter, bootloader);
+    // The nRF52840 has 1 MiB of flash.
+    bootloader.set_flash_size(0x100000);
+
+    let device_id = static_init!(
+        bootloader_nrf52::device_id_ficr::DeviceIdFicr,
+        bootloader_nrf52::device_id_ficr::DeviceIdFicr::new()
+    );
+    bootloader.set_device_id(device_id);
+
+    // Let INFO report why we are in the bootloader.
+    if let Some(stay_reason) = bootloader_enterer.stay_reason() {
+        bootloader.set_stay_reason(stay_reason);
+    }
 
     //--------------------------------------------------------------------------
     // ALTERNATIVE BOOTLOADER STACK
@@ -168,6 +168,24 @@ pub unsafe fn main() {
         )
     );
 
+    // Only jump to a kernel that looks like it will run, otherwise stay in
+    // the bootloader.
+    let kernel_validator_vector_table = static_init!(
+        bootloader_cortexm::kernel_validator::CortexMKernelValidator,
+        bootloader_cortexm::kernel_validator::CortexMKernelValidator::new(
+            0x00000000, 0x00080000, 0x20000000, 0x20020000
+        )
+    );
+    let kernel_validator_crc = static_init!(
+        bootloader::kernel_validator_crc::KernelValidatorCrc,
+        bootloader::kernel_validator_crc::KernelValidatorCrc::new()
+    );
+    let kernel_validators = static_init!(
+        [&'static dyn bootloader::interfaces::KernelValidator; 2],
+        [kernel_validator_vector_table, kernel_validator_crc]
+    );
+    bootloader_enterer.set_kernel_validators(kernel_validators);
+
     // First decide if we want to actually run the bootloader or not.
     bootloader_enterer.check();
 
@@ -278,6 +296,37 @@ pub unsafe fn main() {
     hil::uart::Receive::set_receive_client(&base_peripherals.uarte0, recv_auto_uart);
     hil::uart::Receive::set_receive_client(recv_auto_uart, bootloader);
     hil::flash::HasClient::set_client(flash_adapter, bootloader);
+    // The nRF52833 has 512 KiB of flash.
+    bootloader.set_flash_size(0x80000);
+
+    let device_id = static_init!(
+        bootloader_nrf52::device_id_ficr::DeviceIdFicr,
+        bootloader_nrf52::device_id_ficr::DeviceIdFicr::new()
+    );
+    bootloader.set_device_id(device_id);
+
+    // Let INFO report why we are in the bootloader.
+    if let Some(stay_reason) = bootloader_enterer.stay_reason() {
+        bootloader.set_stay_reason(stay_reason);
+    }
+
+    // Timeout for reverting a baud rate change that is never verified.
+    let timeout_virtual_alarm = static_init!(
+        VirtualMuxAlarm<'static, nrf52833::rtc::Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    timeout_virtual_alarm.setup();
+
+    let timeout = static_init!(
+        bootloader::alarm_timeout::AlarmTimeout<
+            'static,
+            VirtualMuxAlarm<'static, nrf52833::rtc::Rtc>,
+        >,
+        bootloader::alarm_timeout::AlarmTimeout::new(timeout_virtual_alarm)
+    );
+    timeout_virtual_alarm.set_alarm_client(timeout);
+    bootloader::interfaces::Timeout::set_client(timeout, bootloader);
+    bootloader.set_timeout(timeout);
 
     //--------------------------------------------------------------------------
     // SCHEDULER
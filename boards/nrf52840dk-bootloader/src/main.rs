@@ -22,6 +22,7 @@ use kernel::static_init;
 use bootloader::null_scheduler::NullScheduler;
 
 use capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm;
+use capsules_core::virtualizers::virtual_spi::VirtualSpiMasterDevice;
 
 use nrf52840::gpio::Pin;
 use nrf52840::interrupt_service::Nrf52840DefaultPeripherals;
@@ -35,6 +36,27 @@ const LED_4: Pin = Pin::P0_16;
 #[allow(dead_code)]
 const BUTTON_4: Pin = Pin::P0_25;
 
+// On-board MX25R6435F QSPI flash. It is driven in single-SPI mode over SPIM0
+// with the capsules-extra MX25R6435F driver: the pinned kernel has no
+// nRF52840 QSPI driver, and the bootloader only moves one page per command,
+// so the extra throughput of quad mode would not be noticed over the UART.
+const SPI_MOSI: Pin = Pin::P0_20;
+const SPI_MISO: Pin = Pin::P0_21;
+const SPI_CLK: Pin = Pin::P0_19;
+const SPI_MX25R6435F_CHIP_SELECT: Pin = Pin::P0_17;
+const SPI_MX25R6435F_WRITE_PROTECT_PIN: Pin = Pin::P0_22;
+const SPI_MX25R6435F_HOLD_PIN: Pin = Pin::P0_23;
+
+/// Size of the MX25R6435F.
+const MX25R6435F_SIZE: u32 = 0x800000;
+
+type Mx25r6435f = capsules_extra::mx25r6435f::MX25R6435F<
+    'static,
+    VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>,
+    nrf52840::gpio::GPIOPin<'static>,
+    VirtualMuxAlarm<'static, nrf52840::rtc::Rtc<'static>>,
+>;
+
 include!(concat!(env!("OUT_DIR"), "/attributes.rs"));
 
 // Number of concurrent processes this platform supports.
@@ -190,6 +212,24 @@ pub unsafe fn main() {
         )
     );
 
+    // Only jump to a kernel that looks like it will run, otherwise stay in
+    // the bootloader.
+    let kernel_validator_vector_table = static_init!(
+        bootloader_cortexm::kernel_validator::CortexMKernelValidator,
+        bootloader_cortexm::kernel_validator::CortexMKernelValidator::new(
+            0x00000000, 0x00100000, 0x20000000, 0x20040000
+        )
+    );
+    let kernel_validator_crc = static_init!(
+        bootloader::kernel_validator_crc::KernelValidatorCrc,
+        bootloader::kernel_validator_crc::KernelValidatorCrc::new()
+    );
+    let kernel_validators = static_init!(
+        [&'static dyn bootloader::interfaces::KernelValidator; 2],
+        [kernel_validator_vector_table, kernel_validator_crc]
+    );
+    bootloader_enterer.set_kernel_validators(kernel_validators);
+
     // First decide if we want to actually run the bootloader or not.
     bootloader_enterer.check();
 
@@ -306,6 +346,63 @@ pub unsafe fn main() {
     hil::uart::Receive::set_receive_client(&base_peripherals.uarte0, recv_auto_uart);
     hil::uart::Receive::set_receive_client(recv_auto_uart, bootloader);
     hil::flash::HasClient::set_client(flash_adapter, bootloader);
+    // The nRF52840 has 1 MiB of flash.
+    bootloader.set_flash_size(0x100000);
+
+    let device_id = static_init!(
+        bootloader_nrf52::device_id_ficr::DeviceIdFicr,
+        bootloader_nrf52::device_id_ficr::DeviceIdFicr::new()
+    );
+    bootloader.set_device_id(device_id);
+
+    // Let INFO report why we are in the bootloader.
+    if let Some(stay_reason) = bootloader_enterer.stay_reason() {
+        bootloader.set_stay_reason(stay_reason);
+    }
+
+    //--------------------------------------------------------------------------
+    // EXTERNAL FLASH
+    //--------------------------------------------------------------------------
+
+    // Single-SPI mode on SPIM0; see the pin definitions at the top.
+    let mux_spi = components::spi::SpiMuxComponent::new(&base_peripherals.spim0)
+        .finalize(components::spi_mux_component_static!(nrf52840::spi::SPIM));
+
+    base_peripherals.spim0.configure(
+        nrf52840::pinmux::Pinmux::new(SPI_MOSI as u32),
+        nrf52840::pinmux::Pinmux::new(SPI_MISO as u32),
+        nrf52840::pinmux::Pinmux::new(SPI_CLK as u32),
+    );
+
+    let mx25r6435f = components::mx25r6435f::Mx25r6435fComponent::new(
+        Some(&nrf52840_peripherals.gpio_port[SPI_MX25R6435F_WRITE_PROTECT_PIN]),
+        Some(&nrf52840_peripherals.gpio_port[SPI_MX25R6435F_HOLD_PIN]),
+        &nrf52840_peripherals.gpio_port[SPI_MX25R6435F_CHIP_SELECT] as &dyn hil::gpio::Pin,
+        mux_alarm,
+        mux_spi,
+    )
+    .finalize(components::mx25r6435f_component_static!(
+        nrf52840::spi::SPIM,
+        nrf52840::gpio::GPIOPin,
+        nrf52840::rtc::Rtc
+    ));
+
+    let external_pagebuffer = static_init!(
+        capsules_extra::mx25r6435f::Mx25r6435fSector,
+        capsules_extra::mx25r6435f::Mx25r6435fSector::default()
+    );
+
+    let external_flash_adapter = static_init!(
+        bootloader::external_flash_adapter::ExternalFlashAdapter<'static, Mx25r6435f>,
+        bootloader::external_flash_adapter::ExternalFlashAdapter::new(
+            mx25r6435f,
+            external_pagebuffer,
+            MX25R6435F_SIZE
+        )
+    );
+    hil::flash::HasClient::set_client(mx25r6435f, external_flash_adapter);
+    bootloader::interfaces::ExternalFlash::set_client(external_flash_adapter, bootloader);
+    bootloader.set_external_flash(external_flash_adapter);
 
     //--------------------------------------------------------------------------
     // FINAL SETUP AND BOARD BOOT
@@ -0,0 +1,59 @@
+//! Implement the bootloader's `Timeout` interface with an alarm.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let timeout_virtual_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52833::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! timeout_virtual_alarm.setup();
+//!
+//! let timeout = static_init!(
+//!     bootloader::alarm_timeout::AlarmTimeout<'static, VirtualMuxAlarm<'static, nrf52833::rtc::Rtc>>,
+//!     bootloader::alarm_timeout::AlarmTimeout::new(timeout_virtual_alarm)
+//! );
+//! timeout_virtual_alarm.set_alarm_client(timeout);
+//! ```
+
+use kernel::hil;
+use kernel::hil::time::ConvertTicks;
+use kernel::utilities::cells::OptionalCell;
+
+use crate::interfaces;
+
+pub struct AlarmTimeout<'a, A: hil::time::Alarm<'a> + 'a> {
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn interfaces::TimeoutClient>,
+}
+
+impl<'a, A: hil::time::Alarm<'a>> AlarmTimeout<'a, A> {
+    pub fn new(alarm: &'a A) -> AlarmTimeout<'a, A> {
+        AlarmTimeout {
+            alarm,
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a, A: hil::time::Alarm<'a>> interfaces::Timeout<'a> for AlarmTimeout<'a, A> {
+    fn set_client(&self, client: &'a dyn interfaces::TimeoutClient) {
+        self.client.set(client);
+    }
+
+    fn start(&self, ms: u32) {
+        let interval = self.alarm.ticks_from_ms(ms);
+        self.alarm.set_alarm(self.alarm.now(), interval);
+    }
+
+    fn cancel(&self) {
+        let _ = self.alarm.disarm();
+    }
+}
+
+impl<'a, A: hil::time::Alarm<'a>> hil::time::AlarmClient for AlarmTimeout<'a, A> {
+    fn alarm(&self) {
+        self.client.map(|client| client.timeout_expired());
+    }
+}
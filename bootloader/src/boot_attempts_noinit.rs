@@ -0,0 +1,44 @@
+//! Keep the boot attempt count in a word of RAM that is not cleared on boot.
+//!
+//! The board has to set aside the word, for example in a `.noinit` section,
+//! at the same address in the bootloader and the kernel. RAM holds random
+//! values after a power cycle, so the count is stored next to a magic value
+//! and reads as zero without it.
+
+use kernel::utilities::cells::VolatileCell;
+use kernel::utilities::StaticRef;
+
+use crate::interfaces;
+
+/// Magic value in the upper half of the word when it holds a count. The
+/// count is in the lowest byte.
+pub const BOOT_ATTEMPTS_MAGIC: u32 = 0xB007_0000;
+
+pub struct BootAttemptsNoinit {
+    word: StaticRef<VolatileCell<u32>>,
+}
+
+impl BootAttemptsNoinit {
+    /// Use the RAM word at `address`. The kernel clears the count by writing
+    /// zero to it.
+    pub fn new(address: usize) -> BootAttemptsNoinit {
+        BootAttemptsNoinit {
+            word: unsafe { StaticRef::new(address as *const VolatileCell<u32>) },
+        }
+    }
+}
+
+impl interfaces::BootAttempts for BootAttemptsNoinit {
+    fn attempts(&self) -> u8 {
+        let word = self.word.get();
+        if word & 0xFFFF_FF00 == BOOT_ATTEMPTS_MAGIC {
+            word as u8
+        } else {
+            0
+        }
+    }
+
+    fn set_attempts(&self, attempts: u8) {
+        self.word.set(BOOT_ATTEMPTS_MAGIC | attempts as u32);
+    }
+}
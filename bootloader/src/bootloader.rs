@@ -5,21 +5,89 @@ use core::cmp;
 use kernel::ErrorCode;
 
 use kernel::hil;
+use kernel::utilities::cells::MapCell;
+use kernel::utilities::cells::OptionalCell;
 use kernel::utilities::cells::TakeCell;
 use kernel::utilities::cells::VolatileCell;
 use kernel::utilities::StaticRef;
+use tock_bootloader_protocol::auth::{self, AUTH_KEY_LEN, AUTH_MAC_LEN, AUTH_NONCE_LEN};
+use tock_bootloader_protocol::compression;
+use tock_bootloader_protocol::encrypted_image::{ImageCipher, IMAGE_KEY_LEN, IMAGE_NONCE_LEN};
+use tock_bootloader_protocol::hash::{Sha256, HASH_SHA256};
+use tock_bootloader_protocol::prelude::Encoder;
+use tock_bootloader_protocol::{
+    CMD_AUTH_CHALLENGE, CMD_AUTH_RESPONSE, CMD_BEGIN_ENC_IMAGE, CMD_CHANGE_BAUD, CMD_CRCEF,
+    CMD_CRCIF, CMD_END_ENC_IMAGE, CMD_EPAGE, CMD_ERASE_RANGE, CMD_EXIT, CMD_GATTR,
+    CMD_GET_CAPABILITIES, CMD_GET_SKIPPED_PAGES, CMD_GET_SLOTS, CMD_HASHIF, CMD_ID, CMD_INFO,
+    CMD_PING, CMD_RESET, CMD_RRANGE, CMD_SATTR, CMD_SKERNELCRC, CMD_SSLOTPENDING, CMD_SSTARTADDR,
+    CMD_WPAGE, CMD_WRITE_ENC_PAGE, CMD_WRITE_PAGES, CMD_WRITE_PAGE_COMPRESSED,
+    CMD_WRITE_STREAM_PAGE, CMD_XEBLOCK, CMD_XEPAGE, CMD_XFINIT, CMD_XRRANGE, CMD_XWPAGE,
+    ERASE_PROGRESS_PAGES, MAX_INFO_LEN, STREAM_WINDOW, WRITE_PAGES_HEADER_LEN,
+};
 
 use crate::bootloader_crc;
+use crate::flash_policy::{FlashPolicy, FlashRegion};
 use crate::interfaces;
+use crate::kernel_slots;
+use crate::kernel_validator_crc::KERNEL_CRC_FLAGS_OFFSET;
 
 // Main buffer that commands are received into and sent from.
 // Need a buffer big enough for 512 byte pages.
 pub static mut BUF: [u8; 600] = [0; 600];
 
+// Buffer for acknowledging streamed pages while `BUF` receives the next one.
+// Big enough for a `PAGE_ACK` with every byte escaped.
+pub static mut ACK_BUF: [u8; 16] = [0; 16];
+
 // How long to wait, in bit periods, after receiving a byte for the next
 // byte before timing out and calling `receive_complete`.
 const UART_RECEIVE_TIMEOUT: u8 = 100;
 
+// Baud rate the bootloader starts with.
+const DEFAULT_BAUD_RATE: u32 = 115200;
+
+// How long to wait, in milliseconds, for the host to verify a new baud rate
+// before going back to the old one.
+const CHANGE_BAUD_VERIFY_TIMEOUT: u32 = 1000;
+
+// How long to wait, in milliseconds, before trying again to receive when the
+// UART wouldn't start.
+const RECEIVE_RETRY_TIMEOUT: u32 = 10;
+
+// Name reported in the INFO response.
+const BOOTLOADER_NAME: &str = "Tock Bootloader";
+
+// Commands every bootloader handles.
+const COMMANDS: [u8; 17] = [
+    CMD_PING,
+    CMD_INFO,
+    CMD_RESET,
+    CMD_EPAGE,
+    CMD_ERASE_RANGE,
+    CMD_WPAGE,
+    CMD_WRITE_PAGES,
+    CMD_WRITE_PAGE_COMPRESSED,
+    CMD_RRANGE,
+    CMD_SATTR,
+    CMD_GATTR,
+    CMD_CRCIF,
+    CMD_HASHIF,
+    CMD_EXIT,
+    CMD_SSTARTADDR,
+    CMD_GET_CAPABILITIES,
+    CMD_SKERNELCRC,
+];
+
+// Commands handled once the bootloader has external flash.
+const EXTERNAL_FLASH_COMMANDS: [u8; 6] = [
+    CMD_XEBLOCK,
+    CMD_XWPAGE,
+    CMD_XRRANGE,
+    CMD_CRCEF,
+    CMD_XEPAGE,
+    CMD_XFINIT,
+];
+
 // Get the addresses in flash of key components from the linker file.
 extern "C" {
     static _flags_address: u8;
@@ -38,25 +106,124 @@ const RES_BADARGS: u8 = 0x14;
 const RES_OK: u8 = 0x15;
 const RES_UNKNOWN: u8 = 0x16;
 const RES_READ_RANGE: u8 = 0x20;
+const RES_EX_READ_RANGE: u8 = 0x21;
 const RES_GET_ATTR: u8 = 0x22;
 const RES_CRCIF: u8 = 0x23;
+const RES_CRCXF: u8 = 0x24;
 const RES_INFO: u8 = 0x25;
+const RES_CHANGE_BAUD_FAIL: u8 = 0x26;
+const RES_ID: u8 = 0x27;
+const RES_UNAUTHENTICATED: u8 = 0x2B;
+const RES_FLASH_ERROR: u8 = 0x2C;
+const RES_VERIFY_FAILED: u8 = 0x2D;
 
 #[derive(Copy, Clone, PartialEq)]
 enum State {
     Idle,
     Info,
+    /// Reading the attributes to go with the already read flags.
+    InfoAttributes {
+        version: [u8; 8],
+        start_address: u32,
+    },
     ErasePage,
+    /// Erasing the page at `address` of an `ERASE_RANGE` that ends at `end`.
+    EraseRange {
+        address: u32,
+        end: u32,
+    },
     GetAttribute {
         index: u8,
     },
     SetAttribute {
         index: u8,
     },
+    /// Reading the device key to check the MAC from the host against.
+    AuthResponse {
+        nonce: [u8; AUTH_NONCE_LEN],
+        mac: [u8; AUTH_MAC_LEN],
+    },
+    /// Reading the image key to start decrypting an image.
+    BeginEncryptedImage {
+        address: u32,
+        nonce: [u8; IMAGE_NONCE_LEN],
+    },
+    /// Erasing the pages written of an encrypted image that won't be
+    /// finished, from `address` up to `end`, before doing `then`.
+    DiscardEncryptedImage {
+        address: u32,
+        end: u32,
+        then: AfterDiscard,
+    },
     SetStartAddress {
         address: u32,
     },
+    SetKernelCrc {
+        length: u32,
+        crc: u32,
+    },
+    GetSlots,
+    SetSlotPending {
+        slot: usize,
+        length: u32,
+        crc: u32,
+        version: u32,
+    },
     WriteFlashPage,
+    /// Reading the page a page from the host is for, to see whether it
+    /// already holds the data.
+    ComparePage {
+        page_index: usize,
+    },
+    /// Writing a page from the host that will be read back to check it,
+    /// with `retries` more writes allowed if it doesn't match.
+    WriteVerifiedPage {
+        page_index: usize,
+        retries: u8,
+    },
+    /// Reading back a page written in `WriteVerifiedPage`.
+    VerifyPage {
+        page_index: usize,
+        retries: u8,
+    },
+    /// Writing the streamed page for `address`, while the UART receives the
+    /// next one.
+    StreamWrite {
+        address: u32,
+    },
+    /// Writing page `index` of the `pages` pages of a `WRITE_PAGES` burst
+    /// starting at `address`. With write verification on, `retries` more
+    /// writes are allowed if the page doesn't read back the same.
+    WritePages {
+        address: u32,
+        index: u8,
+        pages: u8,
+        retries: u8,
+    },
+    /// Reading the flash page `index` of a `WRITE_PAGES` burst is for, to
+    /// see whether it already holds the data.
+    CompareBurstPage {
+        address: u32,
+        index: u8,
+        pages: u8,
+    },
+    /// Reading back page `index` of a `WRITE_PAGES` burst written in
+    /// `WritePages`.
+    VerifyBurstPage {
+        address: u32,
+        index: u8,
+        pages: u8,
+        retries: u8,
+    },
+    /// Reading back page `index` of a `WRITE_PAGES` burst once all `pages`
+    /// are written, for the CRC of what is in flash. `crc` covers the pages
+    /// before it.
+    CrcBurstPages {
+        address: u32,
+        index: u8,
+        pages: u8,
+        crc: u32,
+    },
     ReadRange {
         address: u32,
         length: u16,
@@ -67,6 +234,150 @@ enum State {
         remaining_length: u32,
         crc: u32,
     },
+    /// Like `Crc`, but adding to `range_hash`.
+    Hash {
+        address: u32,
+        remaining_length: u32,
+    },
+    ExWritePage,
+    ExErase,
+    ExReadRange {
+        address: u32,
+        length: u16,
+        remaining_length: u16,
+    },
+    ExCrc {
+        address: u32,
+        remaining_length: u32,
+        crc: u32,
+    },
+    /// Switch to `baud_rate` once the response to `Set` has been sent.
+    ChangeBaudSet {
+        baud_rate: u32,
+    },
+    /// Running at `new_baud_rate` and waiting for the host to verify it.
+    ChangeBaudVerify {
+        old_baud_rate: u32,
+        new_baud_rate: u32,
+    },
+    /// Go back to `baud_rate` once the failure response has been sent.
+    ChangeBaudRevert {
+        baud_rate: u32,
+    },
+}
+
+/// What to do once the pages of an unfinished encrypted image are erased.
+#[derive(Copy, Clone, PartialEq)]
+enum AfterDiscard {
+    /// The tag did not match. Tell the host.
+    Reject,
+    /// Start the next image the host asked for.
+    Begin {
+        address: u32,
+        nonce: [u8; IMAGE_NONCE_LEN],
+    },
+    /// Leave the bootloader.
+    Exit,
+}
+
+/// `PAGE_ACK`s (address and result) waiting for the UART, oldest first. A
+/// host that keeps to `STREAM_WINDOW` never has more than this waiting.
+#[derive(Copy, Clone, Default)]
+struct AckQueue {
+    acks: [(u32, u8); STREAM_WINDOW],
+    length: usize,
+}
+
+impl AckQueue {
+    /// Add `ack` after the others. Returns `false` if there is no room.
+    fn push(&mut self, ack: (u32, u8)) -> bool {
+        match self.acks.get_mut(self.length) {
+            Some(slot) => {
+                *slot = ack;
+                self.length += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The oldest ack.
+    fn first(&self) -> Option<(u32, u8)> {
+        self.acks[..self.length].first().copied()
+    }
+
+    /// Remove the oldest ack.
+    fn remove_first(&mut self) {
+        if self.length > 0 {
+            self.acks.copy_within(1..self.length, 0);
+            self.length -= 1;
+        }
+    }
+}
+
+/// Locations in flash of the regions the bootloader manages.
+///
+/// On hardware these come from the linker script, see
+/// `FlashLayout::from_linker()`. Hosted builds (like the emulator) provide
+/// them explicitly.
+#[derive(Clone, Copy)]
+pub struct FlashLayout {
+    /// Address of the flags region.
+    pub flags_address: usize,
+    /// Address of the attributes region.
+    pub attributes_address: usize,
+    /// Address of the bootloader in flash.
+    pub bootloader_address: u32,
+    /// Address after the bootloader in flash.
+    pub bootloader_end_address: u32,
+}
+
+impl FlashLayout {
+    /// Get the layout from the symbols defined by the linker script.
+    pub fn from_linker() -> FlashLayout {
+        FlashLayout {
+            flags_address: unsafe { (&_flags_address as *const u8) as usize },
+            attributes_address: unsafe { (&_attributes_address as *const u8) as usize },
+            bootloader_address: unsafe { (&_stext as *const u8) as u32 },
+            bootloader_end_address: unsafe { (&_etext as *const u8) as u32 },
+        }
+    }
+}
+
+/// Why the bootloader is running instead of the kernel. Reported by `INFO`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StayReason {
+    /// The `BootloaderEntry` asked for the bootloader.
+    Requested,
+    /// There is no kernel that passes the checks.
+    NoKernel,
+    /// The kernel did not come up for as many boots as allowed, and there is
+    /// no other kernel to go back to.
+    BootFailures,
+    /// Secure boot is on, and the kernel is not signed with a trusted key.
+    Unsigned,
+}
+
+impl StayReason {
+    /// The name used in the `INFO` JSON.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StayReason::Requested => "requested",
+            StayReason::NoKernel => "no_kernel",
+            StayReason::BootFailures => "boot_failures",
+            StayReason::Unsigned => "unsigned",
+        }
+    }
+}
+
+/// What to do in secure boot mode when no kernel has a valid signature.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SecureBootPolicy {
+    /// Stay in the bootloader, so a signed kernel can be uploaded.
+    StayInBootloader,
+    /// Refuse to run anything and halt. The bootloader is not started, so on
+    /// a board in the field nobody can use it to read or change the flash.
+    Refuse,
 }
 
 /// This struct handles whether we should enter the bootloader or go straight to
@@ -75,6 +386,21 @@ pub struct BootloaderEnterer<'a> {
     entry_decider: &'a dyn interfaces::BootloaderEntry,
     jumper: &'a dyn interfaces::Jumper,
     active_notifier: &'a mut dyn interfaces::ActiveNotifier,
+    /// Checks the kernel has to pass before we jump to it.
+    kernel_validators: &'a [&'a dyn interfaces::KernelValidator],
+    /// Set if the board boots from kernel slots rather than the start address.
+    flags_writer: Option<&'a dyn interfaces::FlagsWriter>,
+    /// Optional count of boots the kernel has not confirmed yet.
+    boot_attempts: Option<&'a dyn interfaces::BootAttempts>,
+    /// How many unconfirmed boots we allow before giving up on a kernel.
+    max_boot_attempts: u8,
+    /// Set by `check()` if we stay in the bootloader.
+    stay_reason: Option<StayReason>,
+    /// Optional signature check for secure boot.
+    kernel_verifier: Option<&'a dyn interfaces::KernelVerifier>,
+    secure_boot_policy: SecureBootPolicy,
+    /// Set if a kernel was turned down because of its signature.
+    signature_failed: Cell<bool>,
     /// This is the address of flash where the flags region of the bootloader
     /// start. We need this to determine what address to jump to.
     bootloader_flags_address: u32,
@@ -90,36 +416,241 @@ impl<'a> BootloaderEnterer<'a> {
             entry_decider,
             jumper,
             active_notifier,
+            kernel_validators: &[],
+            flags_writer: None,
+            boot_attempts: None,
+            max_boot_attempts: 0,
+            stay_reason: None,
+            kernel_verifier: None,
+            secure_boot_policy: SecureBootPolicy::StayInBootloader,
+            signature_failed: Cell::new(false),
             bootloader_flags_address: unsafe { (&_flags_address as *const u8) as u32 },
         }
     }
 
+    /// Only jump to the kernel if it passes all of `kernel_validators`.
+    pub fn set_kernel_validators(
+        &mut self,
+        kernel_validators: &'a [&'a dyn interfaces::KernelValidator],
+    ) {
+        self.kernel_validators = kernel_validators;
+    }
+
+    /// Boot from the kernel slots described in the flags, falling back to the
+    /// start address only if no slot has been used yet. `flags_writer` is
+    /// used to update the slot states.
+    pub fn set_kernel_slots(&mut self, flags_writer: &'a dyn interfaces::FlagsWriter) {
+        self.flags_writer = Some(flags_writer);
+    }
+
+    /// Count the boots of the kernel in `boot_attempts`, and give up on the
+    /// kernel after `max_boot_attempts` boots it did not confirm. Then we go
+    /// back to the other kernel slot if there is one, or stay in the
+    /// bootloader.
+    pub fn set_boot_attempts(
+        &mut self,
+        boot_attempts: &'a dyn interfaces::BootAttempts,
+        max_boot_attempts: u8,
+    ) {
+        self.boot_attempts = Some(boot_attempts);
+        self.max_boot_attempts = max_boot_attempts;
+    }
+
+    /// Turn on secure boot: only jump to a kernel image that `kernel_verifier`
+    /// accepts. The length of the image, with its signature footer, is the
+    /// one stored with `SET_KERNEL_CRC`, or the slot length for kernel
+    /// slots. `policy` decides what happens if no kernel passes.
+    pub fn set_kernel_verifier(
+        &mut self,
+        kernel_verifier: &'a dyn interfaces::KernelVerifier,
+        policy: SecureBootPolicy,
+    ) {
+        self.kernel_verifier = Some(kernel_verifier);
+        self.secure_boot_policy = policy;
+    }
+
+    /// Why `check()` decided to stay in the bootloader.
+    pub fn stay_reason(&self) -> Option<StayReason> {
+        self.stay_reason
+    }
+
     pub fn check(&mut self) {
-        if !self.entry_decider.stay_in_bootloader() {
+        if self.entry_decider.stay_in_bootloader() {
+            self.stay_reason = Some(StayReason::Requested);
+        } else {
+            let failed = self.boot_attempts.map_or(false, |boot_attempts| {
+                boot_attempts.attempts() >= self.max_boot_attempts
+            });
+            // Only get a kernel that looks like it would run. Otherwise we
+            // stay in the bootloader so it can be fixed.
+            let kernel_address = match self.flags_writer {
+                Some(flags_writer) => self.choose_kernel_slot(flags_writer, failed),
+                None if failed => None,
+                None => self.start_kernel(),
+            };
+
             // Jump to the kernel and start the real code.
-            self.jump();
+            if let Some(address) = kernel_address {
+                if let Some(boot_attempts) = self.boot_attempts {
+                    // After a rollback the count starts over for the other
+                    // kernel.
+                    let attempts = if failed { 0 } else { boot_attempts.attempts() };
+                    boot_attempts.set_attempts(attempts.saturating_add(1));
+                }
+                self.jumper.jump(address);
+            }
+
+            self.stay_reason = Some(StayReason::NoKernel);
+            if self.signature_failed.get() {
+                self.stay_reason = Some(StayReason::Unsigned);
+                if self.secure_boot_policy == SecureBootPolicy::Refuse {
+                    loop {
+                        core::hint::spin_loop();
+                    }
+                }
+            } else if failed {
+                self.stay_reason = Some(StayReason::BootFailures);
+                // Try the kernel again on the next reset, which is most
+                // likely after the host has fixed it.
+                if let Some(boot_attempts) = self.boot_attempts {
+                    boot_attempts.set_attempts(0);
+                }
+            }
+        }
+
+        // Staying in the bootloader, allow a custom active notification to
+        // start.
+        self.active_notifier.active();
+    }
+
+    fn kernel_valid(&self, address: u32) -> bool {
+        self.kernel_validators
+            .iter()
+            .all(|validator| validator.kernel_valid(address))
+    }
+
+    /// Check the signature of the `length` byte image at `address`, if
+    /// secure boot is on.
+    fn kernel_signed(&self, address: u32, length: u32) -> bool {
+        let kernel_verifier = match self.kernel_verifier {
+            Some(kernel_verifier) => kernel_verifier,
+            None => return true,
+        };
+        // Without a length there is no way to find the signature.
+        let signed = length != 0
+            && length != 0xFFFFFFFF
+            && address.checked_add(length).is_some()
+            && kernel_verifier.verify(unsafe {
+                core::slice::from_raw_parts(address as *const u8, length as usize)
+            });
+        if !signed {
+            self.signature_failed.set(true);
+        }
+        signed
+    }
+
+    /// The kernel at the start address, if it passes the checks.
+    fn start_kernel(&self) -> Option<u32> {
+        let address = self.start_address();
+        let length = self.read_flag(KERNEL_CRC_FLAGS_OFFSET);
+        if self.kernel_valid(address) && self.kernel_signed(address, length) {
+            Some(address)
         } else {
-            // Staying in the bootloader, allow a custom active notification to
-            // start.
-            self.active_notifier.active();
+            None
+        }
+    }
+
+    /// Pick the kernel slot to boot and store the state changes that go with
+    /// it. `failed` means the kernel booted last did not come up too many
+    /// times. Returns `None` if slots have been used but none can be booted.
+    fn choose_kernel_slot(
+        &self,
+        flags_writer: &dyn interfaces::FlagsWriter,
+        failed: bool,
+    ) -> Option<u32> {
+        let flags_address = self.bootloader_flags_address as usize;
+        let flags = unsafe {
+            core::slice::from_raw_parts(
+                flags_address as *const u8,
+                kernel_slots::descriptor_offset(kernel_slots::NUM_KERNEL_SLOTS),
+            )
+        };
+        let mut slots = [
+            kernel_slots::SlotDescriptor::from_flags(flags, 0),
+            kernel_slots::SlotDescriptor::from_flags(flags, 1),
+        ];
+        if slots
+            .iter()
+            .all(|slot| slot.state == tock_bootloader_protocol::SlotState::Empty)
+        {
+            // Not using slots yet, so the kernel is at the start address,
+            // and there is nothing to go back to if it fails.
+            return if failed { None } else { self.start_kernel() };
+        }
+
+        let previous = slots;
+        let bootable = |slot: &kernel_slots::SlotDescriptor| {
+            if slot.length == 0 {
+                return false;
+            }
+            let kernel = unsafe {
+                core::slice::from_raw_parts(slot.address as *const u8, slot.length as usize)
+            };
+            let crc = bootloader_crc::update(0xFFFFFFFF, kernel) ^ 0xFFFFFFFF;
+            crc == slot.crc
+                && self.kernel_valid(slot.address)
+                && self.kernel_signed(slot.address, slot.length)
+        };
+        let chosen = match kernel_slots::trial_slot(&slots) {
+            // With a boot counter, a kernel on trial is retried until it
+            // confirms or runs out of attempts.
+            Some(i) if self.boot_attempts.is_some() && !failed && bootable(&slots[i]) => Some(i),
+            _ => {
+                if failed {
+                    kernel_slots::reject_booted_slot(&mut slots);
+                }
+                kernel_slots::choose_boot_slot(&mut slots, &bootable)
+            }
+        };
+
+        for (i, slot) in slots.iter().enumerate() {
+            if slot.state != previous[i].state {
+                let address = flags_address
+                    + kernel_slots::descriptor_offset(i)
+                    + kernel_slots::SLOT_STATE_OFFSET;
+                flags_writer.write_word(address as u32, kernel_slots::state_to_word(slot.state));
+            }
         }
+
+        chosen.map(|i| slots[i].address)
     }
 
-    fn jump(&self) {
+    fn start_address(&self) -> u32 {
         // Address of the start address in the flags region is 32 bytes from the start.
-        let start_address_memory_location = self.bootloader_flags_address + 32;
+        self.read_flag(32)
+    }
 
-        let start_address_ptr: StaticRef<VolatileCell<u32>> =
-            unsafe { StaticRef::new(start_address_memory_location as *const VolatileCell<u32>) };
+    fn read_flag(&self, offset: usize) -> u32 {
+        let flag_memory_location = self.bootloader_flags_address + offset as u32;
 
-        let start_address = start_address_ptr.get();
+        let flag_ptr: StaticRef<VolatileCell<u32>> =
+            unsafe { StaticRef::new(flag_memory_location as *const VolatileCell<u32>) };
 
-        self.jumper.jump(start_address);
+        flag_ptr.get()
     }
 }
 
 /// The main bootloader code.
-pub struct Bootloader<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'static> {
+///
+/// `N` is the capacity of the command decoder, which is the longest command
+/// the host may send. It must fit a page and its header; the default fits
+/// pages of up to 4 KiB.
+pub struct Bootloader<
+    'a,
+    U: hil::uart::UartAdvanced<'a> + 'a,
+    F: hil::flash::Flash + 'static,
+    const N: usize = { tock_bootloader_protocol::DEFAULT_DECODER_CAPACITY },
+> {
     uart: &'a U,
     flash: &'a F,
     reset_function: &'a (dyn Fn() + 'a),
@@ -132,16 +663,114 @@ pub struct Bootloader<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Fl
     bootloader_address: u32,
     /// Address after the bootloader in flash.
     bootloader_end_address: u32,
+    /// Optional external flash for the `*Ex*` and `*Ext*` commands.
+    external_flash: OptionalCell<&'a dyn interfaces::ExternalFlash<'a>>,
+    /// Optional source of the ID for the `ID` command.
+    device_id: OptionalCell<&'a dyn interfaces::DeviceId>,
+    /// Optional timeout, needed to support changing the baud rate.
+    timeout: OptionalCell<&'a dyn interfaces::Timeout<'a>>,
+    /// Optional kernel slots for A/B updates.
+    kernel_slots: OptionalCell<kernel_slots::SlotLayout>,
+    /// Why we are running, for `INFO`, if the board told us.
+    stay_reason: OptionalCell<StayReason>,
+    /// Optional source of nonces. If set, commands that change flash need an
+    /// authenticated session.
+    random_source: OptionalCell<&'a dyn interfaces::RandomSource>,
+    /// Index of the attribute that holds the device key.
+    auth_key_index: Cell<u8>,
+    /// The nonce sent for the last `AUTH_CHALLENGE`, until it is answered.
+    auth_nonce: OptionalCell<[u8; AUTH_NONCE_LEN]>,
+    /// Whether the host has answered a challenge since we started.
+    authenticated: Cell<bool>,
+    /// Optional index of the attribute that holds the key for encrypted
+    /// images.
+    image_key_index: OptionalCell<u8>,
+    /// The encrypted image being written, from `BEGIN_ENC_IMAGE` until
+    /// `END_ENC_IMAGE`.
+    image_cipher: MapCell<ImageCipher>,
+    /// `image_cipher` after decrypting the page being written. It replaces
+    /// `image_cipher` once the page is written, so a page that fails can be
+    /// sent again.
+    image_page_cipher: MapCell<ImageCipher>,
+    /// End of the pages of the encrypted image that may have changed,
+    /// including one that failed to write.
+    image_end: Cell<u32>,
+    /// The hash of the range being read for `HASH_INT_FLASH`.
+    range_hash: MapCell<Sha256>,
+    /// Optional number of times to retry writing a page from the host that
+    /// doesn't read back the same. If set, every page is checked.
+    write_retries: OptionalCell<u8>,
+    /// Whether to leave pages from the host that already hold the data
+    /// alone.
+    skip_unchanged: Cell<bool>,
+    /// How many pages were left alone because they already held the data.
+    skipped_pages: Cell<u32>,
+    /// Second page buffer, which the next streamed page waits in while the
+    /// one before it is written.
+    stream_buffer: TakeCell<'static, F::Page>,
+    /// Buffer for `PAGE_ACK`s, so they can be sent while `buffer` receives.
+    ack_buffer: TakeCell<'static, [u8]>,
+    /// Whether the board gave us the buffers for `WRITE_STREAM_PAGE`.
+    stream_enabled: Cell<bool>,
+    /// Address of the streamed page waiting in `stream_buffer`.
+    stream_queued: OptionalCell<u32>,
+    /// Length of what is left of a receive in `buffer`, put aside because
+    /// both page buffers were full when it was reached.
+    stream_deferred: OptionalCell<usize>,
+    /// Whether `ack_buffer` is being sent.
+    ack_sending: Cell<bool>,
+    /// `PAGE_ACK`s that couldn't be sent yet.
+    ack_waiting: Cell<AckQueue>,
+    /// Length of a response left in `buffer` until the `PAGE_ACK`s are sent.
+    response_waiting: OptionalCell<usize>,
+    /// The baud rate the host and bootloader agreed on.
+    baud_rate: Cell<u32>,
+    /// Size of a page of `flash`, as used by the page commands.
+    page_size: usize,
+    /// Size of `buffer`, which is often out with the UART.
+    buffer_size: usize,
+    /// Size of the internal flash, if the board told us.
+    flash_size: Cell<u32>,
+    /// What the host may read, write and erase.
+    flash_policy: Cell<FlashPolicy<'a>>,
+    /// Tool to parse incoming bootloader messages. It needs a big buffer, and
+    /// it keeps partial commands across receives, so it lives here rather
+    /// than on the stack.
+    decoder: MapCell<tock_bootloader_protocol::CommandDecoder<N>>,
 }
 
-impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a> Bootloader<'a, U, F> {
+impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a, const N: usize>
+    Bootloader<'a, U, F, N>
+{
     pub fn new(
         uart: &'a U,
         flash: &'a F,
         reset_function: &'a (dyn Fn() + 'a),
         page_buffer: &'static mut F::Page,
         buffer: &'static mut [u8],
-    ) -> Bootloader<'a, U, F> {
+    ) -> Bootloader<'a, U, F, N> {
+        Bootloader::new_with_layout(
+            uart,
+            flash,
+            reset_function,
+            page_buffer,
+            buffer,
+            FlashLayout::from_linker(),
+        )
+    }
+
+    /// Create the bootloader with an explicit flash layout rather than the
+    /// one from the linker script.
+    pub fn new_with_layout(
+        uart: &'a U,
+        flash: &'a F,
+        reset_function: &'a (dyn Fn() + 'a),
+        page_buffer: &'static mut F::Page,
+        buffer: &'static mut [u8],
+        layout: FlashLayout,
+    ) -> Bootloader<'a, U, F, N> {
+        let page_size = page_buffer.as_mut().len();
+        let buffer_size = buffer.len();
         Bootloader {
             uart: uart,
             flash: flash,
@@ -149,451 +778,2153 @@ impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a> Bootloa
             page_buffer: TakeCell::new(page_buffer),
             buffer: TakeCell::new(buffer),
             state: Cell::new(State::Idle),
-            flags_address: unsafe { (&_flags_address as *const u8) as usize },
-            attributes_address: unsafe { (&_attributes_address as *const u8) as usize },
-            bootloader_address: unsafe { (&_stext as *const u8) as u32 },
-            bootloader_end_address: unsafe { (&_etext as *const u8) as u32 },
+            flags_address: layout.flags_address,
+            attributes_address: layout.attributes_address,
+            bootloader_address: layout.bootloader_address,
+            bootloader_end_address: layout.bootloader_end_address,
+            external_flash: OptionalCell::empty(),
+            device_id: OptionalCell::empty(),
+            timeout: OptionalCell::empty(),
+            kernel_slots: OptionalCell::empty(),
+            stay_reason: OptionalCell::empty(),
+            random_source: OptionalCell::empty(),
+            auth_key_index: Cell::new(0),
+            auth_nonce: OptionalCell::empty(),
+            authenticated: Cell::new(false),
+            image_key_index: OptionalCell::empty(),
+            write_retries: OptionalCell::empty(),
+            skip_unchanged: Cell::new(false),
+            skipped_pages: Cell::new(0),
+            stream_buffer: TakeCell::empty(),
+            ack_buffer: TakeCell::empty(),
+            stream_enabled: Cell::new(false),
+            stream_queued: OptionalCell::empty(),
+            stream_deferred: OptionalCell::empty(),
+            ack_sending: Cell::new(false),
+            ack_waiting: Cell::new(AckQueue::default()),
+            response_waiting: OptionalCell::empty(),
+            image_cipher: MapCell::empty(),
+            image_page_cipher: MapCell::empty(),
+            image_end: Cell::new(0),
+            range_hash: MapCell::empty(),
+            baud_rate: Cell::new(DEFAULT_BAUD_RATE),
+            page_size,
+            buffer_size,
+            flash_size: Cell::new(0),
+            flash_policy: Cell::new(FlashPolicy::new(&layout, page_size as u32)),
+            decoder: MapCell::new(tock_bootloader_protocol::CommandDecoder::with_capacity()),
         }
     }
 
+    /// Tell the bootloader how big the internal flash is, for
+    /// `GET_CAPABILITIES` and to keep the host inside it. Without this the
+    /// size is reported as 0 (unknown).
+    pub fn set_flash_size(&self, flash_size: u32) {
+        self.flash_size.set(flash_size);
+        let policy = self.flash_policy.get();
+        self.flash_policy
+            .set(policy.with_flash(FlashRegion::new(0, flash_size)));
+    }
+
+    /// Replace the default `FlashPolicy`, which protects the bootloader, flags
+    /// and attributes, for example to reserve more regions. Boards that call
+    /// this should call it after `set_flash_size()`, with the flash bounds
+    /// already set on `policy`.
+    pub fn set_flash_policy(&self, policy: FlashPolicy<'a>) {
+        self.flash_policy.set(policy);
+    }
+
+    /// Give the bootloader an external flash chip to operate on. Without one,
+    /// the external flash commands are answered with `RES_UNKNOWN`.
+    pub fn set_external_flash(&self, external_flash: &'a dyn interfaces::ExternalFlash<'a>) {
+        self.external_flash.set(external_flash);
+    }
+
+    /// Give the bootloader a timeout to use. Without one, the
+    /// `CHANGE_BAUD_RATE` command is answered with `RES_UNKNOWN`, as there
+    /// would be no way to recover from the host not verifying the new rate.
+    pub fn set_timeout(&self, timeout: &'a dyn interfaces::Timeout<'a>) {
+        self.timeout.set(timeout);
+    }
+
     pub fn start(&self) {
         // Setup UART and start listening.
-        let _ = self.uart.configure(hil::uart::Parameters {
-            baud_rate: 115200,
-            width: hil::uart::Width::Eight,
-            stop_bits: hil::uart::StopBits::One,
-            parity: hil::uart::Parity::None,
-            hw_flow_control: false,
-        });
+        let _ = self.configure_uart(self.baud_rate.get());
 
         self.buffer.take().map(|buffer| {
-            let _ = self
-                .uart
-                .receive_automatic(buffer, buffer.len(), UART_RECEIVE_TIMEOUT);
+            self.receive(buffer);
         });
     }
 
-    // Helper function for sending single byte responses.
-    fn send_response(&self, response: u8) {
-        self.buffer.take().map(|buffer| {
-            buffer[0] = ESCAPE_CHAR;
-            buffer[1] = response;
-            let _ = self.uart.transmit_buffer(buffer, 2);
-        });
+    /// Give the bootloader a way to identify the board. Without one, the `ID`
+    /// command is answered with `RES_UNKNOWN`.
+    pub fn set_device_id(&self, device_id: &'a dyn interfaces::DeviceId) {
+        self.device_id.set(device_id);
     }
-}
 
-impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a> hil::uart::TransmitClient
-    for Bootloader<'a, U, F>
-{
-    fn transmitted_buffer(
+    /// Tell the bootloader where the kernel slots are. Without them, the slot
+    /// commands are answered with `RES_UNKNOWN`.
+    pub fn set_kernel_slots(&self, layout: kernel_slots::SlotLayout) {
+        self.kernel_slots.set(layout);
+    }
+
+    /// Tell the bootloader why it is running, usually from
+    /// `BootloaderEnterer::stay_reason()`, so `INFO` can report it.
+    pub fn set_stay_reason(&self, stay_reason: StayReason) {
+        self.stay_reason.set(stay_reason);
+    }
+
+    /// Only allow changes to flash once the host has authenticated with
+    /// `AUTH_CHALLENGE` and `AUTH_RESPONSE`. The device key is the value of
+    /// attribute `key_index`, which must be 32 bytes long, and never leaves
+    /// the bootloader. `random_source` provides the nonces.
+    ///
+    /// As long as no key is stored, any host can store one, so boards should
+    /// have it set before they leave the factory. Without this, the
+    /// authentication commands are answered with `RES_UNKNOWN`.
+    pub fn set_authentication(
         &self,
-        buffer: &'static mut [u8],
-        _tx_len: usize,
-        error: Result<(), ErrorCode>,
+        random_source: &'a dyn interfaces::RandomSource,
+        key_index: u8,
     ) {
-        if error.is_err() {
-            // self.led.clear();
-        } else {
-            match self.state.get() {
-                // Check if there is more to be read, and if so, read it and
-                // send it.
-                State::ReadRange {
-                    address,
-                    length: _,
-                    remaining_length,
-                } => {
-                    // We have sent some of the read range to the client.
-                    // We are either done, or need to setup the next read.
-                    if remaining_length == 0 {
-                        self.state.set(State::Idle);
-                        let _ =
-                            self.uart
-                                .receive_automatic(buffer, buffer.len(), UART_RECEIVE_TIMEOUT);
-                    } else {
-                        self.buffer.replace(buffer);
-                        self.page_buffer.take().map(move |page| {
-                            let page_size = page.as_mut().len();
-                            let _ = self.flash.read_page(address as usize / page_size, page);
-                        });
-                    }
-                }
+        self.random_source.set(random_source);
+        self.auth_key_index.set(key_index);
+    }
 
-                _ => {
-                    let _ = self
-                        .uart
-                        .receive_automatic(buffer, buffer.len(), UART_RECEIVE_TIMEOUT);
-                }
-            }
+    /// Accept images encrypted with the key in attribute `key_index`, which
+    /// must be 32 bytes long. Like the device key, it is never sent back to
+    /// the host. Without this, the encrypted image commands are answered with
+    /// `RES_UNKNOWN`.
+    pub fn set_image_key(&self, key_index: u8) {
+        self.image_key_index.set(key_index);
+    }
+
+    /// Read every page written by `WRITE_PAGE`, `WRITE_PAGE_COMPRESSED`,
+    /// `WRITE_PAGES` or `WRITE_ENC_PAGE` back, and write it up to `retries`
+    /// more times if it doesn't match what the host sent. A page that still
+    /// doesn't match is answered with `RES_VERIFY_FAILED`. Attributes, flags
+    /// and external flash are written without being read back, and
+    /// `WRITE_STREAM_PAGE` is turned off.
+    ///
+    /// Single pages are kept in the UART buffer to compare against, so this
+    /// returns `ErrorCode::SIZE` if that is smaller than a page.
+    pub fn set_write_verify(&self, retries: u8) -> Result<(), ErrorCode> {
+        if !self.buffer_holds_page() {
+            return Err(ErrorCode::SIZE);
         }
+        self.write_retries.set(retries);
+        Ok(())
     }
-}
 
-impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a> hil::uart::ReceiveClient
-    for Bootloader<'a, U, F>
-{
-    fn received_buffer(
-        &self,
-        buffer: &'static mut [u8],
-        rx_len: usize,
-        rval: Result<(), ErrorCode>,
-        _error: hil::uart::Error,
-    ) {
-        if rval.is_err() {
-            return;
+    /// Read the page each page from `WRITE_PAGE`, `WRITE_PAGE_COMPRESSED`,
+    /// `WRITE_PAGES` or `WRITE_ENC_PAGE` is for first, and don't write it if
+    /// it already holds the data. This makes reflashing mostly unchanged
+    /// images faster and saves wear. The number of pages skipped can be read
+    /// with `GET_SKIPPED_PAGES`, which is answered with `RES_UNKNOWN` unless
+    /// this is on. Streamed pages can't be compared before the next one
+    /// arrives, so `WRITE_STREAM_PAGE` is turned off. Like write
+    /// verification, this returns `ErrorCode::SIZE` if the UART buffer is
+    /// smaller than a page.
+    pub fn set_skip_unchanged_pages(&self, skip: bool) -> Result<(), ErrorCode> {
+        if skip && !self.buffer_holds_page() {
+            return Err(ErrorCode::SIZE);
         }
+        self.skip_unchanged.set(skip);
+        Ok(())
+    }
 
-        // Tool to parse incoming bootloader messages.
-        // This is currently allocated on the stack, but it too needs a big
-        // buffer, and we need to do something about that.
-        let mut decoder = tock_bootloader_protocol::CommandDecoder::new();
-        // Whether we want to reset the position in the buffer in the
-        // decoder.
-        let mut need_reset = false;
-
-        // Loop through the buffer and pass it to the decoder.
-        for i in 0..rx_len {
-            // Artifact of the original implementation of the bootloader
-            // protocol is the need to reset the pointer internal to the
-            // bootloader receive state machine. This is here because we may
-            // have received two commands in the same buffer and we want to
-            // handle them both back-to-back.
-            if need_reset {
-                decoder.reset();
-                need_reset = false;
-            }
-
-            match decoder.receive(buffer[i]) {
-                Ok(None) => {}
-                Ok(Some(tock_bootloader_protocol::Command::Ping)) => {
-                    self.buffer.replace(buffer);
-                    self.send_response(RES_PONG);
-                    break;
-                }
-                Ok(Some(tock_bootloader_protocol::Command::Reset)) => {
-                    need_reset = true;
-                    // If there are more bytes in the buffer we want to continue
-                    // parsing those. Otherwise, we want to go back to receive.
-                    if i == rx_len - 1 {
-                        let _ =
-                            self.uart
-                                .receive_automatic(buffer, buffer.len(), UART_RECEIVE_TIMEOUT);
-                        break;
-                    }
-                }
-                Ok(Some(tock_bootloader_protocol::Command::Info)) => {
-                    self.state.set(State::Info);
-                    self.buffer.replace(buffer);
-                    self.page_buffer.take().map(move |page| {
-                        // Calculate the page index given that flags start
-                        // at address 1024.
-                        let page_index = self.flags_address / page.as_mut().len();
+    // Helper function for checking that a page from the host can be kept in
+    // the UART buffer while it is compared with flash.
+    fn buffer_holds_page(&self) -> bool {
+        self.buffer_size >= self.page_size
+    }
 
-                        let _ = self.flash.read_page(page_index, page);
-                    });
-                    break;
-                }
-                Ok(Some(tock_bootloader_protocol::Command::ReadRange { address, length })) => {
-                    self.state.set(State::ReadRange {
-                        address,
-                        length,
-                        remaining_length: length,
-                    });
-                    self.buffer.replace(buffer);
-                    self.page_buffer.take().map(move |page| {
-                        let page_size = page.as_mut().len();
-                        let _ = self.flash.read_page(address as usize / page_size, page);
-                    });
-                    break;
-                }
-                Ok(Some(tock_bootloader_protocol::Command::WritePage { address, data })) => {
-                    self.page_buffer.take().map(move |page| {
-                        let page_size = page.as_mut().len();
-                        if page_size != data.len() {
-                            // Error if we didn't get exactly a page of data
-                            // to write to flash.
-                            buffer[0] = ESCAPE_CHAR;
-                            buffer[1] = RES_BADARGS;
-                            self.page_buffer.replace(page);
-                            self.state.set(State::Idle);
-                            let _ = self.uart.transmit_buffer(buffer, 2);
-                        } else if address >= self.bootloader_address
-                            && address < self.bootloader_end_address
-                        {
-                            // Do not allow the bootloader to try to overwrite
-                            // itself. This will largely not work, and would be
-                            // irreversible for the user.
-                            buffer[0] = ESCAPE_CHAR;
-                            buffer[1] = RES_BADADDR;
-                            self.page_buffer.replace(page);
-                            self.state.set(State::Idle);
-                            let _ = self.uart.transmit_buffer(buffer, 2);
-                        } else {
-                            // Otherwise copy into page buffer and write to
-                            // flash.
-                            for i in 0..page_size {
-                                page.as_mut()[i] = data[i];
-                            }
-                            self.state.set(State::WriteFlashPage);
-                            self.buffer.replace(buffer);
-                            let _ = self.flash.write_page(address as usize / page_size, page);
-                        }
-                    });
-                    break;
-                }
-                Ok(Some(tock_bootloader_protocol::Command::ErasePage { address })) => {
-                    self.state.set(State::ErasePage);
-                    self.buffer.replace(buffer);
-                    let page_size = self.page_buffer.map_or(512, |page| page.as_mut().len());
-                    let _ = self.flash.erase_page(address as usize / page_size);
-                    break;
-                }
-                Ok(Some(tock_bootloader_protocol::Command::CrcIntFlash { address, length })) => {
-                    self.state.set(State::Crc {
-                        address,
-                        remaining_length: length,
-                        crc: 0xFFFFFFFF,
-                    });
-                    self.buffer.replace(buffer);
-                    self.page_buffer.take().map(move |page| {
-                        let page_size = page.as_mut().len();
-                        let _ = self.flash.read_page(address as usize / page_size, page);
-                    });
-                    break;
-                }
-                Ok(Some(tock_bootloader_protocol::Command::GetAttr { index })) => {
-                    self.state.set(State::GetAttribute { index: index });
-                    self.buffer.replace(buffer);
-                    self.page_buffer.take().map(move |page| {
-                        // Need to calculate which page to read to get the
-                        // correct attribute (each attribute is 64 bytes long),
-                        // where attributes start at address 0x600.
-                        let page_len = page.as_mut().len();
-                        let read_address = self.attributes_address + (index as usize * 64);
-                        let page_index = read_address / page_len;
+    /// Accept `WRITE_STREAM_PAGE`, which lets the host send the next page
+    /// while the last one is written. `stream_buffer` holds the next page
+    /// meanwhile, and `ack_buffer` (see `ACK_BUF`) sends the `PAGE_ACK`s while
+    /// the UART buffer receives. Streamed pages are written as they come, so
+    /// this is turned off by `set_write_verify()` and
+    /// `set_skip_unchanged_pages()`. Without it, `WRITE_STREAM_PAGE` is
+    /// answered with `RES_UNKNOWN`.
+    pub fn set_streaming(
+        &self,
+        stream_buffer: &'static mut F::Page,
+        ack_buffer: &'static mut [u8],
+    ) {
+        self.stream_buffer.replace(stream_buffer);
+        self.ack_buffer.replace(ack_buffer);
+        self.stream_enabled.set(true);
+    }
 
-                        let _ = self.flash.read_page(page_index, page);
-                    });
-                    break;
-                }
-                Ok(Some(tock_bootloader_protocol::Command::SetAttr { index, key, value })) => {
-                    self.state.set(State::SetAttribute { index });
+    // Helper function for checking whether `WRITE_STREAM_PAGE` is available.
+    fn streaming(&self) -> bool {
+        self.stream_enabled.get() && self.write_retries.is_none() && !self.skip_unchanged.get()
+    }
 
-                    // Copy the key and value into the buffer so it can be added
-                    // to the page buffer when needed.
-                    for i in 0..8 {
-                        buffer[i] = key[i];
-                    }
-                    buffer[8] = value.len() as u8;
-                    for i in 0..55 {
-                        // Copy in the value, otherwise clear to zero.
-                        if i < value.len() {
-                            buffer[9 + i] = value[i];
-                        } else {
-                            buffer[9 + i] = 0;
-                        }
-                    }
-                    self.buffer.replace(buffer);
+    // Helper function for checking whether attribute `index` holds a key that
+    // must not be sent to the host.
+    fn secret_attribute(&self, index: u8) -> bool {
+        (self.random_source.is_some() && index == self.auth_key_index.get())
+            || self
+                .image_key_index
+                .map_or(false, |key_index| key_index == index)
+    }
 
-                    // Initiate things by reading the correct flash page that
-                    // needs to be updated.
-                    self.page_buffer.take().map(move |page| {
-                        // Need to calculate which page to read to get the
-                        // correct attribute (each attribute is 64 bytes long),
-                        // where attributes start at address 0x600.
-                        let page_len = page.as_mut().len();
-                        let read_address = self.attributes_address + (index as usize * 64);
-                        let page_index = read_address / page_len;
+    // Helper function for checking whether `length` bytes from `address`
+    // cover any of attribute `index`.
+    fn covers_attribute(&self, index: u8, address: u32, length: u32) -> bool {
+        let attribute_address = self.attributes_address as u32 + index as u32 * 64;
+        address < attribute_address + 64 && attribute_address < address.saturating_add(length)
+    }
 
-                        let _ = self.flash.read_page(page_index, page);
-                    });
-                    break;
-                }
-                Ok(Some(tock_bootloader_protocol::Command::SetStartAddress { address })) => {
-                    self.state.set(State::SetStartAddress { address });
-                    self.buffer.replace(buffer);
+    // Helper function for checking whether `command` would read, or take a
+    // CRC or hash over, any part of the device or image key. The host never
+    // needs either, so this is refused even in an authenticated session.
+    fn reveals_key(&self, command: &tock_bootloader_protocol::Command) -> bool {
+        let (address, length) = match *command {
+            tock_bootloader_protocol::Command::ReadRange { address, length } => {
+                (address, length as u32)
+            }
+            tock_bootloader_protocol::Command::CrcIntFlash { address, length }
+            | tock_bootloader_protocol::Command::HashIntFlash {
+                address, length, ..
+            } => (address, length),
+            _ => return false,
+        };
+        (0..16).any(|index| {
+            self.secret_attribute(index) && self.covers_attribute(index, address, length)
+        })
+    }
+
+    // Helper function for checking whether the host still has to
+    // authenticate before changing flash.
+    fn locked(&self) -> bool {
+        self.random_source.is_some() && !self.authenticated.get()
+    }
+
+    // Helper function for checking whether `command` needs an authenticated
+    // session. Writes to the device key attribute are checked once it has
+    // been read, as it can be stored the first time without one.
+    fn needs_authentication(&self, command: &tock_bootloader_protocol::Command) -> bool {
+        if !self.locked() {
+            return false;
+        }
+        match *command {
+            tock_bootloader_protocol::Command::ErasePage { .. }
+            | tock_bootloader_protocol::Command::EraseRange { .. }
+            | tock_bootloader_protocol::Command::WritePage { .. }
+            | tock_bootloader_protocol::Command::WriteStreamPage { .. }
+            | tock_bootloader_protocol::Command::WritePages { .. }
+            | tock_bootloader_protocol::Command::WritePageCompressed { .. }
+            | tock_bootloader_protocol::Command::EraseExBlock { .. }
+            | tock_bootloader_protocol::Command::WriteExPage { .. }
+            | tock_bootloader_protocol::Command::EraseExPage { .. }
+            | tock_bootloader_protocol::Command::WriteFlashUserPages { .. }
+            | tock_bootloader_protocol::Command::SetStartAddress { .. }
+            | tock_bootloader_protocol::Command::SetKernelCrc { .. }
+            | tock_bootloader_protocol::Command::SetSlotPending { .. }
+            | tock_bootloader_protocol::Command::BeginEncryptedImage { .. }
+            | tock_bootloader_protocol::Command::WriteEncryptedPage { .. }
+            | tock_bootloader_protocol::Command::EndEncryptedImage { .. } => true,
+            tock_bootloader_protocol::Command::SetAttr { index, .. } => {
+                index != self.auth_key_index.get()
+            }
+            _ => false,
+        }
+    }
+
+    // Helper function for checking `command` against the flash policy. The
+    // handlers check that a page is the right size, so here a write is
+    // always taken to be one page.
+    fn flash_access_allowed(&self, command: &tock_bootloader_protocol::Command) -> bool {
+        let policy = self.flash_policy.get();
+        let page_size = self.page_size as u32;
+        match *command {
+            tock_bootloader_protocol::Command::ReadRange { address, length } => {
+                policy.allows_read(address, length as u32)
+            }
+            tock_bootloader_protocol::Command::CrcIntFlash { address, length }
+            | tock_bootloader_protocol::Command::HashIntFlash {
+                address, length, ..
+            } => policy.allows_read(address, length),
+            tock_bootloader_protocol::Command::WritePage { address, .. }
+            | tock_bootloader_protocol::Command::WriteStreamPage { address, .. }
+            | tock_bootloader_protocol::Command::WritePageCompressed { address, .. }
+            | tock_bootloader_protocol::Command::BeginEncryptedImage { address, .. }
+            | tock_bootloader_protocol::Command::WriteEncryptedPage { address, .. } => {
+                policy.allows_write(address, page_size)
+            }
+            tock_bootloader_protocol::Command::WritePages { address, count, .. } => {
+                policy.allows_write(address, count as u32 * page_size)
+            }
+            tock_bootloader_protocol::Command::ErasePage { address } => {
+                policy.allows_erase(address, page_size)
+            }
+            tock_bootloader_protocol::Command::EraseRange { address, length } => {
+                policy.allows_erase(address, length)
+            }
+            _ => true,
+        }
+    }
+
+    // Helper function for describing what this bootloader supports. Optional
+    // commands are only listed when the board set up what they need.
+    fn capabilities(&self) -> tock_bootloader_protocol::Capabilities {
+        let mut capabilities = tock_bootloader_protocol::Capabilities {
+            protocol_version: tock_bootloader_protocol::PROTOCOL_VERSION,
+            commands: [0; 32],
+            page_size: self.page_size as u32,
+            flash_size: self.flash_size.get(),
+            bootloader_start: self.bootloader_address,
+            bootloader_end: self.bootloader_end_address,
+            // Commands are decoded into the decoder's buffer, so they can be
+            // longer than the UART buffer.
+            max_frame_size: N as u32,
+        };
+        for command in COMMANDS.iter() {
+            capabilities.set_supported(*command);
+        }
+        if self.external_flash.is_some() {
+            for command in EXTERNAL_FLASH_COMMANDS.iter() {
+                capabilities.set_supported(*command);
+            }
+        }
+        if self.device_id.is_some() {
+            capabilities.set_supported(CMD_ID);
+        }
+        if self.timeout.is_some() {
+            capabilities.set_supported(CMD_CHANGE_BAUD);
+        }
+        if self.kernel_slots.is_some() {
+            capabilities.set_supported(CMD_GET_SLOTS);
+            capabilities.set_supported(CMD_SSLOTPENDING);
+        }
+        if self.random_source.is_some() {
+            capabilities.set_supported(CMD_AUTH_CHALLENGE);
+            capabilities.set_supported(CMD_AUTH_RESPONSE);
+        }
+        if self.image_key_index.is_some() {
+            capabilities.set_supported(CMD_BEGIN_ENC_IMAGE);
+            capabilities.set_supported(CMD_WRITE_ENC_PAGE);
+            capabilities.set_supported(CMD_END_ENC_IMAGE);
+        }
+        if self.skip_unchanged.get() {
+            capabilities.set_supported(CMD_GET_SKIPPED_PAGES);
+        }
+        if self.streaming() {
+            capabilities.set_supported(CMD_WRITE_STREAM_PAGE);
+        }
+        capabilities
+    }
 
-                    // Initiate things by reading the correct flash page that
-                    // needs to be updated.
-                    self.page_buffer.take().map(move |page| {
-                        let page_len = page.as_mut().len();
-                        let page_index = self.flags_address / page_len;
+    // Helper function for setting the UART baud rate.
+    fn configure_uart(&self, baud_rate: u32) -> Result<(), ErrorCode> {
+        self.uart.configure(hil::uart::Parameters {
+            baud_rate,
+            width: hil::uart::Width::Eight,
+            stop_bits: hil::uart::StopBits::One,
+            parity: hil::uart::Parity::None,
+            hw_flow_control: false,
+        })
+    }
 
-                        let _ = self.flash.read_page(page_index, page);
+    // Helper function for handling the command received after switching to a
+    // new baud rate. Only a matching verify keeps the new rate.
+    fn verify_baud_rate(&self, command: tock_bootloader_protocol::Command) {
+        if let State::ChangeBaudVerify {
+            old_baud_rate,
+            new_baud_rate,
+        } = self.state.get()
+        {
+            self.timeout.map(|timeout| timeout.cancel());
+            match command {
+                tock_bootloader_protocol::Command::ChangeBaud {
+                    mode: tock_bootloader_protocol::BaudMode::Verify,
+                    baud,
+                } if baud == new_baud_rate => {
+                    self.baud_rate.set(new_baud_rate);
+                    self.state.set(State::Idle);
+                    self.send_response(RES_OK);
+                }
+                _ => {
+                    // The host is talking at the new rate, so respond at that
+                    // rate before switching back.
+                    self.state.set(State::ChangeBaudRevert {
+                        baud_rate: old_baud_rate,
                     });
-                    break;
+                    self.send_response(RES_CHANGE_BAUD_FAIL);
                 }
-                Ok(Some(tock_bootloader_protocol::Command::Exit)) => {
-                    (self.reset_function)();
-                    break;
+            }
+        }
+    }
+
+    // Helper function for sending single byte responses.
+    fn send_response(&self, response: u8) {
+        self.buffer.take().map(|buffer| {
+            buffer[0] = ESCAPE_CHAR;
+            buffer[1] = response;
+            self.transmit(buffer, 2);
+        });
+    }
+
+    // Helper function for giving up on the current command and telling the
+    // host why with `response`.
+    fn fail(&self, response: u8) {
+        self.state.set(State::Idle);
+        // An encrypted page that didn't get written has to be sent again.
+        self.image_page_cipher.take();
+        self.send_response(response);
+    }
+
+    // Helper function for running `f` with the page buffer. Every command
+    // returns the page buffer when it ends, so if it isn't here something
+    // went wrong; the command can't go on, but the host still gets an answer.
+    fn with_page_buffer<G: FnOnce(&'static mut F::Page)>(&self, f: G) {
+        match self.page_buffer.take() {
+            Some(page) => f(page),
+            None => self.fail(RES_INTERNAL_ERROR),
+        }
+    }
+
+    // Helper function for telling the host a page it sent is in flash.
+    fn host_page_written(&self) {
+        self.state.set(State::Idle);
+        if let Some(cipher) = self.image_page_cipher.take() {
+            self.image_cipher.replace(cipher);
+        }
+        self.send_response(RES_OK);
+    }
+
+    // Helper function for reading the image key to start an encrypted image
+    // at `address`.
+    fn begin_encrypted_image(&self, address: u32, nonce: [u8; IMAGE_NONCE_LEN]) {
+        let key_index = self.image_key_index.unwrap_or(0);
+        self.state
+            .set(State::BeginEncryptedImage { address, nonce });
+        self.with_page_buffer(move |page| {
+            let page_len = page.as_mut().len();
+            let read_address = self.attributes_address + (key_index as usize * 64);
+            self.read_flash_page(read_address / page_len, page);
+        });
+    }
+
+    // Helper function for erasing every page written of the encrypted image
+    // at `address`, which won't be finished, so nothing is left that could be
+    // mistaken for a good image, and then doing `then`.
+    fn discard_encrypted_image(&self, address: u32, then: AfterDiscard) {
+        // Whatever was being written of it is thrown away too.
+        self.image_page_cipher.take();
+        let end = self.image_end.get();
+        if end > address {
+            self.state
+                .set(State::DiscardEncryptedImage { address, end, then });
+            self.erase_flash_page(address as usize / self.page_size);
+        } else {
+            self.encrypted_image_discarded(then);
+        }
+    }
+
+    fn encrypted_image_discarded(&self, then: AfterDiscard) {
+        self.state.set(State::Idle);
+        match then {
+            AfterDiscard::Reject => self.send_response(RES_BADARGS),
+            AfterDiscard::Begin { address, nonce } => self.begin_encrypted_image(address, nonce),
+            AfterDiscard::Exit => (self.reset_function)(),
+        }
+    }
+
+    // Helper function for sending the first `length` bytes of `buffer`. While
+    // a `PAGE_ACK` is being sent the response waits for it, and any acks
+    // behind it.
+    fn transmit(&self, buffer: &'static mut [u8], length: usize) {
+        if self.ack_sending.get() {
+            self.buffer.replace(buffer);
+            self.response_waiting.set(length);
+            return;
+        }
+        if let Err((_, buffer)) = self.uart.transmit_buffer(buffer, length) {
+            self.abandon(buffer);
+        }
+    }
+
+    // Helper function for listening for the next command. If the UART won't
+    // start receiving the buffer is kept, but there is nothing more we can
+    // do.
+    //
+    // If the UART won't start receiving, the bootloader would never hear from
+    // the host again, so it tries again once the timeout expires. Without a
+    // timeout there is nothing to try again with. While a new baud rate is
+    // waiting to be verified the timeout is already running, and when it
+    // expires the receive is tried again at the old rate.
+    fn receive(&self, buffer: &'static mut [u8]) {
+        if let Err((_, buffer)) =
+            self.uart
+                .receive_automatic(buffer, buffer.len(), UART_RECEIVE_TIMEOUT)
+        {
+            self.buffer.replace(buffer);
+            if !matches!(self.state.get(), State::ChangeBaudVerify { .. }) {
+                self.timeout
+                    .map(|timeout| timeout.start(RECEIVE_RETRY_TIMEOUT));
+            }
+        }
+    }
+
+    // Helper function for when a response could not be sent. There is no way
+    // to tell the host, so drop the command and listen for the next one. A
+    // baud rate the host did not verify is still reverted.
+    fn abandon(&self, buffer: &'static mut [u8]) {
+        if let State::ChangeBaudRevert { baud_rate } = self.state.get() {
+            // If this fails there is nothing left to try.
+            let _ = self.configure_uart(baud_rate);
+        }
+        // A streamed page being written still gets its `PAGE_ACK`.
+        if !matches!(self.state.get(), State::StreamWrite { .. }) {
+            self.state.set(State::Idle);
+        }
+        self.receive(buffer);
+    }
+
+    // Helper functions for starting internal flash operations. If the flash
+    // won't start one, the page buffer is returned and the host is told.
+    fn read_flash_page(&self, page_index: usize, page: &'static mut F::Page) {
+        if let Err((_, page)) = self.flash.read_page(page_index, page) {
+            self.page_buffer.replace(page);
+            self.fail(RES_FLASH_ERROR);
+        }
+    }
+
+    fn write_flash_page(&self, page_index: usize, page: &'static mut F::Page) {
+        if let Err((_, page)) = self.flash.write_page(page_index, page) {
+            self.page_buffer.replace(page);
+            self.fail(RES_FLASH_ERROR);
+        }
+    }
+
+    fn erase_flash_page(&self, page_index: usize) {
+        if self.flash.erase_page(page_index).is_err() {
+            self.fail(RES_FLASH_ERROR);
+        }
+    }
+
+    // Helper function for writing a page of data from the host. If the page
+    // is compared or verified, the data is also copied into the UART buffer,
+    // which isn't needed again until the response, to check against.
+    fn write_host_page(&self, page_index: usize, page: &'static mut F::Page) {
+        if self.skip_unchanged.get() || self.write_retries.is_some() {
+            let copied = self.buffer.map_or(false, |buffer| {
+                let data = page.as_mut();
+                match buffer.get_mut(..data.len()) {
+                    Some(copy) => {
+                        copy.copy_from_slice(data);
+                        true
+                    }
+                    None => false,
                 }
-                Ok(Some(_)) => {
-                    self.buffer.replace(buffer);
-                    self.send_response(RES_UNKNOWN);
-                    break;
+            });
+            if !copied {
+                self.page_buffer.replace(page);
+                self.fail(RES_INTERNAL_ERROR);
+                return;
+            }
+        }
+        if self.skip_unchanged.get() {
+            self.state.set(State::ComparePage { page_index });
+            self.read_flash_page(page_index, page);
+        } else {
+            self.program_host_page(page_index, page);
+        }
+    }
+
+    // Helper function for copying a page from the host back out of the UART
+    // buffer, where `write_host_page()` kept it.
+    fn copy_host_page(&self, page: &mut [u8]) -> bool {
+        self.buffer
+            .map_or(false, |buffer| match buffer.get(..page.len()) {
+                Some(data) => {
+                    page.copy_from_slice(data);
+                    true
                 }
-                Err(tock_bootloader_protocol::Error::BadArguments) => {
-                    self.buffer.replace(buffer);
-                    self.send_response(RES_BADARGS);
-                    break;
+                None => false,
+            })
+    }
+
+    // Helper function for programming a page of data from the host once it
+    // is known to be needed.
+    fn program_host_page(&self, page_index: usize, page: &'static mut F::Page) {
+        match self.write_retries.get() {
+            Some(retries) => {
+                self.state.set(State::WriteVerifiedPage {
+                    page_index,
+                    retries,
+                });
+            }
+            None => self.state.set(State::WriteFlashPage),
+        }
+        self.write_flash_page(page_index, page);
+    }
+
+    // Helper function for writing page `index` of a `WRITE_PAGES` burst. The
+    // pages are taken from `data`, which is all the pages the host sent.
+    fn write_burst_page(&self, address: u32, index: u8, pages: u8, data: &[u8]) {
+        let start = index as usize * self.page_size;
+        let page_data = match data.get(start..start + self.page_size) {
+            Some(page_data) => page_data,
+            None => {
+                // The command is no longer in the decoder.
+                self.burst_page_failed(address, index, RES_INTERNAL_ERROR);
+                return;
+            }
+        };
+        if self.skip_unchanged.get() {
+            self.state.set(State::CompareBurstPage {
+                address,
+                index,
+                pages,
+            });
+            self.read_burst_page(address, index);
+            return;
+        }
+        let page = match self.page_buffer.take() {
+            Some(page) => page,
+            None => {
+                self.burst_page_failed(address, index, RES_INTERNAL_ERROR);
+                return;
+            }
+        };
+        page.as_mut().copy_from_slice(page_data);
+        let retries = self.write_retries.get().unwrap_or(0);
+        self.program_burst_page(address, index, pages, retries, page);
+    }
+
+    // Helper function for programming page `index` of a `WRITE_PAGES` burst,
+    // once `page` holds its data.
+    fn program_burst_page(
+        &self,
+        address: u32,
+        index: u8,
+        pages: u8,
+        retries: u8,
+        page: &'static mut F::Page,
+    ) {
+        self.state.set(State::WritePages {
+            address,
+            index,
+            pages,
+            retries,
+        });
+        if let Err((_, page)) = self
+            .flash
+            .write_page(self.burst_page_index(address, index), page)
+        {
+            self.page_buffer.replace(page);
+            self.burst_page_failed(address, index, RES_FLASH_ERROR);
+        }
+    }
+
+    // Helper function for reading page `index` of a `WRITE_PAGES` burst from
+    // flash, to compare it, verify it or add it to the CRC.
+    fn read_burst_page(&self, address: u32, index: u8) {
+        let page = match self.page_buffer.take() {
+            Some(page) => page,
+            None => {
+                self.burst_page_failed(address, index, RES_INTERNAL_ERROR);
+                return;
+            }
+        };
+        if let Err((_, page)) = self
+            .flash
+            .read_page(self.burst_page_index(address, index), page)
+        {
+            self.page_buffer.replace(page);
+            self.burst_page_failed(address, index, RES_FLASH_ERROR);
+        }
+    }
+
+    // Helper function for moving on from page `index` of a `WRITE_PAGES`
+    // burst once it is in flash. After the last page the burst is read back
+    // for the CRC.
+    fn next_burst_page(&self, address: u32, index: u8, pages: u8) {
+        if index + 1 < pages {
+            let started = self.decoder.map(|decoder| {
+                let data = decoder
+                    .last_payload()
+                    .get(WRITE_PAGES_HEADER_LEN..)
+                    .unwrap_or(&[]);
+                self.write_burst_page(address, index + 1, pages, data);
+            });
+            if started.is_none() {
+                // The decoder is in use, so the rest of the command can't be
+                // reached.
+                self.burst_page_failed(address, index + 1, RES_INTERNAL_ERROR);
+            }
+        } else {
+            self.state.set(State::CrcBurstPages {
+                address,
+                index: 0,
+                pages,
+                crc: 0xFFFFFFFF,
+            });
+            self.read_burst_page(address, 0);
+        }
+    }
+
+    // Helper function for running `f` on page `index` of the `WRITE_PAGES`
+    // burst in the decoder. Returns `None` if the command is no longer there.
+    fn map_burst_page<R, G: FnOnce(&[u8]) -> R>(&self, index: u8, f: G) -> Option<R> {
+        let start = WRITE_PAGES_HEADER_LEN + index as usize * self.page_size;
+        self.decoder
+            .map(|decoder| {
+                decoder
+                    .last_payload()
+                    .get(start..start + self.page_size)
+                    .map(f)
+            })
+            .flatten()
+    }
+
+    // Helper function for the flash page that page `index` of a `WRITE_PAGES`
+    // burst starting at `address` goes in.
+    fn burst_page_index(&self, address: u32, index: u8) -> usize {
+        address as usize / self.page_size + index as usize
+    }
+
+    // Helper function for ending a `WRITE_PAGES` burst because page `index`
+    // couldn't be written.
+    fn burst_page_failed(&self, address: u32, index: u8, result: u8) {
+        self.send_burst_result(tock_bootloader_protocol::Response::PageFailed {
+            address: address + index as u32 * self.page_size as u32,
+            result,
+        });
+    }
+
+    // Helper function for ending a `WRITE_PAGES` burst with `response`.
+    fn send_burst_result(&self, response: tock_bootloader_protocol::Response) {
+        self.state.set(State::Idle);
+        self.send_encoded(response);
+    }
+
+    // Helper function for sending a response with a payload from the main
+    // buffer, leaving the state alone.
+    fn send_encoded(&self, response: tock_bootloader_protocol::Response) {
+        self.buffer.take().map(|buffer| {
+            match tock_bootloader_protocol::ResponseEncoder::new(&response) {
+                Ok(mut encoder) => {
+                    let length = encoder.write(buffer);
+                    self.transmit(buffer, length);
                 }
                 Err(_) => {
                     self.buffer.replace(buffer);
                     self.send_response(RES_INTERNAL_ERROR);
-                    break;
                 }
-            };
+            }
+        });
+    }
+
+    // Helper function for a page from `WRITE_STREAM_PAGE`, once the UART is
+    // receiving again. The page is written straight away, or waits in
+    // `stream_buffer` if the one before it is still being written.
+    fn stream_page(&self, command: &tock_bootloader_protocol::Command) {
+        let (address, data) = match *command {
+            tock_bootloader_protocol::Command::WriteStreamPage { address, data } => (address, data),
+            _ => return,
+        };
+        if self.needs_authentication(command) {
+            self.send_page_ack(address, RES_UNAUTHENTICATED);
+        } else if !self.flash_access_allowed(command) {
+            self.send_page_ack(address, RES_BADADDR);
+        } else if data.len() != self.page_size {
+            self.send_page_ack(address, RES_BADARGS);
+        } else if let State::StreamWrite { .. } = self.state.get() {
+            match self
+                .stream_buffer
+                .map(|page| page.as_mut().copy_from_slice(data))
+            {
+                Some(()) => self.stream_queued.set(address),
+                None => self.send_page_ack(address, RES_INTERNAL_ERROR),
+            }
+        } else {
+            match self.page_buffer.take() {
+                Some(page) => {
+                    page.as_mut().copy_from_slice(data);
+                    self.write_stream_page(address, page);
+                }
+                None => self.send_page_ack(address, RES_INTERNAL_ERROR),
+            }
+        }
+    }
+
+    // Helper function for starting to write a streamed page. If the flash
+    // won't, the page is acknowledged with `RES_FLASH_ERROR`.
+    fn write_stream_page(&self, address: u32, page: &'static mut F::Page) {
+        self.state.set(State::StreamWrite { address });
+        if let Err((_, page)) = self
+            .flash
+            .write_page(address as usize / self.page_size, page)
+        {
+            self.page_buffer.replace(page);
+            self.state.set(State::Idle);
+            self.send_page_ack(address, RES_FLASH_ERROR);
+        }
+    }
+
+    // Helper function for when the streamed page for `address` is done.
+    // The page waiting in `stream_buffer`, if any, is written next, and a
+    // receive put aside while both page buffers were full is handled.
+    fn stream_page_written(&self, address: u32, result: u8) {
+        self.state.set(State::Idle);
+        self.send_page_ack(address, result);
+        if let Some(next_address) = self.stream_queued.take() {
+            // The buffer just written becomes the spare.
+            let next_page = self.stream_buffer.take();
+            self.page_buffer
+                .take()
+                .map(|page| self.stream_buffer.replace(page));
+            next_page.map(|page| self.write_stream_page(next_address, page));
+        }
+        if let Some(rx_len) = self.stream_deferred.take() {
+            self.buffer.take().map(|buffer| {
+                hil::uart::ReceiveClient::received_buffer(
+                    self,
+                    buffer,
+                    rx_len,
+                    Ok(()),
+                    hil::uart::Error::None,
+                );
+            });
+        }
+    }
+
+    // Helper function for sending a `PAGE_ACK`. Acks go out one at a time
+    // from `ack_buffer`, in order, so this one waits behind any others and
+    // for the UART to finish sending a response.
+    fn send_page_ack(&self, address: u32, result: u8) {
+        let mut acks = self.ack_waiting.get();
+        // Only a host that sends more than `STREAM_WINDOW` pages without
+        // waiting can fill the queue, and it won't get this ack.
+        acks.push((address, result));
+        self.ack_waiting.set(acks);
+        self.send_waiting_ack();
+    }
+
+    // Helper function for sending the oldest waiting `PAGE_ACK`, if
+    // `ack_buffer` and the UART are free.
+    fn send_waiting_ack(&self) {
+        let (address, result) = match self.ack_waiting.get().first() {
+            Some(ack) => ack,
+            None => return,
+        };
+        let buffer = match self.ack_buffer.take() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        let response = tock_bootloader_protocol::Response::PageAck { address, result };
+        let length = match tock_bootloader_protocol::ResponseEncoder::new(&response) {
+            Ok(mut encoder) => encoder.write(buffer),
+            // Can't happen, a `PAGE_ACK` always fits.
+            Err(_) => 0,
+        };
+        self.ack_sending.set(true);
+        match self.uart.transmit_buffer(buffer, length) {
+            Ok(()) => {
+                let mut acks = self.ack_waiting.get();
+                acks.remove_first();
+                self.ack_waiting.set(acks);
+            }
+            Err((_, buffer)) => {
+                self.ack_sending.set(false);
+                self.ack_buffer.replace(buffer);
+            }
         }
+    }
 
-        // Artifact of the original implementation of the bootloader protocol
-        // is the need to reset the pointer internal to the bootloader receive
-        // state machine.
-        if need_reset {
-            decoder.reset();
+    // Helper function for starting an external flash operation. On success
+    // the bootloader moves to `state` and waits for the callback, otherwise
+    // the error is sent to the host.
+    fn start_external_flash(
+        &self,
+        state: State,
+        operation: impl FnOnce(&dyn interfaces::ExternalFlash<'a>) -> Result<(), ErrorCode>,
+    ) {
+        let result = self
+            .external_flash
+            .map_or(Err(ErrorCode::NODEVICE), |external_flash| {
+                operation(external_flash)
+            });
+        match result {
+            Ok(()) => self.state.set(state),
+            Err(ErrorCode::NODEVICE) => self.send_response(RES_UNKNOWN),
+            Err(ErrorCode::INVAL) => self.send_response(RES_BADADDR),
+            Err(_) => self.send_response(RES_INTERNAL_ERROR),
         }
     }
 }
 
-impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a> hil::flash::Client<F>
-    for Bootloader<'a, U, F>
-{
-    fn read_complete(&self, pagebuffer: &'static mut F::Page, _error: hil::flash::Error) {
-        match self.state.get() {
-            // We just read the bootloader info page (page 2). Extract the
-            // version and generate a response JSON blob.
-            State::Info => {
-                self.state.set(State::Idle);
-                self.buffer.take().map(move |buffer| {
-                    buffer[0] = ESCAPE_CHAR;
-                    buffer[1] = RES_INFO;
-                    let mut index = 3;
+// Length of the next external flash read, which can't cross an external
+// flash page boundary.
+fn external_read_length(address: u32, remaining_length: usize) -> usize {
+    let page_remaining =
+        interfaces::EXTERNAL_PAGE_SIZE - address as usize % interfaces::EXTERNAL_PAGE_SIZE;
+    cmp::min(page_remaining, remaining_length)
+}
 
-                    // Insert the first part of the JSON blob into the buffer.
-                    let str01 = "{\"version\":\"";
-                    for i in 0..str01.len() {
-                        buffer[index] = str01.as_bytes()[i];
-                        index += 1;
-                    }
+// The device or image key stored in `attribute`, or `None` if no key has been
+// stored yet. Both keys are the same length.
+fn attribute_key(attribute: &[u8]) -> Option<[u8; AUTH_KEY_LEN]> {
+    if attribute[8] as usize == AUTH_KEY_LEN {
+        let mut key = [0; AUTH_KEY_LEN];
+        key.copy_from_slice(&attribute[9..9 + AUTH_KEY_LEN]);
+        Some(key)
+    } else {
+        None
+    }
+}
 
-                    // Calculate where in the page the flags start.
-                    let page_offset = self.flags_address % pagebuffer.as_mut().len();
+// Key and value of an attribute stored in flash, or `None` if the attribute
+// slot is empty or does not hold valid strings. The key is 8 bytes padded with
+// zeros, followed by the length of the value and the value itself.
+fn attribute_key_value(attribute: &[u8]) -> Option<(&str, &str)> {
+    let key_len = attribute[..8].iter().position(|b| *b == 0).unwrap_or(8);
+    let value_len = attribute[8] as usize;
+    if key_len == 0 || 9 + value_len > attribute.len() {
+        return None;
+    }
+    let key = core::str::from_utf8(&attribute[..key_len]).ok()?;
+    let value = core::str::from_utf8(&attribute[9..9 + value_len]).ok()?;
+    Some((key, value))
+}
 
-                    // Version string is at most 8 bytes long, and starts
-                    // at index 14 in the bootloader page.
-                    for i in 0..8 {
-                        let b = pagebuffer.as_mut()[i + 14 + page_offset];
-                        if b == 0 {
-                            break;
-                        }
-                        buffer[index] = b;
-                        index += 1;
-                    }
+// Whether an attribute slot holds anything, i.e. it is neither zeroed nor
+// erased.
+fn attribute_is_set(attribute: &[u8]) -> bool {
+    attribute[0] != 0 && attribute[0] != 0xFF
+}
 
-                    // Do start address
-                    let str02 = "\", \"start_address\":\"0x";
-                    for i in 0..str02.len() {
-                        buffer[index] = str02.as_bytes()[i];
-                        index += 1;
+impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a, const N: usize>
+    hil::uart::TransmitClient for Bootloader<'a, U, F, N>
+{
+    fn transmitted_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        _tx_len: usize,
+        error: Result<(), ErrorCode>,
+    ) {
+        if self.ack_sending.get() {
+            // A `PAGE_ACK`. If it was lost the host will notice the gap.
+            // The other acks go next, then a response that waited for them.
+            self.ack_sending.set(false);
+            self.ack_buffer.replace(buffer);
+            self.send_waiting_ack();
+            if !self.ack_sending.get() {
+                if let Some(length) = self.response_waiting.take() {
+                    if let Some(buffer) = self.buffer.take() {
+                        self.transmit(buffer, length);
                     }
-                    for i in 0..8 {
-                        let b = (pagebuffer.as_mut()[32 + page_offset + 3 - (i / 2)]
-                            >> (((i + 1) % 2) * 4))
-                            & 0x0F;
-                        buffer[index] = char::from_digit(b.into(), 16).unwrap_or('?') as u8;
-                        index += 1;
+                }
+            }
+            return;
+        }
+
+        if error.is_err() {
+            // The host may not have got the response, and there is no way to
+            // tell it, so drop the command.
+            self.abandon(buffer);
+        } else {
+            match self.state.get() {
+                // Check if there is more to be read, and if so, read it and
+                // send it.
+                State::ReadRange {
+                    address,
+                    length: _,
+                    remaining_length,
+                } => {
+                    // We have sent some of the read range to the client.
+                    // We are either done, or need to setup the next read.
+                    if remaining_length == 0 {
+                        self.state.set(State::Idle);
+                        self.receive(buffer);
+                    } else {
+                        self.buffer.replace(buffer);
+                        self.with_page_buffer(move |page| {
+                            let page_size = page.as_mut().len();
+                            self.read_flash_page(address as usize / page_size, page);
+                        });
                     }
-                    let str02 = "\", ";
-                    for i in 0..str02.len() {
-                        buffer[index] = str02.as_bytes()[i];
-                        index += 1;
+                }
+
+                // The host has been told to switch, so switch too and give it
+                // a limited time to verify the new rate.
+                State::ChangeBaudSet { baud_rate } => {
+                    let old_baud_rate = self.baud_rate.get();
+                    if self.configure_uart(baud_rate).is_ok() {
+                        self.state.set(State::ChangeBaudVerify {
+                            old_baud_rate,
+                            new_baud_rate: baud_rate,
+                        });
+                        self.timeout
+                            .map(|timeout| timeout.start(CHANGE_BAUD_VERIFY_TIMEOUT));
+                    } else {
+                        // If going back fails too there is nothing left to
+                        // try.
+                        self.state.set(State::Idle);
+                        let _ = self.configure_uart(old_baud_rate);
                     }
+                    self.receive(buffer);
+                }
 
-                    // Insert the last half of the JSON blob into the buffer.
-                    let str02 = "\"name\":\"Tock Bootloader\"}";
-                    for i in 0..str02.len() {
-                        buffer[index] = str02.as_bytes()[i];
-                        index += 1;
+                State::ChangeBaudRevert { baud_rate } => {
+                    self.state.set(State::Idle);
+                    let _ = self.configure_uart(baud_rate);
+                    self.receive(buffer);
+                }
+
+                // An `ERASE_PROGRESS` has gone out, carry on erasing.
+                State::EraseRange { address, .. } => {
+                    self.buffer.replace(buffer);
+                    self.erase_flash_page(address as usize / self.page_size);
+                }
+
+                // Same as above, but for external flash.
+                State::ExReadRange {
+                    address,
+                    length: _,
+                    remaining_length,
+                } => {
+                    if remaining_length == 0 {
+                        self.state.set(State::Idle);
+                        self.receive(buffer);
+                    } else {
+                        self.buffer.replace(buffer);
+                        let len = external_read_length(address, remaining_length as usize);
+                        let result = self
+                            .external_flash
+                            .map_or(Err(ErrorCode::NODEVICE), |external_flash| {
+                                external_flash.read(address, len)
+                            });
+                        if result.is_err() {
+                            self.fail(RES_INTERNAL_ERROR);
+                        }
                     }
+                }
+
+                _ => {
+                    self.receive(buffer);
+                }
+            }
+        }
 
-                    // Need to insert the string length as the first byte
-                    // after the header.
-                    buffer[2] = index as u8 - 3;
-                    index += 1;
+        // A `PAGE_ACK` may have been waiting for the UART.
+        self.send_waiting_ack();
+    }
+}
+
+impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a, const N: usize>
+    hil::uart::ReceiveClient for Bootloader<'a, U, F, N>
+{
+    fn received_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        _error: hil::uart::Error,
+    ) {
+        if rval.is_err() {
+            // Some of what the host sent was lost, so drop any partial
+            // command. The host is only told if it was partway through one;
+            // otherwise this is noise on the line, and an answer would be
+            // taken for the response to the next command. While waiting for
+            // a baud rate verify errors are expected if the new rate doesn't
+            // work, and the timeout will go back to the old one.
+            //
+            // While streaming, the host is waiting for a `PAGE_ACK` for every
+            // page it sent. Pages that end in what did arrive can't be
+            // trusted, so they are acknowledged with an error instead of
+            // written. A page cut short is answered once the rest of it
+            // arrives and doesn't decode.
+            if let State::StreamWrite { .. } = self.state.get() {
+                self.decoder.map(|decoder| {
+                    for byte in buffer.iter().take(rx_len) {
+                        if let Ok(Some(tock_bootloader_protocol::Command::WriteStreamPage {
+                            address,
+                            ..
+                        })) = decoder.receive(*byte)
+                        {
+                            self.send_page_ack(address, RES_INTERNAL_ERROR);
+                        }
+                    }
+                    decoder.reset();
+                });
+                self.receive(buffer);
+                return;
+            }
+            let in_command = self.decoder.map_or(false, |decoder| decoder.in_command());
+            self.decoder.map(|decoder| decoder.reset());
+            if !in_command || matches!(self.state.get(), State::ChangeBaudVerify { .. }) {
+                self.receive(buffer);
+            } else {
+                self.buffer.replace(buffer);
+                self.fail(RES_INTERNAL_ERROR);
+            }
+            return;
+        }
+
+        if rx_len == 0 {
+            self.receive(buffer);
+            return;
+        }
+
+        // Both page buffers are full, so this waits until a streamed page
+        // has been written. The UART stops receiving meanwhile, which holds
+        // the host back.
+        if self.stream_queued.is_some() {
+            self.buffer.replace(buffer);
+            self.stream_deferred.set(rx_len);
+            return;
+        }
+
+        // The decoder keeps its state between buffers, so a command split
+        // across two receives is decoded once the rest of it arrives. It
+        // starts over after every complete command (including `RESET`) or
+        // error.
+        self.decoder.map(move |decoder| {
+            // Loop through the buffer and pass it to the decoder.
+            for i in 0..rx_len {
+                match decoder.receive(buffer[i]) {
+                    // Either in the middle of a command, which may continue in
+                    // the next buffer, or a command we ignore.
+                    Ok(None) => {
+                        if i == rx_len - 1 {
+                            self.receive(buffer);
+                            break;
+                        }
+                    }
+                    // After a baud rate change the next command has to be the
+                    // verify. `RESET` is still allowed, as it does nothing.
+                    Ok(Some(command))
+                        if matches!(self.state.get(), State::ChangeBaudVerify { .. })
+                            && command != tock_bootloader_protocol::Command::Reset =>
+                    {
+                        self.buffer.replace(buffer);
+                        self.verify_baud_rate(command);
+                        break;
+                    }
+                    // If this page ends the buffer, start listening for the
+                    // next one before handling it, so the host can keep
+                    // sending. Otherwise the next page may already be here.
+                    // If it can't be taken yet, the rest of the buffer is
+                    // moved to the start and handled once a page is written.
+                    Ok(Some(command))
+                        if self.streaming()
+                            && matches!(
+                                command,
+                                tock_bootloader_protocol::Command::WriteStreamPage { .. }
+                            ) =>
+                    {
+                        if i == rx_len - 1 {
+                            self.receive(buffer);
+                            self.stream_page(&command);
+                            break;
+                        }
+                        self.stream_page(&command);
+                        if self.stream_queued.is_some() {
+                            buffer.copy_within(i + 1..rx_len, 0);
+                            self.buffer.replace(buffer);
+                            self.stream_deferred.set(rx_len - i - 1);
+                            break;
+                        }
+                    }
+                    // Other commands have to wait until the streamed pages
+                    // are written.
+                    Ok(Some(command))
+                        if matches!(self.state.get(), State::StreamWrite { .. })
+                            && command != tock_bootloader_protocol::Command::Reset =>
+                    {
+                        self.buffer.replace(buffer);
+                        self.send_response(RES_BADARGS);
+                        break;
+                    }
+                    Ok(Some(command)) if self.needs_authentication(&command) => {
+                        self.buffer.replace(buffer);
+                        self.send_response(RES_UNAUTHENTICATED);
+                        break;
+                    }
+                    Ok(Some(command))
+                        if self.reveals_key(&command) || !self.flash_access_allowed(&command) =>
+                    {
+                        self.buffer.replace(buffer);
+                        self.send_response(RES_BADADDR);
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::Ping)) => {
+                        self.buffer.replace(buffer);
+                        self.send_response(RES_PONG);
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::Reset)) => {
+                        // The decoder has already dropped anything it had
+                        // buffered.
+                        //
+                        // If there are more bytes in the buffer we want to continue
+                        // parsing those. Otherwise, we want to go back to receive.
+                        if i == rx_len - 1 {
+                            self.receive(buffer);
+                            break;
+                        }
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::Info)) => {
+                        self.state.set(State::Info);
+                        self.buffer.replace(buffer);
+                        self.with_page_buffer(move |page| {
+                            // Calculate the page index given that flags start
+                            // at address 1024.
+                            let page_index = self.flags_address / page.as_mut().len();
+
+                            self.read_flash_page(page_index, page);
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::Id)) => {
+                        let mut id = [0; interfaces::MAX_DEVICE_ID_LEN];
+                        match self.device_id.map(|device_id| device_id.device_id(&mut id)) {
+                            Some(length) => {
+                                buffer[0] = ESCAPE_CHAR;
+                                buffer[1] = RES_ID;
+                                buffer[2] = length as u8;
+                                let mut index = 3;
+                                for i in 0..id.len() {
+                                    // The ID is zero padded to the full length.
+                                    let b = if i < length { id[i] } else { 0 };
+                                    if b == ESCAPE_CHAR {
+                                        // Need to escape the escape character.
+                                        buffer[index] = ESCAPE_CHAR;
+                                        index += 1;
+                                    }
+                                    buffer[index] = b;
+                                    index += 1;
+                                }
+                                self.transmit(buffer, index);
+                            }
+                            None => {
+                                self.buffer.replace(buffer);
+                                self.send_response(RES_UNKNOWN);
+                            }
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::GetCapabilities)) => {
+                        let response = tock_bootloader_protocol::Response::Capabilities {
+                            capabilities: self.capabilities(),
+                        };
+                        match tock_bootloader_protocol::ResponseEncoder::new(&response) {
+                            Ok(mut encoder) => {
+                                let length = encoder.write(buffer);
+                                self.transmit(buffer, length);
+                            }
+                            Err(_) => {
+                                self.buffer.replace(buffer);
+                                self.send_response(RES_INTERNAL_ERROR);
+                            }
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::GetSkippedPages)) => {
+                        if !self.skip_unchanged.get() {
+                            self.buffer.replace(buffer);
+                            self.send_response(RES_UNKNOWN);
+                            break;
+                        }
+
+                        let response = tock_bootloader_protocol::Response::SkippedPages {
+                            count: self.skipped_pages.get(),
+                        };
+                        match tock_bootloader_protocol::ResponseEncoder::new(&response) {
+                            Ok(mut encoder) => {
+                                let length = encoder.write(buffer);
+                                self.transmit(buffer, length);
+                            }
+                            Err(_) => {
+                                self.buffer.replace(buffer);
+                                self.send_response(RES_INTERNAL_ERROR);
+                            }
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::ReadRange { address, length })) => {
+                        self.state.set(State::ReadRange {
+                            address,
+                            length,
+                            remaining_length: length,
+                        });
+                        self.buffer.replace(buffer);
+                        self.with_page_buffer(move |page| {
+                            let page_size = page.as_mut().len();
+                            self.read_flash_page(address as usize / page_size, page);
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::WritePage { address, data })) => {
+                        self.buffer.replace(buffer);
+                        self.with_page_buffer(move |page| {
+                            let page_size = page.as_mut().len();
+                            if page_size != data.len() {
+                                // Error if we didn't get exactly a page of data
+                                // to write to flash.
+                                self.page_buffer.replace(page);
+                                self.fail(RES_BADARGS);
+                            } else {
+                                // Otherwise copy into page buffer and write to
+                                // flash. The flash policy has already checked
+                                // the address.
+                                for i in 0..page_size {
+                                    page.as_mut()[i] = data[i];
+                                }
+                                self.write_host_page(address as usize / page_size, page);
+                            }
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::WritePageCompressed {
+                        address,
+                        data,
+                    })) => {
+                        self.buffer.replace(buffer);
+                        self.with_page_buffer(move |page| {
+                            // The page is written like one from `WRITE_PAGE`
+                            // if the data expands to exactly a page.
+                            match compression::decompress(data, page.as_mut()) {
+                                Ok(length) if length == self.page_size => {
+                                    self.write_host_page(address as usize / self.page_size, page);
+                                }
+                                _ => {
+                                    self.page_buffer.replace(page);
+                                    self.send_response(RES_BADARGS);
+                                }
+                            }
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::WritePages {
+                        address,
+                        count,
+                        data,
+                    })) => {
+                        self.buffer.replace(buffer);
+                        if count == 0 || data.len() != count as usize * self.page_size {
+                            self.send_response(RES_BADARGS);
+                        } else {
+                            // The rest of the pages are taken from the
+                            // decoder as each one is written, as the UART
+                            // doesn't receive again until the burst is done.
+                            self.write_burst_page(address, 0, count, data);
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::ErasePage { address })) => {
+                        self.state.set(State::ErasePage);
+                        self.buffer.replace(buffer);
+                        self.erase_flash_page(address as usize / self.page_size);
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::EraseRange { address, length })) => {
+                        // The flash policy has already checked the range is
+                        // whole pages.
+                        self.buffer.replace(buffer);
+                        if length == 0 {
+                            self.send_response(RES_BADARGS);
+                        } else {
+                            self.state.set(State::EraseRange {
+                                address,
+                                end: address + length,
+                            });
+                            self.erase_flash_page(address as usize / self.page_size);
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::CrcIntFlash {
+                        address,
+                        length,
+                    })) => {
+                        self.state.set(State::Crc {
+                            address,
+                            remaining_length: length,
+                            crc: 0xFFFFFFFF,
+                        });
+                        self.buffer.replace(buffer);
+                        self.with_page_buffer(move |page| {
+                            let page_size = page.as_mut().len();
+                            self.read_flash_page(address as usize / page_size, page);
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::HashIntFlash {
+                        address,
+                        length,
+                        algorithm,
+                    })) => {
+                        self.buffer.replace(buffer);
+                        if algorithm != HASH_SHA256 {
+                            self.send_response(RES_BADARGS);
+                        } else {
+                            self.state.set(State::Hash {
+                                address,
+                                remaining_length: length,
+                            });
+                            self.range_hash.put(Sha256::new());
+                            self.with_page_buffer(move |page| {
+                                self.read_flash_page(address as usize / self.page_size, page);
+                            });
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::GetAttr { index })) => {
+                        self.state.set(State::GetAttribute { index: index });
+                        self.buffer.replace(buffer);
+                        self.with_page_buffer(move |page| {
+                            // Need to calculate which page to read to get the
+                            // correct attribute (each attribute is 64 bytes long),
+                            // where attributes start at address 0x600.
+                            let page_len = page.as_mut().len();
+                            let read_address = self.attributes_address + (index as usize * 64);
+                            let page_index = read_address / page_len;
+
+                            self.read_flash_page(page_index, page);
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::SetAttr { index, key, value })) => {
+                        self.state.set(State::SetAttribute { index });
+
+                        // Copy the key and value into the buffer so it can be added
+                        // to the page buffer when needed.
+                        for i in 0..8 {
+                            buffer[i] = key[i];
+                        }
+                        buffer[8] = value.len() as u8;
+                        for i in 0..55 {
+                            // Copy in the value, otherwise clear to zero.
+                            if i < value.len() {
+                                buffer[9 + i] = value[i];
+                            } else {
+                                buffer[9 + i] = 0;
+                            }
+                        }
+                        self.buffer.replace(buffer);
+
+                        // Initiate things by reading the correct flash page that
+                        // needs to be updated.
+                        self.with_page_buffer(move |page| {
+                            // Need to calculate which page to read to get the
+                            // correct attribute (each attribute is 64 bytes long),
+                            // where attributes start at address 0x600.
+                            let page_len = page.as_mut().len();
+                            let read_address = self.attributes_address + (index as usize * 64);
+                            let page_index = read_address / page_len;
+
+                            self.read_flash_page(page_index, page);
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::SetStartAddress { address })) => {
+                        self.state.set(State::SetStartAddress { address });
+                        self.buffer.replace(buffer);
+
+                        // Initiate things by reading the correct flash page that
+                        // needs to be updated.
+                        self.with_page_buffer(move |page| {
+                            let page_len = page.as_mut().len();
+                            let page_index = self.flags_address / page_len;
+
+                            self.read_flash_page(page_index, page);
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::GetSlots)) => {
+                        self.buffer.replace(buffer);
+                        if self.kernel_slots.is_none() {
+                            self.send_response(RES_UNKNOWN);
+                            break;
+                        }
+
+                        // The slot descriptors are in the flags.
+                        self.state.set(State::GetSlots);
+                        self.with_page_buffer(move |page| {
+                            let page_index = self.flags_address / page.as_mut().len();
+                            self.read_flash_page(page_index, page);
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::SetSlotPending {
+                        slot,
+                        length,
+                        crc,
+                        version,
+                    })) => {
+                        self.buffer.replace(buffer);
+                        match self.kernel_slots.get() {
+                            None => self.send_response(RES_UNKNOWN),
+                            Some(layout)
+                                if slot as usize >= kernel_slots::NUM_KERNEL_SLOTS
+                                    || length == 0
+                                    || length > layout.size =>
+                            {
+                                self.send_response(RES_BADARGS)
+                            }
+                            Some(_) => {
+                                // Update the slot descriptor in the flags page.
+                                self.state.set(State::SetSlotPending {
+                                    slot: slot as usize,
+                                    length,
+                                    crc,
+                                    version,
+                                });
+                                self.with_page_buffer(move |page| {
+                                    let page_index = self.flags_address / page.as_mut().len();
+                                    self.read_flash_page(page_index, page);
+                                });
+                            }
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::SetKernelCrc { length, crc })) => {
+                        self.state.set(State::SetKernelCrc { length, crc });
+                        self.buffer.replace(buffer);
+
+                        // The CRC is kept in the flags, so start by reading
+                        // the flags page.
+                        self.with_page_buffer(move |page| {
+                            let page_len = page.as_mut().len();
+                            let page_index = self.flags_address / page_len;
+
+                            self.read_flash_page(page_index, page);
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::ExtFlashInit)) => {
+                        self.buffer.replace(buffer);
+                        let result = self
+                            .external_flash
+                            .map_or(Err(ErrorCode::NODEVICE), |external_flash| {
+                                external_flash.init()
+                            });
+                        match result {
+                            Ok(()) => self.send_response(RES_OK),
+                            Err(ErrorCode::NODEVICE) => self.send_response(RES_UNKNOWN),
+                            Err(_) => self.send_response(RES_INTERNAL_ERROR),
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::WriteExPage { address, data })) => {
+                        // The external flash copies the data, so the buffer is
+                        // free to be used for the response.
+                        self.buffer.replace(buffer);
+                        self.start_external_flash(State::ExWritePage, |external_flash| {
+                            external_flash.write_page(address, data)
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::EraseExPage { address })) => {
+                        self.buffer.replace(buffer);
+                        self.start_external_flash(State::ExErase, |external_flash| {
+                            external_flash.erase_page(address)
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::EraseExBlock { address })) => {
+                        self.buffer.replace(buffer);
+                        self.start_external_flash(State::ExErase, |external_flash| {
+                            external_flash.erase_block(address)
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::ExReadRange {
+                        address,
+                        length,
+                    })) => {
+                        self.buffer.replace(buffer);
+                        let state = State::ExReadRange {
+                            address,
+                            length,
+                            remaining_length: length,
+                        };
+                        let len = external_read_length(address, length as usize);
+                        self.start_external_flash(state, |external_flash| {
+                            external_flash.read(address, len)
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::CrcExtFlash {
+                        address,
+                        length,
+                    })) => {
+                        self.buffer.replace(buffer);
+                        let state = State::ExCrc {
+                            address,
+                            remaining_length: length,
+                            crc: 0xFFFFFFFF,
+                        };
+                        let len = external_read_length(address, length as usize);
+                        self.start_external_flash(state, |external_flash| {
+                            external_flash.read(address, len)
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::ChangeBaud {
+                        mode: tock_bootloader_protocol::BaudMode::Set,
+                        baud,
+                    })) => {
+                        self.buffer.replace(buffer);
+                        if self.timeout.is_none() {
+                            self.send_response(RES_UNKNOWN);
+                        } else if baud == 0 {
+                            self.send_response(RES_BADARGS);
+                        } else {
+                            // Respond at the current rate, and switch once that
+                            // has been sent.
+                            self.state.set(State::ChangeBaudSet { baud_rate: baud });
+                            self.send_response(RES_OK);
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::ChangeBaud {
+                        mode: tock_bootloader_protocol::BaudMode::Verify,
+                        baud: _,
+                    })) => {
+                        // Not waiting for a verify, so there is nothing to verify.
+                        self.buffer.replace(buffer);
+                        self.send_response(RES_CHANGE_BAUD_FAIL);
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::AuthChallenge)) => {
+                        match self.random_source.get() {
+                            Some(random_source) => {
+                                // A new challenge replaces any earlier one.
+                                let mut nonce = [0; AUTH_NONCE_LEN];
+                                random_source.fill(&mut nonce);
+                                self.auth_nonce.set(nonce);
+                                let response = tock_bootloader_protocol::Response::AuthChallenge {
+                                    nonce: &nonce,
+                                };
+                                match tock_bootloader_protocol::ResponseEncoder::new(&response) {
+                                    Ok(mut encoder) => {
+                                        let length = encoder.write(buffer);
+                                        self.transmit(buffer, length);
+                                    }
+                                    Err(_) => {
+                                        self.buffer.replace(buffer);
+                                        self.send_response(RES_INTERNAL_ERROR);
+                                    }
+                                }
+                            }
+                            None => {
+                                self.buffer.replace(buffer);
+                                self.send_response(RES_UNKNOWN);
+                            }
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::AuthResponse { mac })) => {
+                        self.buffer.replace(buffer);
+                        if self.random_source.is_none() {
+                            self.send_response(RES_UNKNOWN);
+                            break;
+                        }
+                        // Each nonce can only be answered once, right or
+                        // wrong.
+                        match self.auth_nonce.take() {
+                            Some(nonce) => {
+                                let mut copy = [0; AUTH_MAC_LEN];
+                                copy.copy_from_slice(mac);
+                                self.state.set(State::AuthResponse { nonce, mac: copy });
+                                self.with_page_buffer(move |page| {
+                                    let page_len = page.as_mut().len();
+                                    let read_address = self.attributes_address
+                                        + (self.auth_key_index.get() as usize * 64);
+                                    self.read_flash_page(read_address / page_len, page);
+                                });
+                            }
+                            None => self.send_response(RES_UNAUTHENTICATED),
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::BeginEncryptedImage {
+                        address,
+                        nonce,
+                    })) => {
+                        self.buffer.replace(buffer);
+                        if self.image_key_index.is_none() {
+                            self.send_response(RES_UNKNOWN);
+                            break;
+                        }
+                        let mut copy = [0; IMAGE_NONCE_LEN];
+                        copy.copy_from_slice(nonce);
+                        match self.image_cipher.take() {
+                            // A new image replaces any unfinished one.
+                            Some(cipher) => self.discard_encrypted_image(
+                                cipher.address(),
+                                AfterDiscard::Begin {
+                                    address,
+                                    nonce: copy,
+                                },
+                            ),
+                            None => self.begin_encrypted_image(address, copy),
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::WriteEncryptedPage {
+                        address,
+                        data,
+                    })) => {
+                        if self.image_key_index.is_none() {
+                            self.buffer.replace(buffer);
+                            self.send_response(RES_UNKNOWN);
+                            break;
+                        }
+                        let next_address =
+                            match self.image_cipher.map(|cipher| cipher.next_address()) {
+                                Some(next_address) => next_address,
+                                None => {
+                                    // No image was started.
+                                    self.buffer.replace(buffer);
+                                    self.send_response(RES_BADARGS);
+                                    break;
+                                }
+                            };
+                        self.buffer.replace(buffer);
+                        self.with_page_buffer(move |page| {
+                            let page_size = page.as_mut().len();
+                            if page_size != data.len() {
+                                self.page_buffer.replace(page);
+                                self.send_response(RES_BADARGS);
+                            } else if address != next_address {
+                                // Pages have to be sent in order, as the
+                                // keystream and tag depend on where the data
+                                // is in the image.
+                                self.page_buffer.replace(page);
+                                self.send_response(RES_BADADDR);
+                            } else {
+                                // Decrypt with a copy of the cipher, which
+                                // only replaces it once the page is written.
+                                page.as_mut().copy_from_slice(data);
+                                self.image_cipher.map(|cipher| {
+                                    let mut cipher = cipher.clone();
+                                    cipher.decrypt(page.as_mut());
+                                    self.image_page_cipher.replace(cipher);
+                                });
+                                let end = address + page_size as u32;
+                                if end > self.image_end.get() {
+                                    self.image_end.set(end);
+                                }
+                                self.write_host_page(address as usize / page_size, page);
+                            }
+                        });
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::EndEncryptedImage { tag })) => {
+                        self.buffer.replace(buffer);
+                        if self.image_key_index.is_none() {
+                            self.send_response(RES_UNKNOWN);
+                            break;
+                        }
+                        match self.image_cipher.take() {
+                            Some(cipher) => {
+                                let address = cipher.address();
+                                if cipher.verify(tag) {
+                                    self.send_response(RES_OK);
+                                } else {
+                                    self.discard_encrypted_image(address, AfterDiscard::Reject);
+                                }
+                            }
+                            None => self.send_response(RES_BADARGS),
+                        }
+                        break;
+                    }
+                    Ok(Some(tock_bootloader_protocol::Command::Exit)) => {
+                        match self.image_cipher.take() {
+                            // Don't boot into half an encrypted image.
+                            Some(cipher) => {
+                                self.buffer.replace(buffer);
+                                self.discard_encrypted_image(cipher.address(), AfterDiscard::Exit);
+                            }
+                            None => (self.reset_function)(),
+                        }
+                        break;
+                    }
+                    Ok(Some(_)) => {
+                        self.buffer.replace(buffer);
+                        self.send_response(RES_UNKNOWN);
+                        break;
+                    }
+                    Err(tock_bootloader_protocol::Error::BadArguments) => {
+                        self.buffer.replace(buffer);
+                        self.send_response(RES_BADARGS);
+                        break;
+                    }
+                    Err(_) => {
+                        self.buffer.replace(buffer);
+                        self.send_response(RES_INTERNAL_ERROR);
+                        break;
+                    }
+                };
+            }
+        });
+    }
+}
+
+impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a, const N: usize>
+    hil::flash::Client<F> for Bootloader<'a, U, F, N>
+{
+    fn read_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        if error != hil::flash::Error::CommandComplete {
+            self.page_buffer.replace(pagebuffer);
+            match self.state.get() {
+                State::CompareBurstPage { address, index, .. }
+                | State::VerifyBurstPage { address, index, .. }
+                | State::CrcBurstPages { address, index, .. } => {
+                    self.burst_page_failed(address, index, RES_FLASH_ERROR);
+                }
+                _ => self.fail(RES_FLASH_ERROR),
+            }
+            return;
+        }
+
+        match self.state.get() {
+            // We just read the bootloader info page (page 2). Extract the
+            // version and start address, then read the attributes so they can
+            // be included too.
+            State::Info => {
+                // Calculate where in the page the flags start.
+                let page = pagebuffer.as_mut();
+                let page_offset = self.flags_address % page.len();
+
+                // Version string is at most 8 bytes long, and starts at
+                // index 14 in the bootloader page.
+                let mut version = [0; 8];
+                version.copy_from_slice(&page[page_offset + 14..page_offset + 22]);
+
+                let mut start_address = [0; 4];
+                start_address.copy_from_slice(&page[page_offset + 32..page_offset + 36]);
+
+                self.state.set(State::InfoAttributes {
+                    version,
+                    start_address: u32::from_le_bytes(start_address),
+                });
+                let page_index = self.attributes_address / page.len();
+                self.read_flash_page(page_index, pagebuffer);
+            }
+
+            // We just read the attributes page. Generate the response JSON
+            // blob.
+            State::InfoAttributes {
+                version,
+                start_address,
+            } => {
+                self.state.set(State::Idle);
+                match self.buffer.take() {
+                    Some(buffer) => {
+                        let result = {
+                            let version_len = version.iter().position(|b| *b == 0).unwrap_or(8);
+                            let version =
+                                core::str::from_utf8(&version[..version_len]).unwrap_or("");
+                            let mut info = tock_bootloader_protocol::info::BootloaderInfo::new(
+                                version,
+                                start_address,
+                                BOOTLOADER_NAME,
+                            );
+                            info.stay_reason = self.stay_reason.map(|reason| reason.as_str());
+
+                            // There are 16 attributes of 64 bytes each. Only
+                            // the ones in this page are included.
+                            let page = pagebuffer.as_mut();
+                            let page_offset = self.attributes_address % page.len();
+                            for (index, attribute) in
+                                page[page_offset..].chunks_exact(64).take(16).enumerate()
+                            {
+                                // Keys are never sent to the host.
+                                if self.secret_attribute(index as u8) {
+                                    continue;
+                                }
+                                match attribute_key_value(attribute) {
+                                    Some((key, value)) => {
+                                        if info.add_attribute(key, value).is_err() {
+                                            info.dropped_attributes += 1;
+                                        }
+                                    }
+                                    // Not valid UTF-8, so it can't be sent.
+                                    None if attribute_is_set(attribute) => {
+                                        info.dropped_attributes += 1;
+                                    }
+                                    None => {}
+                                }
+                            }
+
+                            // Drop attributes until the JSON fits in the
+                            // response, and say how many were dropped.
+                            let json = &mut buffer[3..3 + MAX_INFO_LEN];
+                            let mut result = info.write_json(json);
+                            while result == Err(tock_bootloader_protocol::Error::BufferTooSmall)
+                                && info.remove_last_attribute().is_some()
+                            {
+                                info.dropped_attributes += 1;
+                                result = info.write_json(json);
+                            }
+                            result
+                        };
+                        self.page_buffer.replace(pagebuffer);
+
+                        match result {
+                            Ok(length) => {
+                                buffer[0] = ESCAPE_CHAR;
+                                buffer[1] = RES_INFO;
+                                // Need to insert the string length as the
+                                // first byte after the header.
+                                buffer[2] = length as u8;
+
+                                // Rest should be 0.
+                                for b in buffer[3 + length..3 + MAX_INFO_LEN].iter_mut() {
+                                    *b = 0;
+                                }
+
+                                self.transmit(buffer, 3 + MAX_INFO_LEN);
+                            }
+                            Err(_) => {
+                                self.buffer.replace(buffer);
+                                self.send_response(RES_INTERNAL_ERROR);
+                            }
+                        }
+                    }
+                    None => {
+                        self.page_buffer.replace(pagebuffer);
+                        self.send_response(RES_INTERNAL_ERROR);
+                    }
+                }
+            }
+
+            // We just read the correct page for this attribute. Copy it to
+            // the out buffer and send it back to the client.
+            State::GetAttribute { index } => {
+                self.state.set(State::Idle);
+                match self.buffer.take() {
+                    Some(buffer) => {
+                        buffer[0] = ESCAPE_CHAR;
+                        buffer[1] = RES_GET_ATTR;
+                        let mut j = 2;
+
+                        // Need to calculate where in the page to look for this
+                        // attribute with attributes starting at address 0x600 and
+                        // where each has length of 64 bytes.
+                        let page_len = pagebuffer.as_mut().len();
+                        let read_address = self.attributes_address + (index as usize * 64);
+                        let page_offset = read_address % page_len;
+
+                        // Keys are never sent to the host, only their names.
+                        let hidden = self.secret_attribute(index);
+
+                        for i in 0..64 {
+                            let b = if hidden && i >= 8 {
+                                0
+                            } else {
+                                pagebuffer.as_mut()[page_offset + i]
+                            };
+                            if b == ESCAPE_CHAR {
+                                // Need to escape the escape character.
+                                buffer[j] = ESCAPE_CHAR;
+                                j += 1;
+                            }
+                            buffer[j] = b;
+                            j += 1;
+                        }
+
+                        self.page_buffer.replace(pagebuffer);
+                        self.transmit(buffer, j);
+                    }
+                    None => {
+                        self.page_buffer.replace(pagebuffer);
+                        self.send_response(RES_INTERNAL_ERROR);
+                    }
+                }
+            }
+
+            // We need to update the page we just read with the new attribute,
+            // and then write that all back to flash.
+            State::SetAttribute { index } => {
+                let page_len = pagebuffer.as_mut().len();
+                let read_address = self.attributes_address + (index as usize * 64);
+                let page_offset = read_address % page_len;
+
+                // Once there is a device key, only an authenticated host can
+                // replace it.
+                let attribute = &pagebuffer.as_mut()[page_offset..page_offset + 64];
+                if self.locked()
+                    && index == self.auth_key_index.get()
+                    && attribute_key(attribute).is_some()
+                {
+                    self.state.set(State::Idle);
+                    self.page_buffer.replace(pagebuffer);
+                    self.send_response(RES_UNAUTHENTICATED);
+                    return;
+                }
+
+                // Copy the first 64 bytes of the buffer into the correct
+                // spot in the page.
+                self.buffer.map(|buffer| {
+                    pagebuffer.as_mut()[page_offset..page_offset + 64]
+                        .copy_from_slice(&buffer[..64]);
+                });
+                self.write_flash_page(read_address / page_len, pagebuffer);
+            }
+
+            // We just read the device key. Check the MAC the host sent.
+            State::AuthResponse { nonce, mac } => {
+                self.state.set(State::Idle);
+                let page_len = pagebuffer.as_mut().len();
+                let read_address =
+                    self.attributes_address + (self.auth_key_index.get() as usize * 64);
+                let page_offset = read_address % page_len;
+                let attribute = &pagebuffer.as_mut()[page_offset..page_offset + 64];
+                let valid = attribute_key(attribute)
+                    .map_or(false, |key| auth::verify_mac(&key, &nonce, &mac));
+                self.page_buffer.replace(pagebuffer);
+
+                if valid {
+                    self.authenticated.set(true);
+                    self.send_response(RES_OK);
+                } else {
+                    self.send_response(RES_UNAUTHENTICATED);
+                }
+            }
+
+            // We just read the image key. Set up decrypting the image.
+            State::BeginEncryptedImage { address, nonce } => {
+                self.state.set(State::Idle);
+                let page_len = pagebuffer.as_mut().len();
+                let key_index = self.image_key_index.unwrap_or(0);
+                let read_address = self.attributes_address + (key_index as usize * 64);
+                let page_offset = read_address % page_len;
+                let attribute = &pagebuffer.as_mut()[page_offset..page_offset + 64];
+                let key: Option<[u8; IMAGE_KEY_LEN]> = attribute_key(attribute);
+                self.page_buffer.replace(pagebuffer);
+
+                match key {
+                    Some(key) => {
+                        self.image_cipher
+                            .put(ImageCipher::new(&key, &nonce, address));
+                        self.image_end.set(address);
+                        self.send_response(RES_OK);
+                    }
+                    // There is no key to decrypt with.
+                    None => self.send_response(RES_BADARGS),
+                }
+            }
+
+            // We need to update the page we just read with the new attribute,
+            // and then write that all back to flash.
+            State::SetStartAddress { address } => {
+                let page_len = pagebuffer.as_mut().len();
+                let read_address = self.flags_address + 32;
+                let page_offset = read_address % page_len;
+                let page_index = read_address / page_len;
+
+                // Copy the first 64 bytes of the buffer into the correct
+                // spot in the page.
+                for (i, v) in address.to_le_bytes().iter().enumerate() {
+                    pagebuffer.as_mut()[page_offset + i] = *v;
+                }
+                self.write_flash_page(page_index, pagebuffer);
+            }
 
-                    // Rest should be 0.
-                    for i in index..195 {
-                        buffer[i] = 0;
+            // We just read the flags page. Send back the slot descriptors.
+            State::GetSlots => {
+                self.state.set(State::Idle);
+                let page = pagebuffer.as_mut();
+                let page_offset = self.flags_address % page.len();
+                let flags = &page[page_offset..];
+                let descriptors = [
+                    kernel_slots::SlotDescriptor::from_flags(flags, 0),
+                    kernel_slots::SlotDescriptor::from_flags(flags, 1),
+                ];
+                self.page_buffer.replace(pagebuffer);
+
+                let mut slots = tock_bootloader_protocol::KernelSlots::default();
+                self.kernel_slots.map(|layout| {
+                    for (i, slot) in slots.slots.iter_mut().enumerate() {
+                        let descriptor = &descriptors[i];
+                        *slot = tock_bootloader_protocol::KernelSlot {
+                            address: layout.address[i],
+                            size: layout.size,
+                            length: descriptor.length,
+                            crc: descriptor.crc,
+                            version: descriptor.version,
+                            state: descriptor.state,
+                        };
                     }
-
-                    self.page_buffer.replace(pagebuffer);
-                    let _ = self.uart.transmit_buffer(buffer, 195);
                 });
-            }
+                slots.active = kernel_slots::active_slot(&descriptors).map(|i| i as u8);
 
-            // We just read the correct page for this attribute. Copy it to
-            // the out buffer and send it back to the client.
-            State::GetAttribute { index } => {
-                self.state.set(State::Idle);
                 self.buffer.take().map(move |buffer| {
-                    buffer[0] = ESCAPE_CHAR;
-                    buffer[1] = RES_GET_ATTR;
-                    let mut j = 2;
-
-                    // Need to calculate where in the page to look for this
-                    // attribute with attributes starting at address 0x600 and
-                    // where each has length of 64 bytes.
-                    let page_len = pagebuffer.as_mut().len();
-                    let read_address = self.attributes_address + (index as usize * 64);
-                    let page_offset = read_address % page_len;
-
-                    for i in 0..64 {
-                        let b = pagebuffer.as_mut()[page_offset + i];
-                        if b == ESCAPE_CHAR {
-                            // Need to escape the escape character.
-                            buffer[j] = ESCAPE_CHAR;
-                            j += 1;
+                    let response = tock_bootloader_protocol::Response::Slots { slots };
+                    match tock_bootloader_protocol::ResponseEncoder::new(&response) {
+                        Ok(mut encoder) => {
+                            let length = encoder.write(buffer);
+                            self.transmit(buffer, length);
+                        }
+                        Err(_) => {
+                            self.buffer.replace(buffer);
+                            self.send_response(RES_INTERNAL_ERROR);
                         }
-                        buffer[j] = b;
-                        j += 1;
                     }
-
-                    self.page_buffer.replace(pagebuffer);
-                    let _ = self.uart.transmit_buffer(buffer, j);
                 });
             }
 
-            // We need to update the page we just read with the new attribute,
-            // and then write that all back to flash.
-            State::SetAttribute { index } => {
-                self.buffer.map(move |buffer| {
-                    let page_len = pagebuffer.as_mut().len();
-                    let read_address = self.attributes_address + (index as usize * 64);
-                    let page_offset = read_address % page_len;
-                    let page_index = read_address / page_len;
-
-                    // Copy the first 64 bytes of the buffer into the correct
-                    // spot in the page.
-                    for i in 0..64 {
-                        pagebuffer.as_mut()[page_offset + i] = buffer[i];
-                    }
-                    let _ = self.flash.write_page(page_index, pagebuffer);
-                });
+            // Fill in the descriptor for the newly uploaded kernel in the
+            // flags page we just read, and write it back. The active slot is
+            // what we fall back to, so it can't be replaced.
+            State::SetSlotPending {
+                slot,
+                length,
+                crc,
+                version,
+            } => {
+                let page_len = pagebuffer.as_mut().len();
+                let page_offset = self.flags_address % page_len;
+                let page_index = self.flags_address / page_len;
+                let flags = &mut pagebuffer.as_mut()[page_offset..];
+                let descriptors = [
+                    kernel_slots::SlotDescriptor::from_flags(flags, 0),
+                    kernel_slots::SlotDescriptor::from_flags(flags, 1),
+                ];
+
+                match self.kernel_slots.get() {
+                    Some(layout) if kernel_slots::active_slot(&descriptors) != Some(slot) => {
+                        let descriptor = kernel_slots::SlotDescriptor {
+                            address: layout.address[slot],
+                            length,
+                            crc,
+                            version,
+                            sequence: kernel_slots::next_sequence(&descriptors),
+                            state: tock_bootloader_protocol::SlotState::Pending,
+                        };
+                        descriptor.to_flags(flags, slot);
+                        self.write_flash_page(page_index, pagebuffer);
+                    }
+                    _ => {
+                        self.state.set(State::Idle);
+                        self.page_buffer.replace(pagebuffer);
+                        self.send_response(RES_BADARGS);
+                    }
+                }
             }
 
-            // We need to update the page we just read with the new attribute,
-            // and then write that all back to flash.
-            State::SetStartAddress { address } => {
+            // Update the kernel length and CRC in the flags page we just
+            // read, and write it back.
+            State::SetKernelCrc { length, crc } => {
                 let page_len = pagebuffer.as_mut().len();
-                let read_address = self.flags_address + 32;
+                let read_address = self.flags_address + KERNEL_CRC_FLAGS_OFFSET;
                 let page_offset = read_address % page_len;
                 let page_index = read_address / page_len;
 
-                // Copy the first 64 bytes of the buffer into the correct
-                // spot in the page.
-                for (i, v) in address.to_le_bytes().iter().enumerate() {
-                    pagebuffer.as_mut()[page_offset + i] = *v;
-                }
-                let _ = self.flash.write_page(page_index, pagebuffer);
+                let page = pagebuffer.as_mut();
+                page[page_offset..page_offset + 4].copy_from_slice(&length.to_le_bytes());
+                page[page_offset + 4..page_offset + 8].copy_from_slice(&crc.to_le_bytes());
+                self.write_flash_page(page_index, pagebuffer);
             }
 
             // Pass what we have read so far to the client.
@@ -605,58 +2936,64 @@ impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a> hil::fl
                 // Take what we need to read out of this page and send it
                 // on uart. If this is the first message be sure to send the
                 // header.
-                self.buffer.take().map(move |buffer| {
-                    let mut index = 0;
-                    if length == remaining_length {
-                        buffer[0] = ESCAPE_CHAR;
-                        buffer[1] = RES_READ_RANGE;
-                        index = 2;
-                    }
-
-                    let page_size = pagebuffer.as_mut().len();
-                    // This will get us our offset into the page.
-                    let page_index = address as usize % page_size;
-                    // Length is either the rest of the page or how much we have left.
-                    let len = cmp::min(page_size - page_index, remaining_length as usize);
-                    // Make sure we don't overflow the buffer.
-                    let copy_len = cmp::min(len, buffer.len() - index);
-
-                    // Copy what we read from the page buffer to the user buffer.
-                    // Keep track of how much was actually copied.
-                    let mut actually_copied = 0;
-                    for i in 0..copy_len {
-                        // Make sure we don't overflow the buffer. We need to
-                        // have at least two open bytes in the buffer
-                        if index >= (buffer.len() - 1) {
-                            break;
+                match self.buffer.take() {
+                    Some(buffer) => {
+                        let mut index = 0;
+                        if length == remaining_length {
+                            buffer[0] = ESCAPE_CHAR;
+                            buffer[1] = RES_READ_RANGE;
+                            index = 2;
                         }
 
-                        // Normally do the copy and check if this needs to be
-                        // escaped.
-                        actually_copied += 1;
-                        let b = pagebuffer.as_mut()[page_index + i];
-                        if b == ESCAPE_CHAR {
-                            // Need to escape the escape character.
-                            buffer[index] = ESCAPE_CHAR;
+                        let page_size = pagebuffer.as_mut().len();
+                        // This will get us our offset into the page.
+                        let page_index = address as usize % page_size;
+                        // Length is either the rest of the page or how much we have left.
+                        let len = cmp::min(page_size - page_index, remaining_length as usize);
+                        // Make sure we don't overflow the buffer.
+                        let copy_len = cmp::min(len, buffer.len() - index);
+
+                        // Copy what we read from the page buffer to the user buffer.
+                        // Keep track of how much was actually copied.
+                        let mut actually_copied = 0;
+                        for i in 0..copy_len {
+                            // Make sure we don't overflow the buffer. We need to
+                            // have at least two open bytes in the buffer
+                            if index >= (buffer.len() - 1) {
+                                break;
+                            }
+
+                            // Normally do the copy and check if this needs to be
+                            // escaped.
+                            actually_copied += 1;
+                            let b = pagebuffer.as_mut()[page_index + i];
+                            if b == ESCAPE_CHAR {
+                                // Need to escape the escape character.
+                                buffer[index] = ESCAPE_CHAR;
+                                index += 1;
+                            }
+                            buffer[index] = b;
                             index += 1;
                         }
-                        buffer[index] = b;
-                        index += 1;
-                    }
 
-                    // Update our state.
-                    let new_address = address as usize + actually_copied;
-                    let new_remaining_length = remaining_length as usize - actually_copied;
-                    self.state.set(State::ReadRange {
-                        address: new_address as u32,
-                        length,
-                        remaining_length: new_remaining_length as u16,
-                    });
+                        // Update our state.
+                        let new_address = address as usize + actually_copied;
+                        let new_remaining_length = remaining_length as usize - actually_copied;
+                        self.state.set(State::ReadRange {
+                            address: new_address as u32,
+                            length,
+                            remaining_length: new_remaining_length as u16,
+                        });
 
-                    // And send the buffer to the client.
-                    self.page_buffer.replace(pagebuffer);
-                    let _ = self.uart.transmit_buffer(buffer, index);
-                });
+                        // And send the buffer to the client.
+                        self.page_buffer.replace(pagebuffer);
+                        self.transmit(buffer, index);
+                    }
+                    None => {
+                        self.page_buffer.replace(pagebuffer);
+                        self.send_response(RES_INTERNAL_ERROR);
+                    }
+                }
             }
 
             // We have some data to calculate the CRC on.
@@ -673,12 +3010,8 @@ impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a> hil::fl
 
                 // Iterate all bytes in the page that are relevant to the CRC
                 // and include them in the CRC calculation.
-                let mut new_crc = crc;
-                for i in 0..len {
-                    let v1 = (new_crc ^ pagebuffer.as_mut()[page_index + i] as u32) & 0xFF;
-                    let v2 = bootloader_crc::CRC32_TABLE[v1 as usize];
-                    new_crc = v2 ^ (new_crc >> 8);
-                }
+                let mut new_crc =
+                    bootloader_crc::update(crc, &pagebuffer.as_mut()[page_index..page_index + len]);
 
                 // Update our state.
                 let new_address = address + len as u32;
@@ -690,17 +3023,23 @@ impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a> hil::fl
                     new_crc = new_crc ^ 0xFFFFFFFF;
 
                     self.state.set(State::Idle);
-                    self.buffer.take().map(move |buffer| {
-                        buffer[0] = ESCAPE_CHAR;
-                        buffer[1] = RES_CRCIF;
-                        buffer[2] = ((new_crc >> 0) & 0xFF) as u8;
-                        buffer[3] = ((new_crc >> 8) & 0xFF) as u8;
-                        buffer[4] = ((new_crc >> 16) & 0xFF) as u8;
-                        buffer[5] = ((new_crc >> 24) & 0xFF) as u8;
-                        // And send the buffer to the client.
-                        self.page_buffer.replace(pagebuffer);
-                        let _ = self.uart.transmit_buffer(buffer, 6);
-                    });
+                    match self.buffer.take() {
+                        Some(buffer) => {
+                            buffer[0] = ESCAPE_CHAR;
+                            buffer[1] = RES_CRCIF;
+                            buffer[2] = ((new_crc >> 0) & 0xFF) as u8;
+                            buffer[3] = ((new_crc >> 8) & 0xFF) as u8;
+                            buffer[4] = ((new_crc >> 16) & 0xFF) as u8;
+                            buffer[5] = ((new_crc >> 24) & 0xFF) as u8;
+                            // And send the buffer to the client.
+                            self.page_buffer.replace(pagebuffer);
+                            self.transmit(buffer, 6);
+                        }
+                        None => {
+                            self.page_buffer.replace(pagebuffer);
+                            self.send_response(RES_INTERNAL_ERROR);
+                        }
+                    }
                 } else {
                     // More CRC to do!
                     self.state.set(State::Crc {
@@ -708,61 +3047,278 @@ impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a> hil::fl
                         remaining_length: new_remaining_length,
                         crc: new_crc,
                     });
-                    let _ = self
-                        .flash
-                        .read_page(new_address as usize / page_size, pagebuffer);
+                    self.read_flash_page(new_address as usize / page_size, pagebuffer);
                 }
             }
 
-            _ => {}
+            // The same as the CRC, but for SHA-256.
+            State::Hash {
+                address,
+                remaining_length,
+            } => {
+                let page_size = pagebuffer.as_mut().len();
+                let page_index = address as usize % page_size;
+                let len = cmp::min(page_size - page_index, remaining_length as usize);
+                self.range_hash.map(|hash| {
+                    hash.update(&pagebuffer.as_mut()[page_index..page_index + len]);
+                });
+
+                let new_address = address + len as u32;
+                let new_remaining_length = remaining_length - len as u32;
+                if new_remaining_length == 0 {
+                    self.state.set(State::Idle);
+                    self.page_buffer.replace(pagebuffer);
+                    match self.range_hash.take() {
+                        Some(hash) => {
+                            self.send_encoded(tock_bootloader_protocol::Response::HashIntFlash {
+                                hash: hash.finalize(),
+                            })
+                        }
+                        None => self.send_response(RES_INTERNAL_ERROR),
+                    }
+                } else {
+                    self.state.set(State::Hash {
+                        address: new_address,
+                        remaining_length: new_remaining_length,
+                    });
+                    self.read_flash_page(new_address as usize / page_size, pagebuffer);
+                }
+            }
+
+            // Read the page a page from the host is for. If it already holds
+            // the data, which is in the UART buffer, there is nothing to do.
+            State::ComparePage { page_index } => {
+                let page = pagebuffer.as_mut();
+                let unchanged = self
+                    .buffer
+                    .map_or(false, |buffer| buffer.get(..page.len()) == Some(&*page));
+                if unchanged {
+                    self.skipped_pages
+                        .set(self.skipped_pages.get().saturating_add(1));
+                    self.page_buffer.replace(pagebuffer);
+                    self.host_page_written();
+                } else if self.copy_host_page(page) {
+                    self.program_host_page(page_index, pagebuffer);
+                } else {
+                    self.page_buffer.replace(pagebuffer);
+                    self.fail(RES_INTERNAL_ERROR);
+                }
+            }
+
+            // Read back a page from the host. If it doesn't match what the
+            // host sent, which is still in the UART buffer, write it again.
+            State::VerifyPage {
+                page_index,
+                retries,
+            } => {
+                let page = pagebuffer.as_mut();
+                let matches = self
+                    .buffer
+                    .map_or(false, |buffer| buffer.get(..page.len()) == Some(&*page));
+                if matches {
+                    self.page_buffer.replace(pagebuffer);
+                    self.host_page_written();
+                } else if retries > 0 && self.copy_host_page(page) {
+                    self.state.set(State::WriteVerifiedPage {
+                        page_index,
+                        retries: retries - 1,
+                    });
+                    self.write_flash_page(page_index, pagebuffer);
+                } else {
+                    self.page_buffer.replace(pagebuffer);
+                    self.fail(RES_VERIFY_FAILED);
+                }
+            }
+
+            // Read the page a page of a `WRITE_PAGES` burst is for. If it
+            // already holds the data, go on to the next one.
+            State::CompareBurstPage {
+                address,
+                index,
+                pages,
+            } => {
+                let page = pagebuffer.as_mut();
+                if self.map_burst_page(index, |data| data == page) == Some(true) {
+                    self.skipped_pages
+                        .set(self.skipped_pages.get().saturating_add(1));
+                    self.page_buffer.replace(pagebuffer);
+                    self.next_burst_page(address, index, pages);
+                } else if self
+                    .map_burst_page(index, |data| page.copy_from_slice(data))
+                    .is_some()
+                {
+                    let retries = self.write_retries.get().unwrap_or(0);
+                    self.program_burst_page(address, index, pages, retries, pagebuffer);
+                } else {
+                    self.page_buffer.replace(pagebuffer);
+                    self.burst_page_failed(address, index, RES_INTERNAL_ERROR);
+                }
+            }
+
+            // Read back a page of a `WRITE_PAGES` burst. If it doesn't match
+            // the command, which is still in the decoder, write it again.
+            State::VerifyBurstPage {
+                address,
+                index,
+                pages,
+                retries,
+            } => {
+                let page = pagebuffer.as_mut();
+                let matches = self.map_burst_page(index, |data| data == page);
+                if matches == Some(true) {
+                    self.page_buffer.replace(pagebuffer);
+                    self.next_burst_page(address, index, pages);
+                } else if retries > 0
+                    && self
+                        .map_burst_page(index, |data| page.copy_from_slice(data))
+                        .is_some()
+                {
+                    self.program_burst_page(address, index, pages, retries - 1, pagebuffer);
+                } else {
+                    self.page_buffer.replace(pagebuffer);
+                    let result = match matches {
+                        Some(_) => RES_VERIFY_FAILED,
+                        None => RES_INTERNAL_ERROR,
+                    };
+                    self.burst_page_failed(address, index, result);
+                }
+            }
+
+            // Add a page of a written `WRITE_PAGES` burst to the CRC, and
+            // either read the next one or send the result.
+            State::CrcBurstPages {
+                address,
+                index,
+                pages,
+                crc,
+            } => {
+                let crc = bootloader_crc::update(crc, pagebuffer.as_mut());
+                self.page_buffer.replace(pagebuffer);
+                if index + 1 < pages {
+                    self.state.set(State::CrcBurstPages {
+                        address,
+                        index: index + 1,
+                        pages,
+                        crc,
+                    });
+                    self.read_burst_page(address, index + 1);
+                } else {
+                    self.send_burst_result(tock_bootloader_protocol::Response::PagesWritten {
+                        crc: crc ^ 0xFFFFFFFF,
+                    });
+                }
+            }
+
+            _ => {
+                self.page_buffer.replace(pagebuffer);
+            }
         }
     }
 
-    fn write_complete(&self, pagebuffer: &'static mut F::Page, _error: hil::flash::Error) {
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
         self.page_buffer.replace(pagebuffer);
+        if let State::StreamWrite { address } = self.state.get() {
+            let result = if error == hil::flash::Error::CommandComplete {
+                RES_OK
+            } else {
+                RES_FLASH_ERROR
+            };
+            self.stream_page_written(address, result);
+            return;
+        }
+        if let State::WritePages {
+            address,
+            index,
+            pages,
+            retries,
+        } = self.state.get()
+        {
+            if error != hil::flash::Error::CommandComplete {
+                self.burst_page_failed(address, index, RES_FLASH_ERROR);
+            } else if self.write_retries.is_some() {
+                self.state.set(State::VerifyBurstPage {
+                    address,
+                    index,
+                    pages,
+                    retries,
+                });
+                self.read_burst_page(address, index);
+            } else {
+                self.next_burst_page(address, index, pages);
+            }
+            return;
+        }
+        if error != hil::flash::Error::CommandComplete {
+            self.fail(RES_FLASH_ERROR);
+            return;
+        }
 
         match self.state.get() {
             // Writing flash page done, send OK.
             State::WriteFlashPage => {
-                self.state.set(State::Idle);
-                self.buffer.take().map(move |buffer| {
-                    buffer[0] = ESCAPE_CHAR;
-                    buffer[1] = RES_OK;
-                    let _ = self.uart.transmit_buffer(buffer, 2);
+                self.host_page_written();
+            }
+
+            // Page from the host written, read it back to check it.
+            State::WriteVerifiedPage {
+                page_index,
+                retries,
+            } => {
+                self.state.set(State::VerifyPage {
+                    page_index,
+                    retries,
+                });
+                self.with_page_buffer(|page| {
+                    self.read_flash_page(page_index, page);
                 });
             }
 
             // Attribute writing done, send an OK response.
             State::SetAttribute { index: _ } => {
                 self.state.set(State::Idle);
-                self.buffer.take().map(move |buffer| {
-                    buffer[0] = ESCAPE_CHAR;
-                    buffer[1] = RES_OK;
-                    let _ = self.uart.transmit_buffer(buffer, 2);
-                });
+                match self.buffer.take() {
+                    Some(buffer) => {
+                        buffer[0] = ESCAPE_CHAR;
+                        buffer[1] = RES_OK;
+                        self.transmit(buffer, 2);
+                    }
+                    None => {
+                        self.send_response(RES_INTERNAL_ERROR);
+                    }
+                }
             }
 
-            // Attribute writing done, send an OK response.
-            State::SetStartAddress { address: _ } => {
+            // Flags writing done, send an OK response.
+            State::SetStartAddress { .. }
+            | State::SetKernelCrc { .. }
+            | State::SetSlotPending { .. } => {
                 self.state.set(State::Idle);
-                self.buffer.take().map(move |buffer| {
-                    buffer[0] = ESCAPE_CHAR;
-                    buffer[1] = RES_OK;
-                    let _ = self.uart.transmit_buffer(buffer, 2);
-                });
+                match self.buffer.take() {
+                    Some(buffer) => {
+                        buffer[0] = ESCAPE_CHAR;
+                        buffer[1] = RES_OK;
+                        self.transmit(buffer, 2);
+                    }
+                    None => {
+                        self.send_response(RES_INTERNAL_ERROR);
+                    }
+                }
             }
 
             _ => {
                 self.buffer.take().map(|buffer| {
-                    let _ = self
-                        .uart
-                        .receive_automatic(buffer, buffer.len(), UART_RECEIVE_TIMEOUT);
+                    self.receive(buffer);
                 });
             }
         }
     }
 
-    fn erase_complete(&self, _error: hil::flash::Error) {
+    fn erase_complete(&self, error: hil::flash::Error) {
+        if error != hil::flash::Error::CommandComplete {
+            self.fail(RES_FLASH_ERROR);
+            return;
+        }
+
         match self.state.get() {
             // Page erased, return OK
             State::ErasePage => {
@@ -770,17 +3326,184 @@ impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a> hil::fl
                 self.buffer.take().map(move |buffer| {
                     buffer[0] = ESCAPE_CHAR;
                     buffer[1] = RES_OK;
-                    let _ = self.uart.transmit_buffer(buffer, 2);
+                    self.transmit(buffer, 2);
                 });
             }
 
+            // Erase the next page of the range, stopping every
+            // `ERASE_PROGRESS_PAGES` pages to tell the host the erase is still
+            // going. The next page is erased once that has been sent.
+            State::EraseRange { address, end } => {
+                let next = address + self.page_size as u32;
+                if next >= end {
+                    self.state.set(State::Idle);
+                    self.send_response(RES_OK);
+                } else {
+                    self.state.set(State::EraseRange { address: next, end });
+                    if (next / self.page_size as u32) % ERASE_PROGRESS_PAGES == 0 {
+                        self.send_encoded(tock_bootloader_protocol::Response::EraseProgress {
+                            address: next,
+                        });
+                    } else {
+                        self.erase_flash_page(next as usize / self.page_size);
+                    }
+                }
+            }
+
+            // Erase the rest of the unfinished image, then carry on.
+            State::DiscardEncryptedImage { address, end, then } => {
+                let next = address + self.page_size as u32;
+                if next < end {
+                    self.state.set(State::DiscardEncryptedImage {
+                        address: next,
+                        end,
+                        then,
+                    });
+                    self.erase_flash_page(next as usize / self.page_size);
+                } else {
+                    self.encrypted_image_discarded(then);
+                }
+            }
+
             _ => {
                 self.buffer.take().map(|buffer| {
-                    let _ = self
-                        .uart
-                        .receive_automatic(buffer, buffer.len(), UART_RECEIVE_TIMEOUT);
+                    self.receive(buffer);
+                });
+            }
+        }
+    }
+}
+
+impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a, const N: usize>
+    interfaces::ExternalFlashClient for Bootloader<'a, U, F, N>
+{
+    fn read_done(&self, data: &[u8], result: Result<(), ErrorCode>) {
+        if result.is_err() {
+            self.state.set(State::Idle);
+            self.send_response(RES_INTERNAL_ERROR);
+            return;
+        }
+
+        match self.state.get() {
+            // Send what we read to the client. A chunk is at most one
+            // external page, so even fully escaped it fits in the buffer.
+            State::ExReadRange {
+                address,
+                length,
+                remaining_length,
+            } => {
+                self.buffer.take().map(move |buffer| {
+                    let mut index = 0;
+                    if length == remaining_length {
+                        buffer[0] = ESCAPE_CHAR;
+                        buffer[1] = RES_EX_READ_RANGE;
+                        index = 2;
+                    }
+
+                    for b in data {
+                        if *b == ESCAPE_CHAR {
+                            // Need to escape the escape character.
+                            buffer[index] = ESCAPE_CHAR;
+                            index += 1;
+                        }
+                        buffer[index] = *b;
+                        index += 1;
+                    }
+
+                    self.state.set(State::ExReadRange {
+                        address: address + data.len() as u32,
+                        length,
+                        remaining_length: remaining_length - data.len() as u16,
+                    });
+                    self.transmit(buffer, index);
                 });
             }
+
+            // Add what we read to the CRC and either read more or send the
+            // result.
+            State::ExCrc {
+                address,
+                remaining_length,
+                crc,
+            } => {
+                let new_crc = bootloader_crc::update(crc, data);
+                let new_address = address + data.len() as u32;
+                let new_remaining_length = remaining_length - data.len() as u32;
+
+                if new_remaining_length == 0 {
+                    let new_crc = new_crc ^ 0xFFFFFFFF;
+
+                    self.state.set(State::Idle);
+                    self.buffer.take().map(move |buffer| {
+                        buffer[0] = ESCAPE_CHAR;
+                        buffer[1] = RES_CRCXF;
+                        buffer[2..6].copy_from_slice(&new_crc.to_le_bytes());
+                        self.transmit(buffer, 6);
+                    });
+                } else {
+                    self.state.set(State::ExCrc {
+                        address: new_address,
+                        remaining_length: new_remaining_length,
+                        crc: new_crc,
+                    });
+                    let len = external_read_length(new_address, new_remaining_length as usize);
+                    let result = self
+                        .external_flash
+                        .map_or(Err(ErrorCode::NODEVICE), |external_flash| {
+                            external_flash.read(new_address, len)
+                        });
+                    if result.is_err() {
+                        self.fail(RES_INTERNAL_ERROR);
+                    }
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    fn write_done(&self, result: Result<(), ErrorCode>) {
+        if self.state.get() == State::ExWritePage {
+            self.state.set(State::Idle);
+            self.send_response(if result.is_ok() {
+                RES_OK
+            } else {
+                RES_INTERNAL_ERROR
+            });
+        }
+    }
+
+    fn erase_done(&self, result: Result<(), ErrorCode>) {
+        if self.state.get() == State::ExErase {
+            self.state.set(State::Idle);
+            self.send_response(if result.is_ok() {
+                RES_OK
+            } else {
+                RES_INTERNAL_ERROR
+            });
+        }
+    }
+}
+
+impl<'a, U: hil::uart::UartAdvanced<'a> + 'a, F: hil::flash::Flash + 'a, const N: usize>
+    interfaces::TimeoutClient for Bootloader<'a, U, F, N>
+{
+    fn timeout_expired(&self) {
+        // The host did not verify the new baud rate in time, so go back to
+        // the old one.
+        if let State::ChangeBaudVerify {
+            old_baud_rate,
+            new_baud_rate: _,
+        } = self.state.get()
+        {
+            self.state.set(State::Idle);
+            let _ = self.configure_uart(old_baud_rate);
+        }
+        // A receive the UART wouldn't start. The buffer is only left here
+        // while idle if that happened, or if it holds a response waiting for
+        // a `PAGE_ACK` to be sent.
+        if self.state.get() == State::Idle && self.response_waiting.is_none() {
+            self.buffer.take().map(|buffer| self.receive(buffer));
         }
     }
 }
@@ -256,3 +256,13 @@ pub static CRC32_TABLE: [u32; 256] = [
     0x5a05df1bu32,
     0x2d02ef8du32,
 ];
+
+/// Add `data` to a running CRC-32. Start with `0xFFFFFFFF` and XOR the final
+/// value with `0xFFFFFFFF`.
+pub fn update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = crc;
+    for b in data {
+        crc = CRC32_TABLE[((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
@@ -0,0 +1,329 @@
+//! Use any `hil::flash::Flash` as the bootloader's external flash.
+//!
+//! The bootloader protocol works on 256 byte pages and 2048 byte blocks of
+//! external flash, while external flash chips (and the capsules for them)
+//! usually have larger pages or sectors. This adapter maps the protocol
+//! operations onto the underlying flash pages, doing a read-modify-write for
+//! any page that is only partially covered by an operation.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let external_flash_adapter = static_init!(
+//!     bootloader::external_flash_adapter::ExternalFlashAdapter<'static, Mx25r6435f>,
+//!     bootloader::external_flash_adapter::ExternalFlashAdapter::new(
+//!         mx25r6435f,
+//!         external_pagebuffer,
+//!         0x800000
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(mx25r6435f, external_flash_adapter);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::hil;
+use kernel::utilities::cells::MapCell;
+use kernel::utilities::cells::OptionalCell;
+use kernel::utilities::cells::TakeCell;
+use kernel::ErrorCode;
+
+use crate::interfaces;
+use crate::interfaces::EXTERNAL_BLOCK_SIZE;
+use crate::interfaces::EXTERNAL_PAGE_SIZE;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Read {
+        address: u32,
+        length: usize,
+    },
+    /// Writing (from `data`) or erasing `start..end`. `address` is where in
+    /// that range the underlying page currently being updated starts.
+    Modify {
+        start: u32,
+        address: u32,
+        end: u32,
+        erase: bool,
+    },
+}
+
+pub struct ExternalFlashAdapter<'a, F: hil::flash::Flash + 'static> {
+    flash: &'a F,
+    client: OptionalCell<&'a dyn interfaces::ExternalFlashClient>,
+    pagebuffer: TakeCell<'static, F::Page>,
+    page_size: usize,
+    /// Size of the external flash in bytes.
+    size: u32,
+    /// Data for the page write in progress.
+    data: MapCell<[u8; EXTERNAL_PAGE_SIZE]>,
+    state: Cell<State>,
+}
+
+impl<'a, F: hil::flash::Flash + 'static> ExternalFlashAdapter<'a, F> {
+    pub fn new(
+        flash: &'a F,
+        pagebuffer: &'static mut F::Page,
+        size: u32,
+    ) -> ExternalFlashAdapter<'a, F> {
+        let page_size = pagebuffer.as_mut().len();
+        ExternalFlashAdapter {
+            flash,
+            client: OptionalCell::empty(),
+            pagebuffer: TakeCell::new(pagebuffer),
+            page_size,
+            size,
+            data: MapCell::new([0; EXTERNAL_PAGE_SIZE]),
+            state: Cell::new(State::Idle),
+        }
+    }
+
+    /// Check that an operation on `length` bytes at `address` is aligned to
+    /// `alignment` and within the flash.
+    fn check_range(&self, address: u32, length: usize, alignment: usize) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            Err(ErrorCode::BUSY)
+        } else if address as usize % alignment != 0
+            || address as usize + length > self.size as usize
+        {
+            Err(ErrorCode::INVAL)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Start writing or erasing `start..end`.
+    fn modify(&self, start: u32, end: u32, erase: bool) -> Result<(), ErrorCode> {
+        self.state.set(State::Modify {
+            start,
+            address: start,
+            end,
+            erase,
+        });
+        self.modify_next_page().map_err(|e| {
+            self.state.set(State::Idle);
+            e
+        })
+    }
+
+    /// Start the next step of the write or erase for the underlying page that
+    /// contains `address`.
+    fn modify_next_page(&self) -> Result<(), ErrorCode> {
+        if let State::Modify {
+            start,
+            address,
+            end,
+            erase,
+        } = self.state.get()
+        {
+            let page_number = address as usize / self.page_size;
+            let page_start = page_number * self.page_size;
+            let page_end = page_start + self.page_size;
+
+            if erase && start as usize <= page_start && page_end <= end as usize {
+                // The whole page is being erased, so we can just erase it.
+                self.flash.erase_page(page_number)
+            } else {
+                self.pagebuffer
+                    .take()
+                    .map_or(Err(ErrorCode::BUSY), move |page| {
+                        let result = if start as usize <= page_start && page_end <= end as usize {
+                            // The whole page is being written, so there is
+                            // nothing to keep from the current contents.
+                            self.fill_page(page.as_mut(), page_start);
+                            self.flash.write_page(page_number, page)
+                        } else {
+                            // Otherwise read the page first so we can keep
+                            // what is outside the range.
+                            self.flash.read_page(page_number, page)
+                        };
+                        result.map_err(|(e, page)| {
+                            self.pagebuffer.replace(page);
+                            e
+                        })
+                    })
+            }
+        } else {
+            Err(ErrorCode::FAIL)
+        }
+    }
+
+    /// Copy the new contents of the range into `page`, which holds the
+    /// underlying page starting at `page_start`.
+    fn fill_page(&self, page: &mut [u8], page_start: usize) {
+        if let State::Modify {
+            start,
+            address: _,
+            end,
+            erase,
+        } = self.state.get()
+        {
+            let from = cmp::max(start as usize, page_start);
+            let to = cmp::min(end as usize, page_start + page.len());
+            if erase {
+                for b in page[from - page_start..to - page_start].iter_mut() {
+                    *b = 0xFF;
+                }
+            } else {
+                self.data.map(|data| {
+                    page[from - page_start..to - page_start]
+                        .copy_from_slice(&data[from - start as usize..to - start as usize]);
+                });
+            }
+        }
+    }
+
+    /// The underlying page containing `address` has been updated. Move on to
+    /// the next page or tell the client we are done.
+    fn modify_page_done(&self, result: Result<(), ErrorCode>) {
+        if let State::Modify {
+            start,
+            address,
+            end,
+            erase,
+        } = self.state.get()
+        {
+            let next_address = (address as usize / self.page_size + 1) * self.page_size;
+
+            let result = if result.is_ok() && next_address < end as usize {
+                self.state.set(State::Modify {
+                    start,
+                    address: next_address as u32,
+                    end,
+                    erase,
+                });
+                match self.modify_next_page() {
+                    // Still going.
+                    Ok(()) => return,
+                    Err(e) => Err(e),
+                }
+            } else {
+                result
+            };
+
+            self.state.set(State::Idle);
+            self.client.map(|client| {
+                if erase {
+                    client.erase_done(result);
+                } else {
+                    client.write_done(result);
+                }
+            });
+        }
+    }
+}
+
+/// Convert the result of a `hil::flash` operation.
+fn flash_result(error: hil::flash::Error) -> Result<(), ErrorCode> {
+    match error {
+        hil::flash::Error::CommandComplete => Ok(()),
+        _ => Err(ErrorCode::FAIL),
+    }
+}
+
+impl<'a, F: hil::flash::Flash + 'static> interfaces::ExternalFlash<'a>
+    for ExternalFlashAdapter<'a, F>
+{
+    fn set_client(&self, client: &'a dyn interfaces::ExternalFlashClient) {
+        self.client.set(client);
+    }
+
+    fn init(&self) -> Result<(), ErrorCode> {
+        // The underlying flash is set up by the board.
+        Ok(())
+    }
+
+    fn read(&self, address: u32, length: usize) -> Result<(), ErrorCode> {
+        self.check_range(address, length, 1)?;
+        if address as usize % EXTERNAL_PAGE_SIZE + length > EXTERNAL_PAGE_SIZE {
+            return Err(ErrorCode::INVAL);
+        }
+
+        self.pagebuffer
+            .take()
+            .map_or(Err(ErrorCode::BUSY), move |page| {
+                self.state.set(State::Read { address, length });
+                self.flash
+                    .read_page(address as usize / self.page_size, page)
+                    .map_err(|(e, page)| {
+                        self.state.set(State::Idle);
+                        self.pagebuffer.replace(page);
+                        e
+                    })
+            })
+    }
+
+    fn write_page(&self, address: u32, data: &[u8]) -> Result<(), ErrorCode> {
+        self.check_range(address, EXTERNAL_PAGE_SIZE, EXTERNAL_PAGE_SIZE)?;
+        if data.len() != EXTERNAL_PAGE_SIZE {
+            return Err(ErrorCode::SIZE);
+        }
+
+        self.data.map(|buffer| buffer.copy_from_slice(data));
+        self.modify(address, address + EXTERNAL_PAGE_SIZE as u32, false)
+    }
+
+    fn erase_page(&self, address: u32) -> Result<(), ErrorCode> {
+        self.check_range(address, EXTERNAL_PAGE_SIZE, EXTERNAL_PAGE_SIZE)?;
+        self.modify(address, address + EXTERNAL_PAGE_SIZE as u32, true)
+    }
+
+    fn erase_block(&self, address: u32) -> Result<(), ErrorCode> {
+        self.check_range(address, EXTERNAL_BLOCK_SIZE, EXTERNAL_BLOCK_SIZE)?;
+        self.modify(address, address + EXTERNAL_BLOCK_SIZE as u32, true)
+    }
+}
+
+impl<F: hil::flash::Flash + 'static> hil::flash::Client<F> for ExternalFlashAdapter<'_, F> {
+    fn read_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        match self.state.get() {
+            State::Read { address, length } => {
+                // Copy out what was asked for and give the page buffer back
+                // first, so the client can start the next read right away.
+                let offset = address as usize % self.page_size;
+                self.data.map(|data| {
+                    data[..length].copy_from_slice(&pagebuffer.as_mut()[offset..offset + length]);
+                });
+                self.pagebuffer.replace(pagebuffer);
+                self.state.set(State::Idle);
+
+                self.data.map(|data| {
+                    self.client.map(|client| {
+                        client.read_done(&data[..length], flash_result(error));
+                    });
+                });
+            }
+
+            // Update the part of the page we are changing and write it back.
+            State::Modify { address, .. } => {
+                if flash_result(error).is_err() {
+                    self.pagebuffer.replace(pagebuffer);
+                    self.modify_page_done(flash_result(error));
+                    return;
+                }
+
+                let page_number = address as usize / self.page_size;
+                self.fill_page(pagebuffer.as_mut(), page_number * self.page_size);
+                if let Err((e, page)) = self.flash.write_page(page_number, pagebuffer) {
+                    self.pagebuffer.replace(page);
+                    self.modify_page_done(Err(e));
+                }
+            }
+
+            State::Idle => {
+                self.pagebuffer.replace(pagebuffer);
+            }
+        }
+    }
+
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        self.pagebuffer.replace(pagebuffer);
+        self.modify_page_done(flash_result(error));
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        self.modify_page_done(flash_result(error));
+    }
+}
@@ -52,6 +52,9 @@ pub struct FlashLargeToSmall<'a, Flarge: hil::flash::Flash + 'static> {
     flash_large: &'a Flarge,
     client: OptionalCell<&'static dyn hil::flash::Client<FlashLargeToSmall<'static, Flarge>>>,
     pagebuffer: TakeCell<'static, Flarge::Page>,
+    /// Size of the underlying flash pages. Saved here because `pagebuffer`
+    /// is lent out while an operation is in progress.
+    large_page_size: usize,
 
     client_pagebuffer: TakeCell<'static, FiveTwelvePage>,
 
@@ -63,18 +66,19 @@ impl<'a, Flarge: hil::flash::Flash> FlashLargeToSmall<'a, Flarge> {
         flash_large: &'a Flarge,
         buffer: &'static mut Flarge::Page,
     ) -> FlashLargeToSmall<'a, Flarge> {
+        let large_page_size = buffer.as_mut().len();
         FlashLargeToSmall {
             flash_large: flash_large,
             client: OptionalCell::empty(),
             pagebuffer: TakeCell::new(buffer),
+            large_page_size,
             client_pagebuffer: TakeCell::empty(),
             state: Cell::new(State::Idle),
         }
     }
 
     fn get_large_page_index_offset(&self, small_page_index: usize) -> (usize, usize) {
-        let large_size = self.pagebuffer.map_or(4096, |page| page.as_mut().len());
-        let multiplier = large_size / 512;
+        let multiplier = self.large_page_size / 512;
         let large_index_start = small_page_index / multiplier;
         let large_index_offset = small_page_index % multiplier;
         (large_index_start, large_index_offset)
@@ -203,7 +207,7 @@ impl<Flarge: hil::flash::Flash> hil::flash::Client<Flarge> for FlashLargeToSmall
                 let large_page_byte_offset = 512 * large_page_offset;
 
                 for i in 0..512 {
-                    pagebuffer.as_mut()[large_page_byte_offset + i] = 1;
+                    pagebuffer.as_mut()[large_page_byte_offset + i] = 0xFF;
                 }
 
                 let _ = self.flash_large.write_page(large_page_index, pagebuffer);
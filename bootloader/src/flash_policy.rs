@@ -0,0 +1,125 @@
+//! Which parts of internal flash the host may read, write and erase.
+//!
+//! Every command that reads, writes or erases internal flash at an address
+//! the host picked is checked against the board's `FlashPolicy`, and refused
+//! with `RES_BADADDR` if it isn't allowed:
+//!
+//! - Reads have to be inside flash.
+//! - Writes and erases have to cover whole pages inside flash, and must not
+//!   touch a protected region. The bootloader itself, its flags and its
+//!   attributes are always protected, and boards can reserve more. Changing
+//!   the running bootloader would largely not work, and would be
+//!   irreversible for the user.
+//!
+//! The flags and attributes can still be changed with the commands meant for
+//! them, like `SET_ATTRIBUTE` and `SET_START_ADDRESS`.
+
+use crate::bootloader::FlashLayout;
+
+/// Length of the flags region.
+pub const FLAGS_LEN: u32 = 512;
+
+/// Length of the attributes region, 16 attributes of 64 bytes.
+pub const ATTRIBUTES_LEN: u32 = 1024;
+
+/// A range of internal flash, from `start` up to but not including `end`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FlashRegion {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl FlashRegion {
+    /// The `length` bytes starting at `start`.
+    pub const fn new(start: u32, length: u32) -> FlashRegion {
+        FlashRegion {
+            start,
+            end: start.saturating_add(length),
+        }
+    }
+
+    /// Whether `length` bytes from `address` are all in this region.
+    pub fn contains(&self, address: u32, length: u32) -> bool {
+        match address.checked_add(length) {
+            Some(end) => address >= self.start && end <= self.end,
+            None => false,
+        }
+    }
+
+    /// Whether any of `length` bytes from `address` are in this region.
+    pub fn overlaps(&self, address: u32, length: u32) -> bool {
+        length > 0 && address < self.end && self.start < address.saturating_add(length)
+    }
+}
+
+/// The parts of internal flash the host may access.
+#[derive(Clone, Copy)]
+pub struct FlashPolicy<'a> {
+    /// All of internal flash.
+    flash: FlashRegion,
+    page_size: u32,
+    /// The bootloader, flags and attributes.
+    protected: [FlashRegion; 3],
+    /// More regions the board keeps from the host, for example for
+    /// calibration data.
+    reserved: &'a [FlashRegion],
+}
+
+impl<'a> FlashPolicy<'a> {
+    /// A policy that protects the bootloader, flags and attributes in
+    /// `layout`. Until `with_flash()` says where flash ends, any address is
+    /// taken to be in flash.
+    pub fn new(layout: &FlashLayout, page_size: u32) -> FlashPolicy<'a> {
+        FlashPolicy {
+            flash: FlashRegion {
+                start: 0,
+                end: u32::MAX,
+            },
+            page_size,
+            protected: [
+                FlashRegion {
+                    start: layout.bootloader_address,
+                    end: layout.bootloader_end_address,
+                },
+                FlashRegion::new(layout.flags_address as u32, FLAGS_LEN),
+                FlashRegion::new(layout.attributes_address as u32, ATTRIBUTES_LEN),
+            ],
+            reserved: &[],
+        }
+    }
+
+    /// Only allow access inside `flash`.
+    pub fn with_flash(mut self, flash: FlashRegion) -> FlashPolicy<'a> {
+        self.flash = flash;
+        self
+    }
+
+    /// Protect `reserved` as well, replacing any regions reserved before.
+    pub fn with_reserved(mut self, reserved: &'a [FlashRegion]) -> FlashPolicy<'a> {
+        self.reserved = reserved;
+        self
+    }
+
+    /// Whether the host may read `length` bytes from `address`.
+    pub fn allows_read(&self, address: u32, length: u32) -> bool {
+        self.flash.contains(address, length)
+    }
+
+    /// Whether the host may write `length` bytes from `address`.
+    pub fn allows_write(&self, address: u32, length: u32) -> bool {
+        address % self.page_size == 0
+            && length % self.page_size == 0
+            && self.flash.contains(address, length)
+            && !self
+                .protected
+                .iter()
+                .chain(self.reserved.iter())
+                .any(|region| region.overlaps(address, length))
+    }
+
+    /// Whether the host may erase `length` bytes from `address`. This has
+    /// the same limits as writing.
+    pub fn allows_erase(&self, address: u32, length: u32) -> bool {
+        self.allows_write(address, length)
+    }
+}
@@ -1,5 +1,7 @@
 //! Trait definitions for the bootloader.
 
+use kernel::ErrorCode;
+
 /// Trait for implementing the decision logic on whether to run the bootloader
 /// or jump to application code.
 pub trait BootloaderEntry {
@@ -18,9 +20,139 @@ pub trait Jumper {
     fn jump(&self, address: u32) -> !;
 }
 
+/// Trait for checking the kernel before the bootloader jumps to it, so a
+/// missing or half written kernel leaves the board in the bootloader instead
+/// of faulting.
+pub trait KernelValidator {
+    /// Check the kernel that starts at `address`.
+    ///
+    /// Returns `true` if the kernel looks bootable, or `false` to stay in the
+    /// bootloader.
+    fn kernel_valid(&self, address: u32) -> bool;
+}
+
+/// Trait for checking the signature of a kernel image before the bootloader
+/// jumps to it, for secure boot.
+pub trait KernelVerifier {
+    /// Check `image`, the kernel followed by its signature footer (see
+    /// `tock_bootloader_protocol::signature`).
+    ///
+    /// Returns `true` if the image is signed with a trusted key.
+    fn verify(&self, image: &[u8]) -> bool;
+}
+
+/// Trait for changing a word of the flags region while deciding what to boot,
+/// before any flash driver is running.
+pub trait FlagsWriter {
+    /// Write `word` to the word at `address` in internal flash, and wait for
+    /// the write to finish. The write may only clear bits, so the page does
+    /// not have to be erased.
+    fn write_word(&self, address: u32, word: u32);
+}
+
+/// Trait for a count of boots that did not come up, kept somewhere that
+/// survives a reset (e.g. a retained register or RAM that is not cleared on
+/// boot).
+///
+/// The bootloader increments the count before each jump to the kernel, and
+/// the kernel sets it back to zero once it is up and running.
+pub trait BootAttempts {
+    /// Number of jumps to the kernel since it last cleared the count. Reads
+    /// zero if the count was lost, such as after a power cycle.
+    fn attempts(&self) -> u8;
+
+    /// Store a new count.
+    fn set_attempts(&self, attempts: u8);
+}
+
+/// Trait for getting random bytes, used for the nonces of authentication
+/// challenges. The bytes must not be predictable, so a hardware random number
+/// generator is usually needed.
+pub trait RandomSource {
+    /// Fill `buf` with random bytes.
+    fn fill(&self, buf: &mut [u8]);
+}
+
 /// Trait for notifying the user the bootloader is active.
 pub trait ActiveNotifier {
     /// Called when the bootloader decides it will stay active (i.e. not jump to
     /// the kernel).
     fn active(&mut self);
 }
+
+/// Longest ID a `DeviceId` can provide.
+pub const MAX_DEVICE_ID_LEN: usize = tock_bootloader_protocol::MAX_ID_LEN;
+
+/// Trait for getting an ID that is unique to this board, so host tools can
+/// tell boards apart.
+pub trait DeviceId {
+    /// Copy the ID into `id` and return its length in bytes.
+    fn device_id(&self, id: &mut [u8; MAX_DEVICE_ID_LEN]) -> usize;
+}
+
+/// Size of a page of external flash as seen by the bootloader protocol.
+pub const EXTERNAL_PAGE_SIZE: usize = 256;
+
+/// Size of a block of external flash as seen by the bootloader protocol.
+pub const EXTERNAL_BLOCK_SIZE: usize = 2048;
+
+/// Trait for an external flash chip (e.g. a SPI or QSPI NOR flash) that the
+/// bootloader can read and write on behalf of the host.
+///
+/// Addresses are offsets from the start of the external flash. Operations
+/// other than `init()` are split-phase and complete with a call to the
+/// `ExternalFlashClient`. Only one operation may be outstanding at a time.
+///
+/// Starting an operation on an address that is out of range or not aligned
+/// must fail with `ErrorCode::INVAL`.
+pub trait ExternalFlash<'a> {
+    fn set_client(&self, client: &'a dyn ExternalFlashClient);
+
+    /// Get the chip ready for use.
+    fn init(&self) -> Result<(), ErrorCode>;
+
+    /// Read `length` bytes starting at `address`. The range must not cross an
+    /// `EXTERNAL_PAGE_SIZE` boundary.
+    fn read(&self, address: u32, length: usize) -> Result<(), ErrorCode>;
+
+    /// Write `data`, which must be `EXTERNAL_PAGE_SIZE` bytes long, to the
+    /// page starting at `address`. `data` is copied before this returns.
+    fn write_page(&self, address: u32, data: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Erase the `EXTERNAL_PAGE_SIZE` page starting at `address`.
+    fn erase_page(&self, address: u32) -> Result<(), ErrorCode>;
+
+    /// Erase the `EXTERNAL_BLOCK_SIZE` block starting at `address`.
+    fn erase_block(&self, address: u32) -> Result<(), ErrorCode>;
+}
+
+/// Client for operations on an `ExternalFlash`.
+pub trait ExternalFlashClient {
+    /// A read finished. `data` is only valid for the duration of the call.
+    fn read_done(&self, data: &[u8], result: Result<(), ErrorCode>);
+
+    /// A page write finished.
+    fn write_done(&self, result: Result<(), ErrorCode>);
+
+    /// A page or block erase finished.
+    fn erase_done(&self, result: Result<(), ErrorCode>);
+}
+
+/// Trait for a one-shot timeout, used when the bootloader has to give up on
+/// the host if it doesn't follow up in time.
+pub trait Timeout<'a> {
+    fn set_client(&self, client: &'a dyn TimeoutClient);
+
+    /// Call the client after `ms` milliseconds. Replaces a timeout that is
+    /// already running.
+    fn start(&self, ms: u32);
+
+    /// Stop a running timeout without calling the client.
+    fn cancel(&self);
+}
+
+/// Client for a `Timeout`.
+pub trait TimeoutClient {
+    /// The timeout expired without being cancelled.
+    fn timeout_expired(&self);
+}
@@ -0,0 +1,215 @@
+//! A/B kernel slots.
+//!
+//! Boards that set aside two areas of flash for the kernel can update it
+//! without risking a board that no longer boots. The host uploads the new
+//! kernel into the slot that is not active and marks it pending. On the next
+//! boot `BootloaderEnterer` starts it on trial. The kernel then confirms that
+//! it came up by changing the state of its slot from `Trial` to `Confirmed`.
+//! If it never does, the next boot marks the slot invalid and goes back to the
+//! previous kernel.
+//!
+//! With a boot attempt counter (see `BootloaderEnterer::set_boot_attempts`)
+//! a kernel on trial gets that many boots to confirm instead of one, and a
+//! confirmed kernel that stops coming up is rolled back the same way.
+//!
+//! Each slot has a descriptor in the flags region:
+//!
+//! ```text
+//! 0         4        8     12        16         20      24          32
+//! | Address | Length | CRC | Version | Sequence | State | Reserved... |
+//! ```
+//!
+//! all little endian. `Sequence` orders the uploads, so the newest of two
+//! confirmed kernels is preferred. The state words are chosen so every change
+//! `BootloaderEnterer` and the kernel make only clears bits, which lets them
+//! write the word in place without erasing the flags page.
+
+use tock_bootloader_protocol::SlotState;
+pub use tock_bootloader_protocol::NUM_KERNEL_SLOTS;
+
+/// Offset of the first slot descriptor in the flags region.
+pub const KERNEL_SLOTS_FLAGS_OFFSET: usize = 64;
+
+/// Length of a slot descriptor.
+pub const SLOT_DESCRIPTOR_LEN: usize = 32;
+
+/// Offset of the state word in a slot descriptor.
+pub const SLOT_STATE_OFFSET: usize = 20;
+
+// State words. Erased flash or zeros (as in the flags we were built with)
+// mean the slot was never used.
+const STATE_EMPTY: u32 = 0xFFFFFFFF;
+const STATE_PENDING: u32 = 0x0FFFFFFF;
+const STATE_TRIAL: u32 = 0x00FFFFFF;
+/// The word the kernel writes over `STATE_TRIAL` to confirm it runs.
+pub const STATE_CONFIRMED: u32 = 0x000FFFFF;
+const STATE_INVALID: u32 = 0x0000FFFF;
+
+/// Where the kernel slots are in internal flash.
+#[derive(Clone, Copy)]
+pub struct SlotLayout {
+    pub address: [u32; NUM_KERNEL_SLOTS],
+    /// Size of each slot in bytes.
+    pub size: u32,
+}
+
+/// A slot descriptor as stored in the flags region.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SlotDescriptor {
+    pub address: u32,
+    pub length: u32,
+    pub crc: u32,
+    pub version: u32,
+    pub sequence: u32,
+    pub state: SlotState,
+}
+
+impl SlotDescriptor {
+    /// Read the descriptor of `slot` from the flags region in `flags`.
+    pub fn from_flags(flags: &[u8], slot: usize) -> SlotDescriptor {
+        let start = descriptor_offset(slot);
+        let word = |offset: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&flags[start + offset..start + offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        SlotDescriptor {
+            address: word(0),
+            length: word(4),
+            crc: word(8),
+            version: word(12),
+            sequence: word(16),
+            state: state_from_word(word(SLOT_STATE_OFFSET)),
+        }
+    }
+
+    /// Store the descriptor of `slot` in the flags region in `flags`.
+    pub fn to_flags(&self, flags: &mut [u8], slot: usize) {
+        let start = descriptor_offset(slot);
+        let words = [
+            self.address,
+            self.length,
+            self.crc,
+            self.version,
+            self.sequence,
+            state_to_word(self.state),
+        ];
+        for (i, word) in words.iter().enumerate() {
+            flags[start + i * 4..start + i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+    }
+}
+
+/// Offset of the descriptor for `slot` in the flags region.
+pub fn descriptor_offset(slot: usize) -> usize {
+    KERNEL_SLOTS_FLAGS_OFFSET + slot * SLOT_DESCRIPTOR_LEN
+}
+
+/// Convert a state word from flash. Anything we don't know is treated as
+/// invalid, so it is never booted.
+pub fn state_from_word(word: u32) -> SlotState {
+    match word {
+        STATE_EMPTY | 0 => SlotState::Empty,
+        STATE_PENDING => SlotState::Pending,
+        STATE_TRIAL => SlotState::Trial,
+        STATE_CONFIRMED => SlotState::Confirmed,
+        _ => SlotState::Invalid,
+    }
+}
+
+/// Convert a state to the word stored in flash.
+pub fn state_to_word(state: SlotState) -> u32 {
+    match state {
+        SlotState::Empty => STATE_EMPTY,
+        SlotState::Pending => STATE_PENDING,
+        SlotState::Trial => STATE_TRIAL,
+        SlotState::Confirmed => STATE_CONFIRMED,
+        SlotState::Invalid => STATE_INVALID,
+    }
+}
+
+/// The slot in `state` with the newest upload, if any.
+fn newest(slots: &[SlotDescriptor; NUM_KERNEL_SLOTS], state: SlotState) -> Option<usize> {
+    let mut newest: Option<usize> = None;
+    for (i, slot) in slots.iter().enumerate() {
+        if slot.state != state {
+            continue;
+        }
+        newest = match newest {
+            Some(n) if (slot.sequence.wrapping_sub(slots[n].sequence) as i32) <= 0 => Some(n),
+            _ => Some(i),
+        };
+    }
+    newest
+}
+
+/// The slot with the newest confirmed kernel. This is what we fall back to if
+/// an update fails, so the host must not overwrite it.
+pub fn active_slot(slots: &[SlotDescriptor; NUM_KERNEL_SLOTS]) -> Option<usize> {
+    newest(slots, SlotState::Confirmed)
+}
+
+/// Sequence number for the next upload.
+pub fn next_sequence(slots: &[SlotDescriptor; NUM_KERNEL_SLOTS]) -> u32 {
+    let mut sequence = 0;
+    for slot in slots.iter() {
+        if slot.state != SlotState::Empty && (slot.sequence.wrapping_sub(sequence) as i32) > 0 {
+            sequence = slot.sequence;
+        }
+    }
+    sequence.wrapping_add(1)
+}
+
+/// The slot with a kernel on trial, if any. With a boot attempt counter the
+/// kernel gets more than one try to confirm.
+pub fn trial_slot(slots: &[SlotDescriptor; NUM_KERNEL_SLOTS]) -> Option<usize> {
+    newest(slots, SlotState::Trial)
+}
+
+/// The kernel that was booted last failed to come up too many times. Mark its
+/// slot invalid, so `choose_boot_slot` goes back to the other one. Returns
+/// the slot.
+pub fn reject_booted_slot(slots: &mut [SlotDescriptor; NUM_KERNEL_SLOTS]) -> Option<usize> {
+    let booted = trial_slot(slots).or_else(|| active_slot(slots));
+    if let Some(i) = booted {
+        slots[i].state = SlotState::Invalid;
+    }
+    booted
+}
+
+/// Decide which slot to boot, updating the states in `slots` along the way.
+/// `bootable` checks the kernel in a slot.
+///
+/// The caller has to store the changed states before booting the returned
+/// slot. All of the changes only clear bits of the state words.
+pub fn choose_boot_slot(
+    slots: &mut [SlotDescriptor; NUM_KERNEL_SLOTS],
+    bootable: &dyn Fn(&SlotDescriptor) -> bool,
+) -> Option<usize> {
+    // A kernel that is still on trial was started last time and never
+    // confirmed that it came up.
+    for slot in slots.iter_mut() {
+        if slot.state == SlotState::Trial {
+            slot.state = SlotState::Invalid;
+        }
+    }
+
+    // A newly uploaded kernel gets one try.
+    while let Some(i) = newest(slots, SlotState::Pending) {
+        if bootable(&slots[i]) {
+            slots[i].state = SlotState::Trial;
+            return Some(i);
+        }
+        slots[i].state = SlotState::Invalid;
+    }
+
+    // Otherwise go with the newest kernel that is known to work.
+    while let Some(i) = newest(slots, SlotState::Confirmed) {
+        if bootable(&slots[i]) {
+            return Some(i);
+        }
+        slots[i].state = SlotState::Invalid;
+    }
+
+    None
+}
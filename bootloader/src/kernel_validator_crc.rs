@@ -0,0 +1,53 @@
+//! Check the kernel against the CRC-32 stored in the flags region.
+//!
+//! The host stores the length and CRC of the kernel with the `SKERNELCRC`
+//! command after flashing it. If no CRC has been stored, every kernel passes.
+
+use kernel::utilities::cells::VolatileCell;
+use kernel::utilities::StaticRef;
+
+use crate::bootloader::FlashLayout;
+use crate::bootloader_crc;
+use crate::interfaces;
+
+/// Offset of the kernel length in the flags region. The CRC follows it.
+pub const KERNEL_CRC_FLAGS_OFFSET: usize = 36;
+
+pub struct KernelValidatorCrc {
+    flags_address: usize,
+}
+
+impl KernelValidatorCrc {
+    pub fn new() -> KernelValidatorCrc {
+        KernelValidatorCrc {
+            flags_address: FlashLayout::from_linker().flags_address,
+        }
+    }
+
+    fn read_flag(&self, offset: usize) -> u32 {
+        let flag: StaticRef<VolatileCell<u32>> =
+            unsafe { StaticRef::new((self.flags_address + offset) as *const VolatileCell<u32>) };
+        flag.get()
+    }
+}
+
+impl interfaces::KernelValidator for KernelValidatorCrc {
+    fn kernel_valid(&self, address: u32) -> bool {
+        let length = self.read_flag(KERNEL_CRC_FLAGS_OFFSET);
+        let expected_crc = self.read_flag(KERNEL_CRC_FLAGS_OFFSET + 4);
+
+        // Zero (as in the flags we were built with) or erased flash means no
+        // CRC has been stored. The CRC is only for the kernel at the start
+        // address, kernel slots have their own.
+        if length == 0 || length == 0xFFFFFFFF || address != self.read_flag(32) {
+            return true;
+        }
+        if address.checked_add(length).is_none() {
+            return false;
+        }
+
+        let kernel = unsafe { core::slice::from_raw_parts(address as *const u8, length as usize) };
+        let crc = bootloader_crc::update(0xFFFFFFFF, kernel) ^ 0xFFFFFFFF;
+        crc == expected_crc
+    }
+}
@@ -0,0 +1,36 @@
+//! Check the Ed25519 signature of the kernel image.
+//!
+//! The public key is built into the bootloader, for example with
+//! `bootloader_attributes::write_public_key()`, so it is as hard to change as
+//! the bootloader itself. Images are signed with the `bootloader_sign` tool.
+
+use ed25519_compact::{PublicKey, Signature};
+use tock_bootloader_protocol::signature::{SignedImage, PUBLIC_KEY_LEN};
+
+use crate::interfaces;
+
+pub struct KernelVerifierEd25519 {
+    public_key: &'static [u8; PUBLIC_KEY_LEN],
+}
+
+impl KernelVerifierEd25519 {
+    pub fn new(public_key: &'static [u8; PUBLIC_KEY_LEN]) -> KernelVerifierEd25519 {
+        KernelVerifierEd25519 { public_key }
+    }
+}
+
+impl interfaces::KernelVerifier for KernelVerifierEd25519 {
+    fn verify(&self, image: &[u8]) -> bool {
+        let signed = match SignedImage::parse(image) {
+            Ok(signed) => signed,
+            Err(_) => return false,
+        };
+        let signature = match Signature::from_slice(signed.signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        PublicKey::new(*self.public_key)
+            .verify(signed.message, &signature)
+            .is_ok()
+    }
+}
@@ -3,12 +3,19 @@
 
 pub mod active_notifier_ledon;
 pub mod active_notifier_null;
+pub mod alarm_timeout;
+pub mod boot_attempts_noinit;
 pub mod bootloader;
 pub mod bootloader_crc;
 pub mod bootloader_entry_always;
 pub mod bootloader_entry_gpio;
+pub mod external_flash_adapter;
 pub mod flash_large_to_small;
+pub mod flash_policy;
 pub mod interfaces;
+pub mod kernel_slots;
+pub mod kernel_validator_crc;
+pub mod kernel_verifier_ed25519;
 pub mod null_scheduler;
 pub mod uart_receive_multiple_timeout;
 pub mod uart_receive_timeout;
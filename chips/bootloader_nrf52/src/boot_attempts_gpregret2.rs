@@ -0,0 +1,35 @@
+//! Keep the boot attempt count in the nRF52 GPREGRET2 register.
+//!
+//! Like GPREGRET, GPREGRET2 keeps its value over a soft reset or a watchdog
+//! reset, but not a power cycle, after which it reads zero. GPREGRET itself
+//! is left to `BootloaderEntryGpRegRet`. The kernel clears the count by
+//! writing zero to GPREGRET2.
+
+use kernel::utilities::cells::VolatileCell;
+use kernel::utilities::StaticRef;
+
+/// POWER GPREGRET2 register. Only the lowest byte is retained.
+const GPREGRET2: StaticRef<VolatileCell<u32>> =
+    unsafe { StaticRef::new(0x40000520 as *const VolatileCell<u32>) };
+
+pub struct BootAttemptsGpRegRet2 {
+    gpregret2: StaticRef<VolatileCell<u32>>,
+}
+
+impl BootAttemptsGpRegRet2 {
+    pub fn new() -> BootAttemptsGpRegRet2 {
+        BootAttemptsGpRegRet2 {
+            gpregret2: GPREGRET2,
+        }
+    }
+}
+
+impl bootloader::interfaces::BootAttempts for BootAttemptsGpRegRet2 {
+    fn attempts(&self) -> u8 {
+        self.gpregret2.get() as u8
+    }
+
+    fn set_attempts(&self, attempts: u8) {
+        self.gpregret2.set(attempts as u32);
+    }
+}
@@ -0,0 +1,44 @@
+//! Identify the board by the IDs programmed into the nRF52 FICR.
+//!
+//! Nordic programs a random 64 bit DEVICEID and a 48 bit DEVICEADDR (the BLE
+//! address) into the factory information configuration registers of every
+//! chip. The ID is the DEVICEID followed by the DEVICEADDR, both little endian.
+
+use kernel::utilities::cells::VolatileCell;
+use kernel::utilities::StaticRef;
+
+/// FICR DEVICEID[0] and DEVICEID[1].
+const FICR_DEVICEID: StaticRef<[VolatileCell<u32>; 2]> =
+    unsafe { StaticRef::new(0x10000060 as *const [VolatileCell<u32>; 2]) };
+
+/// FICR DEVICEADDR[0] and DEVICEADDR[1]. Only the lower 16 bits of
+/// DEVICEADDR[1] are part of the address.
+const FICR_DEVICEADDR: StaticRef<[VolatileCell<u32>; 2]> =
+    unsafe { StaticRef::new(0x100000A4 as *const [VolatileCell<u32>; 2]) };
+
+/// Length of the ID: eight bytes of DEVICEID and six of DEVICEADDR.
+const ID_LEN: usize = 14;
+
+pub struct DeviceIdFicr {
+    deviceid: StaticRef<[VolatileCell<u32>; 2]>,
+    deviceaddr: StaticRef<[VolatileCell<u32>; 2]>,
+}
+
+impl DeviceIdFicr {
+    pub fn new() -> DeviceIdFicr {
+        DeviceIdFicr {
+            deviceid: FICR_DEVICEID,
+            deviceaddr: FICR_DEVICEADDR,
+        }
+    }
+}
+
+impl bootloader::interfaces::DeviceId for DeviceIdFicr {
+    fn device_id(&self, id: &mut [u8; bootloader::interfaces::MAX_DEVICE_ID_LEN]) -> usize {
+        id[0..4].copy_from_slice(&self.deviceid[0].get().to_le_bytes());
+        id[4..8].copy_from_slice(&self.deviceid[1].get().to_le_bytes());
+        id[8..12].copy_from_slice(&self.deviceaddr[0].get().to_le_bytes());
+        id[12..14].copy_from_slice(&self.deviceaddr[1].get().to_le_bytes()[0..2]);
+        ID_LEN
+    }
+}
@@ -0,0 +1,49 @@
+//! Write words of the flags region with the nRF52 NVMC.
+//!
+//! `BootloaderEnterer` runs before the flash driver is set up, so this talks
+//! to the NVMC registers directly and busy waits for each write.
+
+use kernel::utilities::cells::VolatileCell;
+use kernel::utilities::StaticRef;
+
+/// NVMC READY register. Reads 1 when the NVMC is ready for the next write.
+const NVMC_READY: StaticRef<VolatileCell<u32>> =
+    unsafe { StaticRef::new(0x4001E400 as *const VolatileCell<u32>) };
+
+/// NVMC CONFIG register.
+const NVMC_CONFIG: StaticRef<VolatileCell<u32>> =
+    unsafe { StaticRef::new(0x4001E504 as *const VolatileCell<u32>) };
+
+const CONFIG_READ_ONLY: u32 = 0;
+const CONFIG_WRITE_ENABLED: u32 = 1;
+
+pub struct FlagsWriterNvmc {
+    ready: StaticRef<VolatileCell<u32>>,
+    config: StaticRef<VolatileCell<u32>>,
+}
+
+impl FlagsWriterNvmc {
+    pub fn new() -> FlagsWriterNvmc {
+        FlagsWriterNvmc {
+            ready: NVMC_READY,
+            config: NVMC_CONFIG,
+        }
+    }
+
+    fn wait_ready(&self) {
+        while self.ready.get() & 1 == 0 {}
+    }
+}
+
+impl bootloader::interfaces::FlagsWriter for FlagsWriterNvmc {
+    fn write_word(&self, address: u32, word: u32) {
+        let flash_word: StaticRef<VolatileCell<u32>> =
+            unsafe { StaticRef::new(address as *const VolatileCell<u32>) };
+
+        self.config.set(CONFIG_WRITE_ENABLED);
+        self.wait_ready();
+        flash_word.set(word);
+        self.wait_ready();
+        self.config.set(CONFIG_READ_ONLY);
+    }
+}
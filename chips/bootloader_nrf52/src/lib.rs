@@ -1,5 +1,9 @@
 // #![forbid(unsafe_code)]
 #![no_std]
 
+pub mod boot_attempts_gpregret2;
 pub mod bootloader_entry_doublereset;
 pub mod bootloader_entry_gpregret;
+pub mod device_id_ficr;
+pub mod flags_writer_nvmc;
+pub mod random_source_rng;
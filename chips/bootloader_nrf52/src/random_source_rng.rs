@@ -0,0 +1,62 @@
+//! Random bytes from the nRF52 RNG peripheral.
+//!
+//! The RNG is started for each request and polled for one byte at a time.
+//! Bias correction is turned on, which makes it slower but gives evenly
+//! distributed bytes, as needed for authentication nonces.
+
+use kernel::utilities::cells::VolatileCell;
+use kernel::utilities::StaticRef;
+
+/// RNG TASKS_START.
+const RNG_TASKS_START: StaticRef<VolatileCell<u32>> =
+    unsafe { StaticRef::new(0x4000D000 as *const VolatileCell<u32>) };
+
+/// RNG TASKS_STOP.
+const RNG_TASKS_STOP: StaticRef<VolatileCell<u32>> =
+    unsafe { StaticRef::new(0x4000D004 as *const VolatileCell<u32>) };
+
+/// RNG EVENTS_VALRDY.
+const RNG_EVENTS_VALRDY: StaticRef<VolatileCell<u32>> =
+    unsafe { StaticRef::new(0x4000D100 as *const VolatileCell<u32>) };
+
+/// RNG CONFIG. Bit 0 enables bias correction.
+const RNG_CONFIG: StaticRef<VolatileCell<u32>> =
+    unsafe { StaticRef::new(0x4000D504 as *const VolatileCell<u32>) };
+
+/// RNG VALUE. Only the lowest byte is used.
+const RNG_VALUE: StaticRef<VolatileCell<u32>> =
+    unsafe { StaticRef::new(0x4000D508 as *const VolatileCell<u32>) };
+
+pub struct RandomSourceRng {
+    tasks_start: StaticRef<VolatileCell<u32>>,
+    tasks_stop: StaticRef<VolatileCell<u32>>,
+    events_valrdy: StaticRef<VolatileCell<u32>>,
+    config: StaticRef<VolatileCell<u32>>,
+    value: StaticRef<VolatileCell<u32>>,
+}
+
+impl RandomSourceRng {
+    pub fn new() -> RandomSourceRng {
+        RandomSourceRng {
+            tasks_start: RNG_TASKS_START,
+            tasks_stop: RNG_TASKS_STOP,
+            events_valrdy: RNG_EVENTS_VALRDY,
+            config: RNG_CONFIG,
+            value: RNG_VALUE,
+        }
+    }
+}
+
+impl bootloader::interfaces::RandomSource for RandomSourceRng {
+    fn fill(&self, buf: &mut [u8]) {
+        self.config.set(1);
+        self.events_valrdy.set(0);
+        self.tasks_start.set(1);
+        for byte in buf.iter_mut() {
+            while self.events_valrdy.get() == 0 {}
+            self.events_valrdy.set(0);
+            *byte = self.value.get() as u8;
+        }
+        self.tasks_stop.set(1);
+    }
+}
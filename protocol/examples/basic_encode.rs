@@ -1,10 +1,10 @@
-extern crate tockloader_proto;
+extern crate tock_bootloader_protocol;
 
-use tockloader_proto::prelude::*;
+use tock_bootloader_protocol::prelude::*;
 
 fn main() {
-    let r = tockloader_proto::Response::Pong;
-    let mut e = tockloader_proto::ResponseEncoder::new(&r).unwrap();
+    let r = tock_bootloader_protocol::Response::Pong;
+    let mut e = tock_bootloader_protocol::ResponseEncoder::new(&r).unwrap();
     let mut buffer = [0xFFu8; 4];
     let used = e.write(&mut buffer);
     println!("Buffer: {:?}", &buffer[0..used]);
@@ -0,0 +1,79 @@
+//! Challenge–response authentication of bootloader sessions.
+//!
+//! A bootloader with a device key refuses commands that change flash until
+//! the host shows that it knows the key:
+//!
+//! ```text
+//! host                                  bootloader
+//!  | -- AUTH_CHALLENGE ------------------> |
+//!  | <----------------- nonce (32 bytes) -- |
+//!  | -- AUTH_RESPONSE HMAC(key, nonce) --> |
+//!  | <------------- OK / UNAUTHENTICATED -- |
+//! ```
+//!
+//! The MAC is HMAC-SHA256 of the nonce, keyed with the 32 byte device key.
+//! Each nonce can only be answered once, and the session stays authenticated
+//! until the bootloader exits or the board resets.
+
+use hmac_sha256::HMAC;
+
+/// Length of the nonce in an `AuthChallenge` response.
+pub const AUTH_NONCE_LEN: usize = 32;
+
+/// Length of the MAC in an `AuthResponse` command.
+pub const AUTH_MAC_LEN: usize = 32;
+
+/// Length of the device key.
+pub const AUTH_KEY_LEN: usize = 32;
+
+/// The MAC the host sends in `AuthResponse` for `nonce`.
+pub fn auth_mac(key: &[u8], nonce: &[u8]) -> [u8; AUTH_MAC_LEN] {
+    HMAC::mac(nonce, key)
+}
+
+/// Check `mac` against the one expected for `nonce`. The comparison takes
+/// the same time however many bytes match.
+pub fn verify_mac(key: &[u8], nonce: &[u8], mac: &[u8]) -> bool {
+    constant_time_eq(&auth_mac(key, nonce), mac)
+}
+
+/// Compare two MACs without giving away how much of them matches.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut difference = 0;
+    for (x, y) in a.iter().zip(b.iter()) {
+        difference |= x ^ y;
+    }
+    difference == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac() {
+        // RFC 4231 test case 2.
+        let mac = auth_mac(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(&mac[..4], &[0x5b, 0xdc, 0xc1, 0x46]);
+        assert_eq!(&mac[28..], &[0x64, 0xec, 0x38, 0x43]);
+    }
+
+    #[test]
+    fn verify() {
+        let key = [0x42; AUTH_KEY_LEN];
+        let nonce = [0x17; AUTH_NONCE_LEN];
+        let mut mac = auth_mac(&key, &nonce);
+        assert!(verify_mac(&key, &nonce, &mac));
+        assert!(!verify_mac(&key, &nonce, &mac[1..]));
+        mac[31] ^= 1;
+        assert!(!verify_mac(&key, &nonce, &mac));
+        assert!(!verify_mac(
+            &[0x43; AUTH_KEY_LEN],
+            &nonce,
+            &auth_mac(&key, &nonce)
+        ));
+    }
+}
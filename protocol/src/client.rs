@@ -0,0 +1,1042 @@
+//! Blocking host-side client for the Tock bootloader.
+//!
+//! A `Session` wraps a `Port` (a serial port, a socket, a pipe to an emulator)
+//! and provides one method per bootloader command. Each call sends a `RESET`
+//! so the bootloader starts from an empty receive buffer, encodes the command,
+//! and then decodes the response. If no complete response arrives before the
+//! session timeout expires the call fails with `Error::Timeout`. A `Port` is a
+//! byte stream whose reads can be given a timeout, so that a response that
+//! never comes can't block the session forever. It is implemented for
+//! `TcpStream` and `UnixStream`. A serial port opened with a read timeout can
+//! be wrapped in `PresetTimeout`.
+//!
+//! ```no_run
+//! # extern crate tock_bootloader_protocol;
+//! # fn main() {
+//! use std::net::TcpStream;
+//! use tock_bootloader_protocol::client::Session;
+//!
+//! let stream = TcpStream::connect("127.0.0.1:4000").unwrap();
+//! let mut session = Session::new(stream);
+//! session.ping().unwrap();
+//! println!("{}", session.info().unwrap());
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use super::auth::auth_mac;
+use super::compression::compress;
+use super::encrypted_image::{ImageCipher, IMAGE_KEY_LEN, IMAGE_NONCE_LEN};
+use super::hash::{HASH_LEN, HASH_SHA256};
+use super::{
+    BaudMode, Capabilities, Command, CommandEncoder, KernelSlots, Response, ResponseDecoder,
+    INT_PAGE_SIZE, RES_OK, STREAM_WINDOW,
+};
+
+/// How long to wait for a response if `Session::set_timeout` is not called.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Length of an attribute key on the wire.
+const KEY_LEN: usize = 8;
+
+/// Errors that can occur while talking to the bootloader.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to the underlying port failed.
+    Io(io::Error),
+    /// The command could not be encoded or the response could not be decoded.
+    Protocol(super::Error),
+    /// No complete response was received before the timeout expired.
+    Timeout,
+    /// The bootloader sent a valid response, but not the one this command
+    /// expects. This is usually an error response such as `BadAddress`.
+    UnexpectedResponse(String),
+}
+
+/// A byte stream to the bootloader.
+pub trait Port: Read + Write {
+    /// Make `read` give up after `timeout`, with `TimedOut` or
+    /// `WouldBlock`, if nothing arrives. `timeout` is never zero.
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Port for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, Some(timeout))
+    }
+}
+
+#[cfg(unix)]
+impl Port for UnixStream {
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, Some(timeout))
+    }
+}
+
+/// A port whose reads already time out by themselves, such as a serial port
+/// opened with a timeout. That timeout is left as it is, so it should be
+/// short compared to the session's.
+pub struct PresetTimeout<T: Read + Write>(pub T);
+
+impl<T: Read + Write> Read for PresetTimeout<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<T: Read + Write> Write for PresetTimeout<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<T: Read + Write> Port for PresetTimeout<T> {
+    fn set_read_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An attribute as read back from the bootloader.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    /// The eight byte key, zero padded.
+    pub key: Vec<u8>,
+    /// The attribute value.
+    pub value: Vec<u8>,
+}
+
+/// A connection to a bootloader over a byte stream.
+pub struct Session<T: Port> {
+    port: T,
+    timeout: Duration,
+}
+
+impl<T: Port> Session<T> {
+    /// Create a new `Session` that talks to the bootloader over `port`.
+    pub fn new(port: T) -> Session<T> {
+        Session {
+            port,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Set how long to wait for each response.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Get how long the session waits for each response.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Get a mutable reference to the underlying port.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.port
+    }
+
+    /// Consume the session and return the underlying port.
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    /// Check that the bootloader is there and responding.
+    pub fn ping(&mut self) -> Result<(), Error> {
+        self.transact(&Command::Ping, None, |response| match response {
+            Response::Pong => Ok(()),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Retrieve the information string from the bootloader.
+    pub fn info(&mut self) -> Result<String, Error> {
+        self.transact(&Command::Info, None, |response| match response {
+            Response::Info { info } => Ok(String::from_utf8_lossy(info).into_owned()),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Retrieve the ID that uniquely identifies the board.
+    pub fn id(&mut self) -> Result<Vec<u8>, Error> {
+        self.transact(&Command::Id, None, |response| match response {
+            Response::Id { id } => Ok(id.to_vec()),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Find out which commands the bootloader implements, and its flash
+    /// geometry.
+    pub fn capabilities(&mut self) -> Result<Capabilities, Error> {
+        self.transact(&Command::GetCapabilities, None, |response| match response {
+            Response::Capabilities { capabilities } => Ok(capabilities),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Get the kernel slots and which of them is active.
+    pub fn slots(&mut self) -> Result<KernelSlots, Error> {
+        self.transact(&Command::GetSlots, None, |response| match response {
+            Response::Slots { slots } => Ok(slots),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Get how many pages the bootloader didn't write because they already
+    /// held the data sent.
+    pub fn skipped_pages(&mut self) -> Result<u32, Error> {
+        self.transact(&Command::GetSkippedPages, None, |response| match response {
+            Response::SkippedPages { count } => Ok(count),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Mark `slot`, which the kernel has been written to, as pending so the
+    /// bootloader tries it on the next boot.
+    pub fn set_slot_pending(
+        &mut self,
+        slot: u8,
+        length: u32,
+        crc: u32,
+        version: u32,
+    ) -> Result<(), Error> {
+        self.expect_ok(&Command::SetSlotPending {
+            slot,
+            length,
+            crc,
+            version,
+        })
+    }
+
+    /// Prove to the bootloader that we know the device `key`, so it accepts
+    /// commands that change flash for the rest of the session.
+    pub fn authenticate(&mut self, key: &[u8]) -> Result<(), Error> {
+        let nonce = self.transact(&Command::AuthChallenge, None, |response| match response {
+            Response::AuthChallenge { nonce } => Ok(nonce.to_vec()),
+            r => Err(unexpected(&r)),
+        })?;
+        let mac = auth_mac(key, &nonce);
+        self.expect_ok(&Command::AuthResponse { mac: &mac })
+    }
+
+    /// Read `length` bytes of internal flash starting at `address`.
+    pub fn read_range(&mut self, address: u32, length: u16) -> Result<Vec<u8>, Error> {
+        // The `ReadRange` response has no length field, so the decoder needs
+        // to be told how many bytes to wait for.
+        let command = Command::ReadRange { address, length };
+        self.transact(&command, Some(length as usize), |response| match response {
+            Response::ReadRange { data } => Ok(data.to_vec()),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Write one 512 byte page of internal flash.
+    pub fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        self.expect_ok(&Command::WritePage { address, data })
+    }
+
+    /// Write one 512 byte page of internal flash, compressed on the way. This
+    /// is much quicker than `write_page()` for pages that are mostly 0xFF or
+    /// zeros.
+    pub fn write_page_compressed(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        let compressed = compress(data);
+        self.expect_ok(&Command::WritePageCompressed {
+            address,
+            data: &compressed,
+        })
+    }
+
+    /// Write up to `MAX_WRITE_PAGES` whole 512 byte pages of internal flash
+    /// starting at `address` with one command. Returns the CRC32 of the
+    /// written range as read back from flash, to check against the host's
+    /// copy of `data`. If a page can't be written the error names its
+    /// address.
+    pub fn write_pages(&mut self, address: u32, data: &[u8]) -> Result<u32, Error> {
+        let command = Command::WritePages {
+            address,
+            count: (data.len() / INT_PAGE_SIZE) as u8,
+            data,
+        };
+        self.transact(&command, None, |response| match response {
+            Response::PagesWritten { crc } => Ok(crc),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Encrypt `image` with the board's image `key` and write it to internal
+    /// flash starting at `address`. The last page is padded with 0xFF.
+    ///
+    /// `nonce` must be different for every image sent with the same key.
+    pub fn write_encrypted_image(
+        &mut self,
+        key: &[u8; IMAGE_KEY_LEN],
+        nonce: &[u8; IMAGE_NONCE_LEN],
+        address: u32,
+        image: &[u8],
+    ) -> Result<(), Error> {
+        let mut cipher = ImageCipher::new(key, nonce, address);
+        self.expect_ok(&Command::BeginEncryptedImage { address, nonce })?;
+        for chunk in image.chunks(INT_PAGE_SIZE) {
+            let mut page = [0xFF; INT_PAGE_SIZE];
+            page[..chunk.len()].copy_from_slice(chunk);
+            let page_address = cipher.next_address();
+            cipher.encrypt(&mut page);
+            self.expect_ok(&Command::WriteEncryptedPage {
+                address: page_address,
+                data: &page,
+            })?;
+        }
+        self.expect_ok(&Command::EndEncryptedImage { tag: &cipher.tag() })
+    }
+
+    /// Write `image` to internal flash starting at `address` with
+    /// `WriteStreamPage`, padding the last page with 0xFF. Up to
+    /// `STREAM_WINDOW` pages are sent before their acks arrive, so the
+    /// bootloader receives the next page while it writes the last one.
+    pub fn stream_image(&mut self, address: u32, image: &[u8]) -> Result<(), Error> {
+        let pages: Vec<&[u8]> = image.chunks(INT_PAGE_SIZE).collect();
+        let page_address = |index: usize| address + (index * INT_PAGE_SIZE) as u32;
+        let mut decoder = Box::new(ResponseDecoder::new());
+        let mut pending = VecDeque::new();
+        let mut sent = 0;
+        let mut result = Ok(());
+        for acked in 0..pages.len() {
+            // Stop sending after a failure, but still collect the acks for
+            // pages already sent so they aren't taken for the response to a
+            // later command.
+            while result.is_ok() && sent < pages.len() && sent < acked + STREAM_WINDOW {
+                let mut page = [0xFF; INT_PAGE_SIZE];
+                page[..pages[sent].len()].copy_from_slice(pages[sent]);
+                self.send(&Command::WriteStreamPage {
+                    address: page_address(sent),
+                    data: &page,
+                })?;
+                sent += 1;
+            }
+            if acked == sent {
+                break;
+            }
+
+            let expected = page_address(acked);
+            let ack = self.next_response(&mut decoder, &mut pending, |response| match response {
+                Response::PageAck { address, result }
+                    if address == expected && result == RES_OK =>
+                {
+                    Ok(())
+                }
+                r => Err(unexpected(&r)),
+            });
+            match ack {
+                Err(Error::Io(e)) => return Err(Error::Io(e)),
+                Err(e) if result.is_ok() => result = Err(e),
+                _ => {}
+            }
+        }
+        result
+    }
+
+    /// Erase the page of internal flash starting at `address`.
+    pub fn erase_page(&mut self, address: u32) -> Result<(), Error> {
+        self.expect_ok(&Command::ErasePage { address })
+    }
+
+    /// Erase `length` bytes of internal flash starting at `address`, which
+    /// must both be whole pages. The timeout applies between each
+    /// `EraseProgress` rather than to the whole erase.
+    pub fn erase_range(&mut self, address: u32, length: u32) -> Result<(), Error> {
+        let mut decoder = Box::new(ResponseDecoder::new());
+        let mut pending = VecDeque::new();
+        self.send(&Command::EraseRange { address, length })?;
+        loop {
+            let done =
+                self.next_response(&mut decoder, &mut pending, |response| match response {
+                    Response::EraseProgress { .. } => Ok(false),
+                    Response::Ok => Ok(true),
+                    r => Err(unexpected(&r)),
+                })?;
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Get the CRC32 of `length` bytes of internal flash starting at
+    /// `address`.
+    pub fn crc_int_flash(&mut self, address: u32, length: u32) -> Result<u32, Error> {
+        let command = Command::CrcIntFlash { address, length };
+        self.transact(&command, None, |response| match response {
+            Response::CrcIntFlash { crc } => Ok(crc),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Get the SHA-256 of `length` bytes of internal flash starting at
+    /// `address`, to check against `hash::sha256()` of an image.
+    pub fn hash_int_flash(&mut self, address: u32, length: u32) -> Result<[u8; HASH_LEN], Error> {
+        let command = Command::HashIntFlash {
+            address,
+            length,
+            algorithm: HASH_SHA256,
+        };
+        self.transact(&command, None, |response| match response {
+            Response::HashIntFlash { hash } => Ok(hash),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Read the attribute stored at `index`.
+    pub fn get_attr(&mut self, index: u8) -> Result<Attribute, Error> {
+        self.transact(
+            &Command::GetAttr { index },
+            None,
+            |response| match response {
+                Response::GetAttr { key, value } => Ok(Attribute {
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                }),
+                r => Err(unexpected(&r)),
+            },
+        )
+    }
+
+    /// Store an attribute at `index`. The key may be up to eight bytes long
+    /// and is zero padded.
+    pub fn set_attr(&mut self, index: u8, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        if key.len() > KEY_LEN {
+            return Err(Error::Protocol(super::Error::BadArguments));
+        }
+        let mut padded_key = [0u8; KEY_LEN];
+        padded_key[..key.len()].copy_from_slice(key);
+        self.expect_ok(&Command::SetAttr {
+            index,
+            key: &padded_key,
+            value,
+        })
+    }
+
+    /// Set the address the bootloader jumps to when starting the kernel.
+    pub fn set_start_address(&mut self, address: u32) -> Result<(), Error> {
+        self.expect_ok(&Command::SetStartAddress { address })
+    }
+
+    /// Store the length and CRC-32 of the kernel at the start address, so the
+    /// bootloader can check it before booting. A length of zero clears it.
+    pub fn set_kernel_crc(&mut self, length: u32, crc: u32) -> Result<(), Error> {
+        self.expect_ok(&Command::SetKernelCrc { length, crc })
+    }
+
+    /// Get the external flash ready for use.
+    pub fn ext_flash_init(&mut self) -> Result<(), Error> {
+        self.expect_ok(&Command::ExtFlashInit)
+    }
+
+    /// Read `length` bytes of external flash starting at `address`.
+    pub fn ex_read_range(&mut self, address: u32, length: u16) -> Result<Vec<u8>, Error> {
+        let command = Command::ExReadRange { address, length };
+        self.transact(&command, Some(length as usize), |response| match response {
+            Response::ExReadRange { data } => Ok(data.to_vec()),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Write one 256 byte page of external flash.
+    pub fn write_ex_page(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        self.expect_ok(&Command::WriteExPage { address, data })
+    }
+
+    /// Erase the 256 byte page of external flash starting at `address`.
+    pub fn erase_ex_page(&mut self, address: u32) -> Result<(), Error> {
+        self.expect_ok(&Command::EraseExPage { address })
+    }
+
+    /// Erase the 2048 byte block of external flash starting at `address`.
+    pub fn erase_ex_block(&mut self, address: u32) -> Result<(), Error> {
+        self.expect_ok(&Command::EraseExBlock { address })
+    }
+
+    /// Get the CRC32 of `length` bytes of external flash starting at
+    /// `address`.
+    pub fn crc_ext_flash(&mut self, address: u32, length: u32) -> Result<u32, Error> {
+        let command = Command::CrcExtFlash { address, length };
+        self.transact(&command, None, |response| match response {
+            Response::CrcExtFlash { crc } => Ok(crc),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Ask the bootloader to switch to `baud`. The response is sent at the
+    /// current rate. The caller must then switch the port to `baud` and call
+    /// `verify_baud_rate()`, otherwise the bootloader goes back to the current
+    /// rate after a timeout.
+    pub fn set_baud_rate(&mut self, baud: u32) -> Result<(), Error> {
+        self.expect_ok(&Command::ChangeBaud {
+            mode: BaudMode::Set,
+            baud,
+        })
+    }
+
+    /// Confirm the switch to `baud` requested with `set_baud_rate()`. If this
+    /// fails the bootloader goes back to the old rate.
+    pub fn verify_baud_rate(&mut self, baud: u32) -> Result<(), Error> {
+        self.expect_ok(&Command::ChangeBaud {
+            mode: BaudMode::Verify,
+            baud,
+        })
+    }
+
+    /// Tell the bootloader to exit. The bootloader does not respond to this
+    /// command.
+    pub fn exit(&mut self) -> Result<(), Error> {
+        self.send(&Command::Exit)
+    }
+
+    fn expect_ok(&mut self, command: &Command) -> Result<(), Error> {
+        self.transact(command, None, |response| match response {
+            Response::Ok => Ok(()),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Send the `RESET` prefix followed by `command`.
+    fn send(&mut self, command: &Command) -> Result<(), Error> {
+        let mut frame = Vec::new();
+        frame.extend(CommandEncoder::new(&Command::Reset)?);
+        frame.extend(CommandEncoder::new(command)?);
+        self.port.write_all(&frame)?;
+        self.port.flush()?;
+        Ok(())
+    }
+
+    /// Send `command` and wait for a response, which is passed to `handler`.
+    ///
+    /// `payload_len` must be set for responses that do not carry their own
+    /// length.
+    fn transact<R, H>(
+        &mut self,
+        command: &Command,
+        payload_len: Option<usize>,
+        handler: H,
+    ) -> Result<R, Error>
+    where
+        H: FnOnce(Response) -> Result<R, Error>,
+    {
+        // Start from a clean decoder so that a previous timed out response
+        // can't confuse this one.
+        let mut decoder = Box::new(ResponseDecoder::new());
+        if let Some(length) = payload_len {
+            decoder.set_payload_len(length)?;
+        }
+
+        self.send(command)?;
+        // Any bytes after the response are discarded; the next command
+        // starts with a `RESET` anyway.
+        self.next_response(&mut decoder, &mut VecDeque::new(), handler)
+    }
+
+    /// Wait for the next response, which is passed to `handler`. Bytes read
+    /// after it are left in `pending` for the next call.
+    fn next_response<R, H>(
+        &mut self,
+        decoder: &mut ResponseDecoder,
+        pending: &mut VecDeque<u8>,
+        handler: H,
+    ) -> Result<R, Error>
+    where
+        H: FnOnce(Response) -> Result<R, Error>,
+    {
+        let deadline = Instant::now() + self.timeout;
+        let mut buffer = [0u8; 512];
+        loop {
+            while let Some(byte) = pending.pop_front() {
+                if let Some(response) = decoder.receive(byte)? {
+                    return handler(response);
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+
+            // Don't let `read` wait past the deadline.
+            self.port.set_read_timeout(deadline - now)?;
+            let count = match self.port.read(&mut buffer) {
+                // The other end has gone, so nothing more will arrive.
+                Ok(0) => return Err(Error::Io(io::ErrorKind::UnexpectedEof.into())),
+                Ok(count) => count,
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::TimedOut
+                        || e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::Interrupted =>
+                {
+                    0
+                }
+                Err(e) => return Err(Error::Io(e)),
+            };
+            pending.extend(&buffer[..count]);
+        }
+    }
+}
+
+fn unexpected(response: &Response) -> Error {
+    Error::UnexpectedResponse(format!("{:?}", response))
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<super::Error> for Error {
+    fn from(e: super::Error) -> Error {
+        Error::Protocol(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Protocol(ref e) => write!(f, "protocol error: {:?}", e),
+            Error::Timeout => write!(f, "timed out waiting for the bootloader"),
+            Error::UnexpectedResponse(ref r) => write!(f, "unexpected response: {}", r),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    use super::super::{encrypted_image, KernelSlot, ResponseEncoder, SlotState, CMD_PING};
+
+    /// A fake port that records what was written and plays back canned
+    /// replies, one per read. Once they run out, reads time out.
+    struct FakePort {
+        written: Vec<u8>,
+        reply: VecDeque<u8>,
+        queued: VecDeque<VecDeque<u8>>,
+    }
+
+    impl FakePort {
+        fn new(response: &Response) -> FakePort {
+            FakePort {
+                written: Vec::new(),
+                reply: ResponseEncoder::new(response).unwrap().collect(),
+                queued: VecDeque::new(),
+            }
+        }
+
+        /// Play back `response` once the earlier replies have been read.
+        fn then(mut self, response: &Response) -> FakePort {
+            self.queued
+                .push_back(ResponseEncoder::new(response).unwrap().collect());
+            self
+        }
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.reply.is_empty() {
+                self.reply = self.queued.pop_front().unwrap_or_default();
+            }
+            if self.reply.is_empty() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let count = std::cmp::min(buf.len(), self.reply.len());
+            for byte in buf.iter_mut().take(count) {
+                *byte = self.reply.pop_front().unwrap();
+            }
+            Ok(count)
+        }
+    }
+
+    impl Port for FakePort {
+        fn set_read_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn encoded(command: &Command) -> Vec<u8> {
+        CommandEncoder::new(command).unwrap().collect()
+    }
+
+    #[test]
+    fn ping_sends_reset_prefix() {
+        let mut session = Session::new(FakePort::new(&Response::Pong));
+        session.ping().unwrap();
+        let mut expected = encoded(&Command::Reset);
+        expected.extend(encoded(&Command::Ping));
+        assert_eq!(session.into_inner().written, expected);
+    }
+
+    #[test]
+    fn read_range_sets_payload_len() {
+        let data = [0xFCu8, 0x01, 0x02, 0x03];
+        let response = Response::ReadRange { data: &data };
+        let mut session = Session::new(FakePort::new(&response));
+        assert_eq!(session.read_range(0x1000, 4).unwrap(), data.to_vec());
+    }
+
+    #[test]
+    fn ex_read_range_sets_payload_len() {
+        let data = [0x01u8, 0xFC, 0x02];
+        let response = Response::ExReadRange { data: &data };
+        let mut session = Session::new(FakePort::new(&response));
+        assert_eq!(session.ex_read_range(0x100, 3).unwrap(), data.to_vec());
+    }
+
+    #[test]
+    fn id() {
+        let response = Response::Id {
+            id: &[0x12, 0x34, 0x56, 0x78],
+        };
+        let mut session = Session::new(FakePort::new(&response));
+        assert_eq!(session.id().unwrap(), vec![0x12, 0x34, 0x56, 0x78]);
+    }
+
+    #[test]
+    fn capabilities() {
+        let mut capabilities = Capabilities {
+            protocol_version: 1,
+            page_size: 512,
+            flash_size: 0x80000,
+            bootloader_end: 0x8000,
+            max_frame_size: 4224,
+            ..Capabilities::default()
+        };
+        capabilities.set_supported(CMD_PING);
+        let response = Response::Capabilities { capabilities };
+        let mut session = Session::new(FakePort::new(&response));
+        assert_eq!(session.capabilities().unwrap(), capabilities);
+    }
+
+    #[test]
+    fn slots() {
+        let mut slots = KernelSlots::default();
+        slots.slots[1] = KernelSlot {
+            address: 0x80000,
+            size: 0x70000,
+            length: 0x1000,
+            crc: 0xDEADBEEF,
+            version: 2,
+            state: SlotState::Trial,
+        };
+        let response = Response::Slots { slots };
+        let mut session = Session::new(FakePort::new(&response));
+        assert_eq!(session.slots().unwrap(), slots);
+    }
+
+    #[test]
+    fn skipped_pages() {
+        let response = Response::SkippedPages { count: 3 };
+        let mut session = Session::new(FakePort::new(&response));
+        assert_eq!(session.skipped_pages().unwrap(), 3);
+    }
+
+    #[test]
+    fn authenticate() {
+        let key = [0x42; 32];
+        let nonce = [0x17; 32];
+        let port = FakePort::new(&Response::AuthChallenge { nonce: &nonce }).then(&Response::Ok);
+        let mut session = Session::new(port);
+        session.authenticate(&key).unwrap();
+        let mut expected = encoded(&Command::Reset);
+        expected.extend(encoded(&Command::AuthChallenge));
+        expected.extend(encoded(&Command::Reset));
+        expected.extend(encoded(&Command::AuthResponse {
+            mac: &auth_mac(&key, &nonce),
+        }));
+        assert_eq!(session.into_inner().written, expected);
+    }
+
+    #[test]
+    fn authenticate_wrong_key() {
+        let nonce = [0x17; 32];
+        let port = FakePort::new(&Response::AuthChallenge { nonce: &nonce })
+            .then(&Response::Unauthenticated);
+        let mut session = Session::new(port);
+        match session.authenticate(&[0x43; 32]) {
+            Err(Error::UnexpectedResponse(_)) => {}
+            r => panic!("Did not expect: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn write_encrypted_image() {
+        let key = [0x42; 32];
+        let nonce = [0x17; 12];
+        let port = FakePort::new(&Response::Ok)
+            .then(&Response::Ok)
+            .then(&Response::Ok)
+            .then(&Response::Ok);
+        let mut session = Session::new(port);
+        let image = [0xAA; 600];
+        session
+            .write_encrypted_image(&key, &nonce, 0x10000, &image)
+            .unwrap();
+
+        let mut padded = [0xFF; 1024];
+        padded[..600].copy_from_slice(&image);
+        let tag = encrypted_image::encrypt_image(&key, &nonce, 0x10000, &mut padded);
+        let mut expected = encoded(&Command::Reset);
+        expected.extend(encoded(&Command::BeginEncryptedImage {
+            address: 0x10000,
+            nonce: &nonce,
+        }));
+        for (address, page) in [(0x10000, &padded[..512]), (0x10200, &padded[512..])] {
+            expected.extend(encoded(&Command::Reset));
+            expected.extend(encoded(&Command::WriteEncryptedPage {
+                address,
+                data: page,
+            }));
+        }
+        expected.extend(encoded(&Command::Reset));
+        expected.extend(encoded(&Command::EndEncryptedImage { tag: &tag }));
+        assert_eq!(session.into_inner().written, expected);
+    }
+
+    #[test]
+    fn stream_image() {
+        let ack = |address| Response::PageAck {
+            address,
+            result: RES_OK,
+        };
+        let port = FakePort::new(&ack(0x10000))
+            .then(&ack(0x10200))
+            .then(&ack(0x10400));
+        let mut session = Session::new(port);
+        let image = [0xAA; 1100];
+        session.stream_image(0x10000, &image).unwrap();
+
+        let mut padded = [0xFF; 1536];
+        padded[..1100].copy_from_slice(&image);
+        let mut expected = Vec::new();
+        for (i, page) in padded.chunks(512).enumerate() {
+            expected.extend(encoded(&Command::Reset));
+            expected.extend(encoded(&Command::WriteStreamPage {
+                address: 0x10000 + i as u32 * 512,
+                data: page,
+            }));
+        }
+        assert_eq!(session.into_inner().written, expected);
+    }
+
+    #[test]
+    fn stream_image_failure() {
+        let port = FakePort::new(&Response::PageAck {
+            address: 0x10000,
+            result: 0x2C,
+        })
+        .then(&Response::PageAck {
+            address: 0x10200,
+            result: RES_OK,
+        });
+        let mut session = Session::new(port);
+        match session.stream_image(0x10000, &[0xAA; 2048]) {
+            Err(Error::UnexpectedResponse(r)) => assert!(r.contains("65536")),
+            r => panic!("Did not expect: {:?}", r),
+        }
+
+        // Only the two pages sent before the failure was seen.
+        let written = session.into_inner().written;
+        let page = encoded(&Command::WriteStreamPage {
+            address: 0x10000,
+            data: &[0xAA; 512],
+        });
+        let reset = encoded(&Command::Reset);
+        assert_eq!(written.len(), 2 * (reset.len() + page.len()));
+    }
+
+    #[test]
+    fn write_page_compressed() {
+        let mut session = Session::new(FakePort::new(&Response::Ok));
+        session
+            .write_page_compressed(0x10000, &[0xFF; 512])
+            .unwrap();
+        let written = session.into_inner().written;
+        let command = encoded(&Command::WritePageCompressed {
+            address: 0x10000,
+            data: &compress(&[0xFF; 512]),
+        });
+        assert!(written.ends_with(&command));
+        assert!(written.len() < 32);
+    }
+
+    #[test]
+    fn write_pages() {
+        let response = Response::PagesWritten { crc: 0xDEADBEEF };
+        let mut session = Session::new(FakePort::new(&response));
+        assert_eq!(
+            session.write_pages(0x10000, &[0xAA; 1024]).unwrap(),
+            0xDEADBEEF
+        );
+        let written = session.into_inner().written;
+        let command = encoded(&Command::WritePages {
+            address: 0x10000,
+            count: 2,
+            data: &[0xAA; 1024],
+        });
+        assert!(written.ends_with(&command));
+
+        let response = Response::PageFailed {
+            address: 0x10200,
+            result: 0x2C,
+        };
+        let mut session = Session::new(FakePort::new(&response));
+        match session.write_pages(0x10000, &[0xAA; 1024]) {
+            Err(Error::UnexpectedResponse(r)) => assert!(r.contains("66048")),
+            r => panic!("Did not expect: {:?}", r),
+        }
+
+        // Not whole pages.
+        assert!(session.write_pages(0x10000, &[0xAA; 1000]).is_err());
+    }
+
+    #[test]
+    fn erase_range() {
+        let port = FakePort::new(&Response::EraseProgress { address: 0x11000 })
+            .then(&Response::EraseProgress { address: 0x12000 })
+            .then(&Response::Ok);
+        let mut session = Session::new(port);
+        session.erase_range(0x10000, 0x2400).unwrap();
+        let written = session.into_inner().written;
+        let command = encoded(&Command::EraseRange {
+            address: 0x10000,
+            length: 0x2400,
+        });
+        assert!(written.ends_with(&command));
+
+        let mut session = Session::new(FakePort::new(&Response::BadAddress));
+        match session.erase_range(0, 0x200) {
+            Err(Error::UnexpectedResponse(r)) => assert_eq!(r, "BadAddress"),
+            r => panic!("Did not expect: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn crc_int_flash() {
+        let response = Response::CrcIntFlash { crc: 0xDEADBEEF };
+        let mut session = Session::new(FakePort::new(&response));
+        assert_eq!(session.crc_int_flash(0x1000, 0x200).unwrap(), 0xDEADBEEF);
+    }
+
+    #[test]
+    fn hash_int_flash() {
+        let response = Response::HashIntFlash { hash: [0xFC; 32] };
+        let mut session = Session::new(FakePort::new(&response));
+        assert_eq!(session.hash_int_flash(0x1000, 0x200).unwrap(), [0xFC; 32]);
+        let written = session.into_inner().written;
+        let command = encoded(&Command::HashIntFlash {
+            address: 0x1000,
+            length: 0x200,
+            algorithm: HASH_SHA256,
+        });
+        assert!(written.ends_with(&command));
+    }
+
+    #[test]
+    fn get_attr() {
+        let response = Response::GetAttr {
+            key: b"board\0\0\0",
+            value: b"hail",
+        };
+        let mut session = Session::new(FakePort::new(&response));
+        let attribute = session.get_attr(0).unwrap();
+        assert_eq!(attribute.key, b"board\0\0\0".to_vec());
+        assert_eq!(attribute.value, b"hail".to_vec());
+    }
+
+    #[test]
+    fn set_attr_pads_key() {
+        let mut session = Session::new(FakePort::new(&Response::Ok));
+        session.set_attr(1, b"arch", b"cortex-m4").unwrap();
+        let mut expected = encoded(&Command::Reset);
+        expected.extend(encoded(&Command::SetAttr {
+            index: 1,
+            key: b"arch\0\0\0\0",
+            value: b"cortex-m4",
+        }));
+        assert_eq!(session.into_inner().written, expected);
+    }
+
+    #[test]
+    fn error_response() {
+        let mut session = Session::new(FakePort::new(&Response::BadAddress));
+        match session.erase_page(0) {
+            Err(Error::UnexpectedResponse(_)) => {}
+            r => panic!("Did not expect: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn verify_baud_rate_failed() {
+        let mut session = Session::new(FakePort::new(&Response::ChangeBaudFail));
+        match session.verify_baud_rate(921600) {
+            Err(Error::UnexpectedResponse(_)) => {}
+            r => panic!("Did not expect: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let mut port = FakePort::new(&Response::Ok);
+        port.reply.clear();
+        let mut session = Session::new(port);
+        session.set_timeout(Duration::from_millis(10));
+        match session.set_start_address(0x10000) {
+            Err(Error::Timeout) => {}
+            r => panic!("Did not expect: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn read_timeout_set() {
+        use std::net::TcpListener;
+
+        // A socket that never answers must not block past the timeout.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        let mut session = Session::new(stream);
+        session.set_timeout(Duration::from_millis(50));
+        let start = Instant::now();
+        match session.ping() {
+            Err(Error::Timeout) => {}
+            r => panic!("Did not expect: {:?}", r),
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // Once the other end has closed there is no point waiting at all.
+        peer.shutdown(std::net::Shutdown::Write).unwrap();
+        session.set_timeout(Duration::from_secs(60));
+        match session.ping() {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            r => panic!("Did not expect: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Compressed pages, so the runs of 0xFF and zeros in kernel and app images
+//! don't have to be sent byte by byte.
+//!
+//! `WritePageCompressed` carries one page compressed on its own in the LZ4
+//! block format (without the LZ4 frame around it), so any LZ4 block
+//! compressor can produce it. The bootloader only needs `decompress()`.
+//! `compress()` is for host tools and needs the `std` feature.
+//!
+//! A block is a list of sequences. Each one is a token byte, with the number
+//! of literals in the top four bits and the match length minus 4 in the
+//! bottom four, then more length bytes if the literal count is 15, the
+//! literals, a little endian two byte offset back into the output to copy the
+//! match from, and more length bytes if the match count is 15. The last
+//! sequence stops after its literals.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::Error;
+
+// Shortest match a sequence can encode.
+const MIN_MATCH: usize = 4;
+
+/// Expand the LZ4 block `input` into `output`, and return how many bytes it
+/// expanded to. Fails with `BadArguments` if the block is malformed, and
+/// `BufferTooSmall` if it expands to more than `output` holds.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize, Error> {
+    let mut position = 0;
+    let mut written = 0;
+    loop {
+        let token = *input.get(position).ok_or(Error::BadArguments)?;
+        position += 1;
+
+        let literals = read_length(input, &mut position, (token >> 4) as usize)?;
+        let literal_data = input
+            .get(position..position + literals)
+            .ok_or(Error::BadArguments)?;
+        output
+            .get_mut(written..written + literals)
+            .ok_or(Error::BufferTooSmall)?
+            .copy_from_slice(literal_data);
+        position += literals;
+        written += literals;
+
+        if position == input.len() {
+            return Ok(written);
+        }
+
+        let offset = LittleEndian::read_u16(
+            input
+                .get(position..position + 2)
+                .ok_or(Error::BadArguments)?,
+        ) as usize;
+        position += 2;
+        if offset == 0 || offset > written {
+            return Err(Error::BadArguments);
+        }
+        let length = read_length(input, &mut position, (token & 0x0F) as usize)? + MIN_MATCH;
+        if written + length > output.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        // The match may overlap what it is writing, which is how runs are
+        // encoded, so copy a byte at a time.
+        for i in written..written + length {
+            output[i] = output[i - offset];
+        }
+        written += length;
+    }
+}
+
+// Helper function for a length from a token nibble, followed by more bytes
+// if the nibble is 15.
+fn read_length(input: &[u8], position: &mut usize, nibble: usize) -> Result<usize, Error> {
+    let mut length = nibble;
+    if nibble == 15 {
+        loop {
+            let byte = *input.get(*position).ok_or(Error::BadArguments)?;
+            *position += 1;
+            length += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(length)
+}
+
+/// Compress `input` into an LZ4 block for `decompress()`.
+///
+/// This is a simple greedy compressor. It is nowhere near as thorough as the
+/// reference one, but it is plenty for the runs in flash images. It keeps to
+/// the reference decoder's rules for the end of a block (the last 5 bytes are
+/// literals, and no match starts in the last 12), so its blocks can be
+/// checked with other LZ4 tools.
+#[cfg(feature = "std")]
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    // Rules for the end of a block.
+    const LAST_LITERALS: usize = 5;
+    const MATCH_LIMIT: usize = 12;
+    const HASH_BITS: u32 = 12;
+
+    let hash = |position: usize| {
+        (LittleEndian::read_u32(&input[position..]).wrapping_mul(2654435761) >> (32 - HASH_BITS))
+            as usize
+    };
+
+    let mut output = Vec::new();
+    let mut table = vec![None; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut position = 0;
+    while position + MATCH_LIMIT <= input.len() {
+        let slot = hash(position);
+        let candidate = table[slot].replace(position);
+        let found = candidate.filter(|&candidate: &usize| {
+            position - candidate <= 0xFFFF
+                && input[candidate..candidate + MIN_MATCH] == input[position..position + MIN_MATCH]
+        });
+        let candidate = match found {
+            Some(candidate) => candidate,
+            None => {
+                position += 1;
+                continue;
+            }
+        };
+
+        let limit = input.len() - LAST_LITERALS;
+        let mut length = MIN_MATCH;
+        while position + length < limit && input[candidate + length] == input[position + length] {
+            length += 1;
+        }
+        write_sequence(
+            &mut output,
+            &input[anchor..position],
+            Some(((position - candidate) as u16, length)),
+        );
+        position += length;
+        anchor = position;
+    }
+    write_sequence(&mut output, &input[anchor..], None);
+    output
+}
+
+// Helper function for adding a sequence of `literals` and an optional match
+// (offset and length) to `output`.
+#[cfg(feature = "std")]
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], found: Option<(u16, usize)>) {
+    let match_length = found.map_or(0, |(_, length)| length - MIN_MATCH);
+    let nibble = |length: usize| std::cmp::min(length, 15) as u8;
+    output.push(nibble(literals.len()) << 4 | nibble(match_length));
+    write_length(output, literals.len());
+    output.extend_from_slice(literals);
+    if let Some((offset, _)) = found {
+        output.extend_from_slice(&offset.to_le_bytes());
+        write_length(output, match_length);
+    }
+}
+
+// Helper function for the bytes after a nibble of 15.
+#[cfg(feature = "std")]
+fn write_length(output: &mut Vec<u8>, length: usize) {
+    if length >= 15 {
+        let mut remaining = length - 15;
+        while remaining >= 255 {
+            output.push(255);
+            remaining -= 255;
+        }
+        output.push(remaining as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals_only() {
+        // One sequence of 3 literals.
+        let mut output = [0; 8];
+        assert_eq!(decompress(&[0x30, 1, 2, 3], &mut output), Ok(3));
+        assert_eq!(&output[..3], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn runs() {
+        // One 0xFF, then a 511 byte match one byte back, then nothing.
+        let block = [0x1F, 0xFF, 0x01, 0x00, 0xFF, 0xED, 0x00];
+        let mut output = [0; 512];
+        assert_eq!(decompress(&block, &mut output), Ok(512));
+        assert_eq!(&output[..], &[0xFF; 512][..]);
+
+        // Too long for the output.
+        let mut output = [0; 511];
+        assert_eq!(decompress(&block, &mut output), Err(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn malformed() {
+        let mut output = [0; 512];
+        // Empty.
+        assert_eq!(decompress(&[], &mut output), Err(Error::BadArguments));
+        // Fewer literals than the token says.
+        assert_eq!(
+            decompress(&[0x40, 1, 2, 3], &mut output),
+            Err(Error::BadArguments)
+        );
+        // Offset back past the start.
+        assert_eq!(
+            decompress(&[0x10, 1, 0x02, 0x00, 0x00], &mut output),
+            Err(Error::BadArguments)
+        );
+        // Offset of 0.
+        assert_eq!(
+            decompress(&[0x10, 1, 0x00, 0x00, 0x00], &mut output),
+            Err(Error::BadArguments)
+        );
+        // Length bytes missing.
+        assert_eq!(decompress(&[0xF0], &mut output), Err(Error::BadArguments));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn round_trip() {
+        let mut page = [0xFF; 512];
+        for (i, b) in page[..100].iter_mut().enumerate() {
+            *b = (i * 7) as u8;
+        }
+        page[300..340].copy_from_slice(&[0; 40]);
+
+        let inputs: [&[u8]; 5] = [&page, &[0xFF; 512], &[0; 512], &page[..11], &[]];
+        for input in inputs.iter() {
+            let block = compress(input);
+            let mut output = [0; 512];
+            assert_eq!(decompress(&block, &mut output), Ok(input.len()));
+            assert_eq!(&output[..input.len()], *input);
+        }
+        assert!(compress(&[0xFF; 512]).len() < 16);
+        assert!(compress(&page).len() < 150);
+
+        // Data that doesn't compress grows by very little.
+        let mut noise = [0; 512];
+        let mut state = 1u32;
+        for b in noise.iter_mut() {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            *b = (state >> 16) as u8;
+        }
+        let block = compress(&noise);
+        assert!(block.len() <= 512 + 512 / 255 + 16);
+        let mut output = [0; 512];
+        assert_eq!(decompress(&block, &mut output), Ok(512));
+        assert_eq!(&output[..], &noise[..]);
+    }
+}
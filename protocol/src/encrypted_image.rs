@@ -0,0 +1,242 @@
+//! Encrypted images, so what is sent to the bootloader can't be read on the
+//! way.
+//!
+//! The host sends `BeginEncryptedImage` with the address and a nonce, the
+//! pages of the image encrypted with ChaCha20 in `WriteEncryptedPage`s, and
+//! finally the tag in `EndEncryptedImage`. The bootloader decrypts each page
+//! before writing it, and only accepts the image if the tag matches.
+//!
+//! Both the ChaCha20 key and the HMAC-SHA256 key for the tag are derived
+//! from the 32 byte image key provisioned in the board. The keystream for the
+//! byte `offset` bytes into the image comes from ChaCha20 block
+//! `offset / 64`. The tag is the HMAC-SHA256 of:
+//!
+//! ```text
+//! | Nonce (12 bytes) | Address | Encrypted image... |
+//! ```
+//!
+//! with the address little endian. A nonce must never be used twice with the
+//! same key.
+
+use byteorder::{ByteOrder, LittleEndian};
+use hmac_sha256::HMAC;
+
+use super::auth::constant_time_eq;
+
+/// Length of the image key.
+pub const IMAGE_KEY_LEN: usize = 32;
+
+/// Length of the nonce in `BeginEncryptedImage`.
+pub const IMAGE_NONCE_LEN: usize = 12;
+
+/// Length of the tag in `EndEncryptedImage`.
+pub const IMAGE_TAG_LEN: usize = 32;
+
+// Labels for deriving the two keys from the image key.
+const CIPHER_KEY_LABEL: &[u8] = b"tock image cipher";
+const MAC_KEY_LABEL: &[u8] = b"tock image mac";
+
+/// Encrypts or decrypts an image as it is sent, and calculates its tag.
+#[derive(Clone)]
+pub struct ImageCipher {
+    cipher_key: [u8; 32],
+    nonce: [u8; IMAGE_NONCE_LEN],
+    mac: HMAC,
+    address: u32,
+    offset: u32,
+}
+
+impl ImageCipher {
+    /// Start on the image at `address`.
+    pub fn new(
+        key: &[u8; IMAGE_KEY_LEN],
+        nonce: &[u8; IMAGE_NONCE_LEN],
+        address: u32,
+    ) -> ImageCipher {
+        let mut mac = HMAC::new(HMAC::mac(MAC_KEY_LABEL, &key[..]));
+        mac.update(&nonce[..]);
+        mac.update(address.to_le_bytes());
+        ImageCipher {
+            cipher_key: HMAC::mac(CIPHER_KEY_LABEL, &key[..]),
+            nonce: *nonce,
+            mac,
+            address,
+            offset: 0,
+        }
+    }
+
+    /// Address of the start of the image.
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+
+    /// Address the next data is for.
+    pub fn next_address(&self) -> u32 {
+        self.address.wrapping_add(self.offset)
+    }
+
+    /// Encrypt the next `data` of the image in place.
+    pub fn encrypt(&mut self, data: &mut [u8]) {
+        self.apply_keystream(data);
+        self.mac.update(&*data);
+    }
+
+    /// Decrypt the next `data` of the image in place.
+    pub fn decrypt(&mut self, data: &mut [u8]) {
+        self.mac.update(&*data);
+        self.apply_keystream(data);
+    }
+
+    /// The tag for everything encrypted or decrypted so far.
+    pub fn tag(self) -> [u8; IMAGE_TAG_LEN] {
+        self.mac.finalize()
+    }
+
+    /// Check `tag` against the one for everything decrypted so far.
+    pub fn verify(self, tag: &[u8]) -> bool {
+        constant_time_eq(&self.tag(), tag)
+    }
+
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        let mut block = [0; 64];
+        for (i, byte) in data.iter_mut().enumerate() {
+            let position = self.offset as usize + i;
+            if i == 0 || position % 64 == 0 {
+                block = chacha20_block(&self.cipher_key, (position / 64) as u32, &self.nonce);
+            }
+            *byte ^= block[position % 64];
+        }
+        self.offset += data.len() as u32;
+    }
+}
+
+/// Encrypt the image that will be written at `address` in place, and return
+/// its tag.
+pub fn encrypt_image(
+    key: &[u8; IMAGE_KEY_LEN],
+    nonce: &[u8; IMAGE_NONCE_LEN],
+    address: u32,
+    image: &mut [u8],
+) -> [u8; IMAGE_TAG_LEN] {
+    let mut cipher = ImageCipher::new(key, nonce, address);
+    cipher.encrypt(image);
+    cipher.tag()
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// One block of ChaCha20 keystream, as in RFC 8439.
+fn chacha20_block(key: &[u8; 32], counter: u32, nonce: &[u8; IMAGE_NONCE_LEN]) -> [u8; 64] {
+    let mut initial = [0u32; 16];
+    initial[0] = 0x61707865;
+    initial[1] = 0x3320646e;
+    initial[2] = 0x79622d32;
+    initial[3] = 0x6b206574;
+    for i in 0..8 {
+        initial[4 + i] = LittleEndian::read_u32(&key[i * 4..i * 4 + 4]);
+    }
+    initial[12] = counter;
+    for i in 0..3 {
+        initial[13 + i] = LittleEndian::read_u32(&nonce[i * 4..i * 4 + 4]);
+    }
+
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut block = [0; 64];
+    for i in 0..16 {
+        LittleEndian::write_u32(
+            &mut block[i * 4..i * 4 + 4],
+            state[i].wrapping_add(initial[i]),
+        );
+    }
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chacha20() {
+        // RFC 8439 section 2.3.2.
+        let mut key = [0; 32];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let nonce = [0, 0, 0, 0x09, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let block = chacha20_block(&key, 1, &nonce);
+        assert_eq!(
+            &block[..8],
+            &[0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15]
+        );
+        assert_eq!(
+            &block[56..],
+            &[0xcb, 0xd0, 0x83, 0xe8, 0xa2, 0x50, 0x3c, 0x4e]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = [0x42; IMAGE_KEY_LEN];
+        let nonce = [0x17; IMAGE_NONCE_LEN];
+        let mut plain = [0; 1000];
+        for (i, b) in plain.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut image = plain;
+        let tag = encrypt_image(&key, &nonce, 0x10000, &mut image);
+        assert_ne!(&image[..], &plain[..]);
+
+        // Decrypting in pieces of any size gives the same result.
+        let mut cipher = ImageCipher::new(&key, &nonce, 0x10000);
+        for chunk in image.chunks_mut(100) {
+            cipher.decrypt(chunk);
+        }
+        assert_eq!(cipher.next_address(), 0x10000 + 1000);
+        assert!(cipher.verify(&tag));
+        assert_eq!(&image[..], &plain[..]);
+    }
+
+    #[test]
+    fn tag_covers_everything() {
+        let key = [0x42; IMAGE_KEY_LEN];
+        let nonce = [0x17; IMAGE_NONCE_LEN];
+        let mut image = [0xAA; 128];
+        let tag = encrypt_image(&key, &nonce, 0x10000, &mut image);
+
+        let check = |key: &[u8; 32], nonce: &[u8; 12], address: u32, image: &[u8]| {
+            let mut copy = [0; 128];
+            let copy = &mut copy[..image.len()];
+            copy.copy_from_slice(image);
+            let mut cipher = ImageCipher::new(key, nonce, address);
+            cipher.decrypt(copy);
+            cipher.verify(&tag)
+        };
+        assert!(check(&key, &nonce, 0x10000, &image));
+        assert!(!check(&[0x43; 32], &nonce, 0x10000, &image));
+        assert!(!check(&key, &[0x18; 12], 0x10000, &image));
+        assert!(!check(&key, &nonce, 0x10200, &image));
+        assert!(!check(&key, &nonce, 0x10000, &image[..64]));
+        image[100] ^= 1;
+        assert!(!check(&key, &nonce, 0x10000, &image));
+    }
+}
@@ -0,0 +1,80 @@
+//! Hashes of internal flash, for `HashIntFlash`.
+//!
+//! `CrcIntFlash` is enough to catch a corrupted transfer, but it is easy to
+//! make two images with the same CRC, so it can't show that flash holds one
+//! particular image. `HashIntFlash` asks for a cryptographic hash instead.
+//! The command names the algorithm so more can be added later; for now the
+//! only one is SHA-256.
+
+use hmac_sha256::Hash;
+
+/// `algorithm` in `HashIntFlash` for SHA-256.
+pub const HASH_SHA256: u8 = 0x01;
+
+/// Length of the hash in a `HashIntFlash` response.
+pub const HASH_LEN: usize = 32;
+
+/// SHA-256 over data given a piece at a time, such as the pages of a range
+/// of flash read one after another.
+#[derive(Clone, Copy)]
+pub struct Sha256 {
+    hash: Hash,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 { hash: Hash::new() }
+    }
+
+    /// Add `data` to the hash.
+    pub fn update(&mut self, data: &[u8]) {
+        self.hash.update(data);
+    }
+
+    /// The hash of everything added.
+    pub fn finalize(self) -> [u8; HASH_LEN] {
+        self.hash.finalize()
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Sha256 {
+        Sha256::new()
+    }
+}
+
+/// The SHA-256 of `data`, to compare with a `HashIntFlash` response.
+pub fn sha256(data: &[u8]) -> [u8; HASH_LEN] {
+    Hash::hash(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answer() {
+        // FIPS 180-2 example "abc".
+        assert_eq!(
+            sha256(b"abc"),
+            [
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad
+            ]
+        );
+    }
+
+    #[test]
+    fn pieces() {
+        let mut data = [0u8; 1000];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut hash = Sha256::new();
+        for piece in data.chunks(300) {
+            hash.update(piece);
+        }
+        assert_eq!(hash.finalize(), sha256(&data));
+    }
+}
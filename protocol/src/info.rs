@@ -0,0 +1,617 @@
+//! The JSON blob returned by the `INFO` command.
+//!
+//! A bootloader fills in a `BootloaderInfo` and sends the output of
+//! `write_json`. Flash tools read it back with `BootloaderInfo::parse_json`:
+//!
+//! ```rust
+//! # use tock_bootloader_protocol::info::BootloaderInfo;
+//! let json = r#"{"version":"1.1.0","start_address":"0x00010000","name":"Tock Bootloader","attributes":{"board":"hail"}}"#;
+//! let info = BootloaderInfo::parse_json(json).unwrap();
+//! assert_eq!(info.start_address, 0x10000);
+//! assert_eq!(info.attribute("board"), Some("hail"));
+//! ```
+//!
+//! Neither side needs an allocator. `write_json` escapes `"`, `\`, control
+//! characters and anything outside ASCII, so the output is always printable
+//! ASCII. The parser borrows every string from the JSON, so a string that
+//! holds escapes comes back as written, escapes and all; `unescape` decodes
+//! it into a buffer:
+//!
+//! ```rust
+//! # use tock_bootloader_protocol::info::{unescape, BootloaderInfo};
+//! let json = r#"{"version":"1","start_address":"0x00010000","name":"x","attributes":{"owner":"J\u00fcrgen"}}"#;
+//! let info = BootloaderInfo::parse_json(json).unwrap();
+//! assert_eq!(info.attribute("owner"), Some(r"J\u00fcrgen"));
+//! let mut buffer = [0; 16];
+//! assert_eq!(unescape(info.attribute("owner").unwrap(), &mut buffer), Ok("Jürgen"));
+//! ```
+
+use super::Error;
+
+/// The most board attributes a `BootloaderInfo` can hold. This is the number
+/// of attribute slots in the bootloader's flash.
+pub const MAX_INFO_ATTRIBUTES: usize = 16;
+
+/// Information about a bootloader and the board it runs on.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BootloaderInfo<'a> {
+    /// Version of the bootloader.
+    pub version: &'a str,
+    /// Address the bootloader starts the kernel at.
+    pub start_address: u32,
+    /// Name of the bootloader.
+    pub name: &'a str,
+    /// Why the bootloader is running instead of the kernel, such as
+    /// `"boot_failures"`, if it knows.
+    pub stay_reason: Option<&'a str>,
+    /// How many board attributes the bootloader had but left out, because
+    /// they were not valid UTF-8 or did not fit in the response.
+    pub dropped_attributes: u32,
+    attributes: [(&'a str, &'a str); MAX_INFO_ATTRIBUTES],
+    attribute_count: usize,
+}
+
+impl<'a> BootloaderInfo<'a> {
+    /// Create a `BootloaderInfo` without any attributes.
+    pub fn new(version: &'a str, start_address: u32, name: &'a str) -> BootloaderInfo<'a> {
+        BootloaderInfo {
+            version,
+            start_address,
+            name,
+            stay_reason: None,
+            dropped_attributes: 0,
+            attributes: [("", ""); MAX_INFO_ATTRIBUTES],
+            attribute_count: 0,
+        }
+    }
+
+    /// Add a board attribute, such as `("board", "hail")`.
+    ///
+    /// Returns `Err(Error::BufferTooSmall)` if there is no room for another
+    /// attribute.
+    pub fn add_attribute(&mut self, key: &'a str, value: &'a str) -> Result<(), Error> {
+        if self.attribute_count == MAX_INFO_ATTRIBUTES {
+            Err(Error::BufferTooSmall)
+        } else {
+            self.attributes[self.attribute_count] = (key, value);
+            self.attribute_count += 1;
+            Ok(())
+        }
+    }
+
+    /// Remove the most recently added attribute.
+    pub fn remove_last_attribute(&mut self) -> Option<(&'a str, &'a str)> {
+        if self.attribute_count == 0 {
+            None
+        } else {
+            self.attribute_count -= 1;
+            let attribute = self.attributes[self.attribute_count];
+            self.attributes[self.attribute_count] = ("", "");
+            Some(attribute)
+        }
+    }
+
+    /// All board attributes, in the order they were added.
+    pub fn attributes(&self) -> &[(&'a str, &'a str)] {
+        &self.attributes[..self.attribute_count]
+    }
+
+    /// Look up the value of the board attribute `key`.
+    pub fn attribute(&self, key: &str) -> Option<&'a str> {
+        self.attributes()
+            .iter()
+            .find(|&&(k, _)| k == key)
+            .map(|&(_, value)| value)
+    }
+
+    /// Write the JSON representation into `buffer`. Returns the number of
+    /// bytes used.
+    ///
+    /// Returns `Err(Error::BufferTooSmall)` if it does not fit.
+    pub fn write_json(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = JsonWriter { buffer, index: 0 };
+        writer.raw("{\"version\":")?;
+        writer.string(self.version)?;
+        writer.raw(",\"start_address\":\"0x")?;
+        writer.hex(self.start_address, 8)?;
+        writer.raw("\",\"name\":")?;
+        writer.string(self.name)?;
+        if let Some(stay_reason) = self.stay_reason {
+            writer.raw(",\"stay_reason\":")?;
+            writer.string(stay_reason)?;
+        }
+        if self.dropped_attributes > 0 {
+            writer.raw(",\"dropped_attributes\":")?;
+            writer.decimal(self.dropped_attributes)?;
+        }
+        writer.raw(",\"attributes\":{")?;
+        for (i, &(key, value)) in self.attributes().iter().enumerate() {
+            if i > 0 {
+                writer.raw(",")?;
+            }
+            writer.string(key)?;
+            writer.raw(":")?;
+            writer.string(value)?;
+        }
+        writer.raw("}}")?;
+        Ok(writer.index)
+    }
+
+    /// Parse the JSON written by `write_json`.
+    ///
+    /// Unknown fields are skipped, so older tools can read the output of
+    /// newer bootloaders. Returns `Err(Error::BadArguments)` if `json` is
+    /// not valid, or has more attributes than a `BootloaderInfo` holds.
+    pub fn parse_json(json: &'a str) -> Result<BootloaderInfo<'a>, Error> {
+        let mut info = BootloaderInfo::new("", 0, "");
+        let mut reader = JsonReader { json, index: 0 };
+
+        reader.object(|reader, key| {
+            match key {
+                "version" => info.version = reader.string()?,
+                "start_address" => info.start_address = parse_hex(reader.string()?)?,
+                "name" => info.name = reader.string()?,
+                "stay_reason" => info.stay_reason = Some(reader.string()?),
+                "dropped_attributes" => info.dropped_attributes = reader.number()?,
+                "attributes" => reader.object(|reader, key| {
+                    let value = reader.string()?;
+                    info.add_attribute(key, value)
+                        .map_err(|_| Error::BadArguments)
+                })?,
+                _ => reader.skip_value()?,
+            }
+            Ok(())
+        })?;
+
+        reader.skip_whitespace();
+        if reader.index == json.len() {
+            Ok(info)
+        } else {
+            Err(Error::BadArguments)
+        }
+    }
+}
+
+/// Decode the escapes in `s`, a string from `parse_json`, into `buffer`.
+///
+/// Returns `Err(Error::BufferTooSmall)` if the decoded string does not fit,
+/// and `Err(Error::BadArguments)` if an escape is not valid.
+pub fn unescape<'b>(s: &str, buffer: &'b mut [u8]) -> Result<&'b str, Error> {
+    let mut length = 0;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        let c = if c != '\\' {
+            c
+        } else {
+            match chars.next() {
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some('/') => '/',
+                Some('b') => '\u{8}',
+                Some('f') => '\u{c}',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('u') => {
+                    let unit = unescape_u16(&mut chars)?;
+                    if (0xD800..0xDC00).contains(&unit) {
+                        // The first half of a surrogate pair.
+                        if chars.next() != Some('\\') || chars.next() != Some('u') {
+                            return Err(Error::BadArguments);
+                        }
+                        let low = unescape_u16(&mut chars)?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return Err(Error::BadArguments);
+                        }
+                        let high = (unit as u32 - 0xD800) << 10;
+                        core::char::from_u32(0x10000 + high + (low as u32 - 0xDC00))
+                            .ok_or(Error::BadArguments)?
+                    } else {
+                        core::char::from_u32(unit as u32).ok_or(Error::BadArguments)?
+                    }
+                }
+                _ => return Err(Error::BadArguments),
+            }
+        };
+        let encoded_length = c.len_utf8();
+        if length + encoded_length > buffer.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        c.encode_utf8(&mut buffer[length..]);
+        length += encoded_length;
+    }
+    // Only whole characters were written.
+    core::str::from_utf8(&buffer[..length]).map_err(|_| Error::BadArguments)
+}
+
+/// Read the four hex digits of a `\u` escape.
+fn unescape_u16(chars: &mut core::str::Chars) -> Result<u16, Error> {
+    let mut unit = 0;
+    for _ in 0..4 {
+        let digit = chars
+            .next()
+            .and_then(|c| c.to_digit(16))
+            .ok_or(Error::BadArguments)?;
+        unit = unit << 4 | digit as u16;
+    }
+    Ok(unit)
+}
+
+/// Parse an address written as `0x` followed by hex digits.
+fn parse_hex(s: &str) -> Result<u32, Error> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).map_err(|_| Error::BadArguments),
+        None => Err(Error::BadArguments),
+    }
+}
+
+struct JsonWriter<'b> {
+    buffer: &'b mut [u8],
+    index: usize,
+}
+
+impl<'b> JsonWriter<'b> {
+    fn byte(&mut self, b: u8) -> Result<(), Error> {
+        if self.index < self.buffer.len() {
+            self.buffer[self.index] = b;
+            self.index += 1;
+            Ok(())
+        } else {
+            Err(Error::BufferTooSmall)
+        }
+    }
+
+    fn raw(&mut self, s: &str) -> Result<(), Error> {
+        for b in s.bytes() {
+            self.byte(b)?;
+        }
+        Ok(())
+    }
+
+    /// Write `s` as a JSON string, escaping anything that is not printable
+    /// ASCII.
+    fn string(&mut self, s: &str) -> Result<(), Error> {
+        self.byte(b'"')?;
+        for c in s.chars() {
+            match c {
+                '"' | '\\' => {
+                    self.byte(b'\\')?;
+                    self.byte(c as u8)?;
+                }
+                ' '..='~' => self.byte(c as u8)?,
+                _ => {
+                    let mut units = [0; 2];
+                    for unit in c.encode_utf16(&mut units).iter() {
+                        self.raw("\\u")?;
+                        self.hex(*unit as u32, 4)?;
+                    }
+                }
+            }
+        }
+        self.byte(b'"')
+    }
+
+    fn decimal(&mut self, value: u32) -> Result<(), Error> {
+        let mut divisor = 1;
+        while value / divisor >= 10 {
+            divisor *= 10;
+        }
+        while divisor > 0 {
+            self.byte(b'0' + (value / divisor % 10) as u8)?;
+            divisor /= 10;
+        }
+        Ok(())
+    }
+
+    /// Write the low `digits` hex digits of `value`.
+    fn hex(&mut self, value: u32, digits: u32) -> Result<(), Error> {
+        for i in (0..digits).rev() {
+            let digit = ((value >> (i * 4)) & 0xF) as u8;
+            self.byte(if digit < 10 {
+                b'0' + digit
+            } else {
+                b'a' + digit - 10
+            })?;
+        }
+        Ok(())
+    }
+}
+
+struct JsonReader<'a> {
+    json: &'a str,
+    index: usize,
+}
+
+impl<'a> JsonReader<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.json.as_bytes().get(self.index).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') = self.peek() {
+            self.index += 1;
+        }
+    }
+
+    /// Skip whitespace and then consume `expected`.
+    fn expect(&mut self, expected: u8) -> Result<(), Error> {
+        self.skip_whitespace();
+        if self.peek() == Some(expected) {
+            self.index += 1;
+            Ok(())
+        } else {
+            Err(Error::BadArguments)
+        }
+    }
+
+    /// Read a string. Escapes are checked but left in place; see
+    /// `unescape`.
+    fn string(&mut self) -> Result<&'a str, Error> {
+        self.expect(b'"')?;
+        let start = self.index;
+        loop {
+            match self.peek() {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.index += 1;
+                    match self.peek() {
+                        Some(b'"') | Some(b'\\') | Some(b'/') | Some(b'b') | Some(b'f')
+                        | Some(b'n') | Some(b'r') | Some(b't') => self.index += 1,
+                        Some(b'u') => {
+                            self.index += 1;
+                            for _ in 0..4 {
+                                match self.peek() {
+                                    Some(b) if b.is_ascii_hexdigit() => self.index += 1,
+                                    _ => return Err(Error::BadArguments),
+                                }
+                            }
+                        }
+                        _ => return Err(Error::BadArguments),
+                    }
+                }
+                // Anything else but control characters, including UTF-8
+                // that a writer didn't escape.
+                Some(b) if b >= 0x20 => self.index += 1,
+                _ => return Err(Error::BadArguments),
+            }
+        }
+        let s = &self.json[start..self.index];
+        self.index += 1;
+        Ok(s)
+    }
+
+    /// Read a non-negative integer.
+    fn number(&mut self) -> Result<u32, Error> {
+        self.skip_whitespace();
+        let start = self.index;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.index += 1;
+        }
+        self.json[start..self.index]
+            .parse()
+            .map_err(|_| Error::BadArguments)
+    }
+
+    /// Read an object, calling `field` with each key. `field` must consume
+    /// the value.
+    fn object<F>(&mut self, mut field: F) -> Result<(), Error>
+    where
+        F: FnMut(&mut JsonReader<'a>, &'a str) -> Result<(), Error>,
+    {
+        self.expect(b'{')?;
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.index += 1;
+            return Ok(());
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(b':')?;
+            field(self, key)?;
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.index += 1,
+                Some(b'}') => {
+                    self.index += 1;
+                    return Ok(());
+                }
+                _ => return Err(Error::BadArguments),
+            }
+        }
+    }
+
+    /// Skip over a value we do not care about.
+    fn skip_value(&mut self) -> Result<(), Error> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'"') => self.string().map(|_| ()),
+            Some(b'{') => self.object(|reader, _| reader.skip_value()),
+            Some(b'[') => {
+                self.index += 1;
+                self.skip_whitespace();
+                if self.peek() == Some(b']') {
+                    self.index += 1;
+                    return Ok(());
+                }
+                loop {
+                    self.skip_value()?;
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.index += 1,
+                        Some(b']') => {
+                            self.index += 1;
+                            return Ok(());
+                        }
+                        _ => return Err(Error::BadArguments),
+                    }
+                }
+            }
+            // Numbers, `true`, `false` and `null`.
+            _ => {
+                let start = self.index;
+                while let Some(b'0'..=b'9') | Some(b'a'..=b'z') | Some(b'+') | Some(b'-')
+                | Some(b'.') | Some(b'E') = self.peek()
+                {
+                    self.index += 1;
+                }
+                if self.index > start {
+                    Ok(())
+                } else {
+                    Err(Error::BadArguments)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE_JSON: &str = r#"{"version":"1.1.0","start_address":"0x00010000","name":"Tock Bootloader","attributes":{"board":"hail","arch":"cortex-m4"}}"#;
+
+    fn example() -> BootloaderInfo<'static> {
+        let mut info = BootloaderInfo::new("1.1.0", 0x10000, "Tock Bootloader");
+        info.add_attribute("board", "hail").unwrap();
+        info.add_attribute("arch", "cortex-m4").unwrap();
+        info
+    }
+
+    #[test]
+    fn write() {
+        let mut buffer = [0; 192];
+        let length = example().write_json(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], EXAMPLE_JSON.as_bytes());
+    }
+
+    #[test]
+    fn write_too_small() {
+        let mut buffer = [0; 64];
+        assert_eq!(
+            example().write_json(&mut buffer),
+            Err(Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(BootloaderInfo::parse_json(EXAMPLE_JSON), Ok(example()));
+    }
+
+    #[test]
+    fn stay_reason() {
+        let mut info = example();
+        info.stay_reason = Some("boot_failures");
+        let mut buffer = [0; 192];
+        let length = info.write_json(&mut buffer).unwrap();
+        let json = core::str::from_utf8(&buffer[..length]).unwrap();
+        assert!(json.contains(r#""name":"Tock Bootloader","stay_reason":"boot_failures","#));
+        assert_eq!(BootloaderInfo::parse_json(json), Ok(info));
+        assert_eq!(example().stay_reason, None);
+    }
+
+    #[test]
+    fn parse_legacy() {
+        // What bootloaders sent before `BootloaderInfo` existed.
+        let info = BootloaderInfo::parse_json(
+            "{\"version\":\"1.1.0\", \"start_address\":\"0x00040000\", \"name\":\"Tock Bootloader\"}",
+        )
+        .unwrap();
+        assert_eq!(info.version, "1.1.0");
+        assert_eq!(info.start_address, 0x40000);
+        assert_eq!(info.name, "Tock Bootloader");
+        assert!(info.attributes().is_empty());
+    }
+
+    #[test]
+    fn parse_skips_unknown_fields() {
+        let info = BootloaderInfo::parse_json(
+            r#"{"future":[1, true, {"a":"b"}], "version":"2", "other":null}"#,
+        )
+        .unwrap();
+        assert_eq!(info.version, "2");
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for json in &[
+            "",
+            "{",
+            r#"{"version":"1"} trailing"#,
+            r#"{"version":1.0.0"}"#,
+            r#"{"version":"a\qb"}"#,
+            r#"{"version":"\u12"}"#,
+            "{\"version\":\"a\nb\"}",
+            r#"{"start_address":"10000"}"#,
+        ] {
+            assert_eq!(BootloaderInfo::parse_json(json), Err(Error::BadArguments));
+        }
+    }
+
+    #[test]
+    fn attributes() {
+        let mut info = example();
+        assert_eq!(info.attribute("arch"), Some("cortex-m4"));
+        assert_eq!(info.attribute("appaddr"), None);
+        assert_eq!(info.remove_last_attribute(), Some(("arch", "cortex-m4")));
+        assert_eq!(info.attributes(), &[("board", "hail")]);
+
+        for _ in 1..MAX_INFO_ATTRIBUTES {
+            info.add_attribute("k", "v").unwrap();
+        }
+        assert_eq!(info.add_attribute("k", "v"), Err(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn escapes() {
+        let mut info = BootloaderInfo::new("1", 0x10000, "a \"b\" \\c");
+        info.add_attribute("line", "1\n2").unwrap();
+        info.add_attribute("owner", "J\u{fc}rgen \u{1F980}")
+            .unwrap();
+        let mut buffer = [0; 192];
+        let length = info.write_json(&mut buffer).unwrap();
+        let json = core::str::from_utf8(&buffer[..length]).unwrap();
+        assert_eq!(
+            json,
+            r#"{"version":"1","start_address":"0x00010000","name":"a \"b\" \\c","attributes":{"line":"1\u000a2","owner":"J\u00fcrgen \ud83e\udd80"}}"#
+        );
+
+        let parsed = BootloaderInfo::parse_json(json).unwrap();
+        let mut decoded = [0; 32];
+        assert_eq!(unescape(parsed.name, &mut decoded), Ok("a \"b\" \\c"));
+        for &(key, value) in info.attributes() {
+            let escaped = parsed.attribute(key).unwrap();
+            assert_eq!(unescape(escaped, &mut decoded), Ok(value));
+        }
+    }
+
+    #[test]
+    fn unescape_invalid() {
+        let mut buffer = [0; 8];
+        assert_eq!(unescape(r"\x", &mut buffer), Err(Error::BadArguments));
+        assert_eq!(unescape(r"\u00", &mut buffer), Err(Error::BadArguments));
+        // A lone first half of a surrogate pair.
+        assert_eq!(unescape(r"\ud83ex", &mut buffer), Err(Error::BadArguments));
+        assert_eq!(
+            unescape(r"\u00fc\u00fc\u00fc\u00fc\u00fc", &mut buffer),
+            Err(Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn dropped_attributes() {
+        let mut info = example();
+        info.dropped_attributes = 12;
+        let mut buffer = [0; 192];
+        let length = info.write_json(&mut buffer).unwrap();
+        let json = core::str::from_utf8(&buffer[..length]).unwrap();
+        assert!(json.contains(r#""dropped_attributes":12,"#));
+        assert_eq!(BootloaderInfo::parse_json(json), Ok(info));
+        assert_eq!(
+            BootloaderInfo::parse_json(EXAMPLE_JSON)
+                .unwrap()
+                .dropped_attributes,
+            0
+        );
+    }
+}
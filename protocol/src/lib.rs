@@ -5,8 +5,12 @@
 //! protocol. This crate implements that protocol so
 //! that you can write future tockloader compatible bootloaders
 //! in Rust!
+//!
+//! With the `std` feature enabled, the `client` module also provides a
+//! blocking `Session` for host-side flash tools, and
+//! `compression::compress()` produces the pages for `WritePageCompressed`.
 
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 // ****************************************************************************
 //
@@ -15,6 +19,7 @@
 // ****************************************************************************
 
 extern crate byteorder;
+extern crate hmac_sha256;
 
 use byteorder::{ByteOrder, LittleEndian};
 
@@ -22,6 +27,16 @@ pub mod prelude {
     pub use super::Encoder;
 }
 
+pub mod auth;
+pub mod compression;
+pub mod encrypted_image;
+pub mod hash;
+pub mod info;
+pub mod signature;
+
+#[cfg(feature = "std")]
+pub mod client;
+
 // ****************************************************************************
 //
 // Public Types
@@ -38,8 +53,8 @@ pub enum Command<'a> {
     /// Get info about the bootloader. The result is one byte of length, plus
     /// length bytes of string, followed by 192-length zeroes.
     Info,
-    /// Get the Unique ID. Result is 8 bytes of unique ID (but I'm not sure
-    /// what the result code should be).
+    /// Get the Unique ID. The result is one byte of length, plus length
+    /// bytes of ID, followed by `MAX_ID_LEN`-length zeroes.
     Id,
     /// Reset all TX and RX buffers.
     Reset,
@@ -115,29 +130,192 @@ pub enum Command<'a> {
     ChangeBaud { mode: BaudMode, baud: u32 },
     /// Exit the bootloader.
     Exit,
+    /// Find out what the bootloader supports. The result is a
+    /// `Capabilities`.
+    GetCapabilities,
+    /// Store the length and CRC-32 of the kernel at the start address, so the
+    /// bootloader can check the kernel before jumping to it. A length of zero
+    /// clears the stored CRC.
+    SetKernelCrc { length: u32, crc: u32 },
+    /// Get the kernel slots. The result is a `Slots` response.
+    GetSlots,
+    /// Mark `slot` as holding a newly uploaded kernel of `length` bytes with
+    /// the given CRC-32 and version, to be tried on the next boot. The
+    /// active slot can't be marked.
+    SetSlotPending {
+        slot: u8,
+        length: u32,
+        crc: u32,
+        version: u32,
+    },
+    /// Start authenticating the session. The result is an `AuthChallenge`
+    /// with a fresh nonce.
+    AuthChallenge,
+    /// Finish authenticating the session. The RX buffer should contain the
+    /// 32 byte HMAC-SHA256 of the last nonce, keyed with the device key (see
+    /// `auth::auth_mac`). The result is `Ok` or `Unauthenticated`.
+    AuthResponse { mac: &'a [u8] },
+    /// Start writing an encrypted image at `address`, which must be the
+    /// start of a page. The RX buffer should contain the 4 byte address
+    /// followed by the 12 byte nonce the image was encrypted with (see
+    /// `encrypted_image`).
+    BeginEncryptedImage { address: u32, nonce: &'a [u8] },
+    /// Write the next page of the encrypted image. The RX buffer should
+    /// contain the 4 byte address of the page, followed by 512 bytes of
+    /// encrypted page. Pages must be sent in order.
+    WriteEncryptedPage { address: u32, data: &'a [u8] },
+    /// Finish the encrypted image. The RX buffer should contain the 32 byte
+    /// tag. If it doesn't match, the first page of the image is erased and
+    /// the result is `BadArguments`.
+    EndEncryptedImage { tag: &'a [u8] },
+    /// Get how many `WritePage`s were skipped because the page already held
+    /// the data. The result is a `SkippedPages` response.
+    GetSkippedPages,
+    /// Write a page of internal flash without waiting for the previous one.
+    /// The RX buffer should contain the 4 byte address followed by 512 bytes
+    /// of page, like `WritePage`. There is no immediate response. Instead a
+    /// `PageAck` with the address follows once the page has been written.
+    WriteStreamPage { address: u32, data: &'a [u8] },
+    /// Write `count` consecutive pages of internal flash with one command.
+    /// The RX buffer should contain the 4 byte address of the first page,
+    /// the 1 byte count, then 512 bytes for each page, up to
+    /// `MAX_WRITE_PAGES` of them. The result is `PagesWritten` with the CRC
+    /// of the written pages read back from flash, or `PageFailed` for the
+    /// first page that wasn't written.
+    WritePages {
+        address: u32,
+        count: u8,
+        data: &'a [u8],
+    },
+    /// Write a page of internal flash sent compressed. The RX buffer should
+    /// contain the 4 byte address followed by the page compressed as an LZ4
+    /// block (see the `compression` module). The result is the same as for
+    /// `WritePage`.
+    WritePageCompressed { address: u32, data: &'a [u8] },
+    /// Erase `length` bytes of internal flash, one page after another. The
+    /// RX buffer should contain the 4 byte page aligned address followed by
+    /// the 4 byte length, a whole number of pages. An `EraseProgress` is
+    /// sent every `ERASE_PROGRESS_PAGES` pages so a long erase doesn't look
+    /// like a lost response, then `Ok` once the range is erased.
+    EraseRange { address: u32, length: u32 },
+    /// Get a cryptographic hash of a range of internal flash. The RX buffer
+    /// should contain the 4 byte address, the 4 byte length and a 1 byte
+    /// algorithm, which must be `hash::HASH_SHA256`. The result is a
+    /// `HashIntFlash` response.
+    HashIntFlash {
+        address: u32,
+        length: u32,
+        algorithm: u8,
+    },
 }
 
 /// Responses supported by the protocol. A bootloader will encode these
 /// and a flash tool will decode them.
 #[derive(Debug, PartialEq)]
 pub enum Response<'a> {
-    Overflow,                                   // RES_OVERFLOW
-    Pong,                                       // RES_PONG
-    BadAddress,                                 // RES_BADADDR
-    InternalError,                              // RES_INTERROR
-    BadArguments,                               // RES_BADARGS
-    Ok,                                         // RES_OK
-    Unknown,                                    // RES_UNKNOWN
-    ExtFlashTimeout,                            // RES_XFTIMEOUT
-    ExtFlashPageError,                          // RES_XFEPE ??
-    CrcRxBuffer { length: u16, crc: u32 },      // RES_CRCRX
-    ReadRange { data: &'a [u8] },               // RES_RRANGE
-    ExReadRange { data: &'a [u8] },             // RES_XRRANGE
-    GetAttr { key: &'a [u8], value: &'a [u8] }, // RES_GATTR
-    CrcIntFlash { crc: u32 },                   // RES_CRCIF
-    CrcExtFlash { crc: u32 },                   // RES_CRCXF
-    Info { info: &'a [u8] },                    // RES_INFO
-    ChangeBaudFail,                             // RES_CHANGE_BAUD_FAIL
+    Overflow,                                    // RES_OVERFLOW
+    Pong,                                        // RES_PONG
+    BadAddress,                                  // RES_BADADDR
+    InternalError,                               // RES_INTERROR
+    BadArguments,                                // RES_BADARGS
+    Ok,                                          // RES_OK
+    Unknown,                                     // RES_UNKNOWN
+    ExtFlashTimeout,                             // RES_XFTIMEOUT
+    ExtFlashPageError,                           // RES_XFEPE ??
+    CrcRxBuffer { length: u16, crc: u32 },       // RES_CRCRX
+    ReadRange { data: &'a [u8] },                // RES_RRANGE
+    ExReadRange { data: &'a [u8] },              // RES_XRRANGE
+    GetAttr { key: &'a [u8], value: &'a [u8] },  // RES_GATTR
+    CrcIntFlash { crc: u32 },                    // RES_CRCIF
+    CrcExtFlash { crc: u32 },                    // RES_CRCXF
+    Info { info: &'a [u8] },                     // RES_INFO
+    ChangeBaudFail,                              // RES_CHANGE_BAUD_FAIL
+    Id { id: &'a [u8] },                         // RES_ID
+    Capabilities { capabilities: Capabilities }, // RES_CAPABILITIES
+    Slots { slots: KernelSlots },                // RES_SLOTS
+    AuthChallenge { nonce: &'a [u8] },           // RES_AUTH_CHALLENGE
+    Unauthenticated,                             // RES_UNAUTHENTICATED
+    FlashError,                                  // RES_FLASH_ERROR
+    VerifyFailed,                                // RES_VERIFY_FAILED
+    SkippedPages { count: u32 },                 // RES_SKIPPED_PAGES
+    PageAck { address: u32, result: u8 },        // RES_PAGE_ACK
+    PagesWritten { crc: u32 },                   // RES_PAGES_WRITTEN
+    PageFailed { address: u32, result: u8 },     // RES_PAGE_FAILED
+    EraseProgress { address: u32 },              // RES_ERASE_PROGRESS
+    HashIntFlash { hash: [u8; hash::HASH_LEN] }, // RES_HASHIF
+}
+
+/// What a bootloader build supports, as returned for `GetCapabilities`.
+///
+/// On the wire this is the protocol version (two bytes), the command bitmap
+/// (32 bytes), then the remaining fields as four bytes each, all little
+/// endian.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Capabilities {
+    /// Version of the protocol the bootloader implements.
+    pub protocol_version: u16,
+    /// Bitmap of the implemented command codes. Command `c` is implemented if
+    /// bit `c % 8` of byte `c / 8` is set.
+    pub commands: [u8; 32],
+    /// Size of an internal flash page, in bytes.
+    pub page_size: u32,
+    /// Size of the internal flash in bytes, or 0 if not known.
+    pub flash_size: u32,
+    /// Address of the bootloader in flash. Writes to
+    /// `bootloader_start..bootloader_end` are refused.
+    pub bootloader_start: u32,
+    /// Address after the bootloader in flash.
+    pub bootloader_end: u32,
+    /// The longest command, in bytes (not counting escapes), the bootloader
+    /// can receive.
+    pub max_frame_size: u32,
+}
+
+/// The kernel slots, as returned for `GetSlots`.
+///
+/// On the wire this is each `KernelSlot`, then the index of the active slot
+/// as one byte (0xFF for none).
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct KernelSlots {
+    pub slots: [KernelSlot; NUM_KERNEL_SLOTS],
+    /// The slot with the newest confirmed kernel. This is what the bootloader
+    /// falls back to, so it can't be marked pending.
+    pub active: Option<u8>,
+}
+
+/// One of the kernel slots.
+///
+/// On the wire this is the address, size, length, CRC and version as four
+/// bytes each, little endian, then the state as one byte.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct KernelSlot {
+    /// Address of the slot in internal flash.
+    pub address: u32,
+    /// Size of the slot in bytes.
+    pub size: u32,
+    /// Length of the kernel in the slot.
+    pub length: u32,
+    /// CRC-32 of the kernel, as calculated by `CrcIntFlash`.
+    pub crc: u32,
+    /// Version of the kernel, as given by the host.
+    pub version: u32,
+    pub state: SlotState,
+}
+
+/// Where a kernel slot is in the update process.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum SlotState {
+    /// Nothing has been uploaded to the slot.
+    #[default]
+    Empty, // 0x00
+    /// A kernel has been uploaded and will be tried on the next boot.
+    Pending, // 0x01
+    /// The kernel has been started once and has not confirmed that it runs.
+    Trial, // 0x02
+    /// The kernel has confirmed that it runs.
+    Confirmed, // 0x03
+    /// The kernel failed its checks or never confirmed, and won't be started.
+    Invalid, // 0x04
 }
 
 #[derive(Debug, PartialEq)]
@@ -152,21 +330,32 @@ pub enum Error {
     /// The user called `set_payload_len` yet we
     /// got a response of bounded length.
     SetLength,
-    /// The buffer passed by the user wasn't large enough for the packet.
+    /// The buffer passed by the user, or the decoder's own buffer, wasn't
+    /// large enough for the packet.
     BufferTooSmall,
 }
 
 /// The `ComandDecoder` takes bytes and gives you `Command`s.
-pub struct CommandDecoder {
+///
+/// It can hold commands of up to `N` bytes (not counting escapes), which
+/// must be large enough for the biggest page you want to write.
+pub struct CommandDecoder<const N: usize = DEFAULT_DECODER_CAPACITY> {
     state: DecoderState,
-    buffer: [u8; 4224],
+    buffer: [u8; N],
     count: usize,
+    /// The command being loaded did not fit in `buffer`.
+    overflow: bool,
+    /// Length of the payload of the last command, while it is still in
+    /// `buffer`.
+    last_len: usize,
 }
 
 /// The `ResponseDecoder` takes bytes and gives you `Responses`s.
-pub struct ResponseDecoder {
+///
+/// It can hold responses of up to `N` bytes (not counting escapes).
+pub struct ResponseDecoder<const N: usize = DEFAULT_DECODER_CAPACITY> {
     state: DecoderState,
-    buffer: [u8; 4224],
+    buffer: [u8; N],
     count: usize,
     needed: Option<usize>,
 }
@@ -197,7 +386,76 @@ pub enum BaudMode {
 //
 // ****************************************************************************
 
-// None
+/// The longest string an `Info` response can carry.
+pub const MAX_INFO_LEN: usize = 192;
+
+/// The longest device ID an `Id` response can carry.
+pub const MAX_ID_LEN: usize = 16;
+
+/// Number of kernel slots in a `Slots` response.
+pub const NUM_KERNEL_SLOTS: usize = 2;
+
+/// Version of the protocol implemented by this crate, as reported in
+/// `Capabilities`.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+// Command codes. These are also the bit numbers in `Capabilities::commands`.
+pub const CMD_PING: u8 = 0x01;
+pub const CMD_INFO: u8 = 0x03;
+pub const CMD_ID: u8 = 0x04;
+pub const CMD_RESET: u8 = 0x05;
+pub const CMD_EPAGE: u8 = 0x06;
+pub const CMD_WPAGE: u8 = 0x07;
+pub const CMD_XEBLOCK: u8 = 0x08;
+pub const CMD_XWPAGE: u8 = 0x09;
+pub const CMD_CRCRX: u8 = 0x10;
+pub const CMD_RRANGE: u8 = 0x11;
+pub const CMD_XRRANGE: u8 = 0x12;
+pub const CMD_SATTR: u8 = 0x13;
+pub const CMD_GATTR: u8 = 0x14;
+pub const CMD_CRCIF: u8 = 0x15;
+pub const CMD_CRCEF: u8 = 0x16;
+pub const CMD_XEPAGE: u8 = 0x17;
+pub const CMD_XFINIT: u8 = 0x18;
+pub const CMD_CLKOUT: u8 = 0x19;
+pub const CMD_WUSER: u8 = 0x20;
+pub const CMD_CHANGE_BAUD: u8 = 0x21;
+pub const CMD_EXIT: u8 = 0x22;
+pub const CMD_SSTARTADDR: u8 = 0x23;
+pub const CMD_GET_CAPABILITIES: u8 = 0x24;
+pub const CMD_SKERNELCRC: u8 = 0x25;
+pub const CMD_GET_SLOTS: u8 = 0x26;
+pub const CMD_SSLOTPENDING: u8 = 0x27;
+pub const CMD_AUTH_CHALLENGE: u8 = 0x28;
+pub const CMD_AUTH_RESPONSE: u8 = 0x29;
+pub const CMD_BEGIN_ENC_IMAGE: u8 = 0x2A;
+pub const CMD_WRITE_ENC_PAGE: u8 = 0x2B;
+pub const CMD_END_ENC_IMAGE: u8 = 0x2C;
+pub const CMD_GET_SKIPPED_PAGES: u8 = 0x2D;
+pub const CMD_WRITE_STREAM_PAGE: u8 = 0x2E;
+pub const CMD_WRITE_PAGES: u8 = 0x2F;
+pub const CMD_WRITE_PAGE_COMPRESSED: u8 = 0x30;
+pub const CMD_ERASE_RANGE: u8 = 0x31;
+pub const CMD_HASHIF: u8 = 0x32;
+
+/// Capacity of the decoders made by `CommandDecoder::new()` and
+/// `ResponseDecoder::new()`. This fits a 4 KiB page and its header.
+pub const DEFAULT_DECODER_CAPACITY: usize = 4224;
+
+/// How many `WriteStreamPage`s the host may send before the `PageAck` for the
+/// first of them.
+pub const STREAM_WINDOW: usize = 2;
+
+/// Most pages one `WritePages` can carry, so that it fits in the default
+/// decoder.
+pub const MAX_WRITE_PAGES: usize = 8;
+
+/// Length of the 4 byte address and 1 byte count that come before the pages
+/// in a `WritePages` payload.
+pub const WRITE_PAGES_HEADER_LEN: usize = 5;
+
+/// How many pages an `EraseRange` erases between `EraseProgress` responses.
+pub const ERASE_PROGRESS_PAGES: u32 = 8;
 
 // ****************************************************************************
 //
@@ -218,29 +476,6 @@ enum DecoderState {
 
 const ESCAPE_CHAR: u8 = 0xFC;
 
-const CMD_PING: u8 = 0x01;
-const CMD_INFO: u8 = 0x03;
-const CMD_ID: u8 = 0x04;
-const CMD_RESET: u8 = 0x05;
-const CMD_EPAGE: u8 = 0x06;
-const CMD_WPAGE: u8 = 0x07;
-const CMD_XEBLOCK: u8 = 0x08;
-const CMD_XWPAGE: u8 = 0x09;
-const CMD_CRCRX: u8 = 0x10;
-const CMD_RRANGE: u8 = 0x11;
-const CMD_XRRANGE: u8 = 0x12;
-const CMD_SATTR: u8 = 0x13;
-const CMD_GATTR: u8 = 0x14;
-const CMD_CRCIF: u8 = 0x15;
-const CMD_CRCEF: u8 = 0x16;
-const CMD_XEPAGE: u8 = 0x17;
-const CMD_XFINIT: u8 = 0x18;
-const CMD_CLKOUT: u8 = 0x19;
-const CMD_WUSER: u8 = 0x20;
-const CMD_CHANGE_BAUD: u8 = 0x21;
-const CMD_EXIT: u8 = 0x22;
-const CMD_SSTARTADDR: u8 = 0x23;
-
 const RES_OVERFLOW: u8 = 0x10;
 const RES_PONG: u8 = 0x11;
 const RES_BADADDR: u8 = 0x12;
@@ -258,13 +493,29 @@ const RES_CRCIF: u8 = 0x23;
 const RES_CRCXF: u8 = 0x24;
 const RES_INFO: u8 = 0x25;
 const RES_CHANGE_BAUD_FAIL: u8 = 0x26;
+const RES_ID: u8 = 0x27;
+const RES_CAPABILITIES: u8 = 0x28;
+const RES_SLOTS: u8 = 0x29;
+const RES_AUTH_CHALLENGE: u8 = 0x2A;
+const RES_UNAUTHENTICATED: u8 = 0x2B;
+const RES_FLASH_ERROR: u8 = 0x2C;
+const RES_VERIFY_FAILED: u8 = 0x2D;
+const RES_SKIPPED_PAGES: u8 = 0x2E;
+const RES_PAGE_ACK: u8 = 0x2F;
+const RES_PAGES_WRITTEN: u8 = 0x30;
+const RES_PAGE_FAILED: u8 = 0x31;
+const RES_ERASE_PROGRESS: u8 = 0x32;
+const RES_HASHIF: u8 = 0x33;
 
 const MAX_INDEX: u8 = 16;
 const KEY_LEN: usize = 8;
 const MAX_ATTR_LEN: usize = 55;
 const INT_PAGE_SIZE: usize = 512;
 const EXT_PAGE_SIZE: usize = 256;
-const MAX_INFO_LEN: usize = 192;
+const CAPABILITIES_LEN: usize = 2 + 32 + 4 * 5;
+const KERNEL_SLOT_LEN: usize = 4 * 5 + 1;
+const SLOTS_LEN: usize = NUM_KERNEL_SLOTS * KERNEL_SLOT_LEN + 1;
+const NO_ACTIVE_SLOT: u8 = 0xFF;
 
 // ****************************************************************************
 //
@@ -272,6 +523,63 @@ const MAX_INFO_LEN: usize = 192;
 //
 // ****************************************************************************
 
+impl Capabilities {
+    /// Whether the command with code `command` (one of the `CMD_*` constants)
+    /// is implemented.
+    pub fn supports(&self, command: u8) -> bool {
+        self.commands[command as usize / 8] & (1 << (command % 8)) != 0
+    }
+
+    /// Mark the command with code `command` as implemented.
+    pub fn set_supported(&mut self, command: u8) {
+        self.commands[command as usize / 8] |= 1 << (command % 8);
+    }
+}
+
+impl SlotState {
+    fn from_u8(state: u8) -> Result<SlotState, Error> {
+        match state {
+            0x00 => Ok(SlotState::Empty),
+            0x01 => Ok(SlotState::Pending),
+            0x02 => Ok(SlotState::Trial),
+            0x03 => Ok(SlotState::Confirmed),
+            0x04 => Ok(SlotState::Invalid),
+            _ => Err(Error::BadArguments),
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            SlotState::Empty => 0x00,
+            SlotState::Pending => 0x01,
+            SlotState::Trial => 0x02,
+            SlotState::Confirmed => 0x03,
+            SlotState::Invalid => 0x04,
+        }
+    }
+}
+
+fn decode_slots(buffer: &[u8]) -> Result<KernelSlots, Error> {
+    let mut slots = KernelSlots::default();
+    for (i, slot) in slots.slots.iter_mut().enumerate() {
+        let b = &buffer[i * KERNEL_SLOT_LEN..(i + 1) * KERNEL_SLOT_LEN];
+        *slot = KernelSlot {
+            address: LittleEndian::read_u32(&b[0..4]),
+            size: LittleEndian::read_u32(&b[4..8]),
+            length: LittleEndian::read_u32(&b[8..12]),
+            crc: LittleEndian::read_u32(&b[12..16]),
+            version: LittleEndian::read_u32(&b[16..20]),
+            state: SlotState::from_u8(b[20])?,
+        };
+    }
+    slots.active = match buffer[SLOTS_LEN - 1] {
+        NO_ACTIVE_SLOT => None,
+        slot if (slot as usize) < NUM_KERNEL_SLOTS => Some(slot),
+        _ => return Err(Error::BadArguments),
+    };
+    Ok(slots)
+}
+
 pub trait Encoder: Iterator<Item = u8> {
     fn reset(&mut self);
 
@@ -293,14 +601,24 @@ pub trait Encoder: Iterator<Item = u8> {
 }
 
 impl CommandDecoder {
-    /// Create a new `CommandDecoder`.
+    /// Create a new `CommandDecoder` with the default capacity.
     ///
     /// The decoder is fed bytes with the `receive` method.
     pub fn new() -> CommandDecoder {
+        CommandDecoder::with_capacity()
+    }
+}
+
+impl<const N: usize> CommandDecoder<N> {
+    /// Create a new `CommandDecoder` that holds commands of up to `N` bytes,
+    /// e.g. `CommandDecoder::<520>::with_capacity()`.
+    pub fn with_capacity() -> CommandDecoder<N> {
         CommandDecoder {
             state: DecoderState::Loading,
-            buffer: [0u8; 4224],
+            buffer: [0u8; N],
             count: 0,
+            overflow: false,
+            last_len: 0,
         }
     }
 
@@ -325,6 +643,24 @@ impl CommandDecoder {
     /// Empty the RX buffer.
     pub fn reset(&mut self) {
         self.count = 0;
+        self.overflow = false;
+        self.last_len = 0;
+    }
+
+    /// Whether part of a command has arrived, but not yet its end.
+    pub fn in_command(&self) -> bool {
+        if let DecoderState::Escape = self.state {
+            return true;
+        }
+        self.count > 0 || self.overflow
+    }
+
+    /// The payload of the last command decoded. It stays in the decoder until
+    /// the next command starts to arrive, so a bootloader that stops
+    /// receiving while it works through a long command like `WritePages` can
+    /// come back to it. Empty if there is no such command.
+    pub fn last_payload(&self) -> &[u8] {
+        &self.buffer[..self.last_len]
     }
 
     /// Process incoming bytes.
@@ -332,7 +668,8 @@ impl CommandDecoder {
     /// The decoder is fed bytes with the `receive` method. If not enough
     /// bytes have been seen, this function returns `None`. Once enough bytes
     /// have been seen, it returns `Ok(Some(Command))` containing the decoded
-    /// Command. It returns `Err` if it doesn't like the byte received.
+    /// Command. It returns `Err` if it doesn't like the byte received, or
+    /// `Err(Error::BufferTooSmall)` at the end of a command that did not fit.
     pub fn receive(&mut self, ch: u8) -> Result<Option<Command>, Error> {
         match self.state {
             DecoderState::Loading => self.handle_loading(ch),
@@ -341,9 +678,13 @@ impl CommandDecoder {
     }
 
     fn load_char(&mut self, ch: u8) {
+        // The next command overwrites the last one.
+        self.last_len = 0;
         if self.count < self.buffer.len() {
             self.buffer[self.count] = ch;
             self.count = self.count + 1;
+        } else {
+            self.overflow = true;
         }
     }
 
@@ -358,6 +699,11 @@ impl CommandDecoder {
 
     fn handle_escape(&mut self, ch: u8) -> Result<Option<Command>, Error> {
         self.state = DecoderState::Loading;
+        if self.overflow && ch != ESCAPE_CHAR {
+            // Whatever the command was, we only have part of it.
+            self.reset();
+            return Err(Error::BufferTooSmall);
+        }
         let result: Result<Option<Command>, Error> = match ch {
             ESCAPE_CHAR => {
                 // Double escape means just load an escape
@@ -536,11 +882,149 @@ impl CommandDecoder {
                 }
             }
             CMD_EXIT => Ok(Some(Command::Exit)),
+            CMD_GET_CAPABILITIES => Ok(Some(Command::GetCapabilities)),
+            CMD_GET_SLOTS => Ok(Some(Command::GetSlots)),
+            CMD_GET_SKIPPED_PAGES => Ok(Some(Command::GetSkippedPages)),
+            CMD_SSLOTPENDING => {
+                let num_expected_bytes: usize = 13;
+                if self.count == num_expected_bytes {
+                    let slot = self.buffer[0];
+                    let length = LittleEndian::read_u32(&self.buffer[1..5]);
+                    let crc = LittleEndian::read_u32(&self.buffer[5..9]);
+                    let version = LittleEndian::read_u32(&self.buffer[9..13]);
+                    Ok(Some(Command::SetSlotPending {
+                        slot,
+                        length,
+                        crc,
+                        version,
+                    }))
+                } else {
+                    Err(Error::BadArguments)
+                }
+            }
+            CMD_SKERNELCRC => {
+                let num_expected_bytes: usize = 8;
+                if self.count == num_expected_bytes {
+                    let length = LittleEndian::read_u32(&self.buffer[0..4]);
+                    let crc = LittleEndian::read_u32(&self.buffer[4..8]);
+                    Ok(Some(Command::SetKernelCrc { length, crc }))
+                } else {
+                    Err(Error::BadArguments)
+                }
+            }
+            CMD_AUTH_CHALLENGE => Ok(Some(Command::AuthChallenge)),
+            CMD_AUTH_RESPONSE => {
+                let num_expected_bytes: usize = auth::AUTH_MAC_LEN;
+                if self.count == num_expected_bytes {
+                    let mac = &self.buffer[0..num_expected_bytes];
+                    Ok(Some(Command::AuthResponse { mac }))
+                } else {
+                    Err(Error::BadArguments)
+                }
+            }
+            CMD_BEGIN_ENC_IMAGE => {
+                let num_expected_bytes: usize = 4 + encrypted_image::IMAGE_NONCE_LEN;
+                if self.count == num_expected_bytes {
+                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
+                    let nonce = &self.buffer[4..num_expected_bytes];
+                    Ok(Some(Command::BeginEncryptedImage { address, nonce }))
+                } else {
+                    Err(Error::BadArguments)
+                }
+            }
+            CMD_WRITE_ENC_PAGE => {
+                // Like `WritePage`, the bootloader checks the page size.
+                if self.count >= 4 {
+                    let payload = &self.buffer[0..self.count];
+                    let address = LittleEndian::read_u32(&payload[0..4]);
+                    Ok(Some(Command::WriteEncryptedPage {
+                        address,
+                        data: &payload[4..],
+                    }))
+                } else {
+                    Err(Error::BadArguments)
+                }
+            }
+            CMD_WRITE_STREAM_PAGE => {
+                // Like `WritePage`, the bootloader checks the page size.
+                if self.count >= 4 {
+                    let payload = &self.buffer[0..self.count];
+                    let address = LittleEndian::read_u32(&payload[0..4]);
+                    Ok(Some(Command::WriteStreamPage {
+                        address,
+                        data: &payload[4..],
+                    }))
+                } else {
+                    Err(Error::BadArguments)
+                }
+            }
+            CMD_WRITE_PAGES => {
+                // The bootloader checks the data is `count` of its pages.
+                if self.count >= WRITE_PAGES_HEADER_LEN {
+                    let payload = &self.buffer[0..self.count];
+                    let address = LittleEndian::read_u32(&payload[0..4]);
+                    Ok(Some(Command::WritePages {
+                        address,
+                        count: payload[4],
+                        data: &payload[WRITE_PAGES_HEADER_LEN..],
+                    }))
+                } else {
+                    Err(Error::BadArguments)
+                }
+            }
+            CMD_WRITE_PAGE_COMPRESSED => {
+                // The bootloader checks the data expands to a page.
+                if self.count > 4 {
+                    let payload = &self.buffer[0..self.count];
+                    let address = LittleEndian::read_u32(&payload[0..4]);
+                    Ok(Some(Command::WritePageCompressed {
+                        address,
+                        data: &payload[4..],
+                    }))
+                } else {
+                    Err(Error::BadArguments)
+                }
+            }
+            CMD_ERASE_RANGE => {
+                let num_expected_bytes: usize = 8;
+                if self.count == num_expected_bytes {
+                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
+                    let length = LittleEndian::read_u32(&self.buffer[4..8]);
+                    Ok(Some(Command::EraseRange { address, length }))
+                } else {
+                    Err(Error::BadArguments)
+                }
+            }
+            CMD_HASHIF => {
+                let num_expected_bytes: usize = 9;
+                if self.count == num_expected_bytes {
+                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
+                    let length = LittleEndian::read_u32(&self.buffer[4..8]);
+                    let algorithm = self.buffer[8];
+                    Ok(Some(Command::HashIntFlash {
+                        address,
+                        length,
+                        algorithm,
+                    }))
+                } else {
+                    Err(Error::BadArguments)
+                }
+            }
+            CMD_END_ENC_IMAGE => {
+                let num_expected_bytes: usize = encrypted_image::IMAGE_TAG_LEN;
+                if self.count == num_expected_bytes {
+                    let tag = &self.buffer[0..num_expected_bytes];
+                    Ok(Some(Command::EndEncryptedImage { tag }))
+                } else {
+                    Err(Error::BadArguments)
+                }
+            }
 
             _ => Ok(None),
         };
         // A command or error signifies the end of the buffer
         if let Ok(Some(_)) = result {
+            self.last_len = self.count;
             self.count = 0;
         } else if let Err(_) = result {
             self.count = 0;
@@ -549,14 +1033,28 @@ impl CommandDecoder {
     }
 }
 
+impl<const N: usize> Default for CommandDecoder<N> {
+    fn default() -> Self {
+        Self::with_capacity()
+    }
+}
+
 impl ResponseDecoder {
-    /// Create a new `ResponseDecoder`.
+    /// Create a new `ResponseDecoder` with the default capacity.
     ///
     /// The decoder is fed bytes with the `receive` method.
     pub fn new() -> ResponseDecoder {
+        ResponseDecoder::with_capacity()
+    }
+}
+
+impl<const N: usize> ResponseDecoder<N> {
+    /// Create a new `ResponseDecoder` that holds responses of up to `N`
+    /// bytes, e.g. `ResponseDecoder::<64>::with_capacity()`.
+    pub fn with_capacity() -> ResponseDecoder<N> {
         ResponseDecoder {
             state: DecoderState::Loading,
-            buffer: [0u8; 4224],
+            buffer: [0u8; N],
             count: 0,
             needed: None,
         }
@@ -600,9 +1098,12 @@ impl ResponseDecoder {
 
     /// Set the expected length of an unbounded message. This
     /// depends entirely on the last command you sent.
+    ///
+    /// Returns `Err(Error::BufferTooSmall)` if the message will not fit.
     pub fn set_payload_len(&mut self, length: usize) -> Result<(), Error> {
         match self.needed {
             Some(_) => Err(Error::SetLength),
+            None if length + 1 > self.buffer.len() => Err(Error::BufferTooSmall),
             None => {
                 self.needed = Some(length + 1);
                 Ok(())
@@ -614,6 +1115,11 @@ impl ResponseDecoder {
         if self.count < self.buffer.len() {
             self.buffer[self.count] = ch;
             self.count = self.count + 1;
+        } else {
+            // Can only happen for a response we were not expecting.
+            self.needed = None;
+            self.count = 0;
+            return Err(Error::BufferTooSmall);
         }
         if self.needed == Some(self.count) {
             let result = match self.buffer[0] {
@@ -648,6 +1154,33 @@ impl ResponseDecoder {
                     let crc = LittleEndian::read_u32(&self.buffer[1..5]);
                     Ok(Some(Response::CrcExtFlash { crc }))
                 }
+                RES_SKIPPED_PAGES => {
+                    let count = LittleEndian::read_u32(&self.buffer[1..5]);
+                    Ok(Some(Response::SkippedPages { count }))
+                }
+                RES_PAGE_ACK => {
+                    let address = LittleEndian::read_u32(&self.buffer[1..5]);
+                    let result = self.buffer[5];
+                    Ok(Some(Response::PageAck { address, result }))
+                }
+                RES_PAGES_WRITTEN => {
+                    let crc = LittleEndian::read_u32(&self.buffer[1..5]);
+                    Ok(Some(Response::PagesWritten { crc }))
+                }
+                RES_PAGE_FAILED => {
+                    let address = LittleEndian::read_u32(&self.buffer[1..5]);
+                    let result = self.buffer[5];
+                    Ok(Some(Response::PageFailed { address, result }))
+                }
+                RES_ERASE_PROGRESS => {
+                    let address = LittleEndian::read_u32(&self.buffer[1..5]);
+                    Ok(Some(Response::EraseProgress { address }))
+                }
+                RES_HASHIF => {
+                    let mut hash = [0; hash::HASH_LEN];
+                    hash.copy_from_slice(&self.buffer[1..1 + hash::HASH_LEN]);
+                    Ok(Some(Response::HashIntFlash { hash }))
+                }
                 RES_INFO => {
                     let length: usize = self.buffer[1] as usize;
                     if length + 1 < self.count {
@@ -657,6 +1190,35 @@ impl ResponseDecoder {
                         Err(Error::BadArguments)
                     }
                 }
+                RES_ID => {
+                    let length: usize = self.buffer[1] as usize;
+                    if length <= MAX_ID_LEN {
+                        let id = &self.buffer[2..length + 2];
+                        Ok(Some(Response::Id { id }))
+                    } else {
+                        Err(Error::BadArguments)
+                    }
+                }
+                RES_CAPABILITIES => {
+                    let mut commands = [0u8; 32];
+                    commands.copy_from_slice(&self.buffer[3..35]);
+                    let capabilities = Capabilities {
+                        protocol_version: LittleEndian::read_u16(&self.buffer[1..3]),
+                        commands,
+                        page_size: LittleEndian::read_u32(&self.buffer[35..39]),
+                        flash_size: LittleEndian::read_u32(&self.buffer[39..43]),
+                        bootloader_start: LittleEndian::read_u32(&self.buffer[43..47]),
+                        bootloader_end: LittleEndian::read_u32(&self.buffer[47..51]),
+                        max_frame_size: LittleEndian::read_u32(&self.buffer[51..55]),
+                    };
+                    Ok(Some(Response::Capabilities { capabilities }))
+                }
+                RES_SLOTS => decode_slots(&self.buffer[1..1 + SLOTS_LEN])
+                    .map(|slots| Some(Response::Slots { slots })),
+                RES_AUTH_CHALLENGE => {
+                    let nonce = &self.buffer[1..1 + auth::AUTH_NONCE_LEN];
+                    Ok(Some(Response::AuthChallenge { nonce }))
+                }
                 _ => Err(Error::UnknownCommand),
             };
             self.needed = None;
@@ -733,6 +1295,21 @@ impl ResponseDecoder {
                 self.needed = None;
                 Ok(Some(Response::ChangeBaudFail))
             }
+            RES_UNAUTHENTICATED => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::Unauthenticated))
+            }
+            RES_FLASH_ERROR => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::FlashError))
+            }
+            RES_VERIFY_FAILED => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::VerifyFailed))
+            }
             RES_CRCRX => {
                 self.set_payload_len(6)?;
                 self.load_char(ch)?;
@@ -769,17 +1346,74 @@ impl ResponseDecoder {
                 self.load_char(ch)?;
                 Ok(None)
             }
+            RES_SKIPPED_PAGES => {
+                self.set_payload_len(4)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_PAGE_ACK => {
+                self.set_payload_len(5)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_PAGES_WRITTEN => {
+                self.set_payload_len(4)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_PAGE_FAILED => {
+                self.set_payload_len(5)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_ERASE_PROGRESS => {
+                self.set_payload_len(4)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_HASHIF => {
+                self.set_payload_len(hash::HASH_LEN)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
             RES_INFO => {
                 // length + data
                 self.set_payload_len(1 + MAX_INFO_LEN)?;
                 self.load_char(ch)?;
                 Ok(None)
             }
+            RES_ID => {
+                // length + data
+                self.set_payload_len(1 + MAX_ID_LEN)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_CAPABILITIES => {
+                self.set_payload_len(CAPABILITIES_LEN)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_SLOTS => {
+                self.set_payload_len(SLOTS_LEN)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_AUTH_CHALLENGE => {
+                self.set_payload_len(auth::AUTH_NONCE_LEN)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
             _ => Ok(None),
         }
     }
 }
 
+impl<const N: usize> Default for ResponseDecoder<N> {
+    fn default() -> Self {
+        Self::with_capacity()
+    }
+}
+
 impl<'a> CommandEncoder<'a> {
     /// Create a new `CommandEncoder`.
     ///
@@ -810,6 +1444,48 @@ impl<'a> CommandEncoder<'a> {
                     return Err(Error::BadArguments);
                 }
             }
+            &Command::AuthResponse { mac } => {
+                if mac.len() != auth::AUTH_MAC_LEN {
+                    return Err(Error::BadArguments);
+                }
+            }
+            &Command::BeginEncryptedImage { address: _, nonce } => {
+                if nonce.len() != encrypted_image::IMAGE_NONCE_LEN {
+                    return Err(Error::BadArguments);
+                }
+            }
+            &Command::WriteEncryptedPage { address: _, data } => {
+                if data.len() != INT_PAGE_SIZE {
+                    return Err(Error::BadArguments);
+                }
+            }
+            &Command::EndEncryptedImage { tag } => {
+                if tag.len() != encrypted_image::IMAGE_TAG_LEN {
+                    return Err(Error::BadArguments);
+                }
+            }
+            &Command::WriteStreamPage { address: _, data } => {
+                if data.len() != INT_PAGE_SIZE {
+                    return Err(Error::BadArguments);
+                }
+            }
+            &Command::WritePages {
+                address: _,
+                count,
+                data,
+            } => {
+                if count == 0
+                    || count as usize > MAX_WRITE_PAGES
+                    || data.len() != count as usize * INT_PAGE_SIZE
+                {
+                    return Err(Error::BadArguments);
+                }
+            }
+            &Command::WritePageCompressed { address: _, data } => {
+                if data.is_empty() {
+                    return Err(Error::BadArguments);
+                }
+            }
             _ => {}
         };
         Ok(CommandEncoder {
@@ -963,63 +1639,179 @@ impl<'a> CommandEncoder<'a> {
         }
     }
 
-    fn render_crcintflash(&mut self, address: u32, length: u32) -> (usize, Option<u8>) {
+    fn render_setslotpending(
+        &mut self,
+        slot: u8,
+        length: u32,
+        crc: u32,
+        version: u32,
+    ) -> (usize, Option<u8>) {
         let count = self.count;
         match count {
-            0..=3 => self.render_u32(count, address),
-            4..=7 => self.render_u32(count - 4, length),
-            _ => self.render_basic_cmd(count - 8, CMD_CRCIF),
+            0 => self.render_byte(slot),
+            1..=4 => self.render_u32(count - 1, length),
+            5..=8 => self.render_u32(count - 5, crc),
+            9..=12 => self.render_u32(count - 9, version),
+            _ => self.render_basic_cmd(count - 13, CMD_SSLOTPENDING),
         }
     }
 
-    fn render_crcextflash(&mut self, address: u32, length: u32) -> (usize, Option<u8>) {
+    fn render_setkernelcrc(&mut self, length: u32, crc: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, length),
+            4..=7 => self.render_u32(count - 4, crc),
+            _ => self.render_basic_cmd(count - 8, CMD_SKERNELCRC),
+        }
+    }
+
+    fn render_authresponse(&mut self, mac: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            x if x < auth::AUTH_MAC_LEN => self.render_byte(mac[x]),
+            _ => self.render_basic_cmd(count - auth::AUTH_MAC_LEN, CMD_AUTH_RESPONSE),
+        }
+    }
+
+    fn render_beginencimage(&mut self, address: u32, nonce: &[u8]) -> (usize, Option<u8>) {
         let count = self.count;
+        let nonce_len = encrypted_image::IMAGE_NONCE_LEN;
         match count {
             0..=3 => self.render_u32(count, address),
-            4..=7 => self.render_u32(count - 4, length),
-            _ => self.render_basic_cmd(count - 8, CMD_CRCEF),
+            x if x < 4 + nonce_len => self.render_byte(nonce[x - 4]),
+            _ => self.render_basic_cmd(count - (4 + nonce_len), CMD_BEGIN_ENC_IMAGE),
         }
     }
 
-    fn render_eraseexpage(&mut self, address: u32) -> (usize, Option<u8>) {
+    fn render_writeencpage(&mut self, address: u32, data: &[u8]) -> (usize, Option<u8>) {
         let count = self.count;
         match count {
             0..=3 => self.render_u32(count, address),
-            _ => self.render_basic_cmd(count - 4, CMD_XEPAGE),
+            4..=515 => self.render_buffer(count - 4, INT_PAGE_SIZE, data),
+            _ => self.render_basic_cmd(count - 516, CMD_WRITE_ENC_PAGE),
         }
     }
 
-    fn render_writeflashuserpages(&mut self, page1: u32, page2: u32) -> (usize, Option<u8>) {
+    fn render_writestreampage(&mut self, address: u32, data: &[u8]) -> (usize, Option<u8>) {
         let count = self.count;
         match count {
-            0..=3 => self.render_u32(count, page1),
-            4..=7 => self.render_u32(count - 4, page2),
-            _ => self.render_basic_cmd(count - 8, CMD_WUSER),
+            0..=3 => self.render_u32(count, address),
+            4..=515 => self.render_buffer(count - 4, INT_PAGE_SIZE, data),
+            _ => self.render_basic_cmd(count - 516, CMD_WRITE_STREAM_PAGE),
         }
     }
 
-    fn render_changebaud(&mut self, mode: BaudMode, baud: u32) -> (usize, Option<u8>) {
+    fn render_writepages(&mut self, address: u32, pages: u8, data: &[u8]) -> (usize, Option<u8>) {
         let count = self.count;
+        let length = pages as usize * INT_PAGE_SIZE;
         match count {
-            0 => self.render_byte(match mode {
-                BaudMode::Set => 0x01,
-                BaudMode::Verify => 0x02,
-            }),
-            1..=4 => self.render_u32(count - 1, baud),
-            _ => self.render_basic_cmd(count - 5, CMD_CHANGE_BAUD),
+            0..=3 => self.render_u32(count, address),
+            4 => self.render_byte(pages),
+            x if x < WRITE_PAGES_HEADER_LEN + length => {
+                self.render_buffer(x - WRITE_PAGES_HEADER_LEN, length, data)
+            }
+            _ => self.render_basic_cmd(count - WRITE_PAGES_HEADER_LEN - length, CMD_WRITE_PAGES),
         }
     }
-}
 
-impl<'a> Iterator for CommandEncoder<'a> {
-    type Item = u8;
+    fn render_writepagecompressed(&mut self, address: u32, data: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            x if x < 4 + data.len() => self.render_byte(data[x - 4]),
+            _ => self.render_basic_cmd(count - 4 - data.len(), CMD_WRITE_PAGE_COMPRESSED),
+        }
+    }
 
-    /// Supply the next encoded byte. Once all the bytes have been emitted, it
-    /// returns `None` forevermore.
-    fn next(&mut self) -> Option<u8> {
+    fn render_eraserange(&mut self, address: u32, length: u32) -> (usize, Option<u8>) {
         let count = self.count;
-        let (inc, result) = match self.command {
-            &Command::Ping => self.render_basic_cmd(count, CMD_PING),
+        match count {
+            0..=3 => self.render_u32(count, address),
+            4..=7 => self.render_u32(count - 4, length),
+            _ => self.render_basic_cmd(count - 8, CMD_ERASE_RANGE),
+        }
+    }
+
+    fn render_hashintflash(
+        &mut self,
+        address: u32,
+        length: u32,
+        algorithm: u8,
+    ) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            4..=7 => self.render_u32(count - 4, length),
+            8 => self.render_byte(algorithm),
+            _ => self.render_basic_cmd(count - 9, CMD_HASHIF),
+        }
+    }
+
+    fn render_endencimage(&mut self, tag: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            x if x < encrypted_image::IMAGE_TAG_LEN => self.render_byte(tag[x]),
+            _ => self.render_basic_cmd(count - encrypted_image::IMAGE_TAG_LEN, CMD_END_ENC_IMAGE),
+        }
+    }
+
+    fn render_crcintflash(&mut self, address: u32, length: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            4..=7 => self.render_u32(count - 4, length),
+            _ => self.render_basic_cmd(count - 8, CMD_CRCIF),
+        }
+    }
+
+    fn render_crcextflash(&mut self, address: u32, length: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            4..=7 => self.render_u32(count - 4, length),
+            _ => self.render_basic_cmd(count - 8, CMD_CRCEF),
+        }
+    }
+
+    fn render_eraseexpage(&mut self, address: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            _ => self.render_basic_cmd(count - 4, CMD_XEPAGE),
+        }
+    }
+
+    fn render_writeflashuserpages(&mut self, page1: u32, page2: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, page1),
+            4..=7 => self.render_u32(count - 4, page2),
+            _ => self.render_basic_cmd(count - 8, CMD_WUSER),
+        }
+    }
+
+    fn render_changebaud(&mut self, mode: BaudMode, baud: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0 => self.render_byte(match mode {
+                BaudMode::Set => 0x01,
+                BaudMode::Verify => 0x02,
+            }),
+            1..=4 => self.render_u32(count - 1, baud),
+            _ => self.render_basic_cmd(count - 5, CMD_CHANGE_BAUD),
+        }
+    }
+}
+
+impl<'a> Iterator for CommandEncoder<'a> {
+    type Item = u8;
+
+    /// Supply the next encoded byte. Once all the bytes have been emitted, it
+    /// returns `None` forevermore.
+    fn next(&mut self) -> Option<u8> {
+        let count = self.count;
+        let (inc, result) = match self.command {
+            &Command::Ping => self.render_basic_cmd(count, CMD_PING),
             &Command::Info => self.render_basic_cmd(count, CMD_INFO),
             &Command::Id => self.render_basic_cmd(count, CMD_ID),
             &Command::Reset => self.render_basic_cmd(count, CMD_RESET),
@@ -1043,6 +1835,42 @@ impl<'a> Iterator for CommandEncoder<'a> {
             &Command::ChangeBaud { mode, baud } => self.render_changebaud(mode, baud),
             &Command::SetStartAddress { address } => self.render_setstartaddress(address),
             &Command::Exit => self.render_basic_cmd(count, CMD_EXIT),
+            &Command::GetCapabilities => self.render_basic_cmd(count, CMD_GET_CAPABILITIES),
+            &Command::SetKernelCrc { length, crc } => self.render_setkernelcrc(length, crc),
+            &Command::GetSlots => self.render_basic_cmd(count, CMD_GET_SLOTS),
+            &Command::GetSkippedPages => self.render_basic_cmd(count, CMD_GET_SKIPPED_PAGES),
+            &Command::WriteStreamPage { address, data } => {
+                self.render_writestreampage(address, data)
+            }
+            &Command::WritePages {
+                address,
+                count: pages,
+                data,
+            } => self.render_writepages(address, pages, data),
+            &Command::WritePageCompressed { address, data } => {
+                self.render_writepagecompressed(address, data)
+            }
+            &Command::EraseRange { address, length } => self.render_eraserange(address, length),
+            &Command::HashIntFlash {
+                address,
+                length,
+                algorithm,
+            } => self.render_hashintflash(address, length, algorithm),
+            &Command::SetSlotPending {
+                slot,
+                length,
+                crc,
+                version,
+            } => self.render_setslotpending(slot, length, crc, version),
+            &Command::AuthChallenge => self.render_basic_cmd(count, CMD_AUTH_CHALLENGE),
+            &Command::AuthResponse { mac } => self.render_authresponse(mac),
+            &Command::BeginEncryptedImage { address, nonce } => {
+                self.render_beginencimage(address, nonce)
+            }
+            &Command::WriteEncryptedPage { address, data } => {
+                self.render_writeencpage(address, data)
+            }
+            &Command::EndEncryptedImage { tag } => self.render_endencimage(tag),
         };
         self.count = self.count + inc;
         result
@@ -1078,6 +1906,16 @@ impl<'a> ResponseEncoder<'a> {
                     return Err(Error::BadArguments);
                 }
             }
+            &Response::Id { id } => {
+                if id.len() > MAX_ID_LEN {
+                    return Err(Error::BadArguments);
+                }
+            }
+            &Response::AuthChallenge { nonce } => {
+                if nonce.len() != auth::AUTH_NONCE_LEN {
+                    return Err(Error::BadArguments);
+                }
+            }
             _ => {}
         }
         Ok(ResponseEncoder {
@@ -1155,6 +1993,58 @@ impl<'a> ResponseEncoder<'a> {
         }
     }
 
+    fn render_skipped_pages(&mut self, skipped: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_SKIPPED_PAGES),
+            _ => self.render_u32(count - 2, skipped),
+        }
+    }
+
+    fn render_page_ack(&mut self, address: u32, result: u8) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_PAGE_ACK),
+            2..=5 => self.render_u32(count - 2, address),
+            6 => self.render_byte(result),
+            _ => (0, None),
+        }
+    }
+
+    fn render_pages_written(&mut self, crc: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_PAGES_WRITTEN),
+            _ => self.render_u32(count - 2, crc),
+        }
+    }
+
+    fn render_page_failed(&mut self, address: u32, result: u8) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_PAGE_FAILED),
+            2..=5 => self.render_u32(count - 2, address),
+            6 => self.render_byte(result),
+            _ => (0, None),
+        }
+    }
+
+    fn render_erase_progress(&mut self, address: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_ERASE_PROGRESS),
+            _ => self.render_u32(count - 2, address),
+        }
+    }
+
+    fn render_hash_int_flash(&mut self, hash: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_HASHIF),
+            _ => self.render_buffer(count - 2, hash::HASH_LEN, hash),
+        }
+    }
+
     fn render_info(&mut self, info: &[u8]) -> (usize, Option<u8>) {
         let count = self.count;
         match count {
@@ -1164,6 +2054,58 @@ impl<'a> ResponseEncoder<'a> {
         }
     }
 
+    fn render_id(&mut self, id: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_ID),
+            2 => self.render_byte(id.len() as u8),
+            _ => self.render_buffer(count - 3, MAX_ID_LEN, id),
+        }
+    }
+
+    fn render_capabilities(&mut self, capabilities: &Capabilities) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_CAPABILITIES),
+            2..=3 => self.render_u16(count - 2, capabilities.protocol_version),
+            4..=35 => self.render_byte(capabilities.commands[count - 4]),
+            36..=39 => self.render_u32(count - 36, capabilities.page_size),
+            40..=43 => self.render_u32(count - 40, capabilities.flash_size),
+            44..=47 => self.render_u32(count - 44, capabilities.bootloader_start),
+            48..=51 => self.render_u32(count - 48, capabilities.bootloader_end),
+            52..=55 => self.render_u32(count - 52, capabilities.max_frame_size),
+            _ => (0, None),
+        }
+    }
+
+    fn render_slots(&mut self, slots: &KernelSlots) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_SLOTS),
+            x if x < 2 + SLOTS_LEN - 1 => {
+                let slot = &slots.slots[(x - 2) / KERNEL_SLOT_LEN];
+                match (x - 2) % KERNEL_SLOT_LEN {
+                    i @ 0..=3 => self.render_u32(i, slot.address),
+                    i @ 4..=7 => self.render_u32(i - 4, slot.size),
+                    i @ 8..=11 => self.render_u32(i - 8, slot.length),
+                    i @ 12..=15 => self.render_u32(i - 12, slot.crc),
+                    i @ 16..=19 => self.render_u32(i - 16, slot.version),
+                    _ => self.render_byte(slot.state.to_u8()),
+                }
+            }
+            x if x == 2 + SLOTS_LEN - 1 => self.render_byte(slots.active.unwrap_or(NO_ACTIVE_SLOT)),
+            _ => (0, None),
+        }
+    }
+
+    fn render_auth_challenge(&mut self, nonce: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_AUTH_CHALLENGE),
+            _ => self.render_buffer(count - 2, auth::AUTH_NONCE_LEN, nonce),
+        }
+    }
+
     fn render_u16(&mut self, idx: usize, value: u16) -> (usize, Option<u8>) {
         match idx {
             0 => self.render_byte(value as u8),
@@ -1233,6 +2175,19 @@ impl<'a> Iterator for ResponseEncoder<'a> {
             &Response::CrcExtFlash { crc } => self.render_crc_ex_flash(crc),
             &Response::Info { info } => self.render_info(info),
             &Response::ChangeBaudFail => self.render_header(count, RES_CHANGE_BAUD_FAIL),
+            &Response::Id { id } => self.render_id(id),
+            Response::Capabilities { capabilities } => self.render_capabilities(capabilities),
+            Response::Slots { slots } => self.render_slots(slots),
+            &Response::AuthChallenge { nonce } => self.render_auth_challenge(nonce),
+            &Response::Unauthenticated => self.render_header(count, RES_UNAUTHENTICATED),
+            &Response::FlashError => self.render_header(count, RES_FLASH_ERROR),
+            &Response::VerifyFailed => self.render_header(count, RES_VERIFY_FAILED),
+            &Response::SkippedPages { count: skipped } => self.render_skipped_pages(skipped),
+            &Response::PageAck { address, result } => self.render_page_ack(address, result),
+            &Response::PagesWritten { crc } => self.render_pages_written(crc),
+            &Response::PageFailed { address, result } => self.render_page_failed(address, result),
+            &Response::EraseProgress { address } => self.render_erase_progress(address),
+            Response::HashIntFlash { hash } => self.render_hash_int_flash(hash),
         };
         self.count = self.count + inc;
         result
@@ -1950,81 +2905,505 @@ mod tests {
         }
     }
 
-    // Responses
-
-    fn check_rsp_generic(response: Response, cmd: u8) {
-        let mut p = ResponseDecoder::new();
+    #[test]
+    fn decode_cmd_get_capabilities() {
+        let mut p = CommandDecoder::new();
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        match p.receive(cmd) {
-            Ok(Some(ref x)) if x == &response => {}
+        match p.receive(CMD_GET_CAPABILITIES) {
+            Ok(Some(Command::GetCapabilities)) => {}
             e => panic!("Did not expect: {:?}", e),
         }
+    }
 
-        let mut e = ResponseEncoder::new(&response).unwrap();
+    #[test]
+    fn encode_cmd_get_capabilities() {
+        let cmd = Command::GetCapabilities;
+        let mut e = CommandEncoder::new(&cmd).unwrap();
         assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(cmd));
+        assert_eq!(e.next(), Some(CMD_GET_CAPABILITIES));
         assert_eq!(e.next(), None);
         assert_eq!(e.next(), None);
     }
 
     #[test]
-    fn check_rsp_overflow() {
-        check_rsp_generic(Response::Overflow, RES_OVERFLOW);
+    fn decode_cmd_get_slots() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        match p.receive(CMD_GET_SLOTS) {
+            Ok(Some(Command::GetSlots)) => {}
+            e => panic!("Did not expect: {:?}", e),
+        }
     }
 
     #[test]
-    fn check_rsp_pong() {
-        check_rsp_generic(Response::Pong, RES_PONG);
+    fn decode_cmd_get_skipped_pages() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        match p.receive(CMD_GET_SKIPPED_PAGES) {
+            Ok(Some(Command::GetSkippedPages)) => {}
+            e => panic!("Did not expect: {:?}", e),
+        }
     }
 
     #[test]
-    fn check_rsp_badaddress() {
-        check_rsp_generic(Response::BadAddress, RES_BADADDR);
+    fn encode_cmd_set_slot_pending() {
+        let cmd = Command::SetSlotPending {
+            slot: 1,
+            length: 0x00012345,
+            crc: 0xDEADBEEF,
+            version: 0x00020001,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        assert_eq!(e.next(), Some(0x01));
+        // 4 byte length, little-endian
+        assert_eq!(e.next(), Some(0x45));
+        assert_eq!(e.next(), Some(0x23));
+        assert_eq!(e.next(), Some(0x01));
+        assert_eq!(e.next(), Some(0x00));
+        // 4 byte CRC
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        // 4 byte version
+        assert_eq!(e.next(), Some(0x01));
+        assert_eq!(e.next(), Some(0x00));
+        assert_eq!(e.next(), Some(0x02));
+        assert_eq!(e.next(), Some(0x00));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_SSLOTPENDING));
+        assert_eq!(e.next(), None);
     }
 
     #[test]
-    fn check_rsp_internalerror() {
-        check_rsp_generic(Response::InternalError, RES_INTERROR);
+    fn decode_cmd_set_slot_pending() {
+        let mut p = CommandDecoder::new();
+        for b in [
+            0x01, 0x45, 0x23, 0x01, 0x00, 0xEF, 0xBE, 0xAD, 0xDE, 0x01, 0x00, 0x02, 0x00,
+        ]
+        .iter()
+        {
+            assert_eq!(p.receive(*b), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        assert_eq!(
+            p.receive(CMD_SSLOTPENDING),
+            Ok(Some(Command::SetSlotPending {
+                slot: 1,
+                length: 0x00012345,
+                crc: 0xDEADBEEF,
+                version: 0x00020001,
+            }))
+        );
     }
 
     #[test]
-    fn check_rsp_badarguments() {
-        check_rsp_generic(Response::BadArguments, RES_BADARGS);
+    fn encode_cmd_auth_response() {
+        let mac = [0xA5; 32];
+        let cmd = Command::AuthResponse { mac: &mac };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        for _ in 0..32 {
+            assert_eq!(e.next(), Some(0xA5));
+        }
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_AUTH_RESPONSE));
+        assert_eq!(e.next(), None);
+
+        // The MAC must be complete.
+        let cmd = Command::AuthResponse { mac: &mac[1..] };
+        assert!(CommandEncoder::new(&cmd).is_err());
     }
 
     #[test]
-    fn check_rsp_ok() {
-        check_rsp_generic(Response::Ok, RES_OK);
+    fn decode_cmd_auth_response() {
+        let mut p = CommandDecoder::new();
+        for i in 0..32 {
+            assert_eq!(p.receive(i), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_AUTH_RESPONSE) {
+            Ok(Some(Command::AuthResponse { mac })) => {
+                assert_eq!(mac.len(), 32);
+                assert_eq!(mac[31], 31);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+
+        // Too short.
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        assert_eq!(p.receive(CMD_AUTH_RESPONSE), Err(Error::BadArguments));
     }
 
     #[test]
-    fn check_rsp_unknown() {
-        check_rsp_generic(Response::Unknown, RES_UNKNOWN);
+    fn encode_cmd_begin_encrypted_image() {
+        let nonce = [0xFC, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+        let cmd = Command::BeginEncryptedImage {
+            address: 0x00010000,
+            nonce: &nonce,
+        };
+        let mut buffer = [0u8; 32];
+        let length = CommandEncoder::new(&cmd).unwrap().write(&mut buffer);
+        // Address, escaped nonce, escape and command.
+        assert_eq!(length, 4 + 13 + 2);
+        assert_eq!(&buffer[0..7], &[0x00, 0x00, 0x01, 0x00, 0xFC, 0xFC, 0x01]);
+        assert_eq!(&buffer[17..19], &[ESCAPE_CHAR, CMD_BEGIN_ENC_IMAGE]);
+
+        let mut p = CommandDecoder::new();
+        let (last, rest) = buffer[..length].split_last().unwrap();
+        for ch in rest {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(*last), Ok(Some(cmd)));
+
+        let cmd = Command::BeginEncryptedImage {
+            address: 0,
+            nonce: &nonce[1..],
+        };
+        assert!(CommandEncoder::new(&cmd).is_err());
     }
 
     #[test]
-    fn check_rsp_exflashtimeout() {
-        check_rsp_generic(Response::ExtFlashTimeout, RES_XFTIMEOUT);
+    fn encode_cmd_write_encrypted_page() {
+        let data = [0xA5; 512];
+        let cmd = Command::WriteEncryptedPage {
+            address: 0x00010200,
+            data: &data,
+        };
+        let mut buffer = [0u8; 600];
+        let length = CommandEncoder::new(&cmd).unwrap().write(&mut buffer);
+        assert_eq!(length, 4 + 512 + 2);
+        assert_eq!(&buffer[516..518], &[ESCAPE_CHAR, CMD_WRITE_ENC_PAGE]);
+
+        let mut p = CommandDecoder::new();
+        let (last, rest) = buffer[..length].split_last().unwrap();
+        for ch in rest {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(*last), Ok(Some(cmd)));
+
+        let cmd = Command::WriteEncryptedPage {
+            address: 0,
+            data: &data[1..],
+        };
+        assert!(CommandEncoder::new(&cmd).is_err());
     }
 
     #[test]
-    fn check_rsp_exflashpageerror() {
-        check_rsp_generic(Response::ExtFlashPageError, RES_XFEPE);
+    fn encode_cmd_write_stream_page() {
+        let data = [0xFC; 512];
+        let cmd = Command::WriteStreamPage {
+            address: 0x00010200,
+            data: &data,
+        };
+        let mut buffer = [0u8; 1100];
+        let length = CommandEncoder::new(&cmd).unwrap().write(&mut buffer);
+        assert_eq!(length, 4 + 2 * 512 + 2);
+        assert_eq!(
+            &buffer[length - 2..length],
+            &[ESCAPE_CHAR, CMD_WRITE_STREAM_PAGE]
+        );
+
+        let mut p = CommandDecoder::new();
+        let (last, rest) = buffer[..length].split_last().unwrap();
+        for ch in rest {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(*last), Ok(Some(cmd)));
+
+        let cmd = Command::WriteStreamPage {
+            address: 0,
+            data: &data[1..],
+        };
+        assert!(CommandEncoder::new(&cmd).is_err());
     }
 
     #[test]
-    fn check_rsp_changebaudfail() {
-        check_rsp_generic(Response::ChangeBaudFail, RES_CHANGE_BAUD_FAIL);
+    fn encode_cmd_write_pages() {
+        let mut data = [0xAA; 2 * 512];
+        data[512] = ESCAPE_CHAR;
+        let cmd = Command::WritePages {
+            address: 0x00010200,
+            count: 2,
+            data: &data,
+        };
+        let mut buffer = [0u8; 1100];
+        let length = CommandEncoder::new(&cmd).unwrap().write(&mut buffer);
+        assert_eq!(length, 5 + 2 * 512 + 1 + 2);
+        assert_eq!(&buffer[..5], &[0x00, 0x02, 0x01, 0x00, 2]);
+        assert_eq!(&buffer[length - 2..length], &[ESCAPE_CHAR, CMD_WRITE_PAGES]);
+
+        let mut p = CommandDecoder::new();
+        let (last, rest) = buffer[..length].split_last().unwrap();
+        for ch in rest {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(*last), Ok(Some(cmd)));
+
+        // The payload stays until the next command starts.
+        assert_eq!(&p.last_payload()[5..], &data[..]);
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert!(p.last_payload().is_empty());
+
+        for &(count, len) in &[(0, 0), (2, 512), (9, 9 * 512)] {
+            let data = [0; 9 * 512];
+            let cmd = Command::WritePages {
+                address: 0,
+                count,
+                data: &data[..len],
+            };
+            assert!(CommandEncoder::new(&cmd).is_err());
+        }
     }
 
     #[test]
-    fn check_rsp_crc_rx() {
-        let mut p = ResponseDecoder::new();
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        assert_eq!(p.receive(RES_CRCRX), Ok(None));
-        // Length
-        assert_eq!(p.receive(0x34), Ok(None));
-        assert_eq!(p.receive(0x12), Ok(None));
+    fn encode_cmd_write_page_compressed() {
+        let data = [0x1F, ESCAPE_CHAR, 0x01, 0x00, 0xFF, 0xED, 0x00];
+        let cmd = Command::WritePageCompressed {
+            address: 0x00010200,
+            data: &data,
+        };
+        let mut buffer = [0u8; 20];
+        let length = CommandEncoder::new(&cmd).unwrap().write(&mut buffer);
+        assert_eq!(length, 4 + data.len() + 1 + 2);
+        assert_eq!(
+            &buffer[length - 2..length],
+            &[ESCAPE_CHAR, CMD_WRITE_PAGE_COMPRESSED]
+        );
+
+        let mut p = CommandDecoder::new();
+        let (last, rest) = buffer[..length].split_last().unwrap();
+        for ch in rest {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(*last), Ok(Some(cmd)));
+
+        // No data.
+        let cmd = Command::WritePageCompressed {
+            address: 0,
+            data: &[],
+        };
+        assert!(CommandEncoder::new(&cmd).is_err());
+        for _ in 0..4 {
+            assert_eq!(p.receive(0x00), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(
+            p.receive(CMD_WRITE_PAGE_COMPRESSED),
+            Err(Error::BadArguments)
+        );
+    }
+
+    #[test]
+    fn encode_cmd_end_encrypted_image() {
+        let tag = [0x5A; 32];
+        let cmd = Command::EndEncryptedImage { tag: &tag };
+        let mut buffer = [0u8; 40];
+        let length = CommandEncoder::new(&cmd).unwrap().write(&mut buffer);
+        assert_eq!(length, 32 + 2);
+        assert_eq!(&buffer[32..34], &[ESCAPE_CHAR, CMD_END_ENC_IMAGE]);
+
+        let mut p = CommandDecoder::new();
+        let (last, rest) = buffer[..length].split_last().unwrap();
+        for ch in rest {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(*last), Ok(Some(cmd)));
+
+        // Too short.
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_END_ENC_IMAGE), Err(Error::BadArguments));
+    }
+
+    #[test]
+    fn encode_cmd_set_kernel_crc() {
+        let cmd = Command::SetKernelCrc {
+            length: 0x00012345,
+            crc: 0xDEADBEEF,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte length, little-endian
+        assert_eq!(e.next(), Some(0x45));
+        assert_eq!(e.next(), Some(0x23));
+        assert_eq!(e.next(), Some(0x01));
+        assert_eq!(e.next(), Some(0x00));
+        // 4 byte CRC
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_SKERNELCRC));
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_set_kernel_crc() {
+        let mut p = CommandDecoder::new();
+        for b in [0x45, 0x23, 0x01, 0x00, 0xEF, 0xBE, 0xAD, 0xDE].iter() {
+            assert_eq!(p.receive(*b), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_SKERNELCRC) {
+            Ok(Some(Command::SetKernelCrc { length, crc })) => {
+                assert_eq!(length, 0x00012345);
+                assert_eq!(crc, 0xDEADBEEF);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_erase_range() {
+        let cmd = Command::EraseRange {
+            address: 0x00040000,
+            length: 0x00002000,
+        };
+        let mut bytes = [0u8; 10];
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        for b in bytes.iter_mut() {
+            *b = e.next().unwrap();
+        }
+        assert_eq!(e.next(), None);
+        assert_eq!(
+            bytes,
+            [
+                0x00,
+                0x00,
+                0x04,
+                0x00,
+                0x00,
+                0x20,
+                0x00,
+                0x00,
+                ESCAPE_CHAR,
+                CMD_ERASE_RANGE
+            ]
+        );
+
+        let mut p = CommandDecoder::new();
+        for &b in &bytes[..bytes.len() - 1] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(CMD_ERASE_RANGE), Ok(Some(cmd)));
+
+        // The length is required.
+        for &b in &bytes[..4] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_ERASE_RANGE), Err(Error::BadArguments));
+    }
+
+    #[test]
+    fn encode_cmd_hash_int_flash() {
+        let cmd = Command::HashIntFlash {
+            address: 0x00040000,
+            length: 0x00012345,
+            algorithm: hash::HASH_SHA256,
+        };
+        let mut bytes = [0u8; 11];
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        for b in bytes.iter_mut() {
+            *b = e.next().unwrap();
+        }
+        assert_eq!(e.next(), None);
+        assert_eq!(
+            &bytes[..8],
+            &[0x00, 0x00, 0x04, 0x00, 0x45, 0x23, 0x01, 0x00]
+        );
+        assert_eq!(&bytes[8..], &[hash::HASH_SHA256, ESCAPE_CHAR, CMD_HASHIF]);
+
+        let mut p = CommandDecoder::new();
+        for &b in &bytes[..bytes.len() - 1] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(CMD_HASHIF), Ok(Some(cmd)));
+
+        // The algorithm is required.
+        for &b in &bytes[..8] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_HASHIF), Err(Error::BadArguments));
+    }
+
+    // Responses
+
+    fn check_rsp_generic(response: Response, cmd: u8) {
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        match p.receive(cmd) {
+            Ok(Some(ref x)) if x == &response => {}
+            e => panic!("Did not expect: {:?}", e),
+        }
+
+        let mut e = ResponseEncoder::new(&response).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(cmd));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn check_rsp_overflow() {
+        check_rsp_generic(Response::Overflow, RES_OVERFLOW);
+    }
+
+    #[test]
+    fn check_rsp_pong() {
+        check_rsp_generic(Response::Pong, RES_PONG);
+    }
+
+    #[test]
+    fn check_rsp_badaddress() {
+        check_rsp_generic(Response::BadAddress, RES_BADADDR);
+    }
+
+    #[test]
+    fn check_rsp_internalerror() {
+        check_rsp_generic(Response::InternalError, RES_INTERROR);
+    }
+
+    #[test]
+    fn check_rsp_badarguments() {
+        check_rsp_generic(Response::BadArguments, RES_BADARGS);
+    }
+
+    #[test]
+    fn check_rsp_ok() {
+        check_rsp_generic(Response::Ok, RES_OK);
+    }
+
+    #[test]
+    fn check_rsp_unknown() {
+        check_rsp_generic(Response::Unknown, RES_UNKNOWN);
+    }
+
+    #[test]
+    fn check_rsp_exflashtimeout() {
+        check_rsp_generic(Response::ExtFlashTimeout, RES_XFTIMEOUT);
+    }
+
+    #[test]
+    fn check_rsp_exflashpageerror() {
+        check_rsp_generic(Response::ExtFlashPageError, RES_XFEPE);
+    }
+
+    #[test]
+    fn check_rsp_changebaudfail() {
+        check_rsp_generic(Response::ChangeBaudFail, RES_CHANGE_BAUD_FAIL);
+    }
+
+    #[test]
+    fn check_rsp_crc_rx() {
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_CRCRX), Ok(None));
+        // Length
+        assert_eq!(p.receive(0x34), Ok(None));
+        assert_eq!(p.receive(0x12), Ok(None));
         // CRC
         assert_eq!(p.receive(0xEF), Ok(None));
         assert_eq!(p.receive(0xBE), Ok(None));
@@ -2202,6 +3581,128 @@ mod tests {
         assert_eq!(e.next(), None);
     }
 
+    #[test]
+    fn check_rsp_skipped_pages() {
+        let r = Response::SkippedPages { count: 0x1234 };
+        let mut buffer = [0u8; 8];
+        let length = ResponseEncoder::new(&r).unwrap().write(&mut buffer);
+        assert_eq!(
+            &buffer[..length],
+            &[ESCAPE_CHAR, RES_SKIPPED_PAGES, 0x34, 0x12, 0, 0]
+        );
+
+        let mut p = ResponseDecoder::new();
+        for &b in &buffer[..length - 1] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(0), Ok(Some(r)));
+    }
+
+    #[test]
+    fn check_rsp_page_ack() {
+        let r = Response::PageAck {
+            address: 0x00010200,
+            result: RES_OK,
+        };
+        let mut buffer = [0u8; 10];
+        let length = ResponseEncoder::new(&r).unwrap().write(&mut buffer);
+        assert_eq!(
+            &buffer[..length],
+            &[ESCAPE_CHAR, RES_PAGE_ACK, 0x00, 0x02, 0x01, 0x00, RES_OK]
+        );
+
+        let mut p = ResponseDecoder::new();
+        for &b in &buffer[..length - 1] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(RES_OK), Ok(Some(r)));
+    }
+
+    #[test]
+    fn check_rsp_pages_written() {
+        let r = Response::PagesWritten { crc: 0xDEADBEEF };
+        let mut buffer = [0u8; 10];
+        let length = ResponseEncoder::new(&r).unwrap().write(&mut buffer);
+        assert_eq!(
+            &buffer[..length],
+            &[ESCAPE_CHAR, RES_PAGES_WRITTEN, 0xEF, 0xBE, 0xAD, 0xDE]
+        );
+
+        let mut p = ResponseDecoder::new();
+        for &b in &buffer[..length - 1] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(0xDE), Ok(Some(r)));
+    }
+
+    #[test]
+    fn check_rsp_page_failed() {
+        let r = Response::PageFailed {
+            address: 0x00010400,
+            result: RES_FLASH_ERROR,
+        };
+        let mut buffer = [0u8; 10];
+        let length = ResponseEncoder::new(&r).unwrap().write(&mut buffer);
+        assert_eq!(
+            &buffer[..length],
+            &[
+                ESCAPE_CHAR,
+                RES_PAGE_FAILED,
+                0x00,
+                0x04,
+                0x01,
+                0x00,
+                RES_FLASH_ERROR
+            ]
+        );
+
+        let mut p = ResponseDecoder::new();
+        for &b in &buffer[..length - 1] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(RES_FLASH_ERROR), Ok(Some(r)));
+    }
+
+    #[test]
+    fn check_rsp_erase_progress() {
+        let r = Response::EraseProgress {
+            address: 0x00041000,
+        };
+        let mut buffer = [0u8; 10];
+        let length = ResponseEncoder::new(&r).unwrap().write(&mut buffer);
+        assert_eq!(
+            &buffer[..length],
+            &[ESCAPE_CHAR, RES_ERASE_PROGRESS, 0x00, 0x10, 0x04, 0x00]
+        );
+
+        let mut p = ResponseDecoder::new();
+        for &b in &buffer[..length - 1] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(0x00), Ok(Some(r)));
+    }
+
+    #[test]
+    fn check_rsp_hash_int_flash() {
+        let mut hash = [0u8; hash::HASH_LEN];
+        for (i, b) in hash.iter_mut().enumerate() {
+            *b = 0xE0 + i as u8;
+        }
+        let r = Response::HashIntFlash { hash };
+        let mut buffer = [0u8; 40];
+        let length = ResponseEncoder::new(&r).unwrap().write(&mut buffer);
+        // The hash holds an escape, which is doubled.
+        assert_eq!(length, 2 + hash::HASH_LEN + 1);
+        assert_eq!(&buffer[..2], &[ESCAPE_CHAR, RES_HASHIF]);
+        assert_eq!(&buffer[2 + 28..2 + 30], &[ESCAPE_CHAR, ESCAPE_CHAR]);
+
+        let mut p = ResponseDecoder::new();
+        for &b in &buffer[..length - 1] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(buffer[length - 1]), Ok(Some(r)));
+    }
+
     #[test]
     fn check_rsp_crc_ext_flash() {
         let mut p = ResponseDecoder::new();
@@ -2285,6 +3786,222 @@ mod tests {
         assert_eq!(e.next(), None);
     }
 
+    #[test]
+    fn check_rsp_id() {
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_ID), Ok(None));
+        // length
+        assert_eq!(p.receive(0x04), Ok(None));
+        // four bytes of ID, one of them escaped
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(0x03), Ok(None));
+        assert_eq!(p.receive(0x04), Ok(None));
+        // pad up to MAX_ID_LEN - 1 bytes
+        for _ in 4..MAX_ID_LEN - 1 {
+            assert_eq!(p.receive(0x00), Ok(None));
+        }
+        // final padding byte
+        assert_eq!(
+            p.receive(0x00),
+            Ok(Some(Response::Id {
+                id: &[0x01, ESCAPE_CHAR, 0x03, 0x04],
+            }))
+        );
+
+        // Check follow-on command
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_PONG), Ok(Some(Response::Pong)));
+
+        let r = Response::Id {
+            id: &[0x01, ESCAPE_CHAR, 0x03, 0x04],
+        };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(RES_ID));
+        // length
+        assert_eq!(e.next(), Some(0x04));
+        // data
+        assert_eq!(e.next(), Some(0x01));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(0x03));
+        assert_eq!(e.next(), Some(0x04));
+        // padding
+        for _ in 4..MAX_ID_LEN {
+            assert_eq!(e.next(), Some(0x00));
+        }
+        assert_eq!(e.next(), None);
+
+        // Too long
+        let r = Response::Id {
+            id: &[0u8; MAX_ID_LEN + 1],
+        };
+        assert!(ResponseEncoder::new(&r).is_err());
+    }
+
+    #[test]
+    fn check_rsp_capabilities() {
+        let mut capabilities = Capabilities {
+            protocol_version: PROTOCOL_VERSION,
+            commands: [0; 32],
+            page_size: 512,
+            flash_size: 0x100000,
+            bootloader_start: 0,
+            // Includes an escape character.
+            bootloader_end: 0x7FFC,
+            max_frame_size: 4224,
+        };
+        capabilities.set_supported(CMD_PING);
+        capabilities.set_supported(CMD_GET_CAPABILITIES);
+        assert!(capabilities.supports(CMD_PING));
+        assert!(capabilities.supports(CMD_GET_CAPABILITIES));
+        assert!(!capabilities.supports(CMD_CLKOUT));
+
+        let r = Response::Capabilities { capabilities };
+        let mut buffer = [0u8; 64];
+        let length = ResponseEncoder::new(&r).unwrap().write(&mut buffer);
+        let encoded = &buffer[..length];
+        // Header, payload and one extra byte for the escape.
+        assert_eq!(length, 2 + 54 + 1);
+        assert_eq!(&encoded[0..4], &[ESCAPE_CHAR, RES_CAPABILITIES, 0x01, 0x00]);
+        // PING is bit 1 of byte 0, GET_CAPABILITIES bit 4 of byte 4.
+        assert_eq!(&encoded[4..9], &[0x02, 0x00, 0x00, 0x00, 0x10]);
+        assert_eq!(&encoded[48..53], &[0xFC, 0xFC, 0x7F, 0x00, 0x00]);
+
+        let mut p = ResponseDecoder::new();
+        let (last, rest) = encoded.split_last().unwrap();
+        for ch in rest {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(
+            p.receive(*last),
+            Ok(Some(Response::Capabilities { capabilities }))
+        );
+
+        // Check follow-on command
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_PONG), Ok(Some(Response::Pong)));
+    }
+
+    #[test]
+    fn check_rsp_slots() {
+        let slots = KernelSlots {
+            slots: [
+                KernelSlot {
+                    address: 0x10000,
+                    size: 0x70000,
+                    length: 0x2345,
+                    // Includes an escape character.
+                    crc: 0x12FC3456,
+                    version: 3,
+                    state: SlotState::Confirmed,
+                },
+                KernelSlot {
+                    address: 0x80000,
+                    size: 0x70000,
+                    state: SlotState::Empty,
+                    ..KernelSlot::default()
+                },
+            ],
+            active: Some(0),
+        };
+        let r = Response::Slots { slots };
+        let mut buffer = [0u8; 64];
+        let length = ResponseEncoder::new(&r).unwrap().write(&mut buffer);
+        let encoded = &buffer[..length];
+        // Header, payload and one extra byte for the escape.
+        assert_eq!(length, 2 + 43 + 1);
+        assert_eq!(
+            &encoded[0..6],
+            &[ESCAPE_CHAR, RES_SLOTS, 0x00, 0x00, 0x01, 0x00]
+        );
+        assert_eq!(&encoded[14..19], &[0x56, 0x34, 0xFC, 0xFC, 0x12]);
+        assert_eq!(encoded[23], 0x03);
+        assert_eq!(encoded[length - 1], 0x00);
+
+        let mut p = ResponseDecoder::new();
+        let (last, rest) = encoded.split_last().unwrap();
+        for ch in rest {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(*last), Ok(Some(Response::Slots { slots })));
+    }
+
+    #[test]
+    fn check_rsp_auth_challenge() {
+        let mut nonce = [0x11; 32];
+        // Includes an escape character.
+        nonce[5] = ESCAPE_CHAR;
+        let r = Response::AuthChallenge { nonce: &nonce };
+        let mut buffer = [0u8; 64];
+        let length = ResponseEncoder::new(&r).unwrap().write(&mut buffer);
+        let encoded = &buffer[..length];
+        assert_eq!(length, 2 + 32 + 1);
+        assert_eq!(&encoded[0..3], &[ESCAPE_CHAR, RES_AUTH_CHALLENGE, 0x11]);
+
+        let mut p = ResponseDecoder::new();
+        let (last, rest) = encoded.split_last().unwrap();
+        for ch in rest {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(
+            p.receive(*last),
+            Ok(Some(Response::AuthChallenge { nonce: &nonce }))
+        );
+
+        // Check follow-on response
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(
+            p.receive(RES_UNAUTHENTICATED),
+            Ok(Some(Response::Unauthenticated))
+        );
+    }
+
+    #[test]
+    fn check_rsp_flash_error() {
+        let mut buffer = [0u8; 4];
+        let length = ResponseEncoder::new(&Response::FlashError)
+            .unwrap()
+            .write(&mut buffer);
+        assert_eq!(&buffer[..length], &[ESCAPE_CHAR, RES_FLASH_ERROR]);
+
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_FLASH_ERROR), Ok(Some(Response::FlashError)));
+    }
+
+    #[test]
+    fn check_rsp_verify_failed() {
+        let mut buffer = [0u8; 4];
+        let length = ResponseEncoder::new(&Response::VerifyFailed)
+            .unwrap()
+            .write(&mut buffer);
+        assert_eq!(&buffer[..length], &[ESCAPE_CHAR, RES_VERIFY_FAILED]);
+
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(
+            p.receive(RES_VERIFY_FAILED),
+            Ok(Some(Response::VerifyFailed))
+        );
+    }
+
+    #[test]
+    fn check_rsp_slots_bad_state() {
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_SLOTS), Ok(None));
+        for i in 0..42 {
+            // Neither slot has a valid state.
+            let b = if i % 21 == 20 { 0x42 } else { 0x00 };
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(0xFF), Err(Error::BadArguments));
+    }
+
     #[test]
     fn check_response_write() {
         let r = Response::Pong;
@@ -2341,6 +4058,69 @@ mod tests {
         assert_eq!(&buffer[0..2], &[ESCAPE_CHAR, CMD_PING]);
     }
 
+    #[test]
+    fn check_cmd_too_big() {
+        let mut p = CommandDecoder::<4>::with_capacity();
+        // An EPAGE with one byte too many, including an escaped escape.
+        for ch in &[0x00, 0x00, ESCAPE_CHAR, ESCAPE_CHAR, 0x00, 0x00] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_EPAGE), Err(Error::BufferTooSmall));
+
+        // The next command that fits is decoded as normal.
+        for ch in &[0x00, 0x00, 0x01, 0x00, ESCAPE_CHAR] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(
+            p.receive(CMD_EPAGE),
+            Ok(Some(Command::ErasePage { address: 0x10000 }))
+        );
+    }
+
+    #[test]
+    fn check_cmd_reset_clears_too_big() {
+        let mut p = CommandDecoder::<4>::with_capacity();
+        for ch in &[0x00, 0x00, 0x00, 0x00, 0x00] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        p.reset();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+    }
+
+    #[test]
+    fn check_cmd_in_command() {
+        let mut p = CommandDecoder::new();
+        assert!(!p.in_command());
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert!(p.in_command());
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        assert!(!p.in_command());
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert!(p.in_command());
+        p.reset();
+        assert!(!p.in_command());
+    }
+
+    #[test]
+    fn check_rsp_too_big() {
+        let mut p = ResponseDecoder::<5>::with_capacity();
+        assert_eq!(p.set_payload_len(5), Err(Error::BufferTooSmall));
+        p.set_payload_len(4).unwrap();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_RRANGE), Ok(None));
+        for ch in &[0x00, 0x11, 0x22] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(
+            p.receive(0x33),
+            Ok(Some(Response::ReadRange {
+                data: &[0x00, 0x11, 0x22, 0x33]
+            }))
+        );
+    }
+
     #[test]
     fn check_command_decode_buffer() {
         let mut p = CommandDecoder::new();
@@ -0,0 +1,105 @@
+//! The signature footer of kernel images for secure boot.
+//!
+//! A signed image is the kernel followed by a 72 byte footer:
+//!
+//! ```text
+//! | Kernel... | "TKSG" | Kernel length | Ed25519 signature (64 bytes) |
+//! ```
+//!
+//! The kernel length is little endian. The signature covers everything before
+//! it, so the kernel as well as the magic and length. The host writes the
+//! whole image to flash, and gives its length to the bootloader with
+//! `SET_KERNEL_CRC` (or `SET_SLOT_PENDING` for kernel slots) so the footer can
+//! be found.
+
+use super::Error;
+
+/// Marks the start of the footer.
+pub const SIGNATURE_MAGIC: [u8; 4] = *b"TKSG";
+
+/// Length of an Ed25519 signature.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Length of an Ed25519 public key.
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// Length of the footer after the kernel.
+pub const SIGNATURE_FOOTER_LEN: usize = 8 + SIGNATURE_LEN;
+
+/// A signed image split into its parts.
+#[derive(Debug, PartialEq)]
+pub struct SignedImage<'a> {
+    /// The kernel without the footer.
+    pub kernel: &'a [u8],
+    /// The bytes covered by the signature.
+    pub message: &'a [u8],
+    /// The signature.
+    pub signature: &'a [u8],
+}
+
+impl<'a> SignedImage<'a> {
+    /// Split a signed image. Returns `Err(Error::BadArguments)` if `image`
+    /// does not end with a footer for a kernel of the right length.
+    pub fn parse(image: &'a [u8]) -> Result<SignedImage<'a>, Error> {
+        if image.len() < SIGNATURE_FOOTER_LEN {
+            return Err(Error::BadArguments);
+        }
+        let kernel_length = image.len() - SIGNATURE_FOOTER_LEN;
+        let (message, signature) = image.split_at(kernel_length + 8);
+        if message[kernel_length..kernel_length + 4] != SIGNATURE_MAGIC
+            || message[kernel_length + 4..] != (kernel_length as u32).to_le_bytes()
+        {
+            return Err(Error::BadArguments);
+        }
+        Ok(SignedImage {
+            kernel: &message[..kernel_length],
+            message,
+            signature,
+        })
+    }
+}
+
+/// The part of the footer that goes between the kernel and the signature,
+/// for a kernel of `kernel_length` bytes. The host signs the kernel followed
+/// by this, then appends this and the signature to the kernel.
+pub fn footer_header(kernel_length: u32) -> [u8; 8] {
+    let mut header = [0; 8];
+    header[..4].copy_from_slice(&SIGNATURE_MAGIC);
+    header[4..].copy_from_slice(&kernel_length.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(kernel: &[u8]) -> [u8; 4 + SIGNATURE_FOOTER_LEN] {
+        let mut image = [0xAA; 4 + SIGNATURE_FOOTER_LEN];
+        image[..4].copy_from_slice(kernel);
+        image[4..12].copy_from_slice(&footer_header(4));
+        image
+    }
+
+    #[test]
+    fn parse() {
+        let image = image(&[1, 2, 3, 4]);
+        let signed = SignedImage::parse(&image).unwrap();
+        assert_eq!(signed.kernel, &[1, 2, 3, 4]);
+        assert_eq!(signed.message, &image[..12]);
+        assert_eq!(signed.signature, &[0xAA; SIGNATURE_LEN][..]);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let image = image(&[1, 2, 3, 4]);
+        // Too short.
+        assert_eq!(SignedImage::parse(&image[5..]), Err(Error::BadArguments));
+        // The length no longer matches.
+        assert_eq!(
+            SignedImage::parse(&image[..image.len() - 1]),
+            Err(Error::BadArguments)
+        );
+        // No footer.
+        assert_eq!(SignedImage::parse(&[0; 80]), Err(Error::BadArguments));
+    }
+}
@@ -1,5 +1,5 @@
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::iter;
 use std::path::Path;
@@ -78,6 +78,39 @@ pub static ATTRIBUTE_{}: [u8; 64] = [
     let _ = write!(dest, " ]; ");
 }
 
+/// Writes the public key kernels must be signed with for secure boot, as
+/// `KERNEL_PUBLIC_KEY`. This is a plain static in the bootloader binary rather
+/// than an attribute, so the host cannot change it.
+pub fn write_public_key<W: Write>(dest: &mut W, public_key: &[u8; 32]) {
+    let _ = write!(
+        dest,
+        "
+pub static KERNEL_PUBLIC_KEY: [u8; 32] = [
+    "
+    );
+
+    for byte in public_key.iter() {
+        let _ = write!(dest, "{:#x}, ", byte);
+    }
+
+    // And finish the array
+    let _ = write!(dest, " ]; ");
+}
+
+/// Reads a public key file written by `bootloader_sign keygen`: 64 hex
+/// digits.
+pub fn read_public_key<P: AsRef<Path>>(path: P) -> [u8; 32] {
+    let hex = fs::read_to_string(path).unwrap();
+    let hex = hex.trim();
+    assert_eq!(hex.len(), 64, "public key must be 64 hex digits");
+
+    let mut public_key = [0; 32];
+    for (i, byte) in public_key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    public_key
+}
+
 pub fn get_file() -> File {
     let out_dir = env::var("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join(ATTRIBUTES_FILE);
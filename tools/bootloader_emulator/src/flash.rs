@@ -0,0 +1,174 @@
+//! Flash model backed by an image file.
+//!
+//! Every operation is queued when it is requested and performed on the next
+//! call to `service()`, which then calls the client. This mimics the split
+//! phase behavior of a real flash controller.
+
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use kernel::hil;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// A page of emulated flash. The page size is chosen at runtime.
+pub struct EmulatorPage(Vec<u8>);
+
+impl EmulatorPage {
+    pub fn new(size: usize) -> EmulatorPage {
+        EmulatorPage(vec![0; size])
+    }
+}
+
+impl Default for EmulatorPage {
+    fn default() -> Self {
+        EmulatorPage::new(512)
+    }
+}
+
+impl AsMut<[u8]> for EmulatorPage {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Read { page_number: usize },
+    Write { page_number: usize },
+    Erase { page_number: usize },
+}
+
+pub struct FileFlash {
+    file: RefCell<File>,
+    page_size: usize,
+    num_pages: usize,
+    client: OptionalCell<&'static dyn hil::flash::Client<FileFlash>>,
+    operation: Cell<Option<Operation>>,
+    buffer: TakeCell<'static, EmulatorPage>,
+}
+
+impl FileFlash {
+    /// Create a flash model over `file`, which must already be `flash_size`
+    /// bytes long.
+    pub fn new(file: File, page_size: usize, flash_size: usize) -> FileFlash {
+        FileFlash {
+            file: RefCell::new(file),
+            page_size,
+            num_pages: flash_size / page_size,
+            client: OptionalCell::empty(),
+            operation: Cell::new(None),
+            buffer: TakeCell::empty(),
+        }
+    }
+
+    /// Perform the pending operation, if any, and signal the client.
+    ///
+    /// Returns `true` if there was something to do.
+    pub fn service(&self) -> bool {
+        let operation = match self.operation.take() {
+            Some(operation) => operation,
+            None => return false,
+        };
+
+        match operation {
+            Operation::Read { page_number } => {
+                if let Some(page) = self.buffer.take() {
+                    let error = status(self.read_at(page_number, page.as_mut()));
+                    self.client
+                        .map(move |client| client.read_complete(page, error));
+                }
+            }
+            Operation::Write { page_number } => {
+                if let Some(page) = self.buffer.take() {
+                    let error = status(self.write_at(page_number, page.as_mut()));
+                    self.client
+                        .map(move |client| client.write_complete(page, error));
+                }
+            }
+            Operation::Erase { page_number } => {
+                let erased = vec![0xFF; self.page_size];
+                let error = status(self.write_at(page_number, &erased));
+                self.client.map(|client| client.erase_complete(error));
+            }
+        }
+        true
+    }
+
+    fn read_at(&self, page_number: usize, buf: &mut [u8]) -> io::Result<()> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start((page_number * self.page_size) as u64))?;
+        file.read_exact(buf)
+    }
+
+    fn write_at(&self, page_number: usize, buf: &[u8]) -> io::Result<()> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start((page_number * self.page_size) as u64))?;
+        file.write_all(buf)?;
+        file.flush()
+    }
+
+    /// Check that a new operation on `page_number` can be started.
+    fn check(&self, page_number: usize) -> Result<(), ErrorCode> {
+        if self.operation.get().is_some() {
+            Err(ErrorCode::BUSY)
+        } else if page_number >= self.num_pages {
+            Err(ErrorCode::INVAL)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn status(result: io::Result<()>) -> hil::flash::Error {
+    match result {
+        Ok(()) => hil::flash::Error::CommandComplete,
+        Err(e) => {
+            eprintln!("flash: image file access failed: {}", e);
+            hil::flash::Error::FlashError
+        }
+    }
+}
+
+impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for FileFlash {
+    fn set_client(&self, client: &'static C) {
+        self.client.set(client);
+    }
+}
+
+impl hil::flash::Flash for FileFlash {
+    type Page = EmulatorPage;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        if let Err(e) = self.check(page_number) {
+            return Err((e, buf));
+        }
+        self.buffer.replace(buf);
+        self.operation.set(Some(Operation::Read { page_number }));
+        Ok(())
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        if let Err(e) = self.check(page_number) {
+            return Err((e, buf));
+        }
+        self.buffer.replace(buf);
+        self.operation.set(Some(Operation::Write { page_number }));
+        Ok(())
+    }
+
+    fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+        self.check(page_number)?;
+        self.operation.set(Some(Operation::Erase { page_number }));
+        Ok(())
+    }
+}
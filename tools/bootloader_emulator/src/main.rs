@@ -0,0 +1,306 @@
+//! Virtual Tock bootloader running on the host.
+//!
+//! This runs the real `bootloader::bootloader::Bootloader` state machine on
+//! top of a flash model backed by an image file, and exposes the bootloader
+//! UART on a pseudo-terminal (the default) or a Unix socket. This makes it
+//! possible to test flashing tools and scripts without a board:
+//!
+//! ```text
+//! $ cargo run -- --image flash.bin
+//! Tock bootloader emulator listening on pseudo-terminal /dev/pts/4
+//! $ tockloader list --port /dev/pts/4
+//! ```
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::iter;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use kernel::hil;
+
+use bootloader::bootloader::{Bootloader, FlashLayout};
+use bootloader::flash_large_to_small::{FiveTwelvePage, FlashLargeToSmall};
+
+mod flash;
+mod uart;
+
+use flash::{EmulatorPage, FileFlash};
+use uart::{HostUart, Port};
+
+// The bootloader crate refers to these linker script symbols. The emulator
+// passes its flash layout explicitly, but the symbols still have to exist for
+// the binary to link.
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static _flags_address: u8 = 0;
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static _attributes_address: u8 = 0;
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static _stext: u8 = 0;
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static _etext: u8 = 0;
+
+/// Page size used by the bootloader protocol.
+const PROTOCOL_PAGE_SIZE: usize = 512;
+
+/// Version string written into the flags of new images.
+const BOOTLOADER_VERSION: &str = "1.1.3";
+
+const USAGE: &str = "\
+Usage: bootloader_emulator --image <file> [options]
+
+Options:
+  --image <file>               Flash image file. Created if it does not exist.
+  --flash-size <bytes>         Size of the emulated flash [default: 0x100000]
+  --page-size <bytes>          Hardware page size, a multiple of 512 [default: 512]
+  --flags-address <addr>       Address of the flags region [default: 0x400]
+  --attributes-address <addr>  Address of the attributes region [default: 0x600]
+  --bootloader-start <addr>    Start of the protected bootloader range [default: 0x0]
+  --bootloader-end <addr>      End of the protected bootloader range [default: 0x8000]
+  --board <name>               Board attribute for new images [default: emulator]
+  --arch <name>                Arch attribute for new images [default: cortex-m4]
+  --socket <path>              Listen on a Unix socket instead of a pseudo-terminal
+";
+
+struct Config {
+    image: PathBuf,
+    flash_size: usize,
+    page_size: usize,
+    flags_address: usize,
+    attributes_address: usize,
+    bootloader_start: u32,
+    bootloader_end: u32,
+    board: String,
+    arch: String,
+    socket: Option<PathBuf>,
+}
+
+impl Config {
+    fn from_args() -> Result<Config, String> {
+        let mut config = Config {
+            image: PathBuf::new(),
+            flash_size: 0x100000,
+            page_size: PROTOCOL_PAGE_SIZE,
+            flags_address: 0x400,
+            attributes_address: 0x600,
+            bootloader_start: 0x0,
+            bootloader_end: 0x8000,
+            board: String::from("emulator"),
+            arch: String::from("cortex-m4"),
+            socket: None,
+        };
+
+        let mut image = None;
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("missing value for {}", arg))
+            };
+            match arg.as_str() {
+                "--image" => image = Some(PathBuf::from(value()?)),
+                "--flash-size" => config.flash_size = parse_number(&value()?)? as usize,
+                "--page-size" => config.page_size = parse_number(&value()?)? as usize,
+                "--flags-address" => config.flags_address = parse_number(&value()?)? as usize,
+                "--attributes-address" => {
+                    config.attributes_address = parse_number(&value()?)? as usize
+                }
+                "--bootloader-start" => config.bootloader_start = parse_number(&value()?)?,
+                "--bootloader-end" => config.bootloader_end = parse_number(&value()?)?,
+                "--board" => config.board = value()?,
+                "--arch" => config.arch = value()?,
+                "--socket" => config.socket = Some(PathBuf::from(value()?)),
+                "-h" | "--help" => return Err(String::new()),
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+
+        config.image = image.ok_or("--image is required")?;
+        if config.page_size == 0 || config.page_size % PROTOCOL_PAGE_SIZE != 0 {
+            return Err(String::from("page size must be a multiple of 512"));
+        }
+        if config.flash_size % config.page_size != 0 {
+            return Err(String::from(
+                "flash size must be a multiple of the page size",
+            ));
+        }
+        if config.attributes_address + 1024 > config.flash_size {
+            return Err(String::from("flags and attributes must be inside flash"));
+        }
+        Ok(config)
+    }
+
+    fn layout(&self) -> FlashLayout {
+        FlashLayout {
+            flags_address: self.flags_address,
+            attributes_address: self.attributes_address,
+            bootloader_address: self.bootloader_start,
+            bootloader_end_address: self.bootloader_end,
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Result<u32, String> {
+    let parsed = if let Some(hex) = s.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    parsed.map_err(|_| format!("invalid number {}", s))
+}
+
+/// Open the flash image, creating and initializing it if needed.
+fn open_image(config: &Config) -> std::io::Result<File> {
+    let exists = config.image.exists();
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&config.image)?;
+
+    if !exists {
+        // Erased flash.
+        file.write_all(&vec![0xFF; config.flash_size])?;
+
+        // Flags region, matching what `bootloader_attributes` generates.
+        let mut flags = Vec::new();
+        flags.extend(b"TOCKBOOTLOADER");
+        flags.extend(BOOTLOADER_VERSION.bytes().chain(iter::repeat(0)).take(8));
+        flags.extend(iter::repeat(0).take(10));
+        flags.extend(&config.bootloader_end.to_le_bytes());
+        flags.resize(512, 0);
+        file.seek(SeekFrom::Start(config.flags_address as u64))?;
+        file.write_all(&flags)?;
+
+        // Attributes region with the board and arch attributes set.
+        let mut attributes = vec![0; 1024];
+        for (i, (key, value)) in [("board", &config.board), ("arch", &config.arch)]
+            .iter()
+            .enumerate()
+        {
+            let attribute = &mut attributes[i * 64..(i + 1) * 64];
+            for (j, b) in key.bytes().take(8).enumerate() {
+                attribute[j] = b;
+            }
+            let value = &value.as_bytes()[..value.len().min(55)];
+            attribute[8] = value.len() as u8;
+            attribute[9..9 + value.len()].copy_from_slice(value);
+        }
+        file.seek(SeekFrom::Start(config.attributes_address as u64))?;
+        file.write_all(&attributes)?;
+        file.flush()?;
+    } else if file.metadata()?.len() != config.flash_size as u64 {
+        file.set_len(config.flash_size as u64)?;
+    }
+
+    Ok(file)
+}
+
+/// Called when the bootloader gets the `EXIT` command. A real board would
+/// reset into the kernel.
+fn bootloader_exit() {
+    eprintln!("Bootloader exited");
+    process::exit(0);
+}
+
+/// Run the emulator until the bootloader exits.
+fn run(uart: &'static HostUart, file_flash: &'static FileFlash) -> ! {
+    loop {
+        let flash_busy = file_flash.service();
+        let uart_busy = uart.service();
+        if !flash_busy && !uart_busy {
+            thread::sleep(Duration::from_micros(200));
+        }
+    }
+}
+
+fn main() {
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("error: {}\n", e);
+            }
+            eprint!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    let file = open_image(&config).unwrap_or_else(|e| {
+        eprintln!("error: could not open {}: {}", config.image.display(), e);
+        process::exit(1);
+    });
+
+    let port = match config.socket {
+        Some(ref path) => Port::unix_socket(path),
+        None => Port::pty(),
+    }
+    .unwrap_or_else(|e| {
+        eprintln!("error: could not create port: {}", e);
+        process::exit(1);
+    });
+    println!("Tock bootloader emulator listening on {}", port.describe());
+
+    let uart: &'static HostUart = Box::leak(Box::new(HostUart::new(port)));
+    let file_flash: &'static FileFlash = Box::leak(Box::new(FileFlash::new(
+        file,
+        config.page_size,
+        config.flash_size,
+    )));
+    let reset_function: &'static dyn Fn() = &bootloader_exit;
+    let buffer = unsafe { &mut bootloader::bootloader::BUF };
+
+    if config.page_size == PROTOCOL_PAGE_SIZE {
+        let pagebuffer = Box::leak(Box::new(EmulatorPage::new(PROTOCOL_PAGE_SIZE)));
+        let bootloader: &'static Bootloader<'static, HostUart, FileFlash> =
+            Box::leak(Box::new(Bootloader::new_with_layout(
+                uart,
+                file_flash,
+                reset_function,
+                pagebuffer,
+                buffer,
+                config.layout(),
+            )));
+        hil::flash::HasClient::set_client(file_flash, bootloader);
+        hil::uart::Transmit::set_transmit_client(uart, bootloader);
+        hil::uart::Receive::set_receive_client(uart, bootloader);
+        bootloader.set_flash_size(config.flash_size as u32);
+        bootloader.start();
+    } else {
+        // Map the bootloader's 512 byte pages onto the larger hardware pages
+        // the same way boards with larger flash pages do.
+        let large_pagebuffer = Box::leak(Box::new(EmulatorPage::new(config.page_size)));
+        let flash_adapter: &'static FlashLargeToSmall<'static, FileFlash> = Box::leak(Box::new(
+            FlashLargeToSmall::new(file_flash, large_pagebuffer),
+        ));
+        hil::flash::HasClient::set_client(file_flash, flash_adapter);
+
+        let pagebuffer = Box::leak(Box::<FiveTwelvePage>::default());
+        let bootloader: &'static Bootloader<
+            'static,
+            HostUart,
+            FlashLargeToSmall<'static, FileFlash>,
+        > = Box::leak(Box::new(Bootloader::new_with_layout(
+            uart,
+            flash_adapter,
+            reset_function,
+            pagebuffer,
+            buffer,
+            config.layout(),
+        )));
+        hil::flash::HasClient::set_client(flash_adapter, bootloader);
+        hil::uart::Transmit::set_transmit_client(uart, bootloader);
+        hil::uart::Receive::set_receive_client(uart, bootloader);
+        bootloader.set_flash_size(config.flash_size as u32);
+        bootloader.start();
+    }
+
+    run(uart, file_flash);
+}
@@ -0,0 +1,361 @@
+//! UART model that talks to the host over a pseudo-terminal or a Unix socket.
+//!
+//! Like the flash model, transmits and receives complete from `service()`.
+//! `receive_automatic()` completes once bytes have arrived and the line has
+//! then been idle for `RECEIVE_IDLE_TIMEOUT`, mirroring the interbyte timeout
+//! of the hardware implementations.
+
+use std::cell::{Cell, RefCell};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use kernel::hil;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// How long the line must be idle before an automatic receive completes.
+const RECEIVE_IDLE_TIMEOUT: Duration = Duration::from_millis(5);
+
+/// The host side connection the emulated UART is exposed on.
+pub enum Port {
+    /// A pseudo-terminal. Tools open the slave side like a serial port.
+    Pty {
+        master: File,
+        slave_name: String,
+        /// Keep the slave open so reads on the master don't fail while no
+        /// tool is connected.
+        _slave: File,
+    },
+    /// A Unix socket. One client is served at a time.
+    Socket {
+        listener: UnixListener,
+        stream: Option<UnixStream>,
+    },
+}
+
+impl Port {
+    /// Create a new pseudo-terminal in raw mode.
+    pub fn pty() -> io::Result<Port> {
+        let mut master = 0;
+        let mut slave = 0;
+        unsafe {
+            if libc::openpty(
+                &mut master,
+                &mut slave,
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+            ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Raw mode so that the protocol bytes pass through untouched.
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(slave, &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave, libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let flags = libc::fcntl(master, libc::F_GETFL);
+            if libc::fcntl(master, libc::F_SETFL, flags | libc::O_NONBLOCK) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let name = libc::ttyname(slave);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let slave_name = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+            Ok(Port::Pty {
+                master: File::from_raw_fd(master),
+                slave_name,
+                _slave: File::from_raw_fd(slave),
+            })
+        }
+    }
+
+    /// Listen on a Unix socket at `path`.
+    pub fn unix_socket(path: &Path) -> io::Result<Port> {
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Port::Socket {
+            listener,
+            stream: None,
+        })
+    }
+
+    /// Describe where tools should connect to.
+    pub fn describe(&self) -> String {
+        match self {
+            Port::Pty { slave_name, .. } => format!("pseudo-terminal {}", slave_name),
+            Port::Socket { listener, .. } => match listener.local_addr() {
+                Ok(addr) => format!("unix socket {:?}", addr),
+                Err(_) => String::from("unix socket"),
+            },
+        }
+    }
+
+    /// Read whatever is available without blocking.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = match self {
+            Port::Pty { master, .. } => master.read(buf),
+            Port::Socket { listener, stream } => {
+                if stream.is_none() {
+                    match listener.accept() {
+                        Ok((new_stream, _)) => {
+                            new_stream.set_nonblocking(true)?;
+                            *stream = Some(new_stream);
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(0),
+                        Err(e) => return Err(e),
+                    }
+                }
+                let result = stream.as_mut().map_or(Ok(0), |s| s.read(buf));
+                if let Ok(0) = result {
+                    // The client hung up, wait for the next one.
+                    *stream = None;
+                }
+                result
+            }
+        };
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            r => r,
+        }
+    }
+
+    fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let result = match self {
+                Port::Pty { master, .. } => master.write(buf),
+                // Nobody to send to, so the bytes are lost like they would be
+                // on a disconnected serial line.
+                Port::Socket { stream: None, .. } => Ok(buf.len()),
+                Port::Socket {
+                    stream: Some(stream),
+                    ..
+                } => stream.write(buf),
+            };
+            match result {
+                Ok(count) => buf = &buf[count..],
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct HostUart {
+    port: RefCell<Port>,
+    tx_client: OptionalCell<&'static dyn hil::uart::TransmitClient>,
+    rx_client: OptionalCell<&'static dyn hil::uart::ReceiveClient>,
+
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_index: Cell<usize>,
+    rx_automatic: Cell<bool>,
+    rx_last_byte: Cell<Option<Instant>>,
+
+    baud_rate: Cell<u32>,
+}
+
+impl HostUart {
+    pub fn new(port: Port) -> HostUart {
+        HostUart {
+            port: RefCell::new(port),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_index: Cell::new(0),
+            rx_automatic: Cell::new(false),
+            rx_last_byte: Cell::new(None),
+            baud_rate: Cell::new(0),
+        }
+    }
+
+    /// Move bytes to and from the host and signal any completed operations.
+    ///
+    /// Returns `true` if anything happened.
+    pub fn service(&self) -> bool {
+        let mut busy = false;
+
+        if let Some(buffer) = self.tx_buffer.take() {
+            busy = true;
+            let len = self.tx_len.get();
+            let result = self
+                .port
+                .borrow_mut()
+                .write_all(&buffer[..len])
+                .map_err(|e| {
+                    eprintln!("uart: write failed: {}", e);
+                    ErrorCode::FAIL
+                });
+            self.tx_client
+                .map(move |client| client.transmitted_buffer(buffer, len, result));
+        }
+
+        if self.rx_buffer.is_some() {
+            let received = self.rx_buffer.map_or(Ok(0), |buffer| {
+                let start = self.rx_index.get();
+                self.port
+                    .borrow_mut()
+                    .read(&mut buffer[start..self.rx_len.get()])
+            });
+            match received {
+                Ok(0) => {}
+                Ok(count) => {
+                    busy = true;
+                    self.rx_index.set(self.rx_index.get() + count);
+                    self.rx_last_byte.set(Some(Instant::now()));
+                }
+                Err(e) => eprintln!("uart: read failed: {}", e),
+            }
+
+            let full = self.rx_index.get() == self.rx_len.get();
+            let idle = self.rx_automatic.get()
+                && self
+                    .rx_last_byte
+                    .get()
+                    .map_or(false, |last| last.elapsed() >= RECEIVE_IDLE_TIMEOUT);
+            if full || idle {
+                busy = true;
+                let len = self.rx_index.get();
+                self.rx_last_byte.set(None);
+                if let Some(buffer) = self.rx_buffer.take() {
+                    self.rx_client.map(move |client| {
+                        client.received_buffer(buffer, len, Ok(()), hil::uart::Error::None)
+                    });
+                }
+            }
+        }
+
+        busy
+    }
+
+    fn start_receive(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        automatic: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.rx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+        if rx_len == 0 || rx_len > rx_buffer.len() {
+            return Err((ErrorCode::SIZE, rx_buffer));
+        }
+        self.rx_len.set(rx_len);
+        self.rx_index.set(0);
+        self.rx_automatic.set(automatic);
+        self.rx_last_byte.set(None);
+        self.rx_buffer.replace(rx_buffer);
+        Ok(())
+    }
+}
+
+impl hil::uart::Configure for HostUart {
+    fn configure(&self, params: hil::uart::Parameters) -> Result<(), ErrorCode> {
+        if self.baud_rate.get() != params.baud_rate {
+            eprintln!("uart: baud rate set to {}", params.baud_rate);
+            self.baud_rate.set(params.baud_rate);
+        }
+        Ok(())
+    }
+}
+
+impl hil::uart::Transmit<'static> for HostUart {
+    fn set_transmit_client(&self, client: &'static dyn hil::uart::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.tx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, tx_buffer));
+        }
+        if tx_len > tx_buffer.len() {
+            return Err((ErrorCode::SIZE, tx_buffer));
+        }
+        self.tx_len.set(tx_len);
+        self.tx_buffer.replace(tx_buffer);
+        Ok(())
+    }
+
+    fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+}
+
+impl hil::uart::Receive<'static> for HostUart {
+    fn set_receive_client(&self, client: &'static dyn hil::uart::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn receive_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start_receive(rx_buffer, rx_len, false)
+    }
+
+    fn receive_word(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn receive_abort(&self) -> Result<(), ErrorCode> {
+        // Finish the receive with whatever has arrived so far.
+        let len = self.rx_index.get();
+        self.rx_buffer
+            .take()
+            .map_or(Err(ErrorCode::FAIL), |buffer| {
+                self.rx_client.map(move |client| {
+                    client.received_buffer(
+                        buffer,
+                        len,
+                        Err(ErrorCode::CANCEL),
+                        hil::uart::Error::Aborted,
+                    )
+                });
+                Ok(())
+            })
+    }
+}
+
+impl hil::uart::ReceiveAdvanced<'static> for HostUart {
+    fn receive_automatic(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        _interbyte_timeout: u8,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start_receive(rx_buffer, rx_len, true)
+    }
+}
@@ -0,0 +1,254 @@
+//! RAM backed flash that records every operation.
+//!
+//! Operations are accepted like on a real flash controller, but nothing
+//! happens until `service()` is called. `service()` then performs the pending
+//! operation and calls the client, so callbacks never run re-entrantly from
+//! inside the bootloader.
+
+use std::cell::{Cell, RefCell};
+use std::ops::Range;
+
+use kernel::hil;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use bootloader::flash_large_to_small::FiveTwelvePage;
+
+/// Size of a mock flash page. This matches the page size the bootloader
+/// protocol uses.
+pub const PAGE_SIZE: usize = 512;
+
+/// A flash operation requested by the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashOperation {
+    Read { page_number: usize },
+    Write { page_number: usize },
+    Erase { page_number: usize },
+}
+
+/// How an operation made to fail with `MockFlash::inject_fault()` fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashFault {
+    /// The operation is refused when it is requested.
+    Refuse,
+    /// The operation is accepted, but completes with `FlashError` without
+    /// changing flash.
+    Report,
+    /// The operation appears to succeed, but a write leaves the first byte of
+    /// the page with its lowest bit flipped.
+    Corrupt,
+}
+
+pub struct MockFlash {
+    memory: RefCell<Vec<u8>>,
+    client: OptionalCell<&'static dyn hil::flash::Client<MockFlash>>,
+    pending: Cell<Option<FlashOperation>>,
+    buffer: TakeCell<'static, FiveTwelvePage>,
+    operations: RefCell<Vec<FlashOperation>>,
+    /// Faults waiting for a matching operation.
+    faults: RefCell<Vec<(FlashOperation, FlashFault)>>,
+    /// The fault the pending operation has, if any.
+    fault: Cell<Option<FlashFault>>,
+    /// Whether `service()` leaves the pending operation alone.
+    held: Cell<bool>,
+}
+
+impl MockFlash {
+    /// Create an erased flash of `size` bytes.
+    pub fn new(size: usize) -> MockFlash {
+        MockFlash {
+            memory: RefCell::new(vec![0xFF; size]),
+            client: OptionalCell::empty(),
+            pending: Cell::new(None),
+            buffer: TakeCell::empty(),
+            operations: RefCell::new(Vec::new()),
+            faults: RefCell::new(Vec::new()),
+            fault: Cell::new(None),
+            held: Cell::new(false),
+        }
+    }
+
+    /// Size of the flash in bytes.
+    pub fn size(&self) -> usize {
+        self.memory.borrow().len()
+    }
+
+    /// Copy `data` into flash at `address` without going through the HIL.
+    pub fn load(&self, address: usize, data: &[u8]) {
+        self.memory.borrow_mut()[address..address + data.len()].copy_from_slice(data);
+    }
+
+    /// Get a copy of `length` bytes of flash starting at `address`.
+    pub fn contents(&self, address: usize, length: usize) -> Vec<u8> {
+        self.memory.borrow()[address..address + length].to_vec()
+    }
+
+    /// All operations requested since creation or the last
+    /// `clear_operations()`, in order.
+    pub fn operations(&self) -> Vec<FlashOperation> {
+        self.operations.borrow().clone()
+    }
+
+    pub fn clear_operations(&self) {
+        self.operations.borrow_mut().clear();
+    }
+
+    /// Make the next request for `operation` fail with `fault`.
+    pub fn inject_fault(&self, operation: FlashOperation, fault: FlashFault) {
+        self.faults.borrow_mut().push((operation, fault));
+    }
+
+    /// Stop completing operations until `release()`, like a flash that is
+    /// slow to program. Operations are still accepted.
+    pub fn hold(&self) {
+        self.held.set(true);
+    }
+
+    pub fn release(&self) {
+        self.held.set(false);
+    }
+
+    /// Whether an operation has been requested but not completed yet.
+    pub fn is_busy(&self) -> bool {
+        self.pending.get().is_some()
+    }
+
+    /// Perform the pending operation, if any, and signal the client.
+    ///
+    /// Returns `true` if there was something to do.
+    pub fn service(&self) -> bool {
+        if self.held.get() {
+            return false;
+        }
+        let operation = match self.pending.take() {
+            Some(operation) => operation,
+            None => return false,
+        };
+
+        let fault = self.fault.take();
+        if fault == Some(FlashFault::Report) {
+            let error = hil::flash::Error::FlashError;
+            match operation {
+                FlashOperation::Read { .. } => {
+                    if let Some(page) = self.buffer.take() {
+                        self.client
+                            .map(move |client| client.read_complete(page, error));
+                    }
+                }
+                FlashOperation::Write { .. } => {
+                    if let Some(page) = self.buffer.take() {
+                        self.client
+                            .map(move |client| client.write_complete(page, error));
+                    }
+                }
+                FlashOperation::Erase { .. } => {
+                    self.client.map(|client| client.erase_complete(error));
+                }
+            }
+            return true;
+        }
+
+        match operation {
+            FlashOperation::Read { page_number } => {
+                if let Some(page) = self.buffer.take() {
+                    page.0
+                        .copy_from_slice(&self.memory.borrow()[page_range(page_number)]);
+                    self.client.map(move |client| {
+                        client.read_complete(page, hil::flash::Error::CommandComplete)
+                    });
+                }
+            }
+            FlashOperation::Write { page_number } => {
+                if let Some(page) = self.buffer.take() {
+                    let mut memory = self.memory.borrow_mut();
+                    memory[page_range(page_number)].copy_from_slice(&page.0);
+                    if fault == Some(FlashFault::Corrupt) {
+                        memory[page_number * PAGE_SIZE] ^= 1;
+                    }
+                    drop(memory);
+                    self.client.map(move |client| {
+                        client.write_complete(page, hil::flash::Error::CommandComplete)
+                    });
+                }
+            }
+            FlashOperation::Erase { page_number } => {
+                for b in self.memory.borrow_mut()[page_range(page_number)].iter_mut() {
+                    *b = 0xFF;
+                }
+                self.client
+                    .map(|client| client.erase_complete(hil::flash::Error::CommandComplete));
+            }
+        }
+        true
+    }
+
+    /// Accept `operation` if the flash is idle and the page exists.
+    fn start(&self, operation: FlashOperation, page_number: usize) -> Result<(), ErrorCode> {
+        if self.pending.get().is_some() {
+            Err(ErrorCode::BUSY)
+        } else if (page_number + 1) * PAGE_SIZE > self.memory.borrow().len() {
+            Err(ErrorCode::INVAL)
+        } else {
+            self.operations.borrow_mut().push(operation);
+            match self.take_fault(operation) {
+                Some(FlashFault::Refuse) => return Err(ErrorCode::FAIL),
+                fault => self.fault.set(fault),
+            }
+            self.pending.set(Some(operation));
+            Ok(())
+        }
+    }
+
+    /// Remove and return the fault injected for `operation`, if any.
+    fn take_fault(&self, operation: FlashOperation) -> Option<FlashFault> {
+        let mut faults = self.faults.borrow_mut();
+        let index = faults.iter().position(|&(op, _)| op == operation)?;
+        Some(faults.remove(index).1)
+    }
+}
+
+fn page_range(page_number: usize) -> Range<usize> {
+    page_number * PAGE_SIZE..(page_number + 1) * PAGE_SIZE
+}
+
+impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for MockFlash {
+    fn set_client(&self, client: &'static C) {
+        self.client.set(client);
+    }
+}
+
+impl hil::flash::Flash for MockFlash {
+    type Page = FiveTwelvePage;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        match self.start(FlashOperation::Read { page_number }, page_number) {
+            Ok(()) => {
+                self.buffer.replace(buf);
+                Ok(())
+            }
+            Err(e) => Err((e, buf)),
+        }
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        match self.start(FlashOperation::Write { page_number }, page_number) {
+            Ok(()) => {
+                self.buffer.replace(buf);
+                Ok(())
+            }
+            Err(e) => Err((e, buf)),
+        }
+    }
+
+    fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+        self.start(FlashOperation::Erase { page_number }, page_number)
+    }
+}
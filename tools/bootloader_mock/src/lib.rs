@@ -0,0 +1,402 @@
+//! Mock HIL implementations for testing the bootloader on the host.
+//!
+//! `MockUart` and `MockFlash` implement the HIL traits the bootloader needs,
+//! record everything that is asked of them, and only complete operations when
+//! they are serviced. `Harness` wires them up to a real
+//! `bootloader::bootloader::Bootloader` and steps everything until the
+//! bootloader is waiting for the next command:
+//!
+//! ```ignore
+//! let harness = Harness::new();
+//! let mut session = Session::new(&harness);
+//! session.ping().unwrap();
+//! ```
+//!
+//! `&Harness` implements `client::Port`, so it can be driven with the
+//! protocol crate's `client::Session` like a real board, or with raw bytes
+//! through `Harness::command()` when a test needs to send something the
+//! client would not.
+
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use kernel::hil;
+
+use bootloader::bootloader::{Bootloader, FlashLayout};
+use bootloader::external_flash_adapter::ExternalFlashAdapter;
+use bootloader::interfaces::{DeviceId, ExternalFlash, RandomSource, Timeout, MAX_DEVICE_ID_LEN};
+use bootloader::kernel_slots::SlotLayout;
+use tock_bootloader_protocol::client::{Error, Port, Session};
+use tock_bootloader_protocol::{Command, CommandEncoder, DEFAULT_DECODER_CAPACITY};
+
+pub mod flash;
+pub mod timeout;
+pub mod uart;
+
+pub use crate::flash::{FlashFault, FlashOperation, MockFlash, PAGE_SIZE};
+pub use crate::timeout::MockTimeout;
+pub use crate::uart::MockUart;
+
+// The bootloader crate refers to these linker script symbols. The harness
+// passes its flash layout explicitly, but the symbols still have to exist for
+// test binaries to link.
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static _flags_address: u8 = 0;
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static _attributes_address: u8 = 0;
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static _stext: u8 = 0;
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static _etext: u8 = 0;
+
+/// Size of the mock flash.
+pub const FLASH_SIZE: usize = 0x40000;
+
+/// Flash layout the harness gives the bootloader. This matches the layout the
+/// boards use: a 32 kB bootloader with the flags and attributes inside it.
+pub const LAYOUT: FlashLayout = FlashLayout {
+    flags_address: 0x400,
+    attributes_address: 0x600,
+    bootloader_address: 0x0,
+    bootloader_end_address: 0x8000,
+};
+
+/// Size of the mock external flash.
+pub const EXTERNAL_FLASH_SIZE: usize = 0x10000;
+
+/// Version string written into the flags region.
+pub const BOOTLOADER_VERSION: &str = "1.1.3";
+
+/// Start address written into the flags region.
+pub const KERNEL_ADDRESS: u32 = 0x8000;
+
+/// Kernel slots the harness gives the bootloader after
+/// `Harness::set_kernel_slots()`.
+pub const KERNEL_SLOTS: SlotLayout = SlotLayout {
+    address: [0x10000, 0x28000],
+    size: 0x18000,
+};
+
+/// Byte that starts every command and response frame.
+pub const ESCAPE_CHAR: u8 = 0xFC;
+
+// Response codes the tests look for. Same as the ones in
+// `bootloader::bootloader`.
+pub const RES_PONG: u8 = 0x11;
+pub const RES_BADADDR: u8 = 0x12;
+pub const RES_INTERNAL_ERROR: u8 = 0x13;
+pub const RES_BADARGS: u8 = 0x14;
+pub const RES_OK: u8 = 0x15;
+pub const RES_UNKNOWN: u8 = 0x16;
+pub const RES_CHANGE_BAUD_FAIL: u8 = 0x26;
+pub const RES_UNAUTHENTICATED: u8 = 0x2B;
+pub const RES_FLASH_ERROR: u8 = 0x2C;
+
+/// Attribute `Harness::provisioned()` keeps the device key in.
+pub const KEY_INDEX: u8 = 15;
+
+/// Device key `Harness::provisioned()` stores.
+pub const KEY: [u8; 32] = [0x5A; 32];
+
+/// Attribute `Harness::with_image_key()` keeps the image key in.
+pub const IMAGE_KEY_INDEX: u8 = 14;
+
+/// Image key `Harness::with_image_key()` stores.
+pub const IMAGE_KEY: [u8; 32] = [0x3C; 32];
+
+/// Somewhere past the bootloader to put test data.
+pub const DATA_ADDRESS: u32 = 0x10000;
+
+/// Size of the bootloader's command buffer. Same as
+/// `bootloader::bootloader::BUF`.
+const BUFFER_SIZE: usize = 600;
+
+/// Size of the bootloader's `PAGE_ACK` buffer. Same as
+/// `bootloader::bootloader::ACK_BUF`.
+const ACK_BUFFER_SIZE: usize = 16;
+
+/// How many times `run_until_idle()` services the mocks before deciding the
+/// bootloader is stuck.
+const MAX_STEPS: usize = 100_000;
+
+/// `DeviceId` that always reports the same ID.
+pub struct FixedDeviceId {
+    id: Vec<u8>,
+}
+
+impl FixedDeviceId {
+    pub fn new(id: &[u8]) -> FixedDeviceId {
+        assert!(id.len() <= MAX_DEVICE_ID_LEN);
+        FixedDeviceId { id: id.to_vec() }
+    }
+}
+
+impl DeviceId for FixedDeviceId {
+    fn device_id(&self, id: &mut [u8; MAX_DEVICE_ID_LEN]) -> usize {
+        id[..self.id.len()].copy_from_slice(&self.id);
+        self.id.len()
+    }
+}
+
+/// `RandomSource` that counts up, so every nonce is different but tests are
+/// repeatable.
+#[derive(Default)]
+pub struct CountingRandom {
+    next: Cell<u8>,
+}
+
+impl RandomSource for CountingRandom {
+    fn fill(&self, buf: &mut [u8]) {
+        for byte in buf.iter_mut() {
+            *byte = self.next.get();
+            self.next.set(self.next.get().wrapping_add(1));
+        }
+    }
+}
+
+/// A bootloader running on mock hardware.
+///
+/// All the pieces are leaked to get the `'static` lifetimes the HIL needs.
+/// That is fine for tests, where each harness lives until the test ends.
+///
+/// `N` is the capacity of the bootloader's command decoder.
+pub struct Harness<const N: usize = DEFAULT_DECODER_CAPACITY> {
+    pub uart: &'static MockUart,
+    pub flash: &'static MockFlash,
+    /// External flash, if the bootloader has one.
+    pub external_flash: Option<&'static MockFlash>,
+    pub timeout: &'static MockTimeout,
+    pub bootloader: &'static Bootloader<'static, MockUart, MockFlash, N>,
+    exited: &'static Cell<bool>,
+}
+
+impl Harness {
+    /// Create and start a bootloader using `LAYOUT`, with the flags and
+    /// attributes regions set up as `bootloader_attributes` would, and an
+    /// erased external flash of `EXTERNAL_FLASH_SIZE`.
+    pub fn new() -> Harness {
+        Harness::with_decoder_capacity()
+    }
+
+    /// Create and start a bootloader on `flash` with the given layout and,
+    /// optionally, external flash.
+    pub fn with_flash(
+        flash: MockFlash,
+        layout: FlashLayout,
+        external_flash: Option<MockFlash>,
+    ) -> Harness {
+        Harness::build(flash, layout, external_flash)
+    }
+
+    /// A harness that requires authentication, with `KEY` already stored.
+    pub fn provisioned() -> Harness {
+        let harness = Harness::new();
+        harness.set_authentication(KEY_INDEX);
+        Session::new(&harness)
+            .set_attr(KEY_INDEX, b"authkey", &KEY)
+            .unwrap();
+        harness
+    }
+
+    /// A harness that accepts encrypted images, with `IMAGE_KEY` already
+    /// stored.
+    pub fn with_image_key() -> Harness {
+        let harness = Harness::new();
+        harness.set_image_key(IMAGE_KEY_INDEX);
+        Session::new(&harness)
+            .set_attr(IMAGE_KEY_INDEX, b"imagekey", &IMAGE_KEY)
+            .unwrap();
+        harness
+    }
+}
+
+impl<const N: usize> Harness<N> {
+    /// Like `Harness::new()`, but the bootloader's command decoder holds
+    /// commands of up to `N` bytes.
+    pub fn with_decoder_capacity() -> Harness<N> {
+        let flash = MockFlash::new(FLASH_SIZE);
+
+        let mut flags = Vec::new();
+        flags.extend(b"TOCKBOOTLOADER");
+        flags.extend(BOOTLOADER_VERSION.bytes());
+        flags.resize(32, 0);
+        flags.extend(&KERNEL_ADDRESS.to_le_bytes());
+        flags.resize(512, 0);
+        flash.load(LAYOUT.flags_address, &flags);
+        flash.load(LAYOUT.attributes_address, &[0; 1024]);
+
+        Harness::build(flash, LAYOUT, Some(MockFlash::new(EXTERNAL_FLASH_SIZE)))
+    }
+
+    fn build(
+        flash: MockFlash,
+        layout: FlashLayout,
+        external_flash: Option<MockFlash>,
+    ) -> Harness<N> {
+        let uart: &'static MockUart = Box::leak(Box::new(MockUart::new()));
+        let flash: &'static MockFlash = Box::leak(Box::new(flash));
+        let exited: &'static Cell<bool> = Box::leak(Box::new(Cell::new(false)));
+        let reset_function: &'static dyn Fn() = Box::leak(Box::new(move || exited.set(true)));
+        let page_buffer = Box::leak(Box::default());
+        let buffer = Box::leak(vec![0; BUFFER_SIZE].into_boxed_slice());
+
+        let bootloader: &'static Bootloader<'static, MockUart, MockFlash, N> = Box::leak(Box::new(
+            Bootloader::new_with_layout(uart, flash, reset_function, page_buffer, buffer, layout),
+        ));
+        hil::flash::HasClient::set_client(flash, bootloader);
+        hil::uart::Transmit::set_transmit_client(uart, bootloader);
+        hil::uart::Receive::set_receive_client(uart, bootloader);
+        bootloader.set_flash_size(flash.size() as u32);
+
+        let timeout: &'static MockTimeout = Box::leak(Box::new(MockTimeout::new()));
+        timeout.set_client(bootloader);
+        bootloader.set_timeout(timeout);
+
+        let external_flash = external_flash.map(|external_flash| {
+            let external_flash: &'static MockFlash = Box::leak(Box::new(external_flash));
+            let adapter: &'static ExternalFlashAdapter<'static, MockFlash> =
+                Box::leak(Box::new(ExternalFlashAdapter::new(
+                    external_flash,
+                    Box::leak(Box::default()),
+                    external_flash.size() as u32,
+                )));
+            hil::flash::HasClient::set_client(external_flash, adapter);
+            adapter.set_client(bootloader);
+            bootloader.set_external_flash(adapter);
+            external_flash
+        });
+
+        bootloader.start();
+
+        Harness {
+            uart,
+            flash,
+            external_flash,
+            timeout,
+            bootloader,
+            exited,
+        }
+    }
+
+    /// Give the bootloader a device ID to report. Harnesses start without
+    /// one.
+    pub fn set_device_id(&self, id: &[u8]) {
+        let device_id: &'static FixedDeviceId = Box::leak(Box::new(FixedDeviceId::new(id)));
+        self.bootloader.set_device_id(device_id);
+    }
+
+    /// Give the bootloader the `KERNEL_SLOTS`. Harnesses start without kernel
+    /// slots.
+    pub fn set_kernel_slots(&self) {
+        self.bootloader.set_kernel_slots(KERNEL_SLOTS);
+    }
+
+    /// Require authentication, with the device key in attribute `key_index`.
+    /// Harnesses start without authentication.
+    pub fn set_authentication(&self, key_index: u8) {
+        let random: &'static CountingRandom = Box::leak(Box::default());
+        self.bootloader.set_authentication(random, key_index);
+    }
+
+    /// Accept encrypted images, with the image key in attribute `key_index`.
+    pub fn set_image_key(&self, key_index: u8) {
+        self.bootloader.set_image_key(key_index);
+    }
+
+    /// Accept `WRITE_STREAM_PAGE`. Harnesses start without streaming.
+    pub fn set_streaming(&self) {
+        self.bootloader.set_streaming(
+            Box::leak(Box::default()),
+            Box::leak(vec![0; ACK_BUFFER_SIZE].into_boxed_slice()),
+        );
+    }
+
+    /// Service the mocks until neither has anything left to do.
+    ///
+    /// Panics if that doesn't happen within a generous number of steps, as
+    /// the bootloader is then most likely stuck in a loop.
+    pub fn run_until_idle(&self) {
+        for _ in 0..MAX_STEPS {
+            let flash_busy = self.flash.service();
+            let external_flash_busy = self.external_flash.map_or(false, |f| f.service());
+            let uart_busy = self.uart.service();
+            if !flash_busy && !external_flash_busy && !uart_busy {
+                return;
+            }
+        }
+        panic!("bootloader did not go idle after {} steps", MAX_STEPS);
+    }
+
+    /// Send `RESET` followed by `command` as one burst, run until idle and
+    /// return everything the bootloader sent back.
+    pub fn command(&self, command: &Command) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend(CommandEncoder::new(&Command::Reset).unwrap());
+        frame.extend(CommandEncoder::new(command).unwrap());
+        self.raw(&frame)
+    }
+
+    /// Send `bytes` as one burst, run until idle and return everything the
+    /// bootloader sent back.
+    pub fn raw(&self, bytes: &[u8]) -> Vec<u8> {
+        self.uart.host_write(bytes);
+        self.run_until_idle();
+        self.uart.take_output()
+    }
+
+    /// Whether the bootloader called its reset function to leave the
+    /// bootloader.
+    pub fn exited(&self) -> bool {
+        self.exited.get()
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, const N: usize> Read for &'a Harness<N> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.run_until_idle();
+        match self.uart.read_output(buf) {
+            // Nothing more is coming until the host sends something.
+            0 => Err(io::ErrorKind::TimedOut.into()),
+            count => Ok(count),
+        }
+    }
+}
+
+impl<'a, const N: usize> Write for &'a Harness<N> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.uart.host_write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Reads never wait, as everything the bootloader will send has been sent by
+// the time `read` returns.
+impl<'a, const N: usize> Port for &'a Harness<N> {
+    fn set_read_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Check that `result` is the error the client gives when the bootloader sends
+/// `response` instead of what the command expected.
+pub fn assert_response<T: std::fmt::Debug>(result: Result<T, Error>, response: &str) {
+    match result {
+        Err(Error::UnexpectedResponse(r)) => assert_eq!(r, response),
+        r => panic!("Did not expect: {:?}", r),
+    }
+}
@@ -0,0 +1,58 @@
+//! Timeout that only expires when the test says so.
+
+use std::cell::Cell;
+
+use kernel::utilities::cells::OptionalCell;
+
+use bootloader::interfaces;
+
+pub struct MockTimeout {
+    client: OptionalCell<&'static dyn interfaces::TimeoutClient>,
+    /// Length of the running timeout in milliseconds, if one is running.
+    running: Cell<Option<u32>>,
+}
+
+impl MockTimeout {
+    pub fn new() -> MockTimeout {
+        MockTimeout {
+            client: OptionalCell::empty(),
+            running: Cell::new(None),
+        }
+    }
+
+    /// Length of the running timeout in milliseconds, or `None` if the
+    /// timeout isn't running.
+    pub fn running(&self) -> Option<u32> {
+        self.running.get()
+    }
+
+    /// Let the running timeout expire, signalling the client. Returns `false`
+    /// if no timeout was running.
+    pub fn expire(&self) -> bool {
+        if self.running.take().is_none() {
+            return false;
+        }
+        self.client.map(|client| client.timeout_expired());
+        true
+    }
+}
+
+impl Default for MockTimeout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl interfaces::Timeout<'static> for MockTimeout {
+    fn set_client(&self, client: &'static dyn interfaces::TimeoutClient) {
+        self.client.set(client);
+    }
+
+    fn start(&self, ms: u32) {
+        self.running.set(Some(ms));
+    }
+
+    fn cancel(&self) {
+        self.running.set(None);
+    }
+}
@@ -0,0 +1,344 @@
+//! UART that exchanges bytes with the test instead of a host.
+//!
+//! The test plays the role of the host: `host_write()` queues bytes for the
+//! UART to receive and `take_output()` collects what the UART transmitted.
+//! Like the flash, transmits and receives only complete from `service()`.
+//!
+//! Each `host_write()` is treated as one burst on the line. An automatic
+//! receive completes with the next burst (or as much of it as fits), the same
+//! way the hardware implementations complete once the line goes idle.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+use kernel::hil;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+pub struct MockUart {
+    tx_client: OptionalCell<&'static dyn hil::uart::TransmitClient>,
+    rx_client: OptionalCell<&'static dyn hil::uart::ReceiveClient>,
+
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_index: Cell<usize>,
+    rx_automatic: Cell<bool>,
+    rx_abort: Cell<bool>,
+
+    /// Refuse the next transmit when it is requested.
+    refuse_transmit: Cell<bool>,
+    /// Complete the next transmit with an error without sending anything.
+    fail_transmit: Cell<bool>,
+    /// Complete the next receive with a framing error.
+    fail_receive: Cell<bool>,
+    /// Refuse the next receive when it is requested.
+    refuse_receive: Cell<bool>,
+    /// Don't complete transmits until `release_transmit()`.
+    transmit_held: Cell<bool>,
+
+    /// Bursts written by the host that have not been received yet.
+    input: RefCell<VecDeque<Vec<u8>>>,
+    /// Bytes transmitted that the host has not read yet.
+    output: RefCell<VecDeque<u8>>,
+    /// Every completed transmit, in order.
+    transmissions: RefCell<Vec<Vec<u8>>>,
+    /// Baud rate of every call to `configure()`, in order.
+    baud_rates: RefCell<Vec<u32>>,
+}
+
+impl MockUart {
+    pub fn new() -> MockUart {
+        MockUart {
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_index: Cell::new(0),
+            rx_automatic: Cell::new(false),
+            rx_abort: Cell::new(false),
+            refuse_transmit: Cell::new(false),
+            fail_transmit: Cell::new(false),
+            fail_receive: Cell::new(false),
+            refuse_receive: Cell::new(false),
+            transmit_held: Cell::new(false),
+            input: RefCell::new(VecDeque::new()),
+            output: RefCell::new(VecDeque::new()),
+            transmissions: RefCell::new(Vec::new()),
+            baud_rates: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Queue `bytes` to be received as one burst.
+    pub fn host_write(&self, bytes: &[u8]) {
+        self.input.borrow_mut().push_back(bytes.to_vec());
+    }
+
+    /// Move transmitted bytes into `buf`. Returns how many were copied.
+    pub fn read_output(&self, buf: &mut [u8]) -> usize {
+        let mut output = self.output.borrow_mut();
+        let count = buf.len().min(output.len());
+        for (dst, src) in buf.iter_mut().zip(output.drain(..count)) {
+            *dst = src;
+        }
+        count
+    }
+
+    /// Take all transmitted bytes the host has not read yet.
+    pub fn take_output(&self) -> Vec<u8> {
+        self.output.borrow_mut().drain(..).collect()
+    }
+
+    /// Every completed transmit since creation or the last
+    /// `clear_transmissions()`, in order.
+    pub fn transmissions(&self) -> Vec<Vec<u8>> {
+        self.transmissions.borrow().clone()
+    }
+
+    pub fn clear_transmissions(&self) {
+        self.transmissions.borrow_mut().clear();
+    }
+
+    /// Baud rates the UART has been configured with, in order.
+    pub fn baud_rates(&self) -> Vec<u32> {
+        self.baud_rates.borrow().clone()
+    }
+
+    /// Make the next call to `transmit_buffer()` return an error.
+    pub fn refuse_next_transmit(&self) {
+        self.refuse_transmit.set(true);
+    }
+
+    /// Make the next transmit complete with an error, without anything
+    /// reaching the host.
+    pub fn fail_next_transmit(&self) {
+        self.fail_transmit.set(true);
+    }
+
+    /// Make the next receive complete with a framing error. The bytes it
+    /// received are lost.
+    pub fn fail_next_receive(&self) {
+        self.fail_receive.set(true);
+    }
+
+    /// Make the next call to `receive_buffer()` or `receive_automatic()`
+    /// return an error.
+    pub fn refuse_next_receive(&self) {
+        self.refuse_receive.set(true);
+    }
+
+    /// Stop completing transmits until `release_transmit()`, like a UART
+    /// still busy sending.
+    pub fn hold_transmit(&self) {
+        self.transmit_held.set(true);
+    }
+
+    pub fn release_transmit(&self) {
+        self.transmit_held.set(false);
+    }
+
+    /// Whether a receive is outstanding.
+    pub fn is_receiving(&self) -> bool {
+        self.rx_buffer.is_some()
+    }
+
+    /// Whether a transmit is outstanding.
+    pub fn is_transmitting(&self) -> bool {
+        self.tx_buffer.is_some()
+    }
+
+    /// Complete an outstanding transmit and deliver pending input, signalling
+    /// the clients.
+    ///
+    /// Returns `true` if anything happened.
+    pub fn service(&self) -> bool {
+        let mut busy = false;
+
+        let transmitted = if self.transmit_held.get() {
+            None
+        } else {
+            self.tx_buffer.take()
+        };
+        if let Some(buffer) = transmitted {
+            busy = true;
+            let len = self.tx_len.get();
+            if self.fail_transmit.take() {
+                self.tx_client
+                    .map(move |client| client.transmitted_buffer(buffer, 0, Err(ErrorCode::FAIL)));
+                return true;
+            }
+            self.transmissions.borrow_mut().push(buffer[..len].to_vec());
+            self.output.borrow_mut().extend(&buffer[..len]);
+            self.tx_client
+                .map(move |client| client.transmitted_buffer(buffer, len, Ok(())));
+        }
+
+        if self.rx_abort.take() {
+            busy = true;
+            let len = self.rx_index.get();
+            if let Some(buffer) = self.rx_buffer.take() {
+                self.rx_client.map(move |client| {
+                    client.received_buffer(
+                        buffer,
+                        len,
+                        Err(ErrorCode::CANCEL),
+                        hil::uart::Error::Aborted,
+                    )
+                });
+            }
+        } else if self.rx_buffer.is_some() && self.receive_input() {
+            busy = true;
+            let len = self.rx_index.get();
+            if let Some(buffer) = self.rx_buffer.take() {
+                let (rval, error) = if self.fail_receive.take() {
+                    (Err(ErrorCode::FAIL), hil::uart::Error::FramingError)
+                } else {
+                    (Ok(()), hil::uart::Error::None)
+                };
+                self.rx_client
+                    .map(move |client| client.received_buffer(buffer, len, rval, error));
+            }
+        }
+
+        busy
+    }
+
+    /// Copy pending input into the receive buffer. Returns `true` once the
+    /// receive should complete.
+    fn receive_input(&self) -> bool {
+        let mut input = self.input.borrow_mut();
+        let rx_len = self.rx_len.get();
+        let automatic = self.rx_automatic.get();
+
+        self.rx_buffer.map_or(false, |buffer| loop {
+            let burst = match input.front_mut() {
+                Some(burst) => burst,
+                None => return false,
+            };
+
+            let start = self.rx_index.get();
+            let count = burst.len().min(rx_len - start);
+            buffer[start..start + count].copy_from_slice(&burst[..count]);
+            burst.drain(..count);
+            if burst.is_empty() {
+                input.pop_front();
+            }
+            self.rx_index.set(start + count);
+
+            if automatic || start + count == rx_len {
+                return true;
+            }
+        })
+    }
+
+    fn start_receive(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        automatic: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.refuse_receive.take() {
+            return Err((ErrorCode::FAIL, rx_buffer));
+        }
+        if self.rx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+        if rx_len == 0 || rx_len > rx_buffer.len() {
+            return Err((ErrorCode::SIZE, rx_buffer));
+        }
+        self.rx_len.set(rx_len);
+        self.rx_index.set(0);
+        self.rx_automatic.set(automatic);
+        self.rx_buffer.replace(rx_buffer);
+        Ok(())
+    }
+}
+
+impl Default for MockUart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl hil::uart::Configure for MockUart {
+    fn configure(&self, params: hil::uart::Parameters) -> Result<(), ErrorCode> {
+        self.baud_rates.borrow_mut().push(params.baud_rate);
+        Ok(())
+    }
+}
+
+impl hil::uart::Transmit<'static> for MockUart {
+    fn set_transmit_client(&self, client: &'static dyn hil::uart::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.refuse_transmit.take() {
+            return Err((ErrorCode::FAIL, tx_buffer));
+        }
+        if self.tx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, tx_buffer));
+        }
+        if tx_len > tx_buffer.len() {
+            return Err((ErrorCode::SIZE, tx_buffer));
+        }
+        self.tx_len.set(tx_len);
+        self.tx_buffer.replace(tx_buffer);
+        Ok(())
+    }
+
+    fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+}
+
+impl hil::uart::Receive<'static> for MockUart {
+    fn set_receive_client(&self, client: &'static dyn hil::uart::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn receive_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start_receive(rx_buffer, rx_len, false)
+    }
+
+    fn receive_word(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn receive_abort(&self) -> Result<(), ErrorCode> {
+        if self.rx_buffer.is_none() {
+            // Nothing to abort, so there will be no callback.
+            return Ok(());
+        }
+        // Completed with whatever has arrived so far on the next `service()`.
+        self.rx_abort.set(true);
+        Err(ErrorCode::BUSY)
+    }
+}
+
+impl hil::uart::ReceiveAdvanced<'static> for MockUart {
+    fn receive_automatic(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        _interbyte_timeout: u8,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start_receive(rx_buffer, rx_len, true)
+    }
+}
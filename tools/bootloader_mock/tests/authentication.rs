@@ -0,0 +1,145 @@
+//! Check that an authenticated session is needed to change flash.
+
+use bootloader_mock::{
+    assert_response, FlashOperation, Harness, DATA_ADDRESS, ESCAPE_CHAR, KEY, KEY_INDEX, LAYOUT,
+    RES_UNAUTHENTICATED, RES_UNKNOWN,
+};
+use tock_bootloader_protocol::auth::auth_mac;
+use tock_bootloader_protocol::client::{Error, Session};
+use tock_bootloader_protocol::{Command, CMD_AUTH_CHALLENGE, CMD_AUTH_RESPONSE};
+
+fn assert_unauthenticated<T: std::fmt::Debug>(result: Result<T, Error>) {
+    assert_response(result, "Unauthenticated");
+}
+
+#[test]
+fn changes_refused_until_authenticated() {
+    let harness = Harness::provisioned();
+    harness.flash.clear_operations();
+    let mut session = Session::new(&harness);
+
+    assert_unauthenticated(session.write_page(DATA_ADDRESS, &[0xAA; 512]));
+    assert_unauthenticated(session.erase_page(DATA_ADDRESS));
+    assert_unauthenticated(session.set_start_address(DATA_ADDRESS));
+    assert_unauthenticated(session.set_kernel_crc(512, 0));
+    assert_unauthenticated(session.set_attr(0, b"board", b"hail"));
+    assert_eq!(
+        harness.command(&Command::EraseExPage { address: 0 }),
+        vec![ESCAPE_CHAR, RES_UNAUTHENTICATED]
+    );
+    assert!(harness.flash.operations().is_empty());
+
+    // Reading is still fine.
+    assert_eq!(session.read_range(DATA_ADDRESS, 4).unwrap(), vec![0xFF; 4]);
+    session.ping().unwrap();
+
+    session.authenticate(&KEY).unwrap();
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 512),
+        [0xAA; 512]
+    );
+    session.set_attr(0, b"board", b"hail").unwrap();
+}
+
+#[test]
+fn wrong_key() {
+    let harness = Harness::provisioned();
+    let mut session = Session::new(&harness);
+    assert_unauthenticated(session.authenticate(&[0x5B; 32]));
+    assert_unauthenticated(session.write_page(DATA_ADDRESS, &[0xAA; 512]));
+
+    // A response without a challenge, or a second response to the same
+    // challenge, is refused even with the right MAC.
+    let nonce = [0; 32];
+    let mac = auth_mac(&KEY, &nonce);
+    assert_eq!(
+        harness.command(&Command::AuthResponse { mac: &mac }),
+        vec![ESCAPE_CHAR, RES_UNAUTHENTICATED]
+    );
+    assert_unauthenticated(session.write_page(DATA_ADDRESS, &[0xAA; 512]));
+}
+
+#[test]
+fn nonces_are_fresh() {
+    let harness = Harness::provisioned();
+    let first = harness.command(&Command::AuthChallenge);
+    let second = harness.command(&Command::AuthChallenge);
+    assert_eq!(first.len(), 2 + 32);
+    assert_ne!(first, second);
+
+    // Only the last challenge can be answered.
+    let mac = auth_mac(&KEY, &first[2..]);
+    assert_eq!(
+        harness.command(&Command::AuthResponse { mac: &mac }),
+        vec![ESCAPE_CHAR, RES_UNAUTHENTICATED]
+    );
+}
+
+#[test]
+fn key_stored_once() {
+    let harness = Harness::provisioned();
+    let mut session = Session::new(&harness);
+    assert_unauthenticated(session.set_attr(KEY_INDEX, b"authkey", &[0; 32]));
+
+    // An authenticated host can change it.
+    session.authenticate(&KEY).unwrap();
+    session
+        .set_attr(KEY_INDEX, b"authkey", &[0x11; 32])
+        .unwrap();
+}
+
+#[test]
+fn key_is_never_sent() {
+    let harness = Harness::provisioned();
+    let mut session = Session::new(&harness);
+    let key_address = LAYOUT.attributes_address as u32 + KEY_INDEX as u32 * 64;
+
+    let attribute = session.get_attr(KEY_INDEX).unwrap();
+    assert_eq!(attribute.key, b"authkey\0".to_vec());
+    assert!(attribute.value.is_empty());
+    assert!(!session.info().unwrap().contains("authkey"));
+
+    // Not even an authenticated session can read it.
+    for authenticated in [false, true] {
+        if authenticated {
+            session.authenticate(&KEY).unwrap();
+        }
+        assert_response(session.read_range(key_address + 60, 8), "BadAddress");
+        assert_response(session.crc_int_flash(key_address + 9, 1), "BadAddress");
+        assert_response(session.hash_int_flash(key_address + 9, 1), "BadAddress");
+
+        // Around the key is fine.
+        session.read_range(key_address - 8, 8).unwrap();
+        session.crc_int_flash(key_address + 64, 64).unwrap();
+    }
+}
+
+#[test]
+fn capabilities() {
+    let harness = Harness::new();
+    let capabilities = Session::new(&harness).capabilities().unwrap();
+    assert!(!capabilities.supports(CMD_AUTH_CHALLENGE));
+    assert_eq!(
+        harness.command(&Command::AuthChallenge),
+        vec![ESCAPE_CHAR, RES_UNKNOWN]
+    );
+
+    harness.set_authentication(KEY_INDEX);
+    let capabilities = Session::new(&harness).capabilities().unwrap();
+    assert!(capabilities.supports(CMD_AUTH_CHALLENGE));
+    assert!(capabilities.supports(CMD_AUTH_RESPONSE));
+}
+
+#[test]
+fn unprovisioned_key_never_authenticates() {
+    let harness = Harness::new();
+    harness.set_authentication(KEY_INDEX);
+    let mut session = Session::new(&harness);
+    assert_unauthenticated(session.authenticate(&[0; 32]));
+    assert!(!harness
+        .flash
+        .operations()
+        .iter()
+        .any(|op| matches!(op, FlashOperation::Write { .. })));
+}
@@ -0,0 +1,25 @@
+//! Check the boot attempt count kept in RAM.
+
+use bootloader::boot_attempts_noinit::{BootAttemptsNoinit, BOOT_ATTEMPTS_MAGIC};
+use bootloader::interfaces::BootAttempts;
+
+#[test]
+fn noinit_count() {
+    let word: &'static mut u32 = Box::leak(Box::new(0x12345678));
+    let address = word as *mut u32 as usize;
+    let boot_attempts = BootAttemptsNoinit::new(address);
+
+    // Whatever RAM held at power on is not a count.
+    assert_eq!(boot_attempts.attempts(), 0);
+
+    boot_attempts.set_attempts(3);
+    assert_eq!(boot_attempts.attempts(), 3);
+    assert_eq!(
+        unsafe { core::ptr::read_volatile(address as *const u32) },
+        BOOT_ATTEMPTS_MAGIC | 3
+    );
+
+    // The kernel clears the count by writing zero.
+    unsafe { core::ptr::write_volatile(address as *mut u32, 0) };
+    assert_eq!(boot_attempts.attempts(), 0);
+}
@@ -0,0 +1,79 @@
+//! Check that compressed pages are expanded and written like `WRITE_PAGE`.
+
+use bootloader_mock::{
+    assert_response, Harness, DATA_ADDRESS, ESCAPE_CHAR, PAGE_SIZE, RES_BADARGS,
+};
+use tock_bootloader_protocol::client::Session;
+use tock_bootloader_protocol::compression::compress;
+use tock_bootloader_protocol::{Command, CMD_WRITE_PAGE_COMPRESSED};
+
+/// A page like the end of a kernel: some code, then erased flash.
+fn page() -> [u8; PAGE_SIZE] {
+    let mut page = [0xFF; PAGE_SIZE];
+    for (i, b) in page[..64].iter_mut().enumerate() {
+        *b = (i * 13) as u8;
+    }
+    page
+}
+
+#[test]
+fn page_written() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    assert!(session
+        .capabilities()
+        .unwrap()
+        .supports(CMD_WRITE_PAGE_COMPRESSED));
+
+    session
+        .write_page_compressed(DATA_ADDRESS, &page())
+        .unwrap();
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, PAGE_SIZE),
+        page()
+    );
+    session
+        .write_page_compressed(DATA_ADDRESS, &[0; 512])
+        .unwrap();
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, PAGE_SIZE),
+        [0; PAGE_SIZE]
+    );
+}
+
+#[test]
+fn must_expand_to_a_page() {
+    let harness = Harness::new();
+    for data in [
+        compress(&[0xAA; PAGE_SIZE - 1]),
+        compress(&[0xAA; PAGE_SIZE + 1]),
+        // Refers back past the start.
+        vec![0x10, 0xAA, 0x02, 0x00, 0x00],
+    ] {
+        let command = Command::WritePageCompressed {
+            address: DATA_ADDRESS,
+            data: &data,
+        };
+        assert_eq!(harness.command(&command), vec![ESCAPE_CHAR, RES_BADARGS]);
+    }
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, PAGE_SIZE),
+        [0xFF; PAGE_SIZE]
+    );
+}
+
+#[test]
+fn checked_like_write_page() {
+    let harness = Harness::new();
+    harness.bootloader.set_skip_unchanged_pages(true).unwrap();
+    let mut session = Session::new(&harness);
+    assert_response(session.write_page_compressed(0x7E00, &page()), "BadAddress");
+
+    session
+        .write_page_compressed(DATA_ADDRESS, &page())
+        .unwrap();
+    session
+        .write_page_compressed(DATA_ADDRESS, &page())
+        .unwrap();
+    assert_eq!(session.skipped_pages().unwrap(), 1);
+}
@@ -0,0 +1,332 @@
+//! Check writing images encrypted with the board's image key.
+
+use bootloader_mock::{
+    assert_response, FlashFault, FlashOperation, Harness, ESCAPE_CHAR, IMAGE_KEY, IMAGE_KEY_INDEX,
+    KEY_INDEX, LAYOUT, RES_BADADDR, RES_BADARGS, RES_FLASH_ERROR, RES_OK, RES_UNAUTHENTICATED,
+    RES_UNKNOWN,
+};
+use tock_bootloader_protocol::client::Session;
+use tock_bootloader_protocol::encrypted_image::{encrypt_image, ImageCipher};
+use tock_bootloader_protocol::{Command, CMD_BEGIN_ENC_IMAGE, CMD_END_ENC_IMAGE};
+
+const NONCE: [u8; 12] = [0x01; 12];
+
+/// Somewhere past the bootloader to put the image.
+const IMAGE_ADDRESS: u32 = 0x10000;
+
+fn image() -> Vec<u8> {
+    (0..1024).map(|i| (i * 7) as u8).collect()
+}
+
+#[test]
+fn image_is_decrypted() {
+    let harness = Harness::with_image_key();
+    let mut session = Session::new(&harness);
+    let image = image();
+    session
+        .write_encrypted_image(&IMAGE_KEY, &NONCE, IMAGE_ADDRESS, &image)
+        .unwrap();
+    assert_eq!(
+        harness.flash.contents(IMAGE_ADDRESS as usize, image.len()),
+        image
+    );
+
+    // A partial last page is padded.
+    session
+        .write_encrypted_image(&IMAGE_KEY, &[0x02; 12], IMAGE_ADDRESS, &image[..600])
+        .unwrap();
+    assert_eq!(
+        harness.flash.contents(IMAGE_ADDRESS as usize, 600),
+        &image[..600]
+    );
+    assert_eq!(
+        harness.flash.contents(IMAGE_ADDRESS as usize + 600, 424),
+        vec![0xFF; 424]
+    );
+}
+
+#[test]
+fn wrong_key_is_rejected() {
+    let harness = Harness::with_image_key();
+    let mut session = Session::new(&harness);
+    assert_response(
+        session.write_encrypted_image(&[0x3D; 32], &NONCE, IMAGE_ADDRESS, &image()),
+        "BadArguments",
+    );
+    // The image is erased, so it can't be booted.
+    assert_eq!(
+        harness.flash.contents(IMAGE_ADDRESS as usize, 1024),
+        vec![0xFF; 1024]
+    );
+    let operations = harness.flash.operations();
+    assert_eq!(
+        operations[operations.len() - 2..],
+        [
+            FlashOperation::Erase {
+                page_number: IMAGE_ADDRESS as usize / 512
+            },
+            FlashOperation::Erase {
+                page_number: IMAGE_ADDRESS as usize / 512 + 1
+            },
+        ]
+    );
+}
+
+#[test]
+fn tampered_page_is_rejected() {
+    let harness = Harness::with_image_key();
+    let mut image = image();
+    let tag = encrypt_image(&IMAGE_KEY, &NONCE, IMAGE_ADDRESS, &mut image);
+    image[700] ^= 1;
+
+    let response = |command: &Command| harness.command(command);
+    assert_eq!(
+        response(&Command::BeginEncryptedImage {
+            address: IMAGE_ADDRESS,
+            nonce: &NONCE,
+        }),
+        vec![ESCAPE_CHAR, RES_OK]
+    );
+    for (address, page) in [
+        (IMAGE_ADDRESS, &image[..512]),
+        (IMAGE_ADDRESS + 512, &image[512..]),
+    ] {
+        assert_eq!(
+            response(&Command::WriteEncryptedPage {
+                address,
+                data: page,
+            }),
+            vec![ESCAPE_CHAR, RES_OK]
+        );
+    }
+    assert_eq!(
+        response(&Command::EndEncryptedImage { tag: &tag }),
+        vec![ESCAPE_CHAR, RES_BADARGS]
+    );
+    assert_eq!(
+        harness.flash.contents(IMAGE_ADDRESS as usize, 1024),
+        vec![0xFF; 1024]
+    );
+}
+
+#[test]
+fn unfinished_image_is_erased() {
+    let harness = Harness::with_image_key();
+    let mut image = image();
+    let mut cipher = ImageCipher::new(&IMAGE_KEY, &NONCE, IMAGE_ADDRESS);
+    cipher.encrypt(&mut image);
+    let begin = Command::BeginEncryptedImage {
+        address: IMAGE_ADDRESS,
+        nonce: &NONCE,
+    };
+    let first_page = Command::WriteEncryptedPage {
+        address: IMAGE_ADDRESS,
+        data: &image[..512],
+    };
+
+    // Starting another image.
+    harness.command(&begin);
+    assert_eq!(harness.command(&first_page), vec![ESCAPE_CHAR, RES_OK]);
+    assert_ne!(
+        harness.flash.contents(IMAGE_ADDRESS as usize, 512),
+        vec![0xFF; 512]
+    );
+    assert_eq!(harness.command(&begin), vec![ESCAPE_CHAR, RES_OK]);
+    assert_eq!(
+        harness.flash.contents(IMAGE_ADDRESS as usize, 512),
+        vec![0xFF; 512]
+    );
+
+    // Leaving the bootloader.
+    assert_eq!(harness.command(&first_page), vec![ESCAPE_CHAR, RES_OK]);
+    assert_eq!(harness.command(&Command::Exit), vec![]);
+    assert!(harness.exited());
+    assert_eq!(
+        harness.flash.contents(IMAGE_ADDRESS as usize, 512),
+        vec![0xFF; 512]
+    );
+}
+
+#[test]
+fn failed_page_can_be_sent_again() {
+    let harness = Harness::with_image_key();
+    let mut image = image();
+    let tag = encrypt_image(&IMAGE_KEY, &NONCE, IMAGE_ADDRESS, &mut image);
+    let second_page = Command::WriteEncryptedPage {
+        address: IMAGE_ADDRESS + 512,
+        data: &image[512..],
+    };
+
+    harness.command(&Command::BeginEncryptedImage {
+        address: IMAGE_ADDRESS,
+        nonce: &NONCE,
+    });
+    harness.command(&Command::WriteEncryptedPage {
+        address: IMAGE_ADDRESS,
+        data: &image[..512],
+    });
+    harness.flash.inject_fault(
+        FlashOperation::Write {
+            page_number: IMAGE_ADDRESS as usize / 512 + 1,
+        },
+        FlashFault::Report,
+    );
+    assert_eq!(
+        harness.command(&second_page),
+        vec![ESCAPE_CHAR, RES_FLASH_ERROR]
+    );
+    // The cipher went back to the start of the page.
+    assert_eq!(harness.command(&second_page), vec![ESCAPE_CHAR, RES_OK]);
+    assert_eq!(
+        harness.command(&Command::EndEncryptedImage { tag: &tag }),
+        vec![ESCAPE_CHAR, RES_OK]
+    );
+    assert_eq!(
+        harness.flash.contents(IMAGE_ADDRESS as usize, 1024),
+        self::image()
+    );
+}
+
+#[test]
+fn failed_page_is_erased_with_image() {
+    let harness = Harness::with_image_key();
+    let mut image = image();
+    encrypt_image(&IMAGE_KEY, &NONCE, IMAGE_ADDRESS, &mut image);
+
+    harness.command(&Command::BeginEncryptedImage {
+        address: IMAGE_ADDRESS,
+        nonce: &NONCE,
+    });
+    harness.flash.inject_fault(
+        FlashOperation::Write {
+            page_number: IMAGE_ADDRESS as usize / 512,
+        },
+        FlashFault::Report,
+    );
+    assert_eq!(
+        harness.command(&Command::WriteEncryptedPage {
+            address: IMAGE_ADDRESS,
+            data: &image[..512],
+        }),
+        vec![ESCAPE_CHAR, RES_FLASH_ERROR]
+    );
+    harness.flash.clear_operations();
+    assert_eq!(
+        harness.command(&Command::EndEncryptedImage { tag: &[0; 32] }),
+        vec![ESCAPE_CHAR, RES_BADARGS]
+    );
+    assert_eq!(
+        harness.flash.operations(),
+        vec![FlashOperation::Erase {
+            page_number: IMAGE_ADDRESS as usize / 512
+        }]
+    );
+}
+
+#[test]
+fn pages_must_be_in_order() {
+    let harness = Harness::with_image_key();
+    let mut image = image();
+    let mut cipher = ImageCipher::new(&IMAGE_KEY, &NONCE, IMAGE_ADDRESS);
+    cipher.encrypt(&mut image);
+
+    // Nothing started.
+    assert_eq!(
+        harness.command(&Command::WriteEncryptedPage {
+            address: IMAGE_ADDRESS,
+            data: &image[..512],
+        }),
+        vec![ESCAPE_CHAR, RES_BADARGS]
+    );
+    assert_eq!(
+        harness.command(&Command::EndEncryptedImage { tag: &[0; 32] }),
+        vec![ESCAPE_CHAR, RES_BADARGS]
+    );
+
+    harness.command(&Command::BeginEncryptedImage {
+        address: IMAGE_ADDRESS,
+        nonce: &NONCE,
+    });
+    harness.flash.clear_operations();
+    assert_eq!(
+        harness.command(&Command::WriteEncryptedPage {
+            address: IMAGE_ADDRESS + 512,
+            data: &image[512..],
+        }),
+        vec![ESCAPE_CHAR, RES_BADADDR]
+    );
+    assert!(harness.flash.operations().is_empty());
+}
+
+#[test]
+fn bad_start() {
+    let harness = Harness::new();
+    harness.set_image_key(IMAGE_KEY_INDEX);
+    let begin = |address| Command::BeginEncryptedImage {
+        address,
+        nonce: &NONCE,
+    };
+
+    // No key stored yet.
+    assert_eq!(
+        harness.command(&begin(IMAGE_ADDRESS)),
+        vec![ESCAPE_CHAR, RES_BADARGS]
+    );
+
+    Session::new(&harness)
+        .set_attr(IMAGE_KEY_INDEX, b"imagekey", &IMAGE_KEY)
+        .unwrap();
+    assert_eq!(
+        harness.command(&begin(IMAGE_ADDRESS + 4)),
+        vec![ESCAPE_CHAR, RES_BADADDR]
+    );
+    assert_eq!(
+        harness.command(&begin(LAYOUT.bootloader_address)),
+        vec![ESCAPE_CHAR, RES_BADADDR]
+    );
+}
+
+#[test]
+fn key_is_never_sent() {
+    let harness = Harness::with_image_key();
+    let mut session = Session::new(&harness);
+    let key_address = LAYOUT.attributes_address as u32 + IMAGE_KEY_INDEX as u32 * 64;
+
+    let attribute = session.get_attr(IMAGE_KEY_INDEX).unwrap();
+    assert_eq!(attribute.key, b"imagekey".to_vec());
+    assert!(attribute.value.is_empty());
+    assert!(!session.info().unwrap().contains("imagekey"));
+    assert_response(session.read_range(key_address + 8, 16), "BadAddress");
+    assert_response(session.crc_int_flash(key_address, 1024), "BadAddress");
+    assert_response(session.hash_int_flash(key_address + 63, 1), "BadAddress");
+    session.read_range(key_address + 64, 8).unwrap();
+}
+
+#[test]
+fn needs_authentication() {
+    let harness = Harness::with_image_key();
+    harness.set_authentication(KEY_INDEX);
+    assert_eq!(
+        harness.command(&Command::BeginEncryptedImage {
+            address: IMAGE_ADDRESS,
+            nonce: &NONCE,
+        }),
+        vec![ESCAPE_CHAR, RES_UNAUTHENTICATED]
+    );
+}
+
+#[test]
+fn capabilities() {
+    let harness = Harness::new();
+    let capabilities = Session::new(&harness).capabilities().unwrap();
+    assert!(!capabilities.supports(CMD_BEGIN_ENC_IMAGE));
+    assert_eq!(
+        harness.command(&Command::EndEncryptedImage { tag: &[0; 32] }),
+        vec![ESCAPE_CHAR, RES_UNKNOWN]
+    );
+
+    harness.set_image_key(IMAGE_KEY_INDEX);
+    let capabilities = Session::new(&harness).capabilities().unwrap();
+    assert!(capabilities.supports(CMD_BEGIN_ENC_IMAGE));
+    assert!(capabilities.supports(CMD_END_ENC_IMAGE));
+}
@@ -0,0 +1,130 @@
+//! Check that ranges are erased page by page, with progress along the way.
+
+use bootloader_mock::{
+    assert_response, FlashFault, FlashOperation, Harness, DATA_ADDRESS, ESCAPE_CHAR, PAGE_SIZE,
+    RES_BADADDR, RES_BADARGS, RES_FLASH_ERROR, RES_OK,
+};
+use tock_bootloader_protocol::client::Session;
+use tock_bootloader_protocol::{
+    Command, Response, ResponseEncoder, CMD_ERASE_RANGE, ERASE_PROGRESS_PAGES,
+};
+
+fn erase(index: usize) -> FlashOperation {
+    FlashOperation::Erase {
+        page_number: DATA_ADDRESS as usize / PAGE_SIZE + index,
+    }
+}
+
+fn progress(index: usize) -> Vec<u8> {
+    ResponseEncoder::new(&Response::EraseProgress {
+        address: DATA_ADDRESS + (index * PAGE_SIZE) as u32,
+    })
+    .unwrap()
+    .collect()
+}
+
+#[test]
+fn range_erased_with_progress() {
+    let harness = Harness::new();
+    let pages = 2 * ERASE_PROGRESS_PAGES as usize + 4;
+    harness
+        .flash
+        .load(DATA_ADDRESS as usize, &vec![0; (pages + 1) * PAGE_SIZE]);
+    harness.flash.clear_operations();
+
+    let output = harness.command(&Command::EraseRange {
+        address: DATA_ADDRESS,
+        length: (pages * PAGE_SIZE) as u32,
+    });
+    let mut expected = progress(ERASE_PROGRESS_PAGES as usize);
+    expected.extend(progress(2 * ERASE_PROGRESS_PAGES as usize));
+    expected.extend(&[ESCAPE_CHAR, RES_OK]);
+    assert_eq!(output, expected);
+    assert_eq!(
+        harness.flash.operations(),
+        (0..pages).map(erase).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        harness
+            .flash
+            .contents(DATA_ADDRESS as usize, (pages + 1) * PAGE_SIZE),
+        [vec![0xFF; pages * PAGE_SIZE], vec![0; PAGE_SIZE]].concat()
+    );
+}
+
+#[test]
+fn session_erase_range() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    assert!(session.capabilities().unwrap().supports(CMD_ERASE_RANGE));
+
+    harness
+        .flash
+        .load(DATA_ADDRESS as usize, &[0; 32 * PAGE_SIZE]);
+    session
+        .erase_range(DATA_ADDRESS, 32 * PAGE_SIZE as u32)
+        .unwrap();
+    assert_eq!(
+        harness
+            .flash
+            .contents(DATA_ADDRESS as usize, 32 * PAGE_SIZE),
+        vec![0xFF; 32 * PAGE_SIZE]
+    );
+    session.ping().unwrap();
+}
+
+#[test]
+fn protected_range_refused() {
+    let harness = Harness::new();
+    harness.flash.clear_operations();
+    // Starts inside flash the bootloader owns.
+    let command = Command::EraseRange {
+        address: 0x7E00,
+        length: 2 * PAGE_SIZE as u32,
+    };
+    assert_eq!(harness.command(&command), vec![ESCAPE_CHAR, RES_BADADDR]);
+    // Not whole pages.
+    let command = Command::EraseRange {
+        address: DATA_ADDRESS,
+        length: PAGE_SIZE as u32 + 4,
+    };
+    assert_eq!(harness.command(&command), vec![ESCAPE_CHAR, RES_BADADDR]);
+    assert_eq!(harness.flash.operations(), vec![]);
+}
+
+#[test]
+fn empty_range_refused() {
+    let harness = Harness::new();
+    let command = Command::EraseRange {
+        address: DATA_ADDRESS,
+        length: 0,
+    };
+    assert_eq!(harness.command(&command), vec![ESCAPE_CHAR, RES_BADARGS]);
+}
+
+#[test]
+fn flash_error_stops_erase() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    harness.flash.inject_fault(erase(2), FlashFault::Report);
+    harness.flash.clear_operations();
+    let command = Command::EraseRange {
+        address: DATA_ADDRESS,
+        length: 4 * PAGE_SIZE as u32,
+    };
+    assert_eq!(
+        harness.command(&command),
+        vec![ESCAPE_CHAR, RES_FLASH_ERROR]
+    );
+    assert_eq!(
+        harness.flash.operations(),
+        vec![erase(0), erase(1), erase(2)]
+    );
+
+    harness.flash.inject_fault(erase(0), FlashFault::Refuse);
+    assert_response(
+        session.erase_range(DATA_ADDRESS, 4 * PAGE_SIZE as u32),
+        "FlashError",
+    );
+    session.ping().unwrap();
+}
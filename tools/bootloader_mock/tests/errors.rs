@@ -0,0 +1,184 @@
+//! Check that failed flash and UART operations are reported to the host and
+//! leave the bootloader ready for the next command.
+
+use bootloader_mock::{
+    assert_response, FlashFault, FlashOperation, Harness, DATA_ADDRESS, ESCAPE_CHAR, PAGE_SIZE,
+    RES_INTERNAL_ERROR, RES_PONG,
+};
+use tock_bootloader_protocol::client::Session;
+use tock_bootloader_protocol::{Command, CommandEncoder};
+
+const DATA_PAGE: usize = DATA_ADDRESS as usize / PAGE_SIZE;
+
+#[test]
+fn write_refused() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    harness.flash.inject_fault(
+        FlashOperation::Write {
+            page_number: DATA_PAGE,
+        },
+        FlashFault::Refuse,
+    );
+    assert_response(session.write_page(DATA_ADDRESS, &[0xAA; 512]), "FlashError");
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 512),
+        [0xFF; 512]
+    );
+
+    // Trying again works.
+    session.ping().unwrap();
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 512),
+        [0xAA; 512]
+    );
+}
+
+#[test]
+fn write_fails() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    harness.flash.inject_fault(
+        FlashOperation::Write {
+            page_number: DATA_PAGE,
+        },
+        FlashFault::Report,
+    );
+    assert_response(session.write_page(DATA_ADDRESS, &[0xAA; 512]), "FlashError");
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 512),
+        [0xAA; 512]
+    );
+}
+
+#[test]
+fn attribute_write_fails() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    session.set_attr(0, b"board", b"hail").unwrap();
+
+    let page_number = bootloader_mock::LAYOUT.attributes_address / PAGE_SIZE;
+    for fault in [FlashFault::Refuse, FlashFault::Report] {
+        harness
+            .flash
+            .inject_fault(FlashOperation::Write { page_number }, fault);
+        assert_response(session.set_attr(0, b"board", b"imix"), "FlashError");
+        assert_eq!(session.get_attr(0).unwrap().value, b"hail".to_vec());
+    }
+    session.set_attr(0, b"board", b"imix").unwrap();
+    assert_eq!(session.get_attr(0).unwrap().value, b"imix".to_vec());
+}
+
+#[test]
+fn read_fails() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    let read = FlashOperation::Read {
+        page_number: DATA_PAGE,
+    };
+    for fault in [FlashFault::Refuse, FlashFault::Report] {
+        harness.flash.inject_fault(read, fault);
+        assert_response(session.read_range(DATA_ADDRESS, 16), "FlashError");
+        harness.flash.inject_fault(read, fault);
+        assert_response(session.crc_int_flash(DATA_ADDRESS, 512), "FlashError");
+    }
+    assert_eq!(session.read_range(DATA_ADDRESS, 4).unwrap(), vec![0xFF; 4]);
+}
+
+#[test]
+fn erase_fails() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    for fault in [FlashFault::Refuse, FlashFault::Report] {
+        harness.flash.inject_fault(
+            FlashOperation::Erase {
+                page_number: DATA_PAGE,
+            },
+            fault,
+        );
+        assert_response(session.erase_page(DATA_ADDRESS), "FlashError");
+        assert_eq!(
+            harness.flash.contents(DATA_ADDRESS as usize, 512),
+            [0xAA; 512]
+        );
+    }
+    session.erase_page(DATA_ADDRESS).unwrap();
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 512),
+        [0xFF; 512]
+    );
+}
+
+#[test]
+fn transmit_fails() {
+    let harness = Harness::new();
+
+    // The response is lost, but the bootloader keeps listening.
+    harness.uart.refuse_next_transmit();
+    assert!(harness.command(&Command::Ping).is_empty());
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+
+    harness.uart.fail_next_transmit();
+    assert!(harness.command(&Command::Ping).is_empty());
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+}
+
+#[test]
+fn receive_fails() {
+    let harness = Harness::new();
+
+    // Without a command started there is nothing to answer.
+    harness.uart.fail_next_receive();
+    assert!(harness.command(&Command::Ping).is_empty());
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+
+    // Losing the rest of a command is reported.
+    let frame: Vec<u8> = CommandEncoder::new(&Command::WritePage {
+        address: DATA_ADDRESS,
+        data: &[0xAA; 512],
+    })
+    .unwrap()
+    .collect();
+    assert!(harness.raw(&frame[..100]).is_empty());
+    harness.uart.fail_next_receive();
+    assert_eq!(
+        harness.raw(&frame[100..]),
+        vec![ESCAPE_CHAR, RES_INTERNAL_ERROR]
+    );
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+}
+
+#[test]
+fn receive_refused() {
+    let harness = Harness::new();
+    harness.uart.refuse_next_receive();
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+    assert!(!harness.uart.is_receiving());
+
+    // The bootloader tries again once the timeout expires.
+    assert_eq!(harness.timeout.running(), Some(10));
+    assert!(harness.timeout.expire());
+    harness.run_until_idle();
+    assert!(harness.uart.is_receiving());
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+}
+
+#[test]
+fn receive_refused_during_baud_verify() {
+    let harness = Harness::new();
+    harness.uart.refuse_next_receive();
+    Session::new(&harness).set_baud_rate(921600).unwrap();
+    assert!(!harness.uart.is_receiving());
+
+    // Trying again doesn't cut the time the host has to verify short. Once
+    // it is up the bootloader goes back to the old rate and listens again.
+    assert_eq!(harness.timeout.running(), Some(1000));
+    assert!(harness.timeout.expire());
+    harness.run_until_idle();
+    assert_eq!(harness.uart.baud_rates(), vec![115200, 921600, 115200]);
+    assert!(harness.uart.is_receiving());
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+}
@@ -0,0 +1,113 @@
+//! Check that the host can only touch the flash the policy allows.
+
+use bootloader::flash_policy::{FlashPolicy, FlashRegion};
+use bootloader_mock::{assert_response, FlashOperation, Harness, DATA_ADDRESS, FLASH_SIZE, LAYOUT};
+use tock_bootloader_protocol::client::{Error, Session};
+
+fn policy() -> FlashPolicy<'static> {
+    FlashPolicy::new(&LAYOUT, 512).with_flash(FlashRegion::new(0, FLASH_SIZE as u32))
+}
+
+fn assert_bad_address<T: std::fmt::Debug>(result: Result<T, Error>) {
+    assert_response(result, "BadAddress");
+}
+
+#[test]
+fn regions() {
+    let region = FlashRegion::new(0x1000, 0x200);
+    assert_eq!(region.end, 0x1200);
+    assert!(region.contains(0x1000, 0x200));
+    assert!(!region.contains(0x1000, 0x201));
+    assert!(!region.contains(0xFFF, 1));
+    assert!(region.overlaps(0xE00, 0x201));
+    assert!(region.overlaps(0x11FF, 0x10));
+    assert!(!region.overlaps(0x1200, 0x10));
+    assert!(!region.overlaps(0x1000, 0));
+    assert!(!region.contains(0xFFFFFF00, 0x200));
+}
+
+#[test]
+fn protected_regions() {
+    let policy = policy();
+    assert!(policy.allows_write(DATA_ADDRESS, 512));
+    assert!(policy.allows_write(LAYOUT.bootloader_end_address, 1024));
+    assert!(!policy.allows_write(LAYOUT.bootloader_end_address - 512, 1024));
+    assert!(!policy.allows_erase(LAYOUT.flags_address as u32, 512));
+    assert!(!policy.allows_erase(LAYOUT.attributes_address as u32, 512));
+
+    // The flags and attributes don't have to be in the bootloader.
+    let layout = bootloader::bootloader::FlashLayout {
+        bootloader_end_address: 0x400,
+        ..LAYOUT
+    };
+    let policy = FlashPolicy::new(&layout, 512);
+    assert!(policy.allows_write(0x400 + 512 + 1024, 512));
+    assert!(!policy.allows_write(0x400, 512));
+    assert!(!policy.allows_write(0x800, 512));
+
+    // Reading the bootloader is fine.
+    assert!(policy.allows_read(0, 0x8000));
+}
+
+#[test]
+fn bounds_and_alignment() {
+    let policy = policy();
+    let end = FLASH_SIZE as u32;
+    assert!(policy.allows_read(end - 4, 4));
+    assert!(!policy.allows_read(end - 4, 5));
+    assert!(!policy.allows_read(u32::MAX, 2));
+    assert!(policy.allows_write(end - 512, 512));
+    assert!(!policy.allows_write(end, 512));
+    assert!(!policy.allows_write(DATA_ADDRESS + 4, 512));
+    assert!(!policy.allows_write(DATA_ADDRESS, 100));
+}
+
+#[test]
+fn reserved_regions() {
+    static RESERVED: [FlashRegion; 1] = [FlashRegion::new(0x20000, 0x1000)];
+    let policy = policy().with_reserved(&RESERVED);
+    assert!(!policy.allows_write(0x20800, 512));
+    assert!(policy.allows_write(0x21000, 512));
+    assert!(policy.allows_read(0x20000, 0x1000));
+}
+
+#[test]
+fn commands_follow_policy() {
+    let harness = Harness::new();
+    harness.flash.clear_operations();
+    let mut session = Session::new(&harness);
+    let end = FLASH_SIZE as u32;
+
+    assert_bad_address(session.erase_page(0));
+    assert_bad_address(session.erase_page(LAYOUT.flags_address as u32));
+    assert_bad_address(session.erase_page(DATA_ADDRESS + 4));
+    assert_bad_address(session.erase_page(end));
+    assert_bad_address(session.write_page(DATA_ADDRESS + 4, &[0xAA; 512]));
+    assert_bad_address(session.write_page(end, &[0xAA; 512]));
+    assert_bad_address(session.read_range(end - 4, 8));
+    assert_bad_address(session.crc_int_flash(end - 512, 1024));
+    assert_bad_address(session.hash_int_flash(end - 512, 1024));
+    assert!(harness.flash.operations().is_empty());
+
+    session.read_range(end - 4, 4).unwrap();
+    session.erase_page(end - 512).unwrap();
+    assert_eq!(
+        harness.flash.operations().last(),
+        Some(&FlashOperation::Erase {
+            page_number: (end as usize - 512) / 512
+        })
+    );
+}
+
+#[test]
+fn board_policy() {
+    static RESERVED: [FlashRegion; 1] = [FlashRegion::new(0x20000, 0x1000)];
+    let harness = Harness::new();
+    harness
+        .bootloader
+        .set_flash_policy(policy().with_reserved(&RESERVED));
+    let mut session = Session::new(&harness);
+    assert_bad_address(session.write_page(0x20000, &[0xAA; 512]));
+    session.write_page(0x21000, &[0xAA; 512]).unwrap();
+    session.read_range(0x20000, 16).unwrap();
+}
@@ -0,0 +1,148 @@
+//! Check how `BootloaderEnterer` picks the kernel slot to boot.
+
+use bootloader::kernel_slots::{
+    active_slot, choose_boot_slot, next_sequence, reject_booted_slot, state_from_word,
+    state_to_word, trial_slot, SlotDescriptor, STATE_CONFIRMED,
+};
+use tock_bootloader_protocol::SlotState;
+
+fn slot(sequence: u32, state: SlotState) -> SlotDescriptor {
+    SlotDescriptor {
+        address: 0x10000,
+        length: 0x1000,
+        crc: 0,
+        version: sequence,
+        sequence,
+        state,
+    }
+}
+
+#[test]
+fn pending_slot_boots_on_trial() {
+    let mut slots = [slot(1, SlotState::Confirmed), slot(2, SlotState::Pending)];
+    assert_eq!(choose_boot_slot(&mut slots, &|_| true), Some(1));
+    assert_eq!(slots[0].state, SlotState::Confirmed);
+    assert_eq!(slots[1].state, SlotState::Trial);
+    assert_eq!(active_slot(&slots), Some(0));
+}
+
+#[test]
+fn unconfirmed_trial_falls_back() {
+    let mut slots = [slot(1, SlotState::Confirmed), slot(2, SlotState::Trial)];
+    assert_eq!(choose_boot_slot(&mut slots, &|_| true), Some(0));
+    assert_eq!(slots[0].state, SlotState::Confirmed);
+    assert_eq!(slots[1].state, SlotState::Invalid);
+}
+
+#[test]
+fn newest_confirmed_slot_boots() {
+    let mut slots = [slot(3, SlotState::Confirmed), slot(2, SlotState::Confirmed)];
+    assert_eq!(choose_boot_slot(&mut slots, &|_| true), Some(0));
+    assert_eq!(active_slot(&slots), Some(0));
+
+    // Sequence numbers wrap.
+    let mut slots = [
+        slot(0xFFFFFFFF, SlotState::Confirmed),
+        slot(0, SlotState::Confirmed),
+    ];
+    assert_eq!(choose_boot_slot(&mut slots, &|_| true), Some(1));
+}
+
+#[test]
+fn pending_slot_that_fails_checks_is_invalid() {
+    let mut slots = [slot(1, SlotState::Confirmed), slot(2, SlotState::Pending)];
+    let bootable = |slot: &SlotDescriptor| slot.sequence != 2;
+    assert_eq!(choose_boot_slot(&mut slots, &bootable), Some(0));
+    assert_eq!(slots[1].state, SlotState::Invalid);
+}
+
+#[test]
+fn no_bootable_slot() {
+    let mut slots = [slot(1, SlotState::Confirmed), slot(2, SlotState::Trial)];
+    assert_eq!(choose_boot_slot(&mut slots, &|_| false), None);
+    assert_eq!(slots[0].state, SlotState::Invalid);
+    assert_eq!(slots[1].state, SlotState::Invalid);
+
+    let mut slots = [slot(0, SlotState::Empty), slot(0, SlotState::Invalid)];
+    assert_eq!(choose_boot_slot(&mut slots, &|_| true), None);
+}
+
+#[test]
+fn failed_kernel_rolls_back() {
+    // A kernel on trial that never confirmed.
+    let mut slots = [slot(1, SlotState::Confirmed), slot(2, SlotState::Trial)];
+    assert_eq!(trial_slot(&slots), Some(1));
+    assert_eq!(reject_booted_slot(&mut slots), Some(1));
+    assert_eq!(choose_boot_slot(&mut slots, &|_| true), Some(0));
+
+    // A confirmed kernel that stopped coming up.
+    let mut slots = [slot(1, SlotState::Confirmed), slot(2, SlotState::Confirmed)];
+    assert_eq!(trial_slot(&slots), None);
+    assert_eq!(reject_booted_slot(&mut slots), Some(1));
+    assert_eq!(choose_boot_slot(&mut slots, &|_| true), Some(0));
+
+    // Nothing to go back to.
+    let mut slots = [slot(1, SlotState::Confirmed), slot(0, SlotState::Empty)];
+    assert_eq!(reject_booted_slot(&mut slots), Some(0));
+    assert_eq!(choose_boot_slot(&mut slots, &|_| true), None);
+}
+
+#[test]
+fn state_changes_only_clear_bits() {
+    let changes = [
+        (SlotState::Pending, SlotState::Trial),
+        (SlotState::Pending, SlotState::Invalid),
+        (SlotState::Trial, SlotState::Confirmed),
+        (SlotState::Trial, SlotState::Invalid),
+        (SlotState::Confirmed, SlotState::Invalid),
+    ];
+    for (from, to) in changes {
+        let (from, to) = (state_to_word(from), state_to_word(to));
+        assert_eq!(from & to, to);
+    }
+    assert_eq!(state_to_word(SlotState::Confirmed), STATE_CONFIRMED);
+}
+
+#[test]
+fn state_words() {
+    for state in [
+        SlotState::Empty,
+        SlotState::Pending,
+        SlotState::Trial,
+        SlotState::Confirmed,
+        SlotState::Invalid,
+    ] {
+        assert_eq!(state_from_word(state_to_word(state)), state);
+    }
+    // The flags are built with zeros.
+    assert_eq!(state_from_word(0), SlotState::Empty);
+    assert_eq!(state_from_word(0x12345678), SlotState::Invalid);
+}
+
+#[test]
+fn descriptor_round_trip() {
+    let mut flags = [0; 512];
+    let descriptor = SlotDescriptor {
+        address: 0x28000,
+        length: 0x1234,
+        crc: 0xDEADBEEF,
+        version: 3,
+        sequence: 9,
+        state: SlotState::Trial,
+    };
+    descriptor.to_flags(&mut flags, 1);
+    assert_eq!(SlotDescriptor::from_flags(&flags, 1), descriptor);
+    assert_eq!(&flags[96..100], &[0x00, 0x80, 0x02, 0x00]);
+    assert_eq!(
+        SlotDescriptor::from_flags(&flags, 0).state,
+        SlotState::Empty
+    );
+}
+
+#[test]
+fn sequence_numbers() {
+    let slots = [slot(0, SlotState::Empty), slot(0, SlotState::Empty)];
+    assert_eq!(next_sequence(&slots), 1);
+    let slots = [slot(5, SlotState::Invalid), slot(3, SlotState::Confirmed)];
+    assert_eq!(next_sequence(&slots), 6);
+}
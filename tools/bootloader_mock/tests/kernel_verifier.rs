@@ -0,0 +1,55 @@
+//! Check the signature verification used for secure boot.
+
+use bootloader::interfaces::KernelVerifier;
+use bootloader::kernel_verifier_ed25519::KernelVerifierEd25519;
+use ed25519_compact::{KeyPair, Seed};
+use tock_bootloader_protocol::signature::{footer_header, SIGNATURE_FOOTER_LEN};
+
+fn key_pair(seed: u8) -> KeyPair {
+    KeyPair::from_seed(Seed::new([seed; 32]))
+}
+
+/// Sign `kernel` the way `bootloader_sign` does.
+fn sign(key_pair: &KeyPair, kernel: &[u8]) -> Vec<u8> {
+    let mut image = kernel.to_vec();
+    image.extend(footer_header(kernel.len() as u32));
+    let signature = key_pair.sk.sign(&image, None);
+    image.extend(signature.as_ref());
+    image
+}
+
+fn verifier(key_pair: &KeyPair) -> KernelVerifierEd25519 {
+    let public_key: &'static [u8; 32] = Box::leak(Box::new(*key_pair.pk));
+    KernelVerifierEd25519::new(public_key)
+}
+
+#[test]
+fn signed_kernel() {
+    let key_pair = key_pair(1);
+    let kernel: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    let image = sign(&key_pair, &kernel);
+    assert_eq!(image.len(), kernel.len() + SIGNATURE_FOOTER_LEN);
+    assert!(verifier(&key_pair).verify(&image));
+}
+
+#[test]
+fn wrong_key() {
+    let image = sign(&key_pair(1), &[0x55; 100]);
+    assert!(!verifier(&key_pair(2)).verify(&image));
+}
+
+#[test]
+fn modified_kernel() {
+    let key_pair = key_pair(1);
+    let verifier = verifier(&key_pair);
+    let image = sign(&key_pair, &[0x55; 100]);
+
+    let mut modified = image.clone();
+    modified[10] ^= 1;
+    assert!(!verifier.verify(&modified));
+
+    // Cutting the image short loses the footer.
+    assert!(!verifier.verify(&image[..image.len() - 4]));
+    assert!(!verifier.verify(&image[..40]));
+    assert!(!verifier.verify(&[0x55; 100]));
+}
@@ -0,0 +1,1029 @@
+//! Drive the bootloader through every command it handles.
+
+use bootloader::bootloader::StayReason;
+use bootloader::kernel_slots::SlotDescriptor;
+use bootloader_mock::{
+    FlashOperation, Harness, MockFlash, BOOTLOADER_VERSION, DATA_ADDRESS, ESCAPE_CHAR, FLASH_SIZE,
+    KERNEL_ADDRESS, KERNEL_SLOTS, LAYOUT, RES_BADADDR, RES_BADARGS, RES_CHANGE_BAUD_FAIL,
+    RES_INTERNAL_ERROR, RES_OK, RES_PONG, RES_UNKNOWN,
+};
+use tock_bootloader_protocol::client::{Attribute, Error, Session};
+use tock_bootloader_protocol::hash::{sha256, HASH_SHA256};
+use tock_bootloader_protocol::info::{unescape, BootloaderInfo};
+use tock_bootloader_protocol::{
+    BaudMode, Command, CommandEncoder, KernelSlot, SlotState, CMD_CHANGE_BAUD, CMD_CLKOUT,
+    CMD_CRCRX, CMD_GET_CAPABILITIES, CMD_GET_SLOTS, CMD_HASHIF, CMD_ID, CMD_PING, CMD_SKERNELCRC,
+    CMD_SSLOTPENDING, CMD_WPAGE, CMD_WUSER, CMD_XFINIT, CMD_XWPAGE, PROTOCOL_VERSION,
+};
+
+/// Reference CRC-32 (IEEE 802.3), which is what `CRCIF` computes.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc ^ 0xFFFFFFFF
+}
+
+/// Test data that differs from page to page and includes escape characters.
+fn pattern(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i * 7 + i / 512) as u8).collect()
+}
+
+/// The bootloader should always end up waiting for the next command.
+fn assert_ready(harness: &Harness) {
+    assert!(harness.uart.is_receiving());
+    assert!(!harness.uart.is_transmitting());
+    assert!(!harness.flash.is_busy());
+}
+
+#[test]
+fn start_configures_uart() {
+    let harness = Harness::new();
+    assert_eq!(harness.uart.baud_rates(), vec![115200]);
+    assert_ready(&harness);
+}
+
+#[test]
+fn ping() {
+    let harness = Harness::new();
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+    Session::new(&harness).ping().unwrap();
+    assert!(harness.flash.operations().is_empty());
+    assert_ready(&harness);
+}
+
+#[test]
+fn info() {
+    let harness = Harness::new();
+    let info = Session::new(&harness).info().unwrap();
+    assert_eq!(
+        info,
+        format!(
+            "{{\"version\":\"{}\",\"start_address\":\"0x{:08x}\",\"name\":\"Tock Bootloader\",\"attributes\":{{}}}}",
+            BOOTLOADER_VERSION, KERNEL_ADDRESS
+        )
+    );
+    assert_eq!(
+        harness.flash.operations(),
+        vec![
+            FlashOperation::Read { page_number: 2 },
+            FlashOperation::Read { page_number: 3 },
+        ]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn info_with_attributes() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    session.set_attr(0, b"board", b"mockboard").unwrap();
+    session.set_attr(2, b"arch", b"cortex-m4").unwrap();
+    // Erased attributes are left out.
+    harness
+        .flash
+        .load(LAYOUT.attributes_address + 64, &[0xFF; 64]);
+
+    let json = session.info().unwrap();
+    let info = BootloaderInfo::parse_json(&json).unwrap();
+    assert_eq!(info.version, BOOTLOADER_VERSION);
+    assert_eq!(info.start_address, KERNEL_ADDRESS);
+    assert_eq!(info.name, "Tock Bootloader");
+    assert_eq!(
+        info.attributes(),
+        &[("board", "mockboard"), ("arch", "cortex-m4")]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn info_with_stay_reason() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    assert_eq!(
+        BootloaderInfo::parse_json(&session.info().unwrap())
+            .unwrap()
+            .stay_reason,
+        None
+    );
+
+    harness.bootloader.set_stay_reason(StayReason::BootFailures);
+    let json = session.info().unwrap();
+    let info = BootloaderInfo::parse_json(&json).unwrap();
+    assert_eq!(info.stay_reason, Some("boot_failures"));
+    assert_eq!(info.start_address, KERNEL_ADDRESS);
+    assert_ready(&harness);
+}
+
+#[test]
+fn info_drops_attributes_that_do_not_fit() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    let value = [b'v'; 55];
+    for index in 0..4 {
+        session.set_attr(index, b"long", &value).unwrap();
+    }
+
+    let json = session.info().unwrap();
+    let info = BootloaderInfo::parse_json(&json).unwrap();
+    assert_eq!(info.version, BOOTLOADER_VERSION);
+    assert_eq!(info.attributes().len(), 1);
+    assert_eq!(info.attribute("long"), core::str::from_utf8(&value).ok());
+    assert_eq!(info.dropped_attributes, 3);
+    assert_ready(&harness);
+}
+
+#[test]
+fn info_escapes_attributes() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    session.set_attr(0, b"quote", b"\"a\\b\"").unwrap();
+    session
+        .set_attr(1, b"owner", "J\u{fc}rgen".as_bytes())
+        .unwrap();
+    // Not UTF-8, so it can only be counted.
+    session.set_attr(2, b"bad", &[0xC0, 0x80]).unwrap();
+
+    let json = session.info().unwrap();
+    assert!(json.is_ascii());
+    let info = BootloaderInfo::parse_json(&json).unwrap();
+    let mut buffer = [0; 16];
+    assert_eq!(
+        unescape(info.attribute("quote").unwrap(), &mut buffer),
+        Ok("\"a\\b\"")
+    );
+    assert_eq!(
+        unescape(info.attribute("owner").unwrap(), &mut buffer),
+        Ok("J\u{fc}rgen")
+    );
+    assert_eq!(info.attribute("bad"), None);
+    assert_eq!(info.dropped_attributes, 1);
+    assert_ready(&harness);
+}
+
+#[test]
+fn id() {
+    let harness = Harness::new();
+    let id = [0x01, 0x23, ESCAPE_CHAR, 0x45, 0x67, 0x89, 0xAB, 0xCD];
+    harness.set_device_id(&id);
+
+    assert_eq!(Session::new(&harness).id().unwrap(), id);
+    assert!(harness.flash.operations().is_empty());
+    assert_ready(&harness);
+}
+
+#[test]
+fn id_without_device_id() {
+    let harness = Harness::new();
+    assert_eq!(
+        harness.command(&Command::Id),
+        vec![ESCAPE_CHAR, RES_UNKNOWN]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn capabilities() {
+    let harness = Harness::new();
+    let capabilities = Session::new(&harness).capabilities().unwrap();
+    assert_eq!(capabilities.protocol_version, PROTOCOL_VERSION);
+    assert_eq!(capabilities.page_size, 512);
+    assert_eq!(capabilities.flash_size, FLASH_SIZE as u32);
+    assert_eq!(capabilities.bootloader_start, LAYOUT.bootloader_address);
+    assert_eq!(capabilities.bootloader_end, LAYOUT.bootloader_end_address);
+    assert_eq!(capabilities.max_frame_size, 4224);
+    for command in [
+        CMD_PING,
+        CMD_WPAGE,
+        CMD_XWPAGE,
+        CMD_CHANGE_BAUD,
+        CMD_GET_CAPABILITIES,
+        CMD_SKERNELCRC,
+    ] {
+        assert!(capabilities.supports(command));
+    }
+    for command in [
+        CMD_ID,
+        CMD_CRCRX,
+        CMD_CLKOUT,
+        CMD_WUSER,
+        CMD_GET_SLOTS,
+        CMD_SSLOTPENDING,
+    ] {
+        assert!(!capabilities.supports(command));
+    }
+
+    harness.set_device_id(&[0x01]);
+    harness.set_kernel_slots();
+    let capabilities = Session::new(&harness).capabilities().unwrap();
+    for command in [CMD_ID, CMD_GET_SLOTS, CMD_SSLOTPENDING] {
+        assert!(capabilities.supports(command));
+    }
+    assert_ready(&harness);
+}
+
+#[test]
+fn decoder_capacity() {
+    // Enough for one page and its header, but not two.
+    let harness = Harness::<600>::with_decoder_capacity();
+    let mut session = Session::new(&harness);
+    assert_eq!(session.capabilities().unwrap().max_frame_size, 600);
+
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    assert_eq!(
+        harness.command(&Command::WritePages {
+            address: DATA_ADDRESS,
+            count: 2,
+            data: &[0xBB; 1024],
+        }),
+        [ESCAPE_CHAR, RES_INTERNAL_ERROR]
+    );
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 1024),
+        [vec![0xAA; 512], vec![0xFF; 512]].concat()
+    );
+    session.ping().unwrap();
+}
+
+#[test]
+fn capabilities_without_external_flash() {
+    let harness = Harness::with_flash(MockFlash::new(FLASH_SIZE), LAYOUT, None);
+    let capabilities = Session::new(&harness).capabilities().unwrap();
+    assert!(capabilities.supports(CMD_WPAGE));
+    assert!(!capabilities.supports(CMD_XWPAGE));
+    assert!(!capabilities.supports(CMD_XFINIT));
+    assert_ready(&harness);
+}
+
+#[test]
+fn read_range_within_page() {
+    let harness = Harness::new();
+    let data = pattern(512);
+    harness.flash.load(DATA_ADDRESS as usize, &data);
+
+    let read = Session::new(&harness)
+        .read_range(DATA_ADDRESS + 16, 100)
+        .unwrap();
+    assert_eq!(read, &data[16..116]);
+    assert_eq!(harness.uart.transmissions().len(), 1);
+    assert_ready(&harness);
+}
+
+#[test]
+fn read_range_multiple_chunks() {
+    let harness = Harness::new();
+    let data = pattern(2048);
+    harness.flash.load(DATA_ADDRESS as usize, &data);
+
+    // Starts in the middle of a page and ends in the middle of another, so
+    // every page is sent in its own chunk.
+    let read = Session::new(&harness)
+        .read_range(DATA_ADDRESS + 256, 1200)
+        .unwrap();
+    assert_eq!(read, &data[256..1456]);
+    assert_eq!(harness.uart.transmissions().len(), 3);
+    assert_eq!(
+        harness.flash.operations(),
+        vec![
+            FlashOperation::Read { page_number: 0x80 },
+            FlashOperation::Read { page_number: 0x81 },
+            FlashOperation::Read { page_number: 0x82 },
+        ]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn read_range_escaped_page() {
+    let harness = Harness::new();
+    harness
+        .flash
+        .load(DATA_ADDRESS as usize, &[ESCAPE_CHAR; 512]);
+
+    // Escaped, the page doesn't fit in the bootloader's buffer, so it has to
+    // be sent in two chunks.
+    let read = Session::new(&harness)
+        .read_range(DATA_ADDRESS, 512)
+        .unwrap();
+    assert_eq!(read, vec![ESCAPE_CHAR; 512]);
+    let transmissions = harness.uart.transmissions();
+    assert_eq!(transmissions.len(), 2);
+    assert!(transmissions.iter().all(|t| t.len() <= 600));
+    assert_ready(&harness);
+}
+
+#[test]
+fn write_page() {
+    let harness = Harness::new();
+    let data = pattern(512);
+
+    Session::new(&harness)
+        .write_page(DATA_ADDRESS, &data)
+        .unwrap();
+    assert_eq!(harness.flash.contents(DATA_ADDRESS as usize, 512), data);
+    assert_eq!(
+        harness.flash.operations(),
+        vec![FlashOperation::Write { page_number: 0x80 }]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn write_page_in_bootloader_rejected() {
+    let harness = Harness::new();
+    let before = harness.flash.contents(0, 0x8000);
+
+    let data = pattern(512);
+    let command = Command::WritePage {
+        address: LAYOUT.flags_address as u32,
+        data: &data,
+    };
+    assert_eq!(harness.command(&command), vec![ESCAPE_CHAR, RES_BADADDR]);
+    match Session::new(&harness).write_page(0, &data) {
+        Err(Error::UnexpectedResponse(_)) => {}
+        r => panic!("expected BadAddress, got {:?}", r),
+    }
+
+    assert_eq!(harness.flash.contents(0, 0x8000), before);
+    assert!(harness.flash.operations().is_empty());
+    assert_ready(&harness);
+}
+
+#[test]
+fn write_page_short_rejected() {
+    let harness = Harness::new();
+
+    // RESET, then a WPAGE with only 16 bytes of data.
+    let mut frame = vec![ESCAPE_CHAR, 0x05, 0x00, 0x00, 0x01, 0x00];
+    frame.extend(1..=16);
+    frame.extend(&[ESCAPE_CHAR, 0x07]);
+    assert_eq!(harness.raw(&frame), vec![ESCAPE_CHAR, RES_BADARGS]);
+    assert!(harness.flash.operations().is_empty());
+    assert_ready(&harness);
+}
+
+#[test]
+fn erase_page() {
+    let harness = Harness::new();
+    harness.flash.load(DATA_ADDRESS as usize, &pattern(1024));
+
+    Session::new(&harness).erase_page(DATA_ADDRESS).unwrap();
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 512),
+        vec![0xFF; 512]
+    );
+    // The next page is untouched.
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize + 512, 512),
+        &pattern(1024)[512..]
+    );
+    assert_eq!(
+        harness.flash.operations(),
+        vec![FlashOperation::Erase { page_number: 0x80 }]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn crc_single_page() {
+    let harness = Harness::new();
+    let data = pattern(512);
+    harness.flash.load(DATA_ADDRESS as usize, &data);
+
+    let crc = Session::new(&harness)
+        .crc_int_flash(DATA_ADDRESS, 512)
+        .unwrap();
+    assert_eq!(crc, crc32(&data));
+    assert_ready(&harness);
+}
+
+#[test]
+fn crc_multiple_pages() {
+    let harness = Harness::new();
+    let data = pattern(2048);
+    harness.flash.load(DATA_ADDRESS as usize, &data);
+
+    let crc = Session::new(&harness)
+        .crc_int_flash(DATA_ADDRESS + 16, 1500)
+        .unwrap();
+    assert_eq!(crc, crc32(&data[16..1516]));
+    assert_eq!(
+        harness.flash.operations(),
+        vec![
+            FlashOperation::Read { page_number: 0x80 },
+            FlashOperation::Read { page_number: 0x81 },
+            FlashOperation::Read { page_number: 0x82 },
+        ]
+    );
+    // Only the result is sent.
+    assert_eq!(harness.uart.transmissions().len(), 1);
+    assert_ready(&harness);
+}
+
+#[test]
+fn hash_multiple_pages() {
+    let harness = Harness::new();
+    let data = pattern(2048);
+    harness.flash.load(DATA_ADDRESS as usize, &data);
+    let mut session = Session::new(&harness);
+    assert!(session.capabilities().unwrap().supports(CMD_HASHIF));
+
+    harness.flash.clear_operations();
+    harness.uart.clear_transmissions();
+    let hash = session.hash_int_flash(DATA_ADDRESS + 16, 1500).unwrap();
+    assert_eq!(hash, sha256(&data[16..1516]));
+    assert_eq!(
+        harness.flash.operations(),
+        vec![
+            FlashOperation::Read { page_number: 0x80 },
+            FlashOperation::Read { page_number: 0x81 },
+            FlashOperation::Read { page_number: 0x82 },
+        ]
+    );
+    assert_eq!(harness.uart.transmissions().len(), 1);
+
+    // The hash starts afresh for each command.
+    let hash = session.hash_int_flash(DATA_ADDRESS, 512).unwrap();
+    assert_eq!(hash, sha256(&data[..512]));
+    assert_ready(&harness);
+}
+
+#[test]
+fn hash_unknown_algorithm() {
+    let harness = Harness::new();
+    let command = Command::HashIntFlash {
+        address: DATA_ADDRESS,
+        length: 512,
+        algorithm: HASH_SHA256 + 1,
+    };
+    assert_eq!(harness.command(&command), vec![ESCAPE_CHAR, RES_BADARGS]);
+    assert!(harness.flash.operations().is_empty());
+    assert_ready(&harness);
+}
+
+#[test]
+fn set_and_get_attribute() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+
+    session.set_attr(3, b"board", b"mockboard").unwrap();
+
+    let mut expected = Vec::new();
+    expected.extend(b"board\0\0\0");
+    expected.push(9);
+    expected.extend(b"mockboard");
+    expected.resize(64, 0);
+    let address = LAYOUT.attributes_address + 3 * 64;
+    assert_eq!(harness.flash.contents(address, 64), expected);
+
+    assert_eq!(
+        session.get_attr(3).unwrap(),
+        Attribute {
+            key: b"board\0\0\0".to_vec(),
+            value: b"mockboard".to_vec(),
+        }
+    );
+    assert_eq!(
+        harness.flash.operations(),
+        vec![
+            FlashOperation::Read { page_number: 3 },
+            FlashOperation::Write { page_number: 3 },
+            FlashOperation::Read { page_number: 3 },
+        ]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn get_attribute_escaped() {
+    let harness = Harness::new();
+    let mut attribute = Vec::new();
+    attribute.extend(b"key\0\0\0\0\0");
+    attribute.push(3);
+    attribute.extend(&[ESCAPE_CHAR, 0x01, ESCAPE_CHAR]);
+    harness
+        .flash
+        .load(LAYOUT.attributes_address + 64, &attribute);
+
+    assert_eq!(
+        Session::new(&harness).get_attr(1).unwrap(),
+        Attribute {
+            key: b"key\0\0\0\0\0".to_vec(),
+            value: vec![ESCAPE_CHAR, 0x01, ESCAPE_CHAR],
+        }
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn set_start_address() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+
+    session.set_start_address(0x20000).unwrap();
+    assert_eq!(
+        harness.flash.contents(LAYOUT.flags_address + 32, 4),
+        0x20000u32.to_le_bytes()
+    );
+    // The rest of the flags are preserved.
+    assert_eq!(
+        harness.flash.contents(LAYOUT.flags_address, 14),
+        b"TOCKBOOTLOADER"
+    );
+    assert!(session
+        .info()
+        .unwrap()
+        .contains("\"start_address\":\"0x00020000\""));
+    assert_ready(&harness);
+}
+
+#[test]
+fn set_kernel_crc() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+
+    session.set_kernel_crc(0x1234, 0xDEADBEEF).unwrap();
+    assert_eq!(
+        harness.flash.contents(LAYOUT.flags_address + 36, 8),
+        [0x34, 0x12, 0x00, 0x00, 0xEF, 0xBE, 0xAD, 0xDE]
+    );
+    // The rest of the flags are preserved.
+    assert_eq!(
+        harness.flash.contents(LAYOUT.flags_address + 32, 4),
+        KERNEL_ADDRESS.to_le_bytes()
+    );
+    assert_eq!(
+        harness.flash.operations(),
+        vec![
+            FlashOperation::Read { page_number: 2 },
+            FlashOperation::Write { page_number: 2 },
+        ]
+    );
+    assert_ready(&harness);
+}
+
+/// The slot descriptors currently in the flags.
+fn slot_descriptors(harness: &Harness) -> [SlotDescriptor; 2] {
+    let flags = harness.flash.contents(LAYOUT.flags_address, 512);
+    [
+        SlotDescriptor::from_flags(&flags, 0),
+        SlotDescriptor::from_flags(&flags, 1),
+    ]
+}
+
+#[test]
+fn slots_without_kernel_slots() {
+    let harness = Harness::new();
+    assert_eq!(
+        harness.command(&Command::GetSlots),
+        vec![ESCAPE_CHAR, RES_UNKNOWN]
+    );
+    assert_eq!(
+        harness.command(&Command::SetSlotPending {
+            slot: 0,
+            length: 0x100,
+            crc: 0,
+            version: 1,
+        }),
+        vec![ESCAPE_CHAR, RES_UNKNOWN]
+    );
+    assert!(harness.flash.operations().is_empty());
+    assert_ready(&harness);
+}
+
+#[test]
+fn slots_start_empty() {
+    let harness = Harness::new();
+    harness.set_kernel_slots();
+
+    let slots = Session::new(&harness).slots().unwrap();
+    for (i, slot) in slots.slots.iter().enumerate() {
+        assert_eq!(
+            *slot,
+            KernelSlot {
+                address: KERNEL_SLOTS.address[i],
+                size: KERNEL_SLOTS.size,
+                length: 0,
+                crc: 0,
+                version: 0,
+                state: SlotState::Empty,
+            }
+        );
+    }
+    assert_eq!(slots.active, None);
+    assert_eq!(
+        harness.flash.operations(),
+        vec![FlashOperation::Read { page_number: 2 }]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn set_slot_pending() {
+    let harness = Harness::new();
+    harness.set_kernel_slots();
+    let mut session = Session::new(&harness);
+
+    let kernel = pattern(0x1200);
+    for (i, page) in kernel.chunks(512).enumerate() {
+        let mut page = page.to_vec();
+        page.resize(512, 0xFF);
+        session
+            .write_page(KERNEL_SLOTS.address[1] + i as u32 * 512, &page)
+            .unwrap();
+    }
+    session
+        .set_slot_pending(1, kernel.len() as u32, crc32(&kernel), 7)
+        .unwrap();
+
+    let descriptors = slot_descriptors(&harness);
+    assert_eq!(
+        descriptors[1],
+        SlotDescriptor {
+            address: KERNEL_SLOTS.address[1],
+            length: kernel.len() as u32,
+            crc: crc32(&kernel),
+            version: 7,
+            sequence: 1,
+            state: SlotState::Pending,
+        }
+    );
+    // The rest of the flags are preserved.
+    assert_eq!(descriptors[0].state, SlotState::Empty);
+    assert_eq!(
+        harness.flash.contents(LAYOUT.flags_address, 14),
+        b"TOCKBOOTLOADER"
+    );
+
+    let slots = session.slots().unwrap();
+    assert_eq!(slots.slots[1].state, SlotState::Pending);
+    assert_eq!(slots.slots[1].version, 7);
+    assert_eq!(slots.active, None);
+    assert_ready(&harness);
+}
+
+#[test]
+fn set_slot_pending_refuses_active_slot() {
+    let harness = Harness::new();
+    harness.set_kernel_slots();
+    let mut session = Session::new(&harness);
+
+    // Slot 0 holds the kernel that is running.
+    let mut flags = harness.flash.contents(LAYOUT.flags_address, 512);
+    SlotDescriptor {
+        address: KERNEL_SLOTS.address[0],
+        length: 0x1000,
+        crc: 0x12345678,
+        version: 1,
+        sequence: 4,
+        state: SlotState::Confirmed,
+    }
+    .to_flags(&mut flags, 0);
+    harness.flash.load(LAYOUT.flags_address, &flags);
+
+    assert_eq!(session.slots().unwrap().active, Some(0));
+    match session.set_slot_pending(0, 0x1000, 0, 2) {
+        Err(Error::UnexpectedResponse(_)) => {}
+        r => panic!("Did not expect: {:?}", r),
+    }
+    assert_eq!(slot_descriptors(&harness)[0].state, SlotState::Confirmed);
+
+    // The other slot is fine, and is newer than the active one.
+    session.set_slot_pending(1, 0x1000, 0, 2).unwrap();
+    assert_eq!(slot_descriptors(&harness)[1].sequence, 5);
+    assert_eq!(session.slots().unwrap().active, Some(0));
+    assert_ready(&harness);
+}
+
+#[test]
+fn set_slot_pending_bad_arguments() {
+    let harness = Harness::new();
+    harness.set_kernel_slots();
+    for (slot, length) in [(2, 0x100), (0, 0), (1, KERNEL_SLOTS.size + 1)] {
+        assert_eq!(
+            harness.command(&Command::SetSlotPending {
+                slot,
+                length,
+                crc: 0,
+                version: 1,
+            }),
+            vec![ESCAPE_CHAR, RES_BADARGS]
+        );
+    }
+    assert!(harness.flash.operations().is_empty());
+    assert_ready(&harness);
+}
+
+#[test]
+fn ext_flash_init() {
+    let harness = Harness::new();
+    Session::new(&harness).ext_flash_init().unwrap();
+    assert_ready(&harness);
+}
+
+#[test]
+fn write_ex_page() {
+    let harness = Harness::new();
+    let external_flash = harness.external_flash.unwrap();
+    let existing = pattern(512);
+    external_flash.load(0x1000, &existing);
+    let data = vec![0x5A; 256];
+
+    // Second half of a 512 byte mock page, so the first half is kept.
+    Session::new(&harness).write_ex_page(0x1100, &data).unwrap();
+    assert_eq!(external_flash.contents(0x1000, 256), &existing[..256]);
+    assert_eq!(external_flash.contents(0x1100, 256), data);
+    assert_eq!(
+        external_flash.operations(),
+        vec![
+            FlashOperation::Read { page_number: 8 },
+            FlashOperation::Write { page_number: 8 },
+        ]
+    );
+    assert!(harness.flash.operations().is_empty());
+    assert_ready(&harness);
+}
+
+#[test]
+fn write_ex_page_unaligned_rejected() {
+    let harness = Harness::new();
+    let data = vec![0x5A; 256];
+    let command = Command::WriteExPage {
+        address: 0x1080,
+        data: &data,
+    };
+    assert_eq!(harness.command(&command), vec![ESCAPE_CHAR, RES_BADADDR]);
+    assert!(harness.external_flash.unwrap().operations().is_empty());
+    assert_ready(&harness);
+}
+
+#[test]
+fn erase_ex_page() {
+    let harness = Harness::new();
+    let external_flash = harness.external_flash.unwrap();
+    let existing = pattern(512);
+    external_flash.load(0x1000, &existing);
+
+    Session::new(&harness).erase_ex_page(0x1000).unwrap();
+    assert_eq!(external_flash.contents(0x1000, 256), vec![0xFF; 256]);
+    assert_eq!(external_flash.contents(0x1100, 256), &existing[256..]);
+    assert_ready(&harness);
+}
+
+#[test]
+fn erase_ex_block() {
+    let harness = Harness::new();
+    let external_flash = harness.external_flash.unwrap();
+    external_flash.load(0x1000, &pattern(4096));
+
+    Session::new(&harness).erase_ex_block(0x1800).unwrap();
+    assert_eq!(
+        external_flash.contents(0x1000, 2048),
+        &pattern(4096)[..2048]
+    );
+    assert_eq!(external_flash.contents(0x1800, 2048), vec![0xFF; 2048]);
+    // Whole mock pages are erased without reading them first.
+    assert_eq!(
+        external_flash.operations(),
+        vec![
+            FlashOperation::Erase { page_number: 12 },
+            FlashOperation::Erase { page_number: 13 },
+            FlashOperation::Erase { page_number: 14 },
+            FlashOperation::Erase { page_number: 15 },
+        ]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn ex_read_range() {
+    let harness = Harness::new();
+    let external_flash = harness.external_flash.unwrap();
+    let mut data = pattern(1024);
+    data[300..400].copy_from_slice(&[ESCAPE_CHAR; 100]);
+    external_flash.load(0x2000, &data);
+
+    // Sent in one chunk per 256 byte external page.
+    let read = Session::new(&harness)
+        .ex_read_range(0x2000 + 200, 600)
+        .unwrap();
+    assert_eq!(read, &data[200..800]);
+    assert_eq!(harness.uart.transmissions().len(), 4);
+    assert_ready(&harness);
+}
+
+#[test]
+fn crc_ext_flash() {
+    let harness = Harness::new();
+    let data = pattern(2048);
+    harness.external_flash.unwrap().load(0x2000, &data);
+
+    let crc = Session::new(&harness)
+        .crc_ext_flash(0x2000 + 16, 1500)
+        .unwrap();
+    assert_eq!(crc, crc32(&data[16..1516]));
+    assert_eq!(harness.uart.transmissions().len(), 1);
+    assert_ready(&harness);
+}
+
+#[test]
+fn ex_out_of_range_rejected() {
+    let harness = Harness::new();
+    assert_eq!(
+        harness.command(&Command::EraseExBlock { address: 0x10000 }),
+        vec![ESCAPE_CHAR, RES_BADADDR]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn ex_commands_without_external_flash() {
+    let harness = Harness::with_flash(MockFlash::new(FLASH_SIZE), LAYOUT, None);
+    assert_eq!(
+        harness.command(&Command::ExtFlashInit),
+        vec![ESCAPE_CHAR, RES_UNKNOWN]
+    );
+    assert_eq!(
+        harness.command(&Command::EraseExPage { address: 0 }),
+        vec![ESCAPE_CHAR, RES_UNKNOWN]
+    );
+    assert_eq!(
+        harness.command(&Command::ExReadRange {
+            address: 0,
+            length: 16
+        }),
+        vec![ESCAPE_CHAR, RES_UNKNOWN]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn change_baud_rate() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+
+    session.set_baud_rate(921600).unwrap();
+    assert_eq!(harness.uart.baud_rates(), vec![115200, 921600]);
+    assert_eq!(harness.timeout.running(), Some(1000));
+
+    session.verify_baud_rate(921600).unwrap();
+    assert_eq!(harness.timeout.running(), None);
+    session.ping().unwrap();
+    assert_eq!(harness.uart.baud_rates(), vec![115200, 921600]);
+    assert_ready(&harness);
+}
+
+#[test]
+fn change_baud_rate_timeout() {
+    let harness = Harness::new();
+    Session::new(&harness).set_baud_rate(921600).unwrap();
+
+    assert!(harness.timeout.expire());
+    assert_eq!(harness.uart.baud_rates(), vec![115200, 921600, 115200]);
+
+    // Too late to verify now.
+    assert_eq!(
+        harness.command(&Command::ChangeBaud {
+            mode: BaudMode::Verify,
+            baud: 921600
+        }),
+        vec![ESCAPE_CHAR, RES_CHANGE_BAUD_FAIL]
+    );
+    assert_eq!(harness.uart.baud_rates(), vec![115200, 921600, 115200]);
+    assert_ready(&harness);
+}
+
+#[test]
+fn change_baud_rate_wrong_verify() {
+    let harness = Harness::new();
+    Session::new(&harness).set_baud_rate(921600).unwrap();
+
+    // The failure is sent at the new rate, then the bootloader goes back.
+    assert_eq!(
+        harness.command(&Command::ChangeBaud {
+            mode: BaudMode::Verify,
+            baud: 460800
+        }),
+        vec![ESCAPE_CHAR, RES_CHANGE_BAUD_FAIL]
+    );
+    assert_eq!(harness.uart.baud_rates(), vec![115200, 921600, 115200]);
+    assert_eq!(harness.timeout.running(), None);
+    Session::new(&harness).ping().unwrap();
+    assert_ready(&harness);
+}
+
+#[test]
+fn change_baud_rate_other_command_reverts() {
+    let harness = Harness::new();
+    Session::new(&harness).set_baud_rate(921600).unwrap();
+
+    assert_eq!(
+        harness.command(&Command::Ping),
+        vec![ESCAPE_CHAR, RES_CHANGE_BAUD_FAIL]
+    );
+    assert_eq!(harness.uart.baud_rates(), vec![115200, 921600, 115200]);
+    assert_ready(&harness);
+}
+
+#[test]
+fn exit() {
+    let harness = Harness::new();
+    Session::new(&harness).ping().unwrap();
+    assert!(!harness.exited());
+
+    Session::new(&harness).exit().unwrap();
+    harness.run_until_idle();
+    assert!(harness.exited());
+}
+
+#[test]
+fn unknown_command() {
+    let harness = Harness::new();
+    assert_eq!(
+        harness.command(&Command::ClockOut),
+        vec![ESCAPE_CHAR, RES_UNKNOWN]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn bad_arguments() {
+    let harness = Harness::new();
+    // RESET, then an EPAGE with a two byte address.
+    let frame = [ESCAPE_CHAR, 0x05, 0x00, 0x01, ESCAPE_CHAR, 0x06];
+    assert_eq!(harness.raw(&frame), vec![ESCAPE_CHAR, RES_BADARGS]);
+    assert!(harness.flash.operations().is_empty());
+    assert_ready(&harness);
+}
+
+#[test]
+fn command_split_across_receives() {
+    let harness = Harness::new();
+    let data = pattern(512);
+    let frame: Vec<u8> = CommandEncoder::new(&Command::WritePage {
+        address: DATA_ADDRESS,
+        data: &data,
+    })
+    .unwrap()
+    .collect();
+
+    // Like a CDC host sending 64 byte USB packets.
+    let (last, rest) = frame.split_last().unwrap();
+    for packet in rest.chunks(64) {
+        assert!(harness.raw(packet).is_empty());
+        assert_ready(&harness);
+    }
+    assert_eq!(harness.raw(&[*last]), vec![ESCAPE_CHAR, RES_OK]);
+    assert_eq!(harness.flash.contents(DATA_ADDRESS as usize, 512), data);
+    assert_ready(&harness);
+}
+
+#[test]
+fn command_split_after_escape() {
+    let harness = Harness::new();
+    assert!(harness.raw(&[ESCAPE_CHAR]).is_empty());
+    assert_eq!(harness.raw(&[0x01]), vec![ESCAPE_CHAR, RES_PONG]);
+    assert_ready(&harness);
+}
+
+#[test]
+fn reset_drops_partial_command() {
+    let harness = Harness::new();
+    // The start of an EPAGE, abandoned by the host.
+    assert!(harness.raw(&[0x00, 0x00]).is_empty());
+    assert_eq!(
+        harness.command(&Command::ErasePage {
+            address: DATA_ADDRESS
+        }),
+        vec![ESCAPE_CHAR, RES_OK]
+    );
+    assert_ready(&harness);
+}
+
+#[test]
+fn commands_in_sequence() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    let data = pattern(1024);
+
+    session.erase_page(DATA_ADDRESS).unwrap();
+    session.write_page(DATA_ADDRESS, &data[..512]).unwrap();
+    session
+        .write_page(DATA_ADDRESS + 512, &data[512..])
+        .unwrap();
+    assert_eq!(session.read_range(DATA_ADDRESS, 1024).unwrap(), data);
+    assert_eq!(
+        session.crc_int_flash(DATA_ADDRESS, 1024).unwrap(),
+        crc32(&data)
+    );
+    session.ping().unwrap();
+    assert_ready(&harness);
+}
@@ -0,0 +1,153 @@
+//! Check that pages already holding the data are not written again when
+//! skipping is on.
+
+use bootloader_mock::{
+    assert_response, FlashFault, FlashOperation, Harness, DATA_ADDRESS, ESCAPE_CHAR, PAGE_SIZE,
+    RES_UNKNOWN,
+};
+use tock_bootloader_protocol::client::Session;
+use tock_bootloader_protocol::{Command, CMD_GET_SKIPPED_PAGES, CMD_WRITE_STREAM_PAGE};
+
+const WRITE: FlashOperation = FlashOperation::Write {
+    page_number: DATA_ADDRESS as usize / PAGE_SIZE,
+};
+const READ: FlashOperation = FlashOperation::Read {
+    page_number: DATA_ADDRESS as usize / PAGE_SIZE,
+};
+const NEXT_WRITE: FlashOperation = FlashOperation::Write {
+    page_number: DATA_ADDRESS as usize / PAGE_SIZE + 1,
+};
+const NEXT_READ: FlashOperation = FlashOperation::Read {
+    page_number: DATA_ADDRESS as usize / PAGE_SIZE + 1,
+};
+
+#[test]
+fn off_by_default() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    session.write_page(DATA_ADDRESS, &[0xFF; 512]).unwrap();
+    assert_eq!(harness.flash.operations().last(), Some(&WRITE));
+    assert_eq!(
+        harness.command(&Command::GetSkippedPages),
+        vec![ESCAPE_CHAR, RES_UNKNOWN]
+    );
+    assert!(!session
+        .capabilities()
+        .unwrap()
+        .supports(CMD_GET_SKIPPED_PAGES));
+}
+
+#[test]
+fn unchanged_pages_skipped() {
+    let harness = Harness::new();
+    harness.bootloader.set_skip_unchanged_pages(true).unwrap();
+    let mut session = Session::new(&harness);
+    assert!(session
+        .capabilities()
+        .unwrap()
+        .supports(CMD_GET_SKIPPED_PAGES));
+    assert_eq!(session.skipped_pages().unwrap(), 0);
+
+    harness.flash.clear_operations();
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    assert_eq!(harness.flash.operations(), vec![READ, WRITE]);
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 512),
+        [0xAA; 512]
+    );
+
+    harness.flash.clear_operations();
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    assert_eq!(harness.flash.operations(), vec![READ, READ]);
+    assert_eq!(session.skipped_pages().unwrap(), 2);
+
+    // One changed byte is enough to write it.
+    let mut page = [0xAA; 512];
+    page[511] = 0;
+    session.write_page(DATA_ADDRESS, &page).unwrap();
+    assert_eq!(harness.flash.operations().last(), Some(&WRITE));
+    assert_eq!(harness.flash.contents(DATA_ADDRESS as usize, 512), page);
+    assert_eq!(session.skipped_pages().unwrap(), 2);
+}
+
+#[test]
+fn with_write_verify() {
+    let harness = Harness::new();
+    harness.bootloader.set_skip_unchanged_pages(true).unwrap();
+    harness.bootloader.set_write_verify(1).unwrap();
+    let mut session = Session::new(&harness);
+
+    harness.flash.clear_operations();
+    harness.flash.inject_fault(WRITE, FlashFault::Corrupt);
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    assert_eq!(
+        harness.flash.operations(),
+        vec![READ, WRITE, READ, WRITE, READ]
+    );
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 512),
+        [0xAA; 512]
+    );
+}
+
+#[test]
+fn compare_read_fails() {
+    let harness = Harness::new();
+    harness.bootloader.set_skip_unchanged_pages(true).unwrap();
+    let mut session = Session::new(&harness);
+    harness.flash.inject_fault(READ, FlashFault::Report);
+    assert!(session.write_page(DATA_ADDRESS, &[0xAA; 512]).is_err());
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    assert_eq!(session.skipped_pages().unwrap(), 0);
+}
+
+#[test]
+fn unchanged_burst_pages_skipped() {
+    let harness = Harness::new();
+    harness.bootloader.set_skip_unchanged_pages(true).unwrap();
+    let mut session = Session::new(&harness);
+    let mut data = [[0xAA; 512], [0x55; 512]].concat();
+
+    harness.flash.clear_operations();
+    let crc = session.write_pages(DATA_ADDRESS, &data).unwrap();
+    assert_eq!(
+        harness.flash.operations(),
+        vec![READ, WRITE, NEXT_READ, NEXT_WRITE, READ, NEXT_READ]
+    );
+
+    // Only the changed page is written, and the CRC still covers all of it.
+    harness.flash.clear_operations();
+    assert_eq!(session.write_pages(DATA_ADDRESS, &data).unwrap(), crc);
+    data[1023] = 0;
+    session.write_pages(DATA_ADDRESS, &data).unwrap();
+    assert_eq!(
+        harness.flash.operations(),
+        vec![READ, NEXT_READ, READ, NEXT_READ, READ, NEXT_READ, NEXT_WRITE, READ, NEXT_READ]
+    );
+    assert_eq!(harness.flash.contents(DATA_ADDRESS as usize, 1024), data);
+    assert_eq!(session.skipped_pages().unwrap(), 3);
+
+    harness.flash.inject_fault(NEXT_READ, FlashFault::Report);
+    assert_response(
+        session.write_pages(DATA_ADDRESS, &data),
+        "PageFailed { address: 66048, result: 44 }",
+    );
+    session.ping().unwrap();
+}
+
+#[test]
+fn streaming_turned_off() {
+    let harness = Harness::new();
+    harness.set_streaming();
+    let mut session = Session::new(&harness);
+    assert!(session
+        .capabilities()
+        .unwrap()
+        .supports(CMD_WRITE_STREAM_PAGE));
+    harness.bootloader.set_skip_unchanged_pages(true).unwrap();
+    assert!(!session
+        .capabilities()
+        .unwrap()
+        .supports(CMD_WRITE_STREAM_PAGE));
+}
@@ -0,0 +1,326 @@
+//! Check that streamed pages are written in order and acknowledged, including
+//! while the flash is still busy with the page before.
+
+use bootloader_mock::{
+    assert_response, FlashFault, FlashOperation, Harness, DATA_ADDRESS, ESCAPE_CHAR, PAGE_SIZE,
+    RES_BADADDR, RES_BADARGS, RES_FLASH_ERROR, RES_INTERNAL_ERROR, RES_OK, RES_PONG, RES_UNKNOWN,
+};
+use tock_bootloader_protocol::client::Session;
+use tock_bootloader_protocol::{
+    Command, CommandEncoder, Response, ResponseEncoder, CMD_WRITE_STREAM_PAGE,
+};
+
+fn write(index: usize) -> FlashOperation {
+    FlashOperation::Write {
+        page_number: DATA_ADDRESS as usize / PAGE_SIZE + index,
+    }
+}
+
+fn page_address(index: usize) -> u32 {
+    DATA_ADDRESS + (index * PAGE_SIZE) as u32
+}
+
+/// The frame for streaming page `index` filled with `fill`.
+fn stream_frame(index: usize, fill: u8) -> Vec<u8> {
+    CommandEncoder::new(&Command::WriteStreamPage {
+        address: page_address(index),
+        data: &[fill; PAGE_SIZE],
+    })
+    .unwrap()
+    .collect()
+}
+
+fn page_ack(address: u32, result: u8) -> Vec<u8> {
+    ResponseEncoder::new(&Response::PageAck { address, result })
+        .unwrap()
+        .collect()
+}
+
+/// An image of `pages` pages where every page is filled with its index.
+fn image(pages: usize) -> Vec<u8> {
+    (0..pages)
+        .flat_map(|index| vec![index as u8; PAGE_SIZE])
+        .collect()
+}
+
+#[test]
+fn off_by_default() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    assert!(!session
+        .capabilities()
+        .unwrap()
+        .supports(CMD_WRITE_STREAM_PAGE));
+    assert_eq!(
+        harness.raw(&stream_frame(0, 0xAA)),
+        vec![ESCAPE_CHAR, RES_UNKNOWN]
+    );
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, PAGE_SIZE),
+        [0xFF; PAGE_SIZE]
+    );
+}
+
+#[test]
+fn off_with_write_verify() {
+    let harness = Harness::new();
+    harness.set_streaming();
+    harness.bootloader.set_write_verify(1).unwrap();
+    let mut session = Session::new(&harness);
+    assert!(!session
+        .capabilities()
+        .unwrap()
+        .supports(CMD_WRITE_STREAM_PAGE));
+    assert_eq!(
+        harness.raw(&stream_frame(0, 0xAA)),
+        vec![ESCAPE_CHAR, RES_UNKNOWN]
+    );
+}
+
+#[test]
+fn stream_image() {
+    let harness = Harness::new();
+    harness.set_streaming();
+    let mut session = Session::new(&harness);
+    assert!(session
+        .capabilities()
+        .unwrap()
+        .supports(CMD_WRITE_STREAM_PAGE));
+
+    let image = image(5);
+    harness.flash.clear_operations();
+    session.stream_image(DATA_ADDRESS, &image).unwrap();
+    assert_eq!(
+        harness.flash.operations(),
+        (0..5).map(write).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, image.len()),
+        image
+    );
+
+    // Back to normal commands afterwards.
+    session.ping().unwrap();
+}
+
+#[test]
+fn pages_wait_while_writing() {
+    let harness = Harness::new();
+    harness.set_streaming();
+    harness.flash.clear_operations();
+    harness.flash.hold();
+
+    // The first page is written, the second waits in the spare page buffer
+    // and the third has to wait in the UART buffer.
+    for index in 0..3 {
+        harness.uart.host_write(&stream_frame(index, index as u8));
+    }
+    harness.run_until_idle();
+    assert_eq!(harness.flash.operations(), vec![write(0)]);
+    assert!(!harness.uart.is_receiving());
+    assert_eq!(harness.uart.take_output(), vec![]);
+
+    harness.flash.release();
+    harness.run_until_idle();
+    assert_eq!(
+        harness.flash.operations(),
+        vec![write(0), write(1), write(2)]
+    );
+    let acks: Vec<u8> = (0..3)
+        .flat_map(|index| page_ack(page_address(index), RES_OK))
+        .collect();
+    assert_eq!(harness.uart.take_output(), acks);
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 3 * PAGE_SIZE),
+        image(3)
+    );
+    assert!(harness.uart.is_receiving());
+}
+
+#[test]
+fn pages_in_one_burst() {
+    let harness = Harness::new();
+    harness.set_streaming();
+    harness.flash.clear_operations();
+    harness.flash.hold();
+
+    // Each receive ends partway through the next page, which is decoded
+    // before listening again. The third page is still in the UART buffer
+    // when both page buffers are full.
+    let frames: Vec<u8> = (0..3)
+        .flat_map(|index| stream_frame(index, index as u8))
+        .collect();
+    harness.uart.host_write(&frames);
+    harness.run_until_idle();
+    assert_eq!(harness.flash.operations(), vec![write(0)]);
+    assert!(!harness.uart.is_receiving());
+
+    harness.flash.release();
+    harness.run_until_idle();
+    assert_eq!(
+        harness.flash.operations(),
+        vec![write(0), write(1), write(2)]
+    );
+    let acks: Vec<u8> = (0..3)
+        .flat_map(|index| page_ack(page_address(index), RES_OK))
+        .collect();
+    assert_eq!(harness.uart.take_output(), acks);
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 3 * PAGE_SIZE),
+        image(3)
+    );
+    assert!(harness.uart.is_receiving());
+}
+
+#[test]
+fn other_commands_wait() {
+    let harness = Harness::new();
+    harness.set_streaming();
+    harness.flash.hold();
+    assert_eq!(harness.raw(&stream_frame(0, 0xAA)), vec![]);
+    assert_eq!(
+        harness.command(&Command::Ping),
+        vec![ESCAPE_CHAR, RES_BADARGS]
+    );
+
+    harness.flash.release();
+    harness.run_until_idle();
+    assert_eq!(
+        harness.uart.take_output(),
+        page_ack(page_address(0), RES_OK)
+    );
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+}
+
+#[test]
+fn flash_errors_acked() {
+    let harness = Harness::new();
+    harness.set_streaming();
+    harness.flash.inject_fault(write(1), FlashFault::Report);
+    harness.flash.inject_fault(write(2), FlashFault::Refuse);
+    harness.flash.hold();
+    for index in 0..3 {
+        harness.uart.host_write(&stream_frame(index, 0xAA));
+    }
+    harness.flash.release();
+    harness.run_until_idle();
+
+    let mut acks = page_ack(page_address(0), RES_OK);
+    acks.extend(page_ack(page_address(1), RES_FLASH_ERROR));
+    acks.extend(page_ack(page_address(2), RES_FLASH_ERROR));
+    assert_eq!(harness.uart.take_output(), acks);
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+
+    // The client stops at the first page that wasn't written.
+    let mut session = Session::new(&harness);
+    harness.flash.inject_fault(write(0), FlashFault::Report);
+    assert_response(
+        session.stream_image(DATA_ADDRESS, &image(4)),
+        "PageAck { address: 65536, result: 44 }",
+    );
+    session.ping().unwrap();
+}
+
+#[test]
+fn bad_address_acked() {
+    let harness = Harness::new();
+    harness.set_streaming();
+    let frame: Vec<u8> = CommandEncoder::new(&Command::WriteStreamPage {
+        address: 0,
+        data: &[0xAA; PAGE_SIZE],
+    })
+    .unwrap()
+    .collect();
+    assert_eq!(harness.raw(&frame), page_ack(0, RES_BADADDR));
+    assert_eq!(harness.flash.contents(0, 4), vec![0xFF; 4]);
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+}
+
+#[test]
+fn acks_wait_for_uart() {
+    let harness = Harness::new();
+    harness.set_streaming();
+    harness.flash.clear_operations();
+    harness.uart.hold_transmit();
+
+    // Every page is written while the first ack is still being sent.
+    for index in 0..3 {
+        harness.uart.host_write(&stream_frame(index, index as u8));
+    }
+    harness.run_until_idle();
+    assert_eq!(
+        harness.flash.operations(),
+        vec![write(0), write(1), write(2)]
+    );
+    assert_eq!(harness.uart.take_output(), vec![]);
+
+    harness.uart.release_transmit();
+    harness.run_until_idle();
+    let acks: Vec<u8> = (0..3)
+        .flat_map(|index| page_ack(page_address(index), RES_OK))
+        .collect();
+    assert_eq!(harness.uart.take_output(), acks);
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+}
+
+#[test]
+fn response_waits_for_acks() {
+    let harness = Harness::new();
+    harness.set_streaming();
+    harness.uart.hold_transmit();
+    harness.flash.hold();
+    harness.uart.host_write(&stream_frame(0, 0xAA));
+    harness.uart.host_write(&stream_frame(1, 0xAA));
+    harness.run_until_idle();
+
+    // The first page is written and its ack starts going out, then a command
+    // that has to wait is refused while the UART is still sending it.
+    harness.flash.release();
+    harness.flash.service();
+    harness.flash.hold();
+    harness.uart.host_write(
+        &CommandEncoder::new(&Command::Ping)
+            .unwrap()
+            .collect::<Vec<_>>(),
+    );
+    harness.run_until_idle();
+    assert_eq!(harness.uart.take_output(), vec![]);
+
+    harness.uart.release_transmit();
+    harness.flash.release();
+    harness.run_until_idle();
+    let mut output = page_ack(page_address(0), RES_OK);
+    output.extend(page_ack(page_address(1), RES_OK));
+    output.extend(&[ESCAPE_CHAR, RES_BADARGS]);
+    assert_eq!(harness.uart.take_output(), output);
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+}
+
+#[test]
+fn lost_page_acked() {
+    let harness = Harness::new();
+    harness.set_streaming();
+    harness.flash.hold();
+    harness.uart.host_write(&stream_frame(0, 0xAA));
+    harness.run_until_idle();
+
+    harness.uart.fail_next_receive();
+    harness.uart.host_write(&stream_frame(1, 0xAA));
+    harness.run_until_idle();
+    assert_eq!(
+        harness.uart.take_output(),
+        page_ack(page_address(1), RES_INTERNAL_ERROR)
+    );
+
+    harness.flash.release();
+    harness.run_until_idle();
+    assert_eq!(
+        harness.uart.take_output(),
+        page_ack(page_address(0), RES_OK)
+    );
+    assert_eq!(
+        harness.flash.contents(page_address(1) as usize, PAGE_SIZE),
+        [0xFF; PAGE_SIZE]
+    );
+    assert_eq!(harness.command(&Command::Ping), vec![ESCAPE_CHAR, RES_PONG]);
+}
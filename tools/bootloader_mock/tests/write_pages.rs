@@ -0,0 +1,127 @@
+//! Check that bursts of pages are written in order and answered once.
+
+use bootloader::bootloader_crc;
+use bootloader_mock::{
+    assert_response, FlashFault, FlashOperation, Harness, DATA_ADDRESS, ESCAPE_CHAR, PAGE_SIZE,
+    RES_BADARGS,
+};
+use tock_bootloader_protocol::client::Session;
+use tock_bootloader_protocol::{Command, CommandEncoder, CMD_WRITE_PAGES, MAX_WRITE_PAGES};
+
+fn write(index: usize) -> FlashOperation {
+    FlashOperation::Write {
+        page_number: DATA_ADDRESS as usize / PAGE_SIZE + index,
+    }
+}
+
+fn read(index: usize) -> FlashOperation {
+    FlashOperation::Read {
+        page_number: DATA_ADDRESS as usize / PAGE_SIZE + index,
+    }
+}
+
+/// `pages` pages where every page is filled with its index plus one.
+fn pages(pages: usize) -> Vec<u8> {
+    (0..pages)
+        .flat_map(|index| vec![index as u8 + 1; PAGE_SIZE])
+        .collect()
+}
+
+#[test]
+fn pages_written() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    assert!(session.capabilities().unwrap().supports(CMD_WRITE_PAGES));
+
+    let data = pages(MAX_WRITE_PAGES);
+    harness.flash.clear_operations();
+    let crc = session.write_pages(DATA_ADDRESS, &data).unwrap();
+    assert_eq!(crc, bootloader_crc::update(0xFFFFFFFF, &data) ^ 0xFFFFFFFF);
+    // Every page is read back for the CRC once they are all written.
+    assert_eq!(
+        harness.flash.operations(),
+        (0..MAX_WRITE_PAGES)
+            .map(write)
+            .chain((0..MAX_WRITE_PAGES).map(read))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, data.len()),
+        data
+    );
+
+    // A single page works too.
+    let crc = session.write_pages(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    assert_eq!(crc, session.crc_int_flash(DATA_ADDRESS, 512).unwrap());
+}
+
+#[test]
+fn failed_page_named() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    harness.flash.inject_fault(write(2), FlashFault::Report);
+    harness.flash.clear_operations();
+    assert_response(
+        session.write_pages(DATA_ADDRESS, &pages(4)),
+        "PageFailed { address: 66560, result: 44 }",
+    );
+    // The pages after it were not attempted.
+    assert_eq!(
+        harness.flash.operations(),
+        vec![write(0), write(1), write(2)]
+    );
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 2 * PAGE_SIZE),
+        pages(2)
+    );
+
+    harness.flash.inject_fault(write(0), FlashFault::Refuse);
+    assert_response(
+        session.write_pages(DATA_ADDRESS, &pages(4)),
+        "PageFailed { address: 65536, result: 44 }",
+    );
+    session.ping().unwrap();
+}
+
+#[test]
+fn corrupt_page_changes_crc() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    let data = pages(4);
+    harness.flash.inject_fault(write(2), FlashFault::Corrupt);
+    let crc = session.write_pages(DATA_ADDRESS, &data).unwrap();
+    assert_ne!(crc, bootloader_crc::update(0xFFFFFFFF, &data) ^ 0xFFFFFFFF);
+    assert_eq!(crc, session.crc_int_flash(DATA_ADDRESS, 4 * 512).unwrap());
+
+    harness.flash.inject_fault(read(3), FlashFault::Report);
+    assert_response(
+        session.write_pages(DATA_ADDRESS, &data),
+        "PageFailed { address: 67072, result: 44 }",
+    );
+    session.ping().unwrap();
+}
+
+#[test]
+fn protected_range_refused() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    // Ends inside flash the bootloader owns.
+    assert_response(session.write_pages(0x7E00, &pages(2)), "BadAddress");
+    assert_eq!(harness.flash.contents(0x7E00, 4), vec![0xFF; 4]);
+}
+
+#[test]
+fn count_must_match_data() {
+    let harness = Harness::new();
+    // The encoder won't build this, so put the frame together by hand.
+    let mut frame: Vec<u8> = CommandEncoder::new(&Command::Reset).unwrap().collect();
+    frame.extend(&DATA_ADDRESS.to_le_bytes());
+    frame.push(2);
+    frame.extend(&[0xAA; PAGE_SIZE]);
+    frame.extend(&[ESCAPE_CHAR, CMD_WRITE_PAGES]);
+    assert_eq!(harness.raw(&frame), vec![ESCAPE_CHAR, RES_BADARGS]);
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, PAGE_SIZE),
+        [0xFF; PAGE_SIZE]
+    );
+}
@@ -0,0 +1,159 @@
+//! Check that written pages are read back and rewritten when verification is
+//! on.
+
+use bootloader::bootloader::Bootloader;
+use bootloader_mock::{
+    assert_response, FlashFault, FlashOperation, Harness, MockFlash, MockUart, DATA_ADDRESS,
+    FLASH_SIZE, LAYOUT, PAGE_SIZE,
+};
+use kernel::ErrorCode;
+use tock_bootloader_protocol::client::Session;
+
+const WRITE: FlashOperation = FlashOperation::Write {
+    page_number: DATA_ADDRESS as usize / PAGE_SIZE,
+};
+const READ: FlashOperation = FlashOperation::Read {
+    page_number: DATA_ADDRESS as usize / PAGE_SIZE,
+};
+const NEXT_WRITE: FlashOperation = FlashOperation::Write {
+    page_number: DATA_ADDRESS as usize / PAGE_SIZE + 1,
+};
+const NEXT_READ: FlashOperation = FlashOperation::Read {
+    page_number: DATA_ADDRESS as usize / PAGE_SIZE + 1,
+};
+
+#[test]
+fn off_by_default() {
+    let harness = Harness::new();
+    let mut session = Session::new(&harness);
+    harness.flash.clear_operations();
+    harness.flash.inject_fault(WRITE, FlashFault::Corrupt);
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    assert_eq!(harness.flash.operations(), vec![WRITE]);
+    assert_eq!(harness.flash.contents(DATA_ADDRESS as usize, 1), [0xAB]);
+}
+
+#[test]
+fn bad_write_retried() {
+    let harness = Harness::new();
+    harness.bootloader.set_write_verify(2).unwrap();
+    let mut session = Session::new(&harness);
+
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    harness.flash.clear_operations();
+    harness.flash.inject_fault(WRITE, FlashFault::Corrupt);
+    harness.flash.inject_fault(WRITE, FlashFault::Corrupt);
+    session.write_page(DATA_ADDRESS, &[0x55; 512]).unwrap();
+    assert_eq!(
+        harness.flash.operations(),
+        vec![WRITE, READ, WRITE, READ, WRITE, READ]
+    );
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 512),
+        [0x55; 512]
+    );
+}
+
+#[test]
+fn retries_run_out() {
+    let harness = Harness::new();
+    harness.bootloader.set_write_verify(1).unwrap();
+    let mut session = Session::new(&harness);
+    harness.flash.inject_fault(WRITE, FlashFault::Corrupt);
+    harness.flash.inject_fault(WRITE, FlashFault::Corrupt);
+    assert_response(
+        session.write_page(DATA_ADDRESS, &[0xAA; 512]),
+        "VerifyFailed",
+    );
+
+    // The next write starts with all its retries again.
+    harness.flash.inject_fault(WRITE, FlashFault::Corrupt);
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+    assert_eq!(
+        harness.flash.contents(DATA_ADDRESS as usize, 512),
+        [0xAA; 512]
+    );
+}
+
+#[test]
+fn read_back_fails() {
+    let harness = Harness::new();
+    harness.bootloader.set_write_verify(2).unwrap();
+    let mut session = Session::new(&harness);
+    harness.flash.inject_fault(READ, FlashFault::Report);
+    assert_response(session.write_page(DATA_ADDRESS, &[0xAA; 512]), "FlashError");
+    session.write_page(DATA_ADDRESS, &[0xAA; 512]).unwrap();
+}
+
+#[test]
+fn burst_pages_verified() {
+    let harness = Harness::new();
+    harness.bootloader.set_write_verify(1).unwrap();
+    let mut session = Session::new(&harness);
+    let data = [[0xAA; 512], [0x55; 512]].concat();
+
+    harness.flash.clear_operations();
+    harness.flash.inject_fault(NEXT_WRITE, FlashFault::Corrupt);
+    session.write_pages(DATA_ADDRESS, &data).unwrap();
+    assert_eq!(
+        harness.flash.operations(),
+        vec![WRITE, READ, NEXT_WRITE, NEXT_READ, NEXT_WRITE, NEXT_READ, READ, NEXT_READ]
+    );
+    assert_eq!(harness.flash.contents(DATA_ADDRESS as usize, 1024), data);
+
+    // A page that runs out of retries is named, and the burst stops there.
+    harness.flash.inject_fault(WRITE, FlashFault::Corrupt);
+    harness.flash.inject_fault(WRITE, FlashFault::Corrupt);
+    harness.flash.clear_operations();
+    assert_response(
+        session.write_pages(DATA_ADDRESS, &data),
+        "PageFailed { address: 65536, result: 45 }",
+    );
+    assert_eq!(harness.flash.operations(), vec![WRITE, READ, WRITE, READ]);
+
+    harness.flash.inject_fault(NEXT_READ, FlashFault::Report);
+    assert_response(
+        session.write_pages(DATA_ADDRESS, &data),
+        "PageFailed { address: 66048, result: 44 }",
+    );
+    session.ping().unwrap();
+}
+
+#[test]
+fn attributes_not_verified() {
+    let harness = Harness::new();
+    harness.bootloader.set_write_verify(1).unwrap();
+    let mut session = Session::new(&harness);
+    let page_number = LAYOUT.attributes_address / PAGE_SIZE;
+    harness.flash.clear_operations();
+    session.set_attr(0, b"board", b"test").unwrap();
+    assert_eq!(
+        harness.flash.operations(),
+        vec![
+            FlashOperation::Read { page_number },
+            FlashOperation::Write { page_number }
+        ]
+    );
+}
+
+#[test]
+fn uart_buffer_too_small() {
+    let uart: &'static MockUart = Box::leak(Box::new(MockUart::new()));
+    let flash: &'static MockFlash = Box::leak(Box::new(MockFlash::new(FLASH_SIZE)));
+    let reset_function: &'static dyn Fn() = Box::leak(Box::new(|| {}));
+    let bootloader: Bootloader<MockUart, MockFlash> = Bootloader::new_with_layout(
+        uart,
+        flash,
+        reset_function,
+        Box::leak(Box::default()),
+        Box::leak(vec![0; PAGE_SIZE - 1].into_boxed_slice()),
+        LAYOUT,
+    );
+    // Pages from the host would not fit to compare against.
+    assert_eq!(bootloader.set_write_verify(1), Err(ErrorCode::SIZE));
+    assert_eq!(
+        bootloader.set_skip_unchanged_pages(true),
+        Err(ErrorCode::SIZE)
+    );
+    assert_eq!(bootloader.set_skip_unchanged_pages(false), Ok(()));
+}
@@ -0,0 +1,103 @@
+//! Create keys for secure boot and sign kernel images with them.
+//!
+//! ```text
+//! $ cargo run -- keygen kernel.key kernel.pub
+//! $ cargo run -- sign kernel.key kernel.bin kernel-signed.bin
+//! $ cargo run -- verify kernel.pub kernel-signed.bin
+//! ```
+//!
+//! Keys are stored as hex: the secret key file holds the 32 byte seed, and
+//! the public key file the 32 byte public key that
+//! `bootloader_attributes::read_public_key()` builds into the bootloader.
+
+use std::env;
+use std::fs;
+use std::process;
+
+use ed25519_compact::{KeyPair, PublicKey, Seed, Signature};
+use tock_bootloader_protocol::signature::{footer_header, SignedImage};
+
+const USAGE: &str = "\
+Usage:
+  bootloader_sign keygen <secret key file> <public key file>
+  bootloader_sign sign <secret key file> <kernel> <signed image>
+  bootloader_sign verify <public key file> <signed image>
+";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let result = match args.as_slice() {
+        ["keygen", secret_key, public_key] => keygen(secret_key, public_key),
+        ["sign", secret_key, kernel, signed] => sign(secret_key, kernel, signed),
+        ["verify", public_key, signed] => verify(public_key, signed),
+        _ => {
+            eprint!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(error) = result {
+        eprintln!("bootloader_sign: {}", error);
+        process::exit(1);
+    }
+}
+
+fn keygen(secret_key_path: &str, public_key_path: &str) -> Result<(), String> {
+    let seed = Seed::generate();
+    let key_pair = KeyPair::from_seed(seed);
+    write_hex(secret_key_path, seed.as_ref())?;
+    write_hex(public_key_path, key_pair.pk.as_ref())
+}
+
+fn sign(secret_key_path: &str, kernel_path: &str, signed_path: &str) -> Result<(), String> {
+    let seed = Seed::from_slice(&read_hex(secret_key_path)?)
+        .map_err(|_| format!("{}: not a secret key", secret_key_path))?;
+    let key_pair = KeyPair::from_seed(seed);
+
+    let mut image = fs::read(kernel_path).map_err(|e| format!("{}: {}", kernel_path, e))?;
+    let kernel_length =
+        u32::try_from(image.len()).map_err(|_| format!("{}: kernel too large", kernel_path))?;
+    image.extend(footer_header(kernel_length));
+    let signature = key_pair.sk.sign(&image, None);
+    image.extend(signature.as_ref());
+
+    fs::write(signed_path, &image).map_err(|e| format!("{}: {}", signed_path, e))?;
+    println!(
+        "Signed {} bytes of kernel, use {} as the kernel length",
+        kernel_length,
+        image.len()
+    );
+    Ok(())
+}
+
+fn verify(public_key_path: &str, signed_path: &str) -> Result<(), String> {
+    let public_key = PublicKey::from_slice(&read_hex(public_key_path)?)
+        .map_err(|_| format!("{}: not a public key", public_key_path))?;
+    let image = fs::read(signed_path).map_err(|e| format!("{}: {}", signed_path, e))?;
+    let signed =
+        SignedImage::parse(&image).map_err(|_| format!("{}: no signature footer", signed_path))?;
+    let signature = Signature::from_slice(signed.signature)
+        .map_err(|_| format!("{}: bad signature", signed_path))?;
+    public_key
+        .verify(signed.message, &signature)
+        .map_err(|_| format!("{}: signature does not match the key", signed_path))?;
+    println!("Signature OK, kernel is {} bytes", signed.kernel.len());
+    Ok(())
+}
+
+fn read_hex(path: &str) -> Result<Vec<u8>, String> {
+    let hex = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let hex = hex.trim();
+    if hex.len() % 2 != 0 {
+        return Err(format!("{}: not hex", path));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("{}: not hex", path)))
+        .collect()
+}
+
+fn write_hex(path: &str, bytes: &[u8]) -> Result<(), String> {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    fs::write(path, hex + "\n").map_err(|e| format!("{}: {}", path, e))
+}